@@ -0,0 +1,164 @@
+// Jackson Coxson
+// Derive macros for idevice
+
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Fields, LitStr};
+
+/// Generates `FromPlist` and `FromPlistValue` impls for a struct with named fields.
+///
+/// Container attributes:
+/// - `#[plist(rename_all = "PascalCase")]` or `"camelCase"` to derive keys from field names
+///
+/// Field attributes:
+/// - `#[plist(rename = "Key")]` to use an exact key
+/// - `#[plist(default)]` to use `Default::default()` when the key is missing
+///
+/// `Option` fields are `None` when the key is missing.
+#[proc_macro_derive(FromPlist, attributes(plist))]
+pub fn derive_from_plist(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match expand(input) {
+        Ok(t) => t.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+fn expand(input: DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let mut rename_all = None;
+    for attr in &input.attrs {
+        if !attr.path().is_ident("plist") {
+            continue;
+        }
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("rename_all") {
+                let s: LitStr = meta.value()?.parse()?;
+                match s.value().as_str() {
+                    "PascalCase" | "camelCase" => rename_all = Some(s.value()),
+                    _ => return Err(meta.error("expected `PascalCase` or `camelCase`")),
+                }
+                Ok(())
+            } else {
+                Err(meta.error("unknown plist container attribute"))
+            }
+        })?;
+    }
+
+    let fields = match &input.data {
+        Data::Struct(s) => match &s.fields {
+            Fields::Named(f) => &f.named,
+            _ => {
+                return Err(syn::Error::new_spanned(
+                    name,
+                    "FromPlist can only be derived for structs with named fields",
+                ))
+            }
+        },
+        _ => {
+            return Err(syn::Error::new_spanned(
+                name,
+                "FromPlist can only be derived for structs",
+            ))
+        }
+    };
+
+    let mut inits = Vec::new();
+    for field in fields {
+        // We are safe to unwrap as the fields are named
+        let ident = field.ident.as_ref().unwrap();
+        let ty = &field.ty;
+
+        let mut key = None;
+        let mut default = false;
+        for attr in &field.attrs {
+            if !attr.path().is_ident("plist") {
+                continue;
+            }
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("rename") {
+                    let s: LitStr = meta.value()?.parse()?;
+                    key = Some(s.value());
+                    Ok(())
+                } else if meta.path.is_ident("default") {
+                    default = true;
+                    Ok(())
+                } else {
+                    Err(meta.error("unknown plist field attribute"))
+                }
+            })?;
+        }
+        let key = key.unwrap_or_else(|| {
+            let raw = ident.to_string();
+            match rename_all.as_deref() {
+                Some("PascalCase") => to_case(&raw, true),
+                Some("camelCase") => to_case(&raw, false),
+                _ => raw,
+            }
+        });
+
+        let missing = if default {
+            quote! { ::core::default::Default::default() }
+        } else {
+            quote! {
+                <#ty as ::idevice::utils::schema::FromPlistValue>::missing(#key)?
+            }
+        };
+
+        inits.push(quote! {
+            #ident: match dict.get(#key) {
+                Some(v) => <#ty as ::idevice::utils::schema::FromPlistValue>::from_plist_value(v)
+                    .map_err(|e| e.within(#key))?,
+                None => #missing,
+            }
+        });
+    }
+
+    Ok(quote! {
+        impl #impl_generics ::idevice::utils::schema::FromPlist for #name #ty_generics #where_clause {
+            fn from_plist(
+                dict: &::idevice::utils::schema::Dictionary,
+            ) -> ::core::result::Result<Self, ::idevice::utils::schema::SchemaError> {
+                Ok(Self {
+                    #(#inits,)*
+                })
+            }
+        }
+
+        impl #impl_generics ::idevice::utils::schema::FromPlistValue for #name #ty_generics #where_clause {
+            const TYPE_NAME: &'static str = "dictionary";
+
+            fn from_plist_value(
+                value: &::idevice::utils::schema::Value,
+            ) -> ::core::result::Result<Self, ::idevice::utils::schema::SchemaError> {
+                match value {
+                    ::idevice::utils::schema::Value::Dictionary(d) => {
+                        <Self as ::idevice::utils::schema::FromPlist>::from_plist(d)
+                    }
+                    v => Err(::idevice::utils::schema::SchemaError::wrong_type(
+                        Self::TYPE_NAME,
+                        v,
+                    )),
+                }
+            }
+        }
+    })
+}
+
+fn to_case(raw: &str, capitalize_first: bool) -> String {
+    let mut res = String::with_capacity(raw.len());
+    let mut upper = capitalize_first;
+    for c in raw.chars() {
+        if c == '_' {
+            upper = true;
+        } else if upper {
+            res.extend(c.to_uppercase());
+            upper = false;
+        } else {
+            res.push(c);
+        }
+    }
+    res
+}
@@ -0,0 +1,153 @@
+// Jackson Coxson
+
+use std::{sync::Arc, time::UNIX_EPOCH};
+
+use idevice::{afc::opcode::AfcFopenMode, IdeviceService};
+use pyo3::{prelude::*, types::PyDict, IntoPyObjectExt};
+use pyo3_async_runtimes::tokio::future_into_py;
+use tokio::sync::Mutex;
+
+use crate::{provider::Provider, to_py_err};
+
+/// The media directory of the device, over the Apple File Conduit
+#[pyclass(module = "idevice", frozen)]
+pub struct AfcClient {
+    inner: Arc<Mutex<idevice::afc::AfcClient>>,
+}
+
+/// Runs `f` with the client locked and raises its error
+macro_rules! with_client {
+    ($self:ident, $py:ident, |$client:ident| $body:expr) => {{
+        let inner = $self.inner.clone();
+        future_into_py($py, async move {
+            let mut $client = inner.lock().await;
+            $body.await.map_err(to_py_err)
+        })
+    }};
+}
+
+#[pymethods]
+impl AfcClient {
+    #[staticmethod]
+    fn connect<'p>(py: Python<'p>, provider: &Provider) -> PyResult<Bound<'p, PyAny>> {
+        let provider = provider.inner.clone();
+        future_into_py(py, async move {
+            let client = idevice::afc::AfcClient::connect(&*provider)
+                .await
+                .map_err(to_py_err)?;
+            Ok(Self {
+                inner: Arc::new(Mutex::new(client)),
+            })
+        })
+    }
+
+    fn list_dir<'p>(&self, py: Python<'p>, path: String) -> PyResult<Bound<'p, PyAny>> {
+        with_client!(self, py, |client| client.list_dir(path))
+    }
+
+    fn mk_dir<'p>(&self, py: Python<'p>, path: String) -> PyResult<Bound<'p, PyAny>> {
+        with_client!(self, py, |client| client.mk_dir(path))
+    }
+
+    fn remove<'p>(&self, py: Python<'p>, path: String) -> PyResult<Bound<'p, PyAny>> {
+        with_client!(self, py, |client| client.remove(path))
+    }
+
+    fn remove_all<'p>(&self, py: Python<'p>, path: String) -> PyResult<Bound<'p, PyAny>> {
+        with_client!(self, py, |client| client.remove_all(path))
+    }
+
+    fn rename<'p>(
+        &self,
+        py: Python<'p>,
+        source: String,
+        target: String,
+    ) -> PyResult<Bound<'p, PyAny>> {
+        with_client!(self, py, |client| client.rename(source, target))
+    }
+
+    /// Reads a whole file as bytes
+    fn read_file<'p>(&self, py: Python<'p>, path: String) -> PyResult<Bound<'p, PyAny>> {
+        let inner = self.inner.clone();
+        future_into_py(py, async move {
+            let mut client = inner.lock().await;
+            let mut file = client
+                .open(path, AfcFopenMode::RdOnly)
+                .await
+                .map_err(to_py_err)?;
+            let data = file.read().await.map_err(to_py_err)?;
+            file.close().await.map_err(to_py_err)?;
+            Ok(data)
+        })
+    }
+
+    /// Creates or replaces a file with the bytes
+    fn write_file<'p>(
+        &self,
+        py: Python<'p>,
+        path: String,
+        data: Vec<u8>,
+    ) -> PyResult<Bound<'p, PyAny>> {
+        let inner = self.inner.clone();
+        future_into_py(py, async move {
+            let mut client = inner.lock().await;
+            let mut file = client
+                .open(path, AfcFopenMode::WrOnly)
+                .await
+                .map_err(to_py_err)?;
+            file.write(&data).await.map_err(to_py_err)?;
+            file.close().await.map_err(to_py_err)
+        })
+    }
+
+    /// A dict of `size`, `blocks`, `type`, `link_target`, and `created` and `modified`
+    /// as seconds since the Unix epoch
+    fn get_file_info<'p>(&self, py: Python<'p>, path: String) -> PyResult<Bound<'p, PyAny>> {
+        let inner = self.inner.clone();
+        future_into_py(py, async move {
+            let info = inner
+                .lock()
+                .await
+                .get_file_info(path)
+                .await
+                .map_err(to_py_err)?;
+            let seconds = |t: std::time::SystemTime| {
+                t.duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs_f64())
+                    .unwrap_or_default()
+            };
+            Python::attach(|py| {
+                let res = PyDict::new(py);
+                res.set_item("size", info.size)?;
+                res.set_item("blocks", info.blocks)?;
+                res.set_item("type", &info.st_ifmt)?;
+                res.set_item("link_target", &info.st_link_target)?;
+                res.set_item("created", seconds(info.creation))?;
+                res.set_item("modified", seconds(info.modified))?;
+                res.into_py_any(py)
+            })
+        })
+    }
+
+    /// A dict of `model`, `total_bytes`, `free_bytes`, `block_size` and the `raw` keys
+    fn get_device_info<'p>(&self, py: Python<'p>) -> PyResult<Bound<'p, PyAny>> {
+        let inner = self.inner.clone();
+        future_into_py(py, async move {
+            let info = inner
+                .lock()
+                .await
+                .get_device_info()
+                .await
+                .map_err(to_py_err)?;
+            Python::attach(|py| {
+                let res = PyDict::new(py);
+                res.set_item("model", &info.model)?;
+                res.set_item("total_bytes", info.total_bytes)?;
+                res.set_item("free_bytes", info.free_bytes)?;
+                res.set_item("block_size", info.block_size)?;
+                res.set_item("raw", &info.raw)?;
+                res.into_py_any(py)
+            })
+        })
+    }
+}
@@ -0,0 +1,42 @@
+// Jackson Coxson
+// Converts plist values into the equivalent Python objects
+
+use plist::Value;
+use pyo3::{
+    prelude::*,
+    types::{PyBytes, PyDict, PyList},
+    IntoPyObjectExt,
+};
+
+/// Dictionaries become dicts, arrays lists, data bytes, and dates ISO 8601 strings
+pub(crate) fn to_py(py: Python<'_>, value: &Value) -> PyResult<Py<PyAny>> {
+    Ok(match value {
+        Value::Dictionary(d) => dict_to_py(py, d)?,
+        Value::Array(a) => {
+            let list = PyList::empty(py);
+            for v in a {
+                list.append(to_py(py, v)?)?;
+            }
+            list.into_any().unbind()
+        }
+        Value::Boolean(b) => b.into_py_any(py)?,
+        Value::Data(d) => PyBytes::new(py, d).into_any().unbind(),
+        Value::Date(d) => d.to_xml_format().into_py_any(py)?,
+        Value::Real(r) => r.into_py_any(py)?,
+        Value::Integer(i) => match i.as_signed() {
+            Some(i) => i.into_py_any(py)?,
+            None => i.as_unsigned().unwrap_or_default().into_py_any(py)?,
+        },
+        Value::String(s) => s.into_py_any(py)?,
+        Value::Uid(u) => u.get().into_py_any(py)?,
+        _ => py.None(),
+    })
+}
+
+pub(crate) fn dict_to_py(py: Python<'_>, dict: &plist::Dictionary) -> PyResult<Py<PyAny>> {
+    let res = PyDict::new(py);
+    for (k, v) in dict {
+        res.set_item(k, to_py(py, v)?)?;
+    }
+    Ok(res.into_any().unbind())
+}
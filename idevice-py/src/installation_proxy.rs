@@ -0,0 +1,75 @@
+// Jackson Coxson
+
+use std::sync::Arc;
+
+use idevice::IdeviceService;
+use pyo3::{prelude::*, types::PyDict, IntoPyObjectExt};
+use pyo3_async_runtimes::tokio::future_into_py;
+use tokio::sync::Mutex;
+
+use crate::{convert, provider::Provider, to_py_err};
+
+#[pyclass(module = "idevice", frozen)]
+pub struct InstallationProxyClient {
+    inner: Arc<Mutex<idevice::installation_proxy::InstallationProxyClient>>,
+}
+
+#[pymethods]
+impl InstallationProxyClient {
+    #[staticmethod]
+    fn connect<'p>(py: Python<'p>, provider: &Provider) -> PyResult<Bound<'p, PyAny>> {
+        let provider = provider.inner.clone();
+        future_into_py(py, async move {
+            let client = idevice::installation_proxy::InstallationProxyClient::connect(&*provider)
+                .await
+                .map_err(to_py_err)?;
+            Ok(Self {
+                inner: Arc::new(Mutex::new(client)),
+            })
+        })
+    }
+
+    /// Installed apps as a dict of bundle ID to their attributes
+    /// # Arguments
+    /// `application_type` - Such as `User` or `System`, or any if `None`
+    /// `bundle_ids` - Only these apps, or all if `None`
+    /// `attributes` - Only these attributes, or all if `None`
+    #[pyo3(signature = (application_type = None, bundle_ids = None, attributes = None))]
+    fn get_apps<'p>(
+        &self,
+        py: Python<'p>,
+        application_type: Option<String>,
+        bundle_ids: Option<Vec<String>>,
+        attributes: Option<Vec<String>>,
+    ) -> PyResult<Bound<'p, PyAny>> {
+        let inner = self.inner.clone();
+        future_into_py(py, async move {
+            let apps = inner
+                .lock()
+                .await
+                .get_apps_with_attributes(application_type, bundle_ids, attributes)
+                .await
+                .map_err(to_py_err)?;
+            Python::attach(|py| {
+                let res = PyDict::new(py);
+                for (id, app) in &apps {
+                    res.set_item(id, convert::to_py(py, app)?)?;
+                }
+                res.into_py_any(py)
+            })
+        })
+    }
+
+    /// Installs a package already uploaded over AFC, such as `PublicStaging/app.ipa`
+    fn install<'p>(&self, py: Python<'p>, package_path: String) -> PyResult<Bound<'p, PyAny>> {
+        let inner = self.inner.clone();
+        future_into_py(py, async move {
+            inner
+                .lock()
+                .await
+                .install(package_path, None)
+                .await
+                .map_err(to_py_err)
+        })
+    }
+}
@@ -0,0 +1,34 @@
+// Jackson Coxson
+// Python bindings for idevice. Every method that talks to the device returns an awaitable,
+// which runs on a tokio runtime shared by the module.
+
+use pyo3::{create_exception, exceptions::PyException, prelude::*};
+
+mod afc;
+mod convert;
+mod installation_proxy;
+mod lockdown;
+mod provider;
+
+create_exception!(
+    idevice,
+    IdeviceError,
+    PyException,
+    "Raised with the message, the stable error code and the error name"
+);
+
+/// Converts a library error into an `IdeviceError` exception
+pub(crate) fn to_py_err(e: idevice::IdeviceError) -> PyErr {
+    IdeviceError::new_err((e.to_string(), e.code(), e.name()))
+}
+
+#[pymodule]
+#[pyo3(name = "idevice")]
+fn idevice_py(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add("IdeviceError", m.py().get_type::<IdeviceError>())?;
+    m.add_class::<provider::Provider>()?;
+    m.add_class::<lockdown::LockdownClient>()?;
+    m.add_class::<afc::AfcClient>()?;
+    m.add_class::<installation_proxy::InstallationProxyClient>()?;
+    Ok(())
+}
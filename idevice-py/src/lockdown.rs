@@ -0,0 +1,71 @@
+// Jackson Coxson
+
+use std::sync::Arc;
+
+use idevice::{lockdownd::LockdowndClient, IdeviceService};
+use pyo3::prelude::*;
+use pyo3_async_runtimes::tokio::future_into_py;
+use tokio::sync::Mutex;
+
+use crate::{convert, provider::Provider, to_py_err};
+
+/// A lockdown connection with a session already started
+#[pyclass(module = "idevice", frozen)]
+pub struct LockdownClient {
+    inner: Arc<Mutex<LockdowndClient>>,
+}
+
+#[pymethods]
+impl LockdownClient {
+    #[staticmethod]
+    fn connect<'p>(py: Python<'p>, provider: &Provider) -> PyResult<Bound<'p, PyAny>> {
+        let provider = provider.inner.clone();
+        future_into_py(py, async move {
+            let mut client = LockdowndClient::connect(&*provider)
+                .await
+                .map_err(to_py_err)?;
+            let pairing_file = provider.get_pairing_file().await.map_err(to_py_err)?;
+            client
+                .start_session(&pairing_file)
+                .await
+                .map_err(to_py_err)?;
+            Ok(Self {
+                inner: Arc::new(Mutex::new(client)),
+            })
+        })
+    }
+
+    /// Gets a value, from the global domain unless `domain` is given
+    #[pyo3(signature = (key, domain = None))]
+    fn get_value<'p>(
+        &self,
+        py: Python<'p>,
+        key: String,
+        domain: Option<String>,
+    ) -> PyResult<Bound<'p, PyAny>> {
+        let inner = self.inner.clone();
+        future_into_py(py, async move {
+            let mut client = inner.lock().await;
+            let value = match domain {
+                Some(domain) => client.get_value_in_domain(key, domain).await,
+                None => client.get_value(key).await,
+            }
+            .map_err(to_py_err)?;
+            Python::attach(|py| convert::to_py(py, &value))
+        })
+    }
+
+    /// Gets every value in the global domain as a dict
+    fn get_all_values<'p>(&self, py: Python<'p>) -> PyResult<Bound<'p, PyAny>> {
+        let inner = self.inner.clone();
+        future_into_py(py, async move {
+            let values = inner
+                .lock()
+                .await
+                .get_all_values()
+                .await
+                .map_err(to_py_err)?;
+            Python::attach(|py| convert::dict_to_py(py, &values))
+        })
+    }
+}
@@ -0,0 +1,102 @@
+// Jackson Coxson
+// Providers, which say how to reach a device
+
+use std::{net::IpAddr, path::PathBuf, sync::Arc};
+
+use idevice::{
+    pairing_file::PairingFile,
+    provider::{IdeviceProvider, TcpProvider},
+    usbmuxd::{Connection, UsbmuxdAddr, UsbmuxdConnection, UsbmuxdDevice},
+};
+use pyo3::{exceptions::PyValueError, prelude::*, types::PyDict, IntoPyObjectExt};
+use pyo3_async_runtimes::tokio::future_into_py;
+
+use crate::to_py_err;
+
+/// How to reach a device, passed to each client's `connect`
+#[pyclass(module = "idevice", frozen)]
+pub struct Provider {
+    pub(crate) inner: Arc<dyn IdeviceProvider>,
+}
+
+#[pymethods]
+impl Provider {
+    /// Reaches a device over the network with a pairing file from disk
+    #[staticmethod]
+    #[pyo3(signature = (address, pairing_file, label = "idevice-py"))]
+    fn tcp(address: &str, pairing_file: PathBuf, label: &str) -> PyResult<Self> {
+        let addr = address
+            .parse::<IpAddr>()
+            .map_err(|e| PyValueError::new_err(e.to_string()))?;
+        let pairing_file = PairingFile::read_from_file(pairing_file).map_err(to_py_err)?;
+        Ok(Self {
+            inner: Arc::new(TcpProvider {
+                addr,
+                pairing_file,
+                metadata: label.into(),
+                verify_udid: false,
+                pairing_file_source: None,
+            }),
+        })
+    }
+
+    /// Reaches a device through usbmuxd, by UDID or the first one connected
+    #[staticmethod]
+    #[pyo3(signature = (udid = None, label = "idevice-py".to_string()))]
+    fn usbmuxd(py: Python<'_>, udid: Option<String>, label: String) -> PyResult<Bound<'_, PyAny>> {
+        future_into_py(py, async move {
+            let mut usbmuxd = UsbmuxdConnection::default().await.map_err(to_py_err)?;
+            let device = match udid {
+                Some(udid) => usbmuxd.get_device(&udid).await.map_err(to_py_err)?,
+                None => match usbmuxd.get_devices().await.map_err(to_py_err)?.pop() {
+                    Some(d) => d,
+                    None => return Err(to_py_err(idevice::IdeviceError::DeviceNotFound)),
+                },
+            };
+            Ok(Self {
+                inner: Arc::new(device.to_provider(UsbmuxdAddr::default(), 0, label)),
+            })
+        })
+    }
+
+    /// The devices usbmuxd knows about, as dicts of `udid`, `device_id` and `connection`
+    #[staticmethod]
+    fn list_devices(py: Python<'_>) -> PyResult<Bound<'_, PyAny>> {
+        future_into_py(py, async move {
+            let mut usbmuxd = UsbmuxdConnection::default().await.map_err(to_py_err)?;
+            let devices = usbmuxd.get_devices().await.map_err(to_py_err)?;
+            Python::attach(|py| {
+                devices
+                    .iter()
+                    .map(|d| device_to_py(py, d))
+                    .collect::<PyResult<Vec<_>>>()
+            })
+        })
+    }
+
+    /// The device's UDID, if the provider knows it
+    #[getter]
+    fn udid(&self) -> Option<String> {
+        self.inner.udid()
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "Provider({:?})",
+            self.inner.udid().as_deref().unwrap_or("unknown")
+        )
+    }
+}
+
+fn device_to_py(py: Python<'_>, device: &UsbmuxdDevice) -> PyResult<Py<PyAny>> {
+    let res = PyDict::new(py);
+    res.set_item("udid", &device.udid)?;
+    res.set_item("device_id", device.device_id)?;
+    let connection = match &device.connection_type {
+        Connection::Usb => "usb".to_string(),
+        Connection::Network(addr) => addr.to_string(),
+        Connection::Unknown(c) => c.clone(),
+    };
+    res.set_item("connection", connection)?;
+    res.into_py_any(py)
+}
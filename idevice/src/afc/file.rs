@@ -0,0 +1,572 @@
+// Jackson Coxson
+
+use std::{
+    future::Future,
+    io::{self, SeekFrom},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use log::warn;
+use tokio::io::{AsyncRead, AsyncSeek, AsyncWrite, ReadBuf};
+
+use crate::IdeviceError;
+
+use super::{opcode::AfcOpcode, AfcClient};
+
+/// An open file on the device.
+/// Besides its own methods, it implements `AsyncRead`, `AsyncWrite` and `AsyncSeek`,
+/// so it can be used with `tokio::io::copy` and other stream adapters.
+pub struct FileDescriptor<'a> {
+    /// Lent to the in-flight request while a poll is pending
+    client: Option<&'a mut AfcClient>,
+    fd: u64,
+    path: String,
+    pending: Option<Pending<'a>>,
+    /// Bytes read from the device that the caller hasn't consumed yet
+    read_buf: Vec<u8>,
+    /// Passed to `start_seek` and not yet sent
+    seek: Option<SeekFrom>,
+}
+
+enum Done {
+    Read(Vec<u8>),
+    Wrote(usize),
+    Seeked(u64),
+}
+
+type Pending<'a> =
+    Pin<Box<dyn Future<Output = (&'a mut AfcClient, Result<Done, IdeviceError>)> + Send + 'a>>;
+
+impl<'a> FileDescriptor<'a> {
+    pub(crate) fn new(client: &'a mut AfcClient, fd: u64, path: String) -> Self {
+        Self {
+            client: Some(client),
+            fd,
+            path,
+            pending: None,
+            read_buf: Vec::new(),
+            seek: None,
+        }
+    }
+
+    /// The path the file was opened with
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Reads the file from the current position to the end
+    pub async fn read(&mut self) -> Result<Vec<u8>, IdeviceError> {
+        let mut res = Vec::new();
+        loop {
+            let chunk = self.read_chunk().await?;
+            if chunk.is_empty() {
+                break;
+            }
+            res.extend(chunk);
+        }
+        Ok(res)
+    }
+
+    /// Reads up to `AfcClient::MAX_TRANSFER` bytes from the current position,
+    /// for reading large files piece by piece
+    /// # Returns
+    /// The bytes read, which are empty at the end of the file
+    pub async fn read_chunk(&mut self) -> Result<Vec<u8>, IdeviceError> {
+        let fd = self.fd;
+        let client = self.client().await?;
+        let chunk = read_chunk(client, fd, AfcClient::MAX_TRANSFER).await?;
+        if self.read_buf.is_empty() {
+            return Ok(chunk);
+        }
+        let mut res = std::mem::take(&mut self.read_buf);
+        res.extend(chunk);
+        Ok(res)
+    }
+
+    /// Reads up to `len` bytes starting at `offset`, leaving the position after them
+    /// # Returns
+    /// The bytes read, which are fewer than `len` if the file ends first
+    pub async fn read_at(&mut self, offset: u64, len: u64) -> Result<Vec<u8>, IdeviceError> {
+        let fd = self.fd;
+        self.client().await?;
+        // An absolute seek makes anything read ahead stale
+        self.read_buf.clear();
+        let client = self.client().await?;
+        seek_to(client, fd, SeekFrom::Start(offset)).await?;
+        let mut res = Vec::new();
+        while (res.len() as u64) < len {
+            let want = (len - res.len() as u64).min(AfcClient::MAX_TRANSFER);
+            let chunk = read_chunk(client, fd, want).await?;
+            if chunk.is_empty() {
+                break;
+            }
+            res.extend(chunk);
+        }
+        Ok(res)
+    }
+
+    /// Moves the position in the file
+    /// # Returns
+    /// The new position from the start of the file
+    pub async fn seek(&mut self, pos: SeekFrom) -> Result<u64, IdeviceError> {
+        let fd = self.fd;
+        self.client().await?;
+        let pos = self.take_read_ahead(pos);
+        let client = self.client().await?;
+        seek_to(client, fd, pos).await?;
+        tell(client, fd).await
+    }
+
+    /// The position in the file
+    pub async fn tell(&mut self) -> Result<u64, IdeviceError> {
+        self.seek(SeekFrom::Current(0)).await
+    }
+
+    /// Writes the bytes to the file at the current position
+    pub async fn write(&mut self, bytes: &[u8]) -> Result<(), IdeviceError> {
+        let fd = self.fd;
+        self.client().await?;
+        let rewind = self.rewind_read_ahead();
+        let client = self.client().await?;
+        if let Some(pos) = rewind {
+            seek_to(client, fd, pos).await?;
+        }
+        for chunk in bytes.chunks(AfcClient::MAX_TRANSFER as usize) {
+            write_chunk(client, fd, chunk.to_vec()).await?;
+        }
+        Ok(())
+    }
+
+    /// Closes the file, releasing the client
+    pub async fn close(mut self) -> Result<(), IdeviceError> {
+        let fd = self.fd;
+        let client = self.client().await?;
+        client
+            .send(AfcOpcode::FileClose, fd.to_le_bytes().to_vec(), Vec::new())
+            .await?;
+        client.read().await?;
+        Ok(())
+    }
+
+    /// Waits for a request a poll left in flight, so the client can be used directly
+    async fn client(&mut self) -> Result<&mut AfcClient, IdeviceError> {
+        if let Some(pending) = self.pending.take() {
+            let (client, res) = pending.await;
+            self.client = Some(client);
+            match res {
+                Ok(Done::Read(chunk)) => self.read_buf.extend(chunk),
+                Ok(Done::Wrote(_)) | Ok(Done::Seeked(_)) => {}
+                Err(e) => warn!("Abandoned request on {} failed: {e:?}", self.path),
+            }
+        }
+        self.client
+            .as_deref_mut()
+            .ok_or(IdeviceError::NoEstablishedConnection)
+    }
+
+    /// Polls the request in flight, starting one with `start` if there's none
+    fn poll_pending(
+        &mut self,
+        cx: &mut Context<'_>,
+        start: impl FnOnce(&'a mut AfcClient, u64) -> Pending<'a>,
+    ) -> Poll<io::Result<Done>> {
+        if self.pending.is_none() {
+            let client = match self.client.take() {
+                Some(c) => c,
+                None => return Poll::Ready(Err(io::Error::other("AFC client is unavailable"))),
+            };
+            self.pending = Some(start(client, self.fd));
+        }
+        let (client, res) = match self.pending.as_mut().unwrap().as_mut().poll(cx) {
+            Poll::Ready(r) => r,
+            Poll::Pending => return Poll::Pending,
+        };
+        self.pending = None;
+        self.client = Some(client);
+        Poll::Ready(res.map_err(io::Error::other))
+    }
+
+    /// Drops bytes read ahead, which puts the device's position ahead of the caller's,
+    /// and corrects a relative seek for them
+    fn take_read_ahead(&mut self, pos: SeekFrom) -> SeekFrom {
+        let ahead = std::mem::take(&mut self.read_buf).len() as i64;
+        match pos {
+            SeekFrom::Current(offset) => SeekFrom::Current(offset - ahead),
+            pos => pos,
+        }
+    }
+
+    /// The seek that moves the device back to the caller's position before a write,
+    /// if bytes were read ahead. The read ahead bytes are dropped, as the write may change them.
+    fn rewind_read_ahead(&mut self) -> Option<SeekFrom> {
+        match self.read_buf.is_empty() {
+            true => None,
+            false => Some(self.take_read_ahead(SeekFrom::Current(0))),
+        }
+    }
+
+    /// Copies buffered bytes into the caller's buffer
+    fn drain_into(&mut self, buf: &mut ReadBuf<'_>) {
+        let n = self.read_buf.len().min(buf.remaining());
+        buf.put_slice(&self.read_buf[..n]);
+        self.read_buf.drain(..n);
+    }
+}
+
+impl<'a> AsyncRead for FileDescriptor<'a> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if !this.read_buf.is_empty() {
+                this.drain_into(buf);
+                return Poll::Ready(Ok(()));
+            }
+            let start = |client: &'a mut AfcClient, fd| -> Pending<'_> {
+                Box::pin(async move {
+                    let res = read_chunk(client, fd, AfcClient::MAX_TRANSFER)
+                        .await
+                        .map(Done::Read);
+                    (client, res)
+                })
+            };
+            match this.poll_pending(cx, start) {
+                Poll::Ready(Ok(Done::Read(chunk))) => {
+                    // An empty chunk is the end of the file
+                    this.read_buf = chunk;
+                    this.drain_into(buf);
+                    return Poll::Ready(Ok(()));
+                }
+                // A request abandoned by an earlier poll finished, so start the read
+                Poll::Ready(Ok(Done::Wrote(_))) | Poll::Ready(Ok(Done::Seeked(_))) => {}
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl<'a> AsyncWrite for FileDescriptor<'a> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let chunk = buf[..buf.len().min(AfcClient::MAX_TRANSFER as usize)].to_vec();
+        loop {
+            let chunk = chunk.clone();
+            // Only used when no other request is in flight, so the write starts now
+            let rewind = match this.pending {
+                Some(_) => None,
+                None => this.rewind_read_ahead(),
+            };
+            let start = move |client: &'a mut AfcClient, fd| -> Pending<'_> {
+                Box::pin(async move {
+                    let len = chunk.len();
+                    let res = match rewind {
+                        Some(pos) => seek_to(client, fd, pos).await,
+                        None => Ok(()),
+                    };
+                    let res = match res {
+                        Ok(()) => write_chunk(client, fd, chunk)
+                            .await
+                            .map(|_| Done::Wrote(len)),
+                        Err(e) => Err(e),
+                    };
+                    (client, res)
+                })
+            };
+            match this.poll_pending(cx, start) {
+                // A write left pending is retried with the same bytes, so its result is this one's
+                Poll::Ready(Ok(Done::Wrote(n))) => return Poll::Ready(Ok(n)),
+                // Keep what an abandoned read returned for the next read
+                Poll::Ready(Ok(Done::Read(read))) => this.read_buf.extend(read),
+                Poll::Ready(Ok(Done::Seeked(_))) => {}
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+
+    /// Every write is acknowledged by the device, so this only waits for one in flight
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.pending.is_none() {
+            return Poll::Ready(Ok(()));
+        }
+        match this.poll_pending(cx, |_, _| unreachable!()) {
+            Poll::Ready(Ok(Done::Read(read))) => {
+                this.read_buf.extend(read);
+                Poll::Ready(Ok(()))
+            }
+            Poll::Ready(r) => Poll::Ready(r.map(|_| ())),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    /// Doesn't close the file, which is done with `close`
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_flush(cx)
+    }
+}
+
+impl<'a> AsyncSeek for FileDescriptor<'a> {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        self.get_mut().seek = Some(position);
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        let this = self.get_mut();
+        loop {
+            // Only used when no other request is in flight, so the seek starts now
+            let pos = match this.pending {
+                Some(_) => SeekFrom::Current(0),
+                None => {
+                    let pos = this.seek.take().unwrap_or(SeekFrom::Current(0));
+                    this.take_read_ahead(pos)
+                }
+            };
+            let start = move |client: &'a mut AfcClient, fd| -> Pending<'_> {
+                Box::pin(async move {
+                    let res = match seek_to(client, fd, pos).await {
+                        Ok(()) => tell(client, fd).await.map(Done::Seeked),
+                        Err(e) => Err(e),
+                    };
+                    (client, res)
+                })
+            };
+            match this.poll_pending(cx, start) {
+                Poll::Ready(Ok(Done::Seeked(pos))) => return Poll::Ready(Ok(pos)),
+                // Finish what an earlier poll left in flight before seeking
+                Poll::Ready(Ok(Done::Read(read))) => this.read_buf.extend(read),
+                Poll::Ready(Ok(Done::Wrote(_))) => {}
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+async fn read_chunk(client: &mut AfcClient, fd: u64, len: u64) -> Result<Vec<u8>, IdeviceError> {
+    let mut header_payload = fd.to_le_bytes().to_vec();
+    header_payload.extend_from_slice(&len.to_le_bytes());
+    client
+        .send(AfcOpcode::Read, header_payload, Vec::new())
+        .await?;
+    Ok(client.read().await?.payload)
+}
+
+async fn write_chunk(client: &mut AfcClient, fd: u64, chunk: Vec<u8>) -> Result<(), IdeviceError> {
+    client
+        .send(AfcOpcode::Write, fd.to_le_bytes().to_vec(), chunk)
+        .await?;
+    client.read().await?;
+    Ok(())
+}
+
+async fn seek_to(client: &mut AfcClient, fd: u64, pos: SeekFrom) -> Result<(), IdeviceError> {
+    let (whence, offset) = match pos {
+        SeekFrom::Start(offset) => (0u64, offset as i64),
+        SeekFrom::Current(offset) => (1, offset),
+        SeekFrom::End(offset) => (2, offset),
+    };
+    let mut header_payload = fd.to_le_bytes().to_vec();
+    header_payload.extend_from_slice(&whence.to_le_bytes());
+    header_payload.extend_from_slice(&offset.to_le_bytes());
+    client
+        .send(AfcOpcode::FileSeek, header_payload, Vec::new())
+        .await?;
+    client.read().await?;
+    Ok(())
+}
+
+async fn tell(client: &mut AfcClient, fd: u64) -> Result<u64, IdeviceError> {
+    client
+        .send(AfcOpcode::FileTell, fd.to_le_bytes().to_vec(), Vec::new())
+        .await?;
+    let res = client.read().await?;
+    if res.header.operation != AfcOpcode::FileTellRes || res.header_payload.len() < 8 {
+        return Err(IdeviceError::UnexpectedResponse);
+    }
+    Ok(u64::from_le_bytes(
+        res.header_payload[..8].try_into().unwrap(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::{
+        afc::{
+            packet::{AfcPacket, AfcPacketHeader},
+            MAGIC,
+        },
+        Idevice,
+    };
+
+    /// Serves reads from `contents` in short chunks and writes to it at the position
+    async fn serve(mut socket: tokio::io::DuplexStream, contents: Arc<Mutex<Vec<u8>>>) {
+        let mut pos = 0;
+        loop {
+            let mut header = [0; AfcPacketHeader::LEN as usize];
+            if socket.read_exact(&mut header).await.is_err() {
+                return;
+            }
+            let header = AfcPacketHeader::parse(&header).unwrap();
+            let mut rest = vec![0; (header.entire_len - AfcPacketHeader::LEN) as usize];
+            socket.read_exact(&mut rest).await.unwrap();
+            let split = (header.header_payload_len - AfcPacketHeader::LEN) as usize;
+
+            let (operation, header_payload, payload) = match header.operation {
+                AfcOpcode::Read => {
+                    let contents = contents.lock().unwrap();
+                    let len = u64::from_le_bytes(rest[8..16].try_into().unwrap()) as usize;
+                    let end = contents.len().min(pos + len.min(1000));
+                    let chunk = contents[pos..end].to_vec();
+                    pos = end;
+                    (AfcOpcode::Data, Vec::new(), chunk)
+                }
+                AfcOpcode::Write => {
+                    let mut contents = contents.lock().unwrap();
+                    let data = &rest[split..];
+                    let end = pos + data.len();
+                    if contents.len() < end {
+                        contents.resize(end, 0);
+                    }
+                    contents[pos..end].copy_from_slice(data);
+                    pos = end;
+                    (AfcOpcode::Status, 0u64.to_le_bytes().to_vec(), Vec::new())
+                }
+                AfcOpcode::FileSeek => {
+                    let whence = u64::from_le_bytes(rest[8..16].try_into().unwrap());
+                    let offset = i64::from_le_bytes(rest[16..24].try_into().unwrap());
+                    let base = match whence {
+                        0 => 0,
+                        1 => pos as i64,
+                        _ => contents.lock().unwrap().len() as i64,
+                    };
+                    pos = (base + offset) as usize;
+                    (AfcOpcode::Status, 0u64.to_le_bytes().to_vec(), Vec::new())
+                }
+                AfcOpcode::FileTell => (
+                    AfcOpcode::FileTellRes,
+                    (pos as u64).to_le_bytes().to_vec(),
+                    Vec::new(),
+                ),
+                o => panic!("unexpected {o:?}"),
+            };
+            let header_payload_len = AfcPacketHeader::LEN + header_payload.len() as u64;
+            let packet = AfcPacket {
+                header: AfcPacketHeader {
+                    magic: MAGIC,
+                    entire_len: header_payload_len + payload.len() as u64,
+                    header_payload_len,
+                    packet_num: header.packet_num,
+                    operation,
+                },
+                header_payload,
+                payload,
+            };
+            socket.write_all(&packet.serialize()).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn async_read_and_write() {
+        let original = (0..5000u32).map(|i| i as u8).collect::<Vec<_>>();
+        let contents = Arc::new(Mutex::new(original.clone()));
+        let (client, server) = tokio::io::duplex(1 << 16);
+        tokio::spawn(serve(server, contents.clone()));
+        let mut afc = AfcClient::new(Idevice::new(Box::new(client), "test"));
+        let mut file = FileDescriptor::new(&mut afc, 1, "/test".into());
+
+        // Smaller than the chunks the device sends, so they have to be split
+        let mut read = Vec::new();
+        let mut buf = [0; 300];
+        loop {
+            let n = AsyncReadExt::read(&mut file, &mut buf).await.unwrap();
+            if n == 0 {
+                break;
+            }
+            read.extend_from_slice(&buf[..n]);
+        }
+        assert_eq!(read, original);
+
+        let extra = vec![7; AfcClient::MAX_TRANSFER as usize + 10];
+        tokio::io::copy(&mut extra.as_slice(), &mut file)
+            .await
+            .unwrap();
+        file.flush().await.unwrap();
+        assert_eq!(contents.lock().unwrap()[original.len()..], extra);
+    }
+
+    #[tokio::test]
+    async fn seek_and_read_ranges() {
+        let original = (0..5000u32).map(|i| i as u8).collect::<Vec<_>>();
+        let contents = Arc::new(Mutex::new(original.clone()));
+        let (client, server) = tokio::io::duplex(1 << 16);
+        tokio::spawn(serve(server, contents));
+        let mut afc = AfcClient::new(Idevice::new(Box::new(client), "test"));
+        let mut file = FileDescriptor::new(&mut afc, 1, "/test".into());
+
+        assert_eq!(file.read_at(4000, 2500).await.unwrap(), original[4000..]);
+        assert_eq!(file.read_at(10, 20).await.unwrap(), original[10..30]);
+
+        // The device read ahead of what was consumed, which a relative seek accounts for
+        let mut buf = [0; 10];
+        AsyncReadExt::read_exact(&mut file, &mut buf).await.unwrap();
+        assert_eq!(buf, original[30..40]);
+        assert_eq!(file.seek(SeekFrom::Current(5)).await.unwrap(), 45);
+        assert_eq!(
+            tokio::io::AsyncSeekExt::seek(&mut file, SeekFrom::End(-3))
+                .await
+                .unwrap(),
+            4997
+        );
+        let mut rest = Vec::new();
+        file.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest, original[4997..]);
+    }
+
+    #[tokio::test]
+    async fn write_after_read_ahead() {
+        let original = (0..5000u32).map(|i| i as u8).collect::<Vec<_>>();
+        let contents = Arc::new(Mutex::new(original.clone()));
+        let (client, server) = tokio::io::duplex(1 << 16);
+        tokio::spawn(serve(server, contents.clone()));
+        let mut afc = AfcClient::new(Idevice::new(Box::new(client), "test"));
+        let mut file = FileDescriptor::new(&mut afc, 1, "/test".into());
+
+        // The device reads a whole chunk ahead of the 10 bytes consumed
+        let mut buf = [0; 10];
+        AsyncReadExt::read_exact(&mut file, &mut buf).await.unwrap();
+        AsyncWriteExt::write_all(&mut file, &[0xff; 5])
+            .await
+            .unwrap();
+        AsyncReadExt::read_exact(&mut file, &mut buf).await.unwrap();
+        assert_eq!(buf, original[15..25]);
+
+        AsyncReadExt::read_exact(&mut file, &mut buf).await.unwrap();
+        file.write(&[0xee; 5]).await.unwrap();
+        AsyncReadExt::read_exact(&mut file, &mut buf).await.unwrap();
+        assert_eq!(buf, original[40..50]);
+
+        let contents = contents.lock().unwrap();
+        assert_eq!(contents.len(), original.len());
+        assert_eq!(contents[..10], original[..10]);
+        assert_eq!(contents[10..15], [0xff; 5]);
+        assert_eq!(contents[15..35], original[15..35]);
+        assert_eq!(contents[35..40], [0xee; 5]);
+        assert_eq!(contents[40..], original[40..]);
+    }
+}
@@ -0,0 +1,523 @@
+// Jackson Coxson
+// Abstractions for the Apple File Conduit service
+
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use errors::AfcError;
+use file::FileDescriptor;
+use log::warn;
+use opcode::{AfcFopenMode, AfcOpcode};
+use packet::{AfcPacket, AfcPacketHeader};
+
+use crate::{
+    lockdownd::{DiskUsage, LockdowndClient},
+    utils::models::{self, DeviceModel},
+    Idevice, IdeviceError, IdeviceService,
+};
+
+pub mod errors;
+pub mod file;
+pub mod opcode;
+pub mod packet;
+pub mod path;
+pub mod sync;
+
+/// "CFA6LPAA" as a little endian number
+pub const MAGIC: u64 = 0x4141504c36414643;
+
+pub struct AfcClient {
+    pub idevice: Idevice,
+    package_number: u64,
+    /// Paths outside this directory are refused when set
+    root: Option<String>,
+}
+
+/// AFC doesn't report or accept permission bits, so none are exposed here
+#[derive(Clone, Debug)]
+pub struct FileInfo {
+    pub size: u64,
+    pub blocks: u64,
+    pub creation: SystemTime,
+    pub modified: SystemTime,
+    pub st_nlink: String,
+    pub st_ifmt: String,
+    pub st_link_target: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct DeviceInfo {
+    /// The product type, such as `iPhone15,3`
+    pub model: String,
+    /// The size of the data volume, which AFC's root is on
+    pub total_bytes: u64,
+    /// The free space on the data volume
+    pub free_bytes: u64,
+    pub block_size: u64,
+    /// The size of the system volume. AFC doesn't report it, see `with_disk_usage`.
+    pub system_total_bytes: Option<u64>,
+    /// The free space on the system volume. AFC doesn't report it, see `with_disk_usage`.
+    pub system_free_bytes: Option<u64>,
+    /// Keys returned by the device that aren't parsed into the fields above
+    pub raw: HashMap<String, String>,
+}
+
+impl DeviceInfo {
+    /// Fills in the system volume from lockdown's `com.apple.disk_usage` domain
+    pub fn with_disk_usage(mut self, usage: &DiskUsage) -> Self {
+        self.system_total_bytes = usage.total_system_capacity;
+        self.system_free_bytes = usage.total_system_available;
+        self
+    }
+
+    /// The marketing name and hardware of `model`, if it's a known product type
+    pub fn device_model(&self) -> Option<&'static DeviceModel> {
+        models::lookup(&self.model)
+    }
+}
+
+impl IdeviceService for AfcClient {
+    fn service_name() -> &'static str {
+        "com.apple.afc"
+    }
+
+    async fn connect(
+        provider: &dyn crate::provider::IdeviceProvider,
+    ) -> Result<Self, IdeviceError> {
+        Self::connect_with_service_name(provider, Self::service_name()).await
+    }
+}
+
+impl AfcClient {
+    /// Full filesystem access on jailbroken devices
+    pub const AFC2_SERVICE_NAME: &'static str = "com.apple.afc2";
+
+    /// Connects to one of the other services that speak AFC, such as `AFC2_SERVICE_NAME`
+    /// # Arguments
+    /// `service_name` - The lockdown service to start
+    pub async fn connect_with_service_name(
+        provider: &dyn crate::provider::IdeviceProvider,
+        service_name: &str,
+    ) -> Result<Self, IdeviceError> {
+        let mut lockdown = LockdowndClient::connect(provider).await?;
+        lockdown
+            .start_session(&provider.get_pairing_file().await?)
+            .await?;
+
+        let (port, ssl) = lockdown.start_service(service_name).await?;
+
+        let mut idevice = provider.connect(port).await?;
+        if ssl {
+            idevice
+                .start_session(&provider.get_pairing_file().await?)
+                .await?;
+        }
+
+        Ok(Self::new(idevice))
+    }
+
+    /// The largest chunk sent or requested in a single packet
+    pub const MAX_TRANSFER: u64 = 64 * 1024;
+
+    /// The most symlinks followed while canonicalizing a path
+    const MAX_LINK_HOPS: usize = 32;
+
+    pub fn new(idevice: Idevice) -> Self {
+        Self {
+            idevice,
+            package_number: 0,
+            root: None,
+        }
+    }
+
+    /// Refuses paths that resolve outside `root`, following symlinks on the device.
+    /// Each checked path costs a lookup per component.
+    /// Tools syncing into a single directory should set this.
+    pub fn set_root(&mut self, root: Option<String>) {
+        self.root = root.map(|r| path::normalize(&r));
+    }
+
+    /// Resolves `.`, `..` and symlinks in a path on the device.
+    /// Components that don't exist yet are resolved lexically.
+    pub async fn canonicalize(&mut self, path: impl Into<String>) -> Result<String, IdeviceError> {
+        self.resolve(&path.into(), true).await
+    }
+
+    async fn resolve(&mut self, path: &str, follow_last: bool) -> Result<String, IdeviceError> {
+        let mut pending = path
+            .split('/')
+            .filter(|p| !p.is_empty())
+            .map(ToString::to_string)
+            .collect::<VecDeque<_>>();
+        let mut parts: Vec<String> = Vec::new();
+        let mut hops = 0;
+        let mut exists = true;
+
+        while let Some(part) = pending.pop_front() {
+            match part.as_str() {
+                "." => continue,
+                ".." => {
+                    parts.pop();
+                    continue;
+                }
+                _ => parts.push(part),
+            }
+            if !exists || (pending.is_empty() && !follow_last) {
+                continue;
+            }
+
+            let current = format!("/{}", parts.join("/"));
+            match self.file_info_unchecked(&current).await {
+                Ok(FileInfo {
+                    st_link_target: Some(target),
+                    ..
+                }) if !target.is_empty() => {
+                    hops += 1;
+                    if hops > Self::MAX_LINK_HOPS {
+                        warn!("Too many symlinks resolving {path}");
+                        return Err(IdeviceError::Afc(AfcError::InvalidArg));
+                    }
+                    parts.pop();
+                    if target.starts_with('/') {
+                        parts.clear();
+                    }
+                    for t in target.split('/').rev().filter(|t| !t.is_empty()) {
+                        pending.push_front(t.to_string());
+                    }
+                }
+                Ok(_) => {}
+                Err(IdeviceError::Afc(AfcError::ObjectNotFound)) => exists = false,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(format!("/{}", parts.join("/")))
+    }
+
+    /// Applies the root restriction, if any
+    async fn checked(&mut self, path: String, follow_last: bool) -> Result<String, IdeviceError> {
+        let root = match &self.root {
+            Some(r) => r.clone(),
+            None => return Ok(path),
+        };
+        let resolved = self.resolve(&path, follow_last).await?;
+        if path::is_within(&resolved, &root) {
+            Ok(resolved)
+        } else {
+            Err(IdeviceError::PathOutsideRoot(path))
+        }
+    }
+
+    /// Lists the contents of a directory, including `.` and `..`
+    pub async fn list_dir(&mut self, path: impl Into<String>) -> Result<Vec<String>, IdeviceError> {
+        let path = self.checked(path.into(), true).await?;
+        self.send(AfcOpcode::ReadDir, nul_terminated(&path), Vec::new())
+            .await?;
+        let res = self.read().await?;
+        Ok(split_nul(&res.payload))
+    }
+
+    /// Creates a directory
+    pub async fn mk_dir(&mut self, path: impl Into<String>) -> Result<(), IdeviceError> {
+        let path = self.checked(path.into(), false).await?;
+        self.send(AfcOpcode::MakeDir, nul_terminated(&path), Vec::new())
+            .await?;
+        self.read().await?;
+        Ok(())
+    }
+
+    /// Gets information about a file or directory
+    pub async fn get_file_info(
+        &mut self,
+        path: impl Into<String>,
+    ) -> Result<FileInfo, IdeviceError> {
+        let path = self.checked(path.into(), false).await?;
+        self.file_info_unchecked(&path).await
+    }
+
+    async fn file_info_unchecked(&mut self, path: &str) -> Result<FileInfo, IdeviceError> {
+        self.send(AfcOpcode::GetFileInfo, nul_terminated(path), Vec::new())
+            .await?;
+        let res = self.read().await?;
+        let kvs = split_pairs(&res.payload);
+
+        let number = |key: &str| -> Result<u64, IdeviceError> {
+            match kvs.get(key).map(|v| v.parse::<u64>()) {
+                Some(Ok(v)) => Ok(v),
+                _ => {
+                    warn!("File info didn't contain a numeric {key}");
+                    Err(IdeviceError::UnexpectedResponse)
+                }
+            }
+        };
+
+        Ok(FileInfo {
+            size: number("st_size")?,
+            blocks: number("st_blocks")?,
+            creation: UNIX_EPOCH + Duration::from_nanos(number("st_birthtime")?),
+            modified: UNIX_EPOCH + Duration::from_nanos(number("st_mtime")?),
+            st_nlink: kvs.get("st_nlink").cloned().unwrap_or_default(),
+            st_ifmt: kvs.get("st_ifmt").cloned().unwrap_or_default(),
+            st_link_target: kvs.get("LinkTarget").cloned(),
+        })
+    }
+
+    /// Gets information about a path, or `None` if nothing exists there
+    pub async fn exists(
+        &mut self,
+        path: impl Into<String>,
+    ) -> Result<Option<FileInfo>, IdeviceError> {
+        match self.get_file_info(path).await {
+            Ok(info) => Ok(Some(info)),
+            Err(IdeviceError::Afc(AfcError::ObjectNotFound)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Gets information about the device's filesystem
+    pub async fn get_device_info(&mut self) -> Result<DeviceInfo, IdeviceError> {
+        self.send(AfcOpcode::GetDevInfo, Vec::new(), Vec::new())
+            .await?;
+        let res = self.read().await?;
+        let mut raw = split_pairs(&res.payload);
+
+        let mut number = |key: &str| -> Result<u64, IdeviceError> {
+            match raw.remove(key).map(|v| v.parse::<u64>()) {
+                Some(Ok(v)) => Ok(v),
+                _ => {
+                    warn!("Device info didn't contain a numeric {key}");
+                    Err(IdeviceError::UnexpectedResponse)
+                }
+            }
+        };
+        let total_bytes = number("FSTotalBytes")?;
+        let free_bytes = number("FSFreeBytes")?;
+        let block_size = number("FSBlockSize")?;
+
+        Ok(DeviceInfo {
+            model: raw.remove("Model").unwrap_or_default(),
+            total_bytes,
+            free_bytes,
+            block_size,
+            system_total_bytes: None,
+            system_free_bytes: None,
+            raw,
+        })
+    }
+
+    /// Removes a file or an empty directory
+    pub async fn remove(&mut self, path: impl Into<String>) -> Result<(), IdeviceError> {
+        let path = self.checked(path.into(), false).await?;
+        self.send(AfcOpcode::RemovePath, nul_terminated(&path), Vec::new())
+            .await?;
+        self.read().await?;
+        Ok(())
+    }
+
+    /// Removes a path and everything under it
+    pub async fn remove_all(&mut self, path: impl Into<String>) -> Result<(), IdeviceError> {
+        let path = self.checked(path.into(), false).await?;
+        self.send(
+            AfcOpcode::RemovePathAndContents,
+            nul_terminated(&path),
+            Vec::new(),
+        )
+        .await?;
+        self.read().await?;
+        Ok(())
+    }
+
+    /// Moves a file or directory
+    pub async fn rename(
+        &mut self,
+        source: impl Into<String>,
+        target: impl Into<String>,
+    ) -> Result<(), IdeviceError> {
+        let source = self.checked(source.into(), false).await?;
+        let target = self.checked(target.into(), false).await?;
+        let mut header_payload = nul_terminated(&source);
+        header_payload.extend(nul_terminated(&target));
+        self.send(AfcOpcode::RenamePath, header_payload, Vec::new())
+            .await?;
+        self.read().await?;
+        Ok(())
+    }
+
+    /// Sets the modification time of a file or directory
+    pub async fn set_file_time(
+        &mut self,
+        path: impl Into<String>,
+        modified: SystemTime,
+    ) -> Result<(), IdeviceError> {
+        let path = self.checked(path.into(), false).await?;
+        self.send(
+            AfcOpcode::SetFileTime,
+            file_time_payload(&path, modified),
+            Vec::new(),
+        )
+        .await?;
+        self.read().await?;
+        Ok(())
+    }
+
+    /// Creates a symlink
+    /// # Arguments
+    /// `target` - What the link points to, stored as given
+    /// `link` - Where to create the link
+    pub async fn make_symlink(
+        &mut self,
+        target: impl Into<String>,
+        link: impl Into<String>,
+    ) -> Result<(), IdeviceError> {
+        let link = self.checked(link.into(), false).await?;
+        // 1 is a hard link
+        let mut header_payload = 2u64.to_le_bytes().to_vec();
+        header_payload.extend(nul_terminated(&target.into()));
+        header_payload.extend(nul_terminated(&link));
+        self.send(AfcOpcode::MakeLink, header_payload, Vec::new())
+            .await?;
+        self.read().await?;
+        Ok(())
+    }
+
+    /// Opens a file on the device
+    /// # Arguments
+    /// `path` - The path of the file
+    /// `mode` - How to open the file
+    /// # Returns
+    /// A file descriptor that borrows the client until it's closed
+    pub async fn open(
+        &mut self,
+        path: impl Into<String>,
+        mode: AfcFopenMode,
+    ) -> Result<FileDescriptor<'_>, IdeviceError> {
+        let path = self.checked(path.into(), true).await?;
+        let mut header_payload = (mode as u64).to_le_bytes().to_vec();
+        header_payload.extend(nul_terminated(&path));
+        self.send(AfcOpcode::FileOpen, header_payload, Vec::new())
+            .await?;
+        let res = self.read().await?;
+        if res.header.operation != AfcOpcode::FileOpenRes || res.header_payload.len() < 8 {
+            return Err(IdeviceError::UnexpectedResponse);
+        }
+        let fd = u64::from_le_bytes(res.header_payload[..8].try_into().unwrap());
+
+        Ok(FileDescriptor::new(self, fd, path))
+    }
+
+    /// Reads part of a file, for formats such as zip that are read from an offset
+    /// # Returns
+    /// The bytes read, which are fewer than `len` if the file ends first
+    pub async fn read_range(
+        &mut self,
+        path: impl Into<String>,
+        offset: u64,
+        len: u64,
+    ) -> Result<Vec<u8>, IdeviceError> {
+        let mut file = self.open(path, AfcFopenMode::RdOnly).await?;
+        let res = file.read_at(offset, len).await;
+        let closed = file.close().await;
+        let res = res?;
+        closed?;
+        Ok(res)
+    }
+
+    /// Sends a packet to the service
+    pub(crate) async fn send(
+        &mut self,
+        operation: AfcOpcode,
+        header_payload: Vec<u8>,
+        payload: Vec<u8>,
+    ) -> Result<(), IdeviceError> {
+        let header_payload_len = AfcPacketHeader::LEN + header_payload.len() as u64;
+        let packet = AfcPacket {
+            header: AfcPacketHeader {
+                magic: MAGIC,
+                entire_len: header_payload_len + payload.len() as u64,
+                header_payload_len,
+                packet_num: self.package_number,
+                operation,
+            },
+            header_payload,
+            payload,
+        };
+        self.package_number += 1;
+
+        self.idevice.send_raw(&packet.serialize()).await
+    }
+
+    /// Reads a packet from the service, returning an error for non-zero status packets
+    pub(crate) async fn read(&mut self) -> Result<AfcPacket, IdeviceError> {
+        let res = AfcPacket::read(&mut self.idevice).await?;
+        if res.header.operation == AfcOpcode::Status {
+            if res.header_payload.len() < 8 {
+                return Err(IdeviceError::UnexpectedResponse);
+            }
+            let code = u64::from_le_bytes(res.header_payload[..8].try_into().unwrap());
+            if code != 0 {
+                return Err(IdeviceError::Afc(AfcError::from(code)));
+            }
+        }
+        Ok(res)
+    }
+}
+
+fn nul_terminated(s: &str) -> Vec<u8> {
+    let mut res = s.as_bytes().to_vec();
+    res.push(0);
+    res
+}
+
+/// The modification time as little-endian nanoseconds since the Unix epoch, then the path
+fn file_time_payload(path: &str, modified: SystemTime) -> Vec<u8> {
+    let nanos = modified
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64;
+    let mut res = nanos.to_le_bytes().to_vec();
+    res.extend(nul_terminated(path));
+    res
+}
+
+fn split_nul(buf: &[u8]) -> Vec<String> {
+    buf.split(|b| *b == 0)
+        .filter(|s| !s.is_empty())
+        .map(|s| String::from_utf8_lossy(s).to_string())
+        .collect()
+}
+
+/// Splits alternating keys and values. Values can be empty, so nothing is filtered out,
+/// and the empty string after the final NUL is dropped by `chunks_exact`.
+fn split_pairs(buf: &[u8]) -> HashMap<String, String> {
+    let parts = buf
+        .split(|b| *b == 0)
+        .map(|s| String::from_utf8_lossy(s).to_string())
+        .collect::<Vec<_>>();
+    parts
+        .chunks_exact(2)
+        .map(|kv| (kv[0].clone(), kv[1].clone()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_time_payload_layout() {
+        let modified = UNIX_EPOCH + Duration::from_nanos(1_700_000_000_123_456_789);
+        let payload = file_time_payload("/DCIM/a.jpg", modified);
+        assert_eq!(payload[..8], 1_700_000_000_123_456_789u64.to_le_bytes());
+        assert_eq!(&payload[8..], b"/DCIM/a.jpg\0");
+    }
+
+    #[test]
+    fn splits_pairs_with_empty_values() {
+        let pairs = split_pairs(b"Model\0iPhone15,3\0Empty\0\0FSBlockSize\x004096\0");
+        assert_eq!(pairs.len(), 3);
+        assert_eq!(pairs["Model"], "iPhone15,3");
+        assert_eq!(pairs["Empty"], "");
+        assert_eq!(pairs["FSBlockSize"], "4096");
+    }
+}
@@ -0,0 +1,151 @@
+// Jackson Coxson
+
+use log::{debug, warn};
+
+use crate::{Idevice, IdeviceError};
+
+use super::opcode::AfcOpcode;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AfcPacketHeader {
+    pub magic: u64,
+    pub entire_len: u64,
+    pub header_payload_len: u64,
+    pub packet_num: u64,
+    pub operation: AfcOpcode,
+}
+
+impl AfcPacketHeader {
+    pub const LEN: u64 = 40;
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut res = Vec::with_capacity(Self::LEN as usize);
+        res.extend_from_slice(&self.magic.to_le_bytes());
+        res.extend_from_slice(&self.entire_len.to_le_bytes());
+        res.extend_from_slice(&self.header_payload_len.to_le_bytes());
+        res.extend_from_slice(&self.packet_num.to_le_bytes());
+        res.extend_from_slice(&(self.operation as u64).to_le_bytes());
+        res
+    }
+
+    pub fn parse(buf: &[u8]) -> Result<Self, IdeviceError> {
+        if buf.len() < Self::LEN as usize {
+            return Err(IdeviceError::UnexpectedResponse);
+        }
+
+        // We are safe to unwrap as the slices are always 8 bytes
+        let word = |i: usize| u64::from_le_bytes(buf[i * 8..(i + 1) * 8].try_into().unwrap());
+
+        let magic = word(0);
+        if magic != super::MAGIC {
+            return Err(IdeviceError::UnexpectedResponse);
+        }
+
+        let operation = match AfcOpcode::try_from(word(4)) {
+            Ok(o) => o,
+            Err(_) => {
+                debug!("Unknown AFC opcode {}", word(4));
+                return Err(IdeviceError::UnexpectedResponse);
+            }
+        };
+
+        Ok(Self {
+            magic,
+            entire_len: word(1),
+            header_payload_len: word(2),
+            packet_num: word(3),
+            operation,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AfcPacket {
+    pub header: AfcPacketHeader,
+    pub header_payload: Vec<u8>,
+    pub payload: Vec<u8>,
+}
+
+impl AfcPacket {
+    /// The largest packet accepted from the device. File reads are capped at
+    /// `AfcClient::MAX_TRANSFER`, so only big directory listings come close.
+    pub const MAX_PACKET_SIZE: u64 = 16 * 1024 * 1024;
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut res = self.header.serialize();
+        res.extend_from_slice(&self.header_payload);
+        res.extend_from_slice(&self.payload);
+        res
+    }
+
+    pub async fn read(idevice: &mut Idevice) -> Result<Self, IdeviceError> {
+        let header = idevice.read_raw(AfcPacketHeader::LEN as usize).await?;
+        let header = AfcPacketHeader::parse(&header)?;
+
+        if header.header_payload_len < AfcPacketHeader::LEN
+            || header.entire_len < header.header_payload_len
+        {
+            return Err(IdeviceError::PacketSizeMismatch);
+        }
+        if header.entire_len > Self::MAX_PACKET_SIZE {
+            warn!(
+                "AFC packet of {} bytes is larger than {}",
+                header.entire_len,
+                Self::MAX_PACKET_SIZE
+            );
+            return Err(IdeviceError::PacketSizeMismatch);
+        }
+
+        let header_payload = idevice
+            .read_raw((header.header_payload_len - AfcPacketHeader::LEN) as usize)
+            .await?;
+        let payload = idevice
+            .read_raw((header.entire_len - header.header_payload_len) as usize)
+            .await?;
+
+        Ok(Self {
+            header,
+            header_payload,
+            payload,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_round_trip() {
+        let header = AfcPacketHeader {
+            magic: crate::afc::MAGIC,
+            entire_len: 52,
+            header_payload_len: 48,
+            packet_num: 7,
+            operation: AfcOpcode::FileOpen,
+        };
+        let bytes = header.serialize();
+        assert_eq!(&bytes[..8], b"CFA6LPAA");
+        assert_eq!(AfcPacketHeader::parse(&bytes).unwrap(), header);
+    }
+
+    #[tokio::test]
+    async fn refuses_oversized_packets() {
+        use tokio::io::AsyncWriteExt;
+
+        let header = AfcPacketHeader {
+            magic: crate::afc::MAGIC,
+            entire_len: u64::MAX,
+            header_payload_len: AfcPacketHeader::LEN,
+            packet_num: 0,
+            operation: AfcOpcode::Status,
+        };
+        let (client, mut server) = tokio::io::duplex(1024);
+        server.write_all(&header.serialize()).await.unwrap();
+        let mut idevice = Idevice::new(Box::new(client), "test");
+        assert!(matches!(
+            AfcPacket::read(&mut idevice).await,
+            Err(IdeviceError::PacketSizeMismatch)
+        ));
+    }
+}
@@ -0,0 +1,59 @@
+// Jackson Coxson
+// Lexical path handling for AFC paths, which always use `/`
+
+/// Resolves `.` and `..` without asking the device. `..` never climbs above `/`.
+pub fn normalize(path: &str) -> String {
+    let mut parts = Vec::new();
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            p => parts.push(p),
+        }
+    }
+    format!("/{}", parts.join("/"))
+}
+
+/// Whether a path is the root or somewhere under it, after normalizing both
+pub fn is_within(path: &str, root: &str) -> bool {
+    let path = normalize(path);
+    let root = normalize(root);
+    root == "/" || path == root || path.starts_with(&format!("{root}/"))
+}
+
+pub fn join(base: &str, name: &str) -> String {
+    format!("{}/{name}", base.trim_end_matches('/'))
+}
+
+/// Whether a directory entry name is safe to use as a single path component on the host.
+/// Names from the device are untrusted when downloading.
+pub fn is_plain_name(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && !name.contains(['/', '\\', '\0'])
+}
+
+/// Whether a relative symlink target, resolved from the directory holding the link,
+/// stays under the root. Absolute targets never do, as the root differs between hosts.
+pub fn link_stays_within(link_dir: &str, target: &str, root: &str) -> bool {
+    !target.starts_with('/') && is_within(&join(link_dir, target), root)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_and_bounds() {
+        assert_eq!(normalize("Downloads/./a/../b/"), "/Downloads/b");
+        assert_eq!(normalize("../../etc"), "/etc");
+        assert!(is_within("/DCIM/100APPLE/a.jpg", "/DCIM"));
+        assert!(!is_within("/DCIM/../Downloads", "/DCIM"));
+        assert!(!is_within("/DCIMX", "/DCIM"));
+        assert!(!is_plain_name(".."));
+        assert!(!is_plain_name("a/b"));
+        assert!(link_stays_within("/A/b", "../c", "/A"));
+        assert!(!link_stays_within("/A/b", "../../c", "/A"));
+        assert!(!link_stays_within("/A/b", "/A/c", "/A"));
+    }
+}
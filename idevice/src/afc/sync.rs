@@ -0,0 +1,227 @@
+// Jackson Coxson
+// Recursive copies between the host and the device
+
+use std::{
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use log::warn;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::IdeviceError;
+
+use super::{opcode::AfcFopenMode, path, AfcClient};
+
+/// Reported by the `_with_progress` copies after each chunk
+#[derive(Debug, Clone)]
+pub struct SyncProgress {
+    /// The device path of the file being copied
+    pub path: String,
+    pub transferred: u64,
+    pub size: u64,
+}
+
+impl AfcClient {
+    /// Copies a directory from the device to the host.
+    /// See `download_dir_with_progress`.
+    pub async fn download_dir(
+        &mut self,
+        device_dir: impl Into<String>,
+        host_dir: impl AsRef<Path>,
+    ) -> Result<(), IdeviceError> {
+        self.download_dir_with_progress(device_dir, host_dir, |_| {})
+            .await
+    }
+
+    /// Copies a directory from the device to the host, keeping file modification times.
+    /// Entry names that could escape `host_dir` are skipped, as are files that would be
+    /// written through a symlink already at their host path.
+    /// Symlinks are recreated on Unix hosts when their target stays inside the copy,
+    /// and skipped otherwise.
+    /// # Arguments
+    /// `device_dir` - The directory to copy
+    /// `host_dir` - Where to copy it to. It's created if it doesn't exist.
+    /// `progress` - Called after each chunk is written
+    pub async fn download_dir_with_progress(
+        &mut self,
+        device_dir: impl Into<String>,
+        host_dir: impl AsRef<Path>,
+        mut progress: impl FnMut(SyncProgress),
+    ) -> Result<(), IdeviceError> {
+        let device_root = device_dir.into();
+        let mut pending = vec![(device_root.clone(), host_dir.as_ref().to_path_buf())];
+        while let Some((device_dir, host_dir)) = pending.pop() {
+            tokio::fs::create_dir_all(&host_dir).await?;
+            for name in self.list_dir(device_dir.as_str()).await? {
+                if name == "." || name == ".." {
+                    continue;
+                }
+                if !path::is_plain_name(&name) {
+                    warn!("Skipping unsafe entry name {name:?} in {device_dir}");
+                    continue;
+                }
+                let device_path = path::join(&device_dir, &name);
+                let host_path = host_dir.join(&name);
+
+                let info = self.get_file_info(device_path.as_str()).await?;
+                match info.st_ifmt.as_str() {
+                    "S_IFDIR" => pending.push((device_path, host_path)),
+                    "S_IFREG" => {
+                        if is_symlink(&host_path).await? {
+                            warn!("Skipping {device_path}, as {host_path:?} is a symlink");
+                            continue;
+                        }
+                        let mut out = tokio::fs::File::create(&host_path).await?;
+                        let mut file = self
+                            .open(device_path.as_str(), AfcFopenMode::RdOnly)
+                            .await?;
+                        let mut transferred = 0;
+                        loop {
+                            let chunk = file.read_chunk().await?;
+                            if chunk.is_empty() {
+                                break;
+                            }
+                            out.write_all(&chunk).await?;
+                            transferred += chunk.len() as u64;
+                            progress(SyncProgress {
+                                path: device_path.clone(),
+                                transferred,
+                                size: info.size,
+                            });
+                        }
+                        file.close().await?;
+                        out.flush().await?;
+                        let out = out.into_std().await;
+                        let modified = info.modified;
+                        tokio::task::spawn_blocking(move || out.set_modified(modified))
+                            .await
+                            .map_err(std::io::Error::other)??;
+                    }
+                    "S_IFLNK" => {
+                        let target = info.st_link_target.unwrap_or_default();
+                        if !path::link_stays_within(&device_dir, &target, &device_root) {
+                            warn!("Skipping {device_path}, which links outside the copy");
+                            continue;
+                        }
+                        #[cfg(unix)]
+                        tokio::fs::symlink(&target, &host_path).await?;
+                        #[cfg(not(unix))]
+                        warn!("Skipping symlink {device_path}, which can't be created here");
+                    }
+                    t => warn!("Skipping {device_path} of type {t}"),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Copies a directory from the host to the device.
+    /// See `upload_dir_with_progress`.
+    pub async fn upload_dir(
+        &mut self,
+        host_dir: impl AsRef<Path>,
+        device_dir: impl Into<String>,
+    ) -> Result<(), IdeviceError> {
+        self.upload_dir_with_progress(host_dir, device_dir, |_| {})
+            .await
+    }
+
+    /// Copies a directory from the host to the device, keeping modification times.
+    /// Symlinks are recreated when their target stays inside the copy, and skipped otherwise,
+    /// so nothing outside `host_dir` is read.
+    /// # Arguments
+    /// `host_dir` - The directory to copy
+    /// `device_dir` - Where to copy it to. It's created if it doesn't exist.
+    /// `progress` - Called after each chunk is written
+    pub async fn upload_dir_with_progress(
+        &mut self,
+        host_dir: impl AsRef<Path>,
+        device_dir: impl Into<String>,
+        mut progress: impl FnMut(SyncProgress),
+    ) -> Result<(), IdeviceError> {
+        let device_root = device_dir.into();
+        let mut pending: Vec<(PathBuf, String)> =
+            vec![(host_dir.as_ref().to_path_buf(), device_root.clone())];
+        // Filling a directory changes its time, so they're set at the end
+        let mut dir_times: Vec<(String, SystemTime)> = Vec::new();
+        while let Some((host_dir, device_dir)) = pending.pop() {
+            if self.exists(device_dir.as_str()).await?.is_none() {
+                self.mk_dir(device_dir.as_str()).await?;
+            }
+            dir_times.push((
+                device_dir.clone(),
+                tokio::fs::metadata(&host_dir).await?.modified()?,
+            ));
+            let mut entries = tokio::fs::read_dir(&host_dir).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let name = entry.file_name().to_string_lossy().to_string();
+                if !path::is_plain_name(&name) {
+                    warn!("Skipping unsafe entry name {name:?} in {host_dir:?}");
+                    continue;
+                }
+                let device_path = path::join(&device_dir, &name);
+
+                // DirEntry::file_type doesn't follow symlinks
+                let file_type = entry.file_type().await?;
+                if file_type.is_dir() {
+                    pending.push((entry.path(), device_path));
+                } else if file_type.is_file() {
+                    let mut input = tokio::fs::File::open(entry.path()).await?;
+                    let metadata = input.metadata().await?;
+                    let mut file = self
+                        .open(device_path.as_str(), AfcFopenMode::WrOnly)
+                        .await?;
+                    let mut buf = vec![0; AfcClient::MAX_TRANSFER as usize];
+                    let mut transferred = 0;
+                    loop {
+                        let n = input.read(&mut buf).await?;
+                        if n == 0 {
+                            break;
+                        }
+                        file.write(&buf[..n]).await?;
+                        transferred += n as u64;
+                        progress(SyncProgress {
+                            path: device_path.clone(),
+                            transferred,
+                            size: metadata.len(),
+                        });
+                    }
+                    file.close().await?;
+                    self.set_file_time(device_path.as_str(), metadata.modified()?)
+                        .await?;
+                } else if file_type.is_symlink() {
+                    let target = tokio::fs::read_link(entry.path()).await?;
+                    let target = target.to_string_lossy().replace('\\', "/");
+                    if !path::link_stays_within(&device_dir, &target, &device_root) {
+                        warn!("Skipping {:?}, which links outside the copy", entry.path());
+                        continue;
+                    }
+                    if self.exists(device_path.as_str()).await?.is_some() {
+                        self.remove(device_path.as_str()).await?;
+                    }
+                    self.make_symlink(target, device_path).await?;
+                } else {
+                    warn!(
+                        "Skipping {:?}, which isn't a file, directory or symlink",
+                        entry.path()
+                    );
+                }
+            }
+        }
+        // Children were pushed after their parents
+        for (dir, modified) in dir_times.into_iter().rev() {
+            self.set_file_time(dir, modified).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether there's a symlink at the path, without following it
+async fn is_symlink(path: &Path) -> Result<bool, IdeviceError> {
+    match tokio::fs::symlink_metadata(path).await {
+        Ok(m) => Ok(m.file_type().is_symlink()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
@@ -0,0 +1,130 @@
+// Jackson Coxson
+// What a device supports, based on its iOS version and class
+
+use plist::Value;
+
+use crate::{lockdownd::LockdowndClient, IdeviceError};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceClass {
+    IPhone,
+    IPad,
+    IPod,
+    AppleTv,
+    Watch,
+    RealityDevice,
+    Unknown(String),
+}
+
+impl From<&str> for DeviceClass {
+    fn from(value: &str) -> Self {
+        match value {
+            "iPhone" => Self::IPhone,
+            "iPad" => Self::IPad,
+            "iPod" => Self::IPod,
+            "AppleTV" => Self::AppleTv,
+            "Watch" => Self::Watch,
+            "RealityDevice" => Self::RealityDevice,
+            _ => Self::Unknown(value.to_string()),
+        }
+    }
+}
+
+/// Answers which protocols and services to use for a device
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceCapabilities {
+    /// Major, minor and patch of ProductVersion
+    pub version: (u8, u8, u8),
+    pub device_class: DeviceClass,
+}
+
+impl DeviceCapabilities {
+    /// # Arguments
+    /// `product_version` - The ProductVersion value, such as `17.4.1`
+    /// `device_class` - The DeviceClass value, such as `iPhone`
+    pub fn new(product_version: &str, device_class: &str) -> Self {
+        let mut parts = product_version
+            .split('.')
+            .map(|p| p.parse::<u8>().unwrap_or_default());
+        let version = (
+            parts.next().unwrap_or_default(),
+            parts.next().unwrap_or_default(),
+            parts.next().unwrap_or_default(),
+        );
+        Self {
+            version,
+            device_class: device_class.into(),
+        }
+    }
+
+    /// Reads ProductVersion and DeviceClass from lockdown
+    pub async fn from_lockdown(lockdown: &mut LockdowndClient) -> Result<Self, IdeviceError> {
+        let version = match lockdown.get_value("ProductVersion").await? {
+            Value::String(v) => v,
+            _ => return Err(IdeviceError::UnexpectedResponse),
+        };
+        let class = match lockdown.get_value("DeviceClass").await? {
+            Value::String(c) => c,
+            _ => return Err(IdeviceError::UnexpectedResponse),
+        };
+        Ok(Self::new(&version, &class))
+    }
+
+    pub fn major(&self) -> u8 {
+        self.version.0
+    }
+
+    /// Whether developer disk images must be personalized through TSS.
+    /// Before iOS 17, a signed image matching the iOS version is used instead.
+    pub fn needs_personalized_ddi(&self) -> bool {
+        self.major() >= 17
+    }
+
+    /// Whether developer services like instruments and debugserver can be started over lockdown.
+    /// iOS 17 moved them to RemoteXPC behind a tunnel.
+    pub fn developer_services_over_lockdown(&self) -> bool {
+        self.major() < 17
+    }
+
+    /// Whether apps can be installed through CoreDevice's app service.
+    /// installation_proxy is still available on every version.
+    pub fn uses_core_device_for_app_install(&self) -> bool {
+        self.major() >= 17
+    }
+
+    /// Whether the device can be paired over RemoteXPC without a cable
+    pub fn supports_remote_pairing(&self) -> bool {
+        match self.device_class {
+            DeviceClass::AppleTv | DeviceClass::RealityDevice => self.major() >= 17,
+            _ => false,
+        }
+    }
+
+    /// Whether developer mode exists and must be enabled to run developer signed apps
+    pub fn has_developer_mode(&self) -> bool {
+        match self.device_class {
+            DeviceClass::Watch => self.major() >= 9,
+            _ => self.major() >= 16,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gates_on_version() {
+        let old = DeviceCapabilities::new("15.7", "iPhone");
+        assert_eq!(old.version, (15, 7, 0));
+        assert!(!old.needs_personalized_ddi());
+        assert!(old.developer_services_over_lockdown());
+        assert!(!old.has_developer_mode());
+
+        let new = DeviceCapabilities::new("17.4.1", "AppleTV");
+        assert_eq!(new.version, (17, 4, 1));
+        assert!(new.needs_personalized_ddi());
+        assert!(!new.developer_services_over_lockdown());
+        assert!(new.supports_remote_pairing());
+    }
+}
@@ -2,9 +2,16 @@
 
 use crate::{lockdownd::LockdowndClient, Idevice, IdeviceError, IdeviceService};
 
+#[cfg(feature = "os_tun")]
+pub mod os_tun;
+
 use byteorder::{BigEndian, WriteBytesExt};
+use log::warn;
 use serde::{Deserialize, Serialize};
-use std::io::{self, Write};
+use std::{
+    io::{self, Write},
+    net::Ipv6Addr,
+};
 
 #[derive(Debug, PartialEq)]
 pub struct CDTunnelPacket {
@@ -61,7 +68,9 @@ impl CDTunnelPacket {
 
 pub struct CoreDeviceProxy {
     pub idevice: Idevice,
+    /// The MTU to request. Replaced with the device's MTU once the tunnel is established.
     pub mtu: u32,
+    parameters: Option<TunnelParameters>,
 }
 
 impl IdeviceService for CoreDeviceProxy {
@@ -116,20 +125,63 @@ pub struct HandshakeResponse {
     pub server_rsd_port: u16,
 }
 
+/// The network the device assigned for the tunnel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TunnelParameters {
+    pub client_address: Ipv6Addr,
+    pub netmask: Ipv6Addr,
+    pub server_address: Ipv6Addr,
+    /// The largest packet either side will send. Receive buffers must be at least this large.
+    pub mtu: u16,
+    /// The port of the RemoteXPC service discovery service on `server_address`
+    pub rsd_port: u16,
+}
+
+impl TryFrom<&HandshakeResponse> for TunnelParameters {
+    type Error = IdeviceError;
+
+    fn try_from(value: &HandshakeResponse) -> Result<Self, Self::Error> {
+        let parse = |a: &str| -> Result<Ipv6Addr, IdeviceError> {
+            a.parse().map_err(|_| {
+                warn!("Tunnel address {a} isn't IPv6");
+                IdeviceError::UnexpectedResponse
+            })
+        };
+        Ok(Self {
+            client_address: parse(&value.client_parameters.address)?,
+            netmask: parse(&value.client_parameters.netmask)?,
+            server_address: parse(&value.server_address)?,
+            mtu: value.client_parameters.mtu,
+            rsd_port: value.server_rsd_port,
+        })
+    }
+}
+
 impl CoreDeviceProxy {
     const DEFAULT_MTU: u32 = 16000;
+    /// The smallest MTU IPv6 allows
+    const MIN_MTU: u16 = 1280;
 
     pub fn new(idevice: Idevice) -> Self {
         Self {
             idevice,
             mtu: Self::DEFAULT_MTU,
+            parameters: None,
         }
     }
 
+    /// The parameters of the established tunnel, if any
+    pub fn tunnel_parameters(&self) -> Option<&TunnelParameters> {
+        self.parameters.as_ref()
+    }
+
+    /// Performs the handshake that starts the tunnel.
+    /// Receive sizes are set to the MTU the device answers with, which is checked to be
+    /// usable and no larger than requested.
     pub async fn establish_tunnel(&mut self) -> Result<HandshakeResponse, IdeviceError> {
         let req = HandshakeRequest {
             packet_type: "clientHandshakeRequest".to_string(),
-            mtu: Self::DEFAULT_MTU,
+            mtu: self.mtu,
         };
 
         let req = CDTunnelPacket::serialize(&CDTunnelPacket {
@@ -154,6 +206,17 @@ impl CoreDeviceProxy {
         let recv = self.idevice.read_raw(len).await?;
         let res = serde_json::from_slice::<HandshakeResponse>(&recv)?;
 
+        let parameters = TunnelParameters::try_from(&res)?;
+        if parameters.mtu < Self::MIN_MTU || parameters.mtu as u32 > self.mtu {
+            warn!(
+                "Device chose MTU {}, which can't be used with the requested {}",
+                parameters.mtu, self.mtu
+            );
+            return Err(IdeviceError::UnexpectedResponse);
+        }
+        self.mtu = parameters.mtu as u32;
+        self.parameters = Some(parameters);
+
         Ok(res)
     }
 
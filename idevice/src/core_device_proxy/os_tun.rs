@@ -0,0 +1,75 @@
+// Jackson Coxson
+// Binds a CoreDevice tunnel to a TUN interface on the host.
+// Creating the interface needs root or CAP_NET_ADMIN.
+
+use std::net::IpAddr;
+
+use log::debug;
+use tun_rs::AbstractDevice;
+
+use crate::IdeviceError;
+
+use super::{CoreDeviceProxy, TunnelParameters};
+
+/// A TUN interface carrying a CoreDevice tunnel, so any tool on the host can reach the
+/// device's tunnel address directly
+pub struct OsTun {
+    device: tun_rs::AsyncDevice,
+    proxy: CoreDeviceProxy,
+    parameters: TunnelParameters,
+}
+
+impl OsTun {
+    /// Creates and configures a TUN interface for an established tunnel
+    /// # Arguments
+    /// `proxy` - A proxy that `establish_tunnel` has succeeded on
+    pub fn create(proxy: CoreDeviceProxy) -> Result<Self, IdeviceError> {
+        let parameters = match proxy.tunnel_parameters() {
+            Some(p) => *p,
+            None => return Err(IdeviceError::NoEstablishedConnection),
+        };
+
+        let device = tun_rs::create(&tun_rs::Configuration::default())?;
+        device.add_address_v6(parameters.client_address.into(), 32)?;
+        device.set_mtu(parameters.mtu)?;
+        device.set_network_address(
+            IpAddr::V6(parameters.client_address),
+            IpAddr::V6(parameters.netmask),
+            Some(IpAddr::V6(parameters.server_address)),
+        )?;
+
+        let device = tun_rs::AsyncDevice::new(device)?;
+        device.enabled(true)?;
+        debug!("Created TUN interface {:?}", device.name());
+
+        Ok(Self {
+            device,
+            proxy,
+            parameters,
+        })
+    }
+
+    /// The name the OS gave the interface, such as `utun4`
+    pub fn name(&self) -> Result<String, IdeviceError> {
+        Ok(self.device.name()?)
+    }
+
+    pub fn parameters(&self) -> &TunnelParameters {
+        &self.parameters
+    }
+
+    /// Forwards packets between the interface and the device until either side fails
+    pub async fn run(mut self) -> Result<(), IdeviceError> {
+        let mut buf = vec![0; self.parameters.mtu as usize];
+        loop {
+            tokio::select! {
+                len = self.device.recv(&mut buf) => {
+                    self.proxy.send(&buf[..len?]).await?;
+                }
+                pkt = self.proxy.recv() => {
+                    self.device.send(&pkt?).await?;
+                }
+            }
+        }
+    }
+}
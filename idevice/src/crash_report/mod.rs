@@ -0,0 +1,4 @@
+// Jackson Coxson
+// Crash reports in the .ips format written by iOS 15 and later
+
+pub mod parser;
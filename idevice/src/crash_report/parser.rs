@@ -0,0 +1,212 @@
+// Jackson Coxson
+// Typed structures for the JSON body of .ips crash reports
+
+use serde::Deserialize;
+
+use crate::IdeviceError;
+
+/// The body of an `.ips` crash report, the part after the header line.
+/// Everything is optional, as which keys are present depends on the kind of report
+/// and the iOS version.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct IpsReport {
+    /// The name of the process that crashed
+    pub proc_name: Option<String>,
+    pub proc_path: Option<String>,
+    pub pid: Option<u64>,
+    pub parent_proc: Option<String>,
+    pub parent_pid: Option<u64>,
+    /// When the crash happened, such as `2025-01-31 12:00:00.0000 -0800`
+    pub capture_time: Option<String>,
+    pub incident: Option<String>,
+    /// The device's model, such as iPhone15,3
+    pub model_code: Option<String>,
+    pub os_version: Option<OsVersion>,
+    pub bundle_info: Option<BundleInfo>,
+    pub exception: Option<ExceptionInfo>,
+    pub termination: Option<Termination>,
+    /// The index of the thread that crashed in `threads`
+    pub faulting_thread: Option<usize>,
+    pub threads: Vec<Thread>,
+    /// The binaries loaded in the process, which frames refer to by index
+    pub used_images: Vec<BinaryImage>,
+    /// Where an uncaught exception, such as an Objective-C one, was thrown from
+    pub last_exception_backtrace: Vec<Frame>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct OsVersion {
+    /// Such as `iPhone OS 18.2`
+    pub train: Option<String>,
+    /// Such as `22C152`
+    pub build: Option<String>,
+    pub release_type: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct BundleInfo {
+    #[serde(rename = "CFBundleIdentifier")]
+    pub bundle_id: Option<String>,
+    #[serde(rename = "CFBundleShortVersionString")]
+    pub version: Option<String>,
+    #[serde(rename = "CFBundleVersion")]
+    pub build_version: Option<String>,
+}
+
+/// The Mach exception the process got
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ExceptionInfo {
+    /// Such as `EXC_BAD_ACCESS` or `EXC_CRASH`
+    #[serde(rename = "type")]
+    pub exception_type: Option<String>,
+    /// Such as `SIGSEGV`
+    pub signal: Option<String>,
+    /// Such as `KERN_INVALID_ADDRESS at 0x0000000000000000`
+    pub subtype: Option<String>,
+    /// The exception codes, formatted as hex
+    pub codes: Option<String>,
+}
+
+/// Why the process was killed
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Termination {
+    /// Such as `SIGNAL` or `FRONTBOARD`
+    pub namespace: Option<String>,
+    pub code: Option<u64>,
+    /// Such as `Trace/BPT trap: 5`
+    pub indicator: Option<String>,
+    /// The process that killed it
+    pub by_proc: Option<String>,
+    pub by_pid: Option<u64>,
+    pub reasons: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Thread {
+    pub id: Option<u64>,
+    pub name: Option<String>,
+    /// The dispatch queue the thread was running, such as `com.apple.main-thread`
+    pub queue: Option<String>,
+    /// Whether this is the thread that crashed
+    pub triggered: bool,
+    /// The stack, innermost frame first
+    pub frames: Vec<Frame>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Frame {
+    /// The index of the binary in `IpsReport::used_images`
+    pub image_index: usize,
+    /// The frame's address, relative to the start of the binary
+    pub image_offset: u64,
+    /// The symbol, for binaries the device could symbolicate
+    pub symbol: Option<String>,
+    /// The offset into the symbol
+    pub symbol_location: Option<u64>,
+    pub source_file: Option<String>,
+    pub source_line: Option<u64>,
+}
+
+/// A binary loaded in the crashed process
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct BinaryImage {
+    /// Where the binary was loaded
+    pub base: u64,
+    pub size: u64,
+    /// The UUID of the binary, which identifies its dSYM
+    pub uuid: Option<String>,
+    pub path: Option<String>,
+    pub name: Option<String>,
+    /// Such as `arm64e`
+    pub arch: Option<String>,
+}
+
+impl IpsReport {
+    /// Parses the body of an `.ips` report.
+    /// Reports written before iOS 15 are text and can't be parsed.
+    pub fn parse(body: &str) -> Result<Self, IdeviceError> {
+        Ok(serde_json::from_str(body)?)
+    }
+
+    /// Parses a whole `.ips` report, skipping its header line
+    pub fn parse_ips(contents: &[u8]) -> Result<Self, IdeviceError> {
+        let body = match contents.iter().position(|b| *b == b'\n') {
+            Some(i) => &contents[i + 1..],
+            None => return Err(IdeviceError::UnexpectedResponse),
+        };
+        Ok(serde_json::from_slice(body)?)
+    }
+
+    /// The thread that crashed
+    pub fn crashed_thread(&self) -> Option<&Thread> {
+        match self.faulting_thread {
+            Some(i) => self.threads.get(i),
+            None => self.threads.iter().find(|t| t.triggered),
+        }
+    }
+
+    /// The binary a frame is in
+    pub fn image(&self, frame: &Frame) -> Option<&BinaryImage> {
+        self.used_images.get(frame.image_index)
+    }
+
+    /// The address a frame was at in the process, for symbolicating with `atos`
+    pub fn address(&self, frame: &Frame) -> Option<u64> {
+        self.image(frame).map(|i| i.base + frame.image_offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_report_body() {
+        let contents = br#"{"app_name":"Demo","bug_type":"309"}
+{
+  "procName": "Demo",
+  "pid": 812,
+  "modelCode": "iPhone15,3",
+  "osVersion": {"train": "iPhone OS 18.2", "build": "22C152", "releaseType": "User"},
+  "bundleInfo": {"CFBundleIdentifier": "com.example.demo", "CFBundleVersion": "7"},
+  "exception": {"type": "EXC_BAD_ACCESS", "signal": "SIGSEGV", "subtype": "KERN_INVALID_ADDRESS at 0x0000000000000000", "codes": "0x0000000000000001, 0x0000000000000000"},
+  "termination": {"namespace": "SIGNAL", "code": 11, "indicator": "Segmentation fault: 11", "byProc": "exc handler", "byPid": 812},
+  "faultingThread": 1,
+  "threads": [
+    {"id": 1, "queue": "com.apple.main-thread", "frames": [{"imageOffset": 16, "imageIndex": 1}]},
+    {"id": 2, "triggered": true, "frames": [{"imageOffset": 4096, "symbol": "crash", "symbolLocation": 8, "imageIndex": 0}]}
+  ],
+  "usedImages": [
+    {"source": "P", "arch": "arm64", "base": 4294967296, "size": 16384, "uuid": "1234", "path": "/private/var/containers/Bundle/Application/X/Demo.app/Demo", "name": "Demo"},
+    {"source": "A", "size": 0}
+  ],
+  "unknownKey": [1, 2, 3]
+}"#;
+        let report = IpsReport::parse_ips(contents).unwrap();
+        assert_eq!(report.proc_name.as_deref(), Some("Demo"));
+        assert_eq!(
+            report.bundle_info.as_ref().unwrap().bundle_id.as_deref(),
+            Some("com.example.demo")
+        );
+        assert_eq!(
+            report.exception.as_ref().unwrap().exception_type.as_deref(),
+            Some("EXC_BAD_ACCESS")
+        );
+        assert_eq!(report.termination.as_ref().unwrap().code, Some(11));
+
+        let thread = report.crashed_thread().unwrap();
+        assert_eq!(thread.id, Some(2));
+        let frame = &thread.frames[0];
+        assert_eq!(report.image(frame).unwrap().name.as_deref(), Some("Demo"));
+        assert_eq!(report.address(frame), Some(4294967296 + 4096));
+        assert_eq!(report.image(&report.threads[0].frames[0]).unwrap().base, 0);
+    }
+}
@@ -0,0 +1,270 @@
+// Jackson Coxson
+// Abstractions for crashreportcopymobile, an AFC service rooted at the crash and diagnostic logs
+
+use std::{
+    collections::{HashSet, VecDeque},
+    sync::Arc,
+    time::Duration,
+};
+
+use futures::Stream;
+use log::debug;
+use serde::Deserialize;
+
+use crate::{
+    afc::{opcode::AfcFopenMode, path, AfcClient},
+    crash_report::parser::IpsReport,
+    lockdownd::LockdowndClient,
+    provider::IdeviceProvider,
+    utils::stream::{until_stopped, StopHandle},
+    IdeviceError, IdeviceService,
+};
+
+/// Crash reports, sysdiagnose archives and other diagnostic logs,
+/// read with the same operations as `AfcClient`
+pub struct CrashReportCopyMobileClient {
+    pub afc: AfcClient,
+}
+
+impl IdeviceService for CrashReportCopyMobileClient {
+    fn service_name() -> &'static str {
+        "com.apple.crashreportcopymobile"
+    }
+
+    async fn connect(
+        provider: &dyn crate::provider::IdeviceProvider,
+    ) -> Result<Self, IdeviceError> {
+        let afc = AfcClient::connect_with_service_name(provider, Self::service_name()).await?;
+        Ok(Self::new(afc))
+    }
+}
+
+impl CrashReportCopyMobileClient {
+    /// Where sysdiagnose archives are written to
+    pub const SYSDIAGNOSE_DIR: &'static str = "/DiagnosticLogs/sysdiagnose";
+
+    pub fn new(afc: AfcClient) -> Self {
+        Self { afc }
+    }
+
+    /// Lists the crash reports at the root of the service, newest names last.
+    /// Call `move_crash_reports` first to include crashes the device hasn't moved there yet.
+    pub async fn list_crash_reports(&mut self) -> Result<Vec<String>, IdeviceError> {
+        let mut names = self
+            .afc
+            .list_dir("/")
+            .await?
+            .into_iter()
+            .filter(|n| n.ends_with(".ips"))
+            .collect::<Vec<_>>();
+        names.sort();
+        Ok(names)
+    }
+
+    /// Downloads and parses a crash report
+    /// # Arguments
+    /// `name` - The report's path, such as one from `list_crash_reports`
+    pub async fn get_crash_report(&mut self, name: &str) -> Result<CrashReport, IdeviceError> {
+        let path = path::join("/", name);
+        let mut file = self.afc.open(path.as_str(), AfcFopenMode::RdOnly).await?;
+        let contents = file.read().await;
+        let closed = file.close().await;
+        let contents = contents?;
+        closed?;
+        CrashReport::parse(path, &contents)
+    }
+}
+
+/// Asks the device to move new crash reports to where crashreportcopymobile can read them,
+/// and waits until it has
+pub async fn move_crash_reports(provider: &dyn IdeviceProvider) -> Result<(), IdeviceError> {
+    let mut lockdown = LockdowndClient::connect(provider).await?;
+    lockdown
+        .start_session(&provider.get_pairing_file().await?)
+        .await?;
+    let (port, ssl) = lockdown.start_service("com.apple.crashreportmover").await?;
+
+    let mut idevice = provider.connect(port).await?;
+    if ssl {
+        idevice
+            .start_session(&provider.get_pairing_file().await?)
+            .await?;
+    }
+    // The mover says ping once it's done
+    match idevice.read_raw(4).await?.as_slice() {
+        b"ping" => Ok(()),
+        _ => Err(IdeviceError::UnexpectedResponse),
+    }
+}
+
+/// A crash report, with the JSON header of the `.ips` format parsed
+#[derive(Debug, Clone)]
+pub struct CrashReport {
+    /// Where the report is on the device
+    pub path: String,
+    /// The process that crashed
+    pub name: Option<String>,
+    pub app_name: Option<String>,
+    pub bundle_id: Option<String>,
+    pub app_version: Option<String>,
+    pub build_version: Option<String>,
+    /// The kind of report, such as 309 for a crash or 210 for a panic
+    pub bug_type: Option<String>,
+    /// When the report was written, such as `2025-01-31 12:00:00.00 -0800`
+    pub timestamp: Option<String>,
+    pub os_version: Option<String>,
+    pub incident_id: Option<String>,
+    /// The whole header
+    pub header: serde_json::Value,
+    /// Everything after the header line
+    pub body: String,
+}
+
+impl CrashReport {
+    /// Parses a report. The first line of the `.ips` format is a JSON header, and the rest
+    /// is the report in JSON or, on older versions of iOS, text.
+    pub fn parse(path: impl Into<String>, contents: &[u8]) -> Result<Self, IdeviceError> {
+        #[derive(Deserialize)]
+        struct Header {
+            name: Option<String>,
+            app_name: Option<String>,
+            #[serde(rename = "bundleID")]
+            bundle_id: Option<String>,
+            app_version: Option<String>,
+            build_version: Option<String>,
+            bug_type: Option<String>,
+            timestamp: Option<String>,
+            os_version: Option<String>,
+            incident_id: Option<String>,
+        }
+
+        let (header, body) = match contents.iter().position(|b| *b == b'\n') {
+            Some(i) => (&contents[..i], &contents[i + 1..]),
+            None => (contents, &[][..]),
+        };
+        let value: serde_json::Value = serde_json::from_slice(header)?;
+        let h = Header::deserialize(&value)?;
+        Ok(Self {
+            path: path.into(),
+            name: h.name,
+            app_name: h.app_name,
+            bundle_id: h.bundle_id,
+            app_version: h.app_version,
+            build_version: h.build_version,
+            bug_type: h.bug_type,
+            timestamp: h.timestamp,
+            os_version: h.os_version,
+            incident_id: h.incident_id,
+            header: value,
+            body: String::from_utf8_lossy(body).to_string(),
+        })
+    }
+
+    /// Parses the body into the exception, threads and binary images
+    pub fn details(&self) -> Result<IpsReport, IdeviceError> {
+        IpsReport::parse(&self.body)
+    }
+}
+
+/// Watches for new crash reports, pulling each one as it appears
+pub struct CrashWatcher {
+    provider: Arc<dyn IdeviceProvider>,
+    client: CrashReportCopyMobileClient,
+    bundle_id: Option<String>,
+    interval: Duration,
+    seen: HashSet<String>,
+    pending: VecDeque<String>,
+}
+
+impl CrashWatcher {
+    /// How often the device is checked for new reports by default
+    pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(5);
+
+    /// Starts watching. Reports already on the device aren't reported.
+    pub async fn new(provider: Arc<dyn IdeviceProvider>) -> Result<Self, IdeviceError> {
+        move_crash_reports(&*provider).await?;
+        let mut client = CrashReportCopyMobileClient::connect(&*provider).await?;
+        let seen = client.list_crash_reports().await?.into_iter().collect();
+        Ok(Self {
+            provider,
+            client,
+            bundle_id: None,
+            interval: Self::DEFAULT_INTERVAL,
+            seen,
+            pending: VecDeque::new(),
+        })
+    }
+
+    /// Only reports crashes of the app with this bundle identifier
+    pub fn bundle_id(mut self, bundle_id: impl Into<String>) -> Self {
+        self.bundle_id = Some(bundle_id.into());
+        self
+    }
+
+    /// How often to check the device for new reports
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Waits for the next new report
+    pub async fn next_report(&mut self) -> Result<CrashReport, IdeviceError> {
+        loop {
+            let name = match self.pending.pop_front() {
+                Some(n) => n,
+                None => {
+                    tokio::time::sleep(self.interval).await;
+                    move_crash_reports(&*self.provider).await?;
+                    for name in self.client.list_crash_reports().await? {
+                        if self.seen.insert(name.clone()) {
+                            self.pending.push_back(name);
+                        }
+                    }
+                    continue;
+                }
+            };
+
+            let report = self.client.get_crash_report(&name).await?;
+            match &self.bundle_id {
+                Some(id) if report.bundle_id.as_ref() != Some(id) => {
+                    debug!("Skipping crash report {name} for {:?}", report.bundle_id);
+                }
+                _ => return Ok(report),
+            }
+        }
+    }
+
+    /// Turns the watcher into a stream of new reports.
+    /// Use `StreamExt::for_each` to call a function for each one.
+    pub fn into_stream(
+        self,
+    ) -> (
+        impl Stream<Item = Result<CrashReport, IdeviceError>>,
+        StopHandle,
+    ) {
+        let stop = StopHandle::new();
+        let stream = until_stopped(self, stop.clone(), |mut watcher| async move {
+            let res = watcher.next_report().await;
+            (res, watcher)
+        });
+        (stream, stop)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_ips_header() {
+        let contents = br#"{"app_name":"Demo","timestamp":"2025-01-31 12:00:00.00 -0800","app_version":"1.2","bug_type":"309","os_version":"iPhone OS 18.2 (22C152)","bundleID":"com.example.demo","name":"Demo","incident_id":"ABC"}
+{"exception":{"type":"EXC_CRASH"}}"#;
+        let report = CrashReport::parse("/Demo-2025-01-31-120000.ips", contents).unwrap();
+        assert_eq!(report.bundle_id.as_deref(), Some("com.example.demo"));
+        assert_eq!(report.bug_type.as_deref(), Some("309"));
+        assert_eq!(report.build_version, None);
+        assert_eq!(report.header["app_version"], "1.2");
+        assert_eq!(report.body, r#"{"exception":{"type":"EXC_CRASH"}}"#);
+        assert!(CrashReport::parse("/bad.ips", b"not json").is_err());
+    }
+}
@@ -0,0 +1,168 @@
+// Jackson Coxson
+// DeviceLink, the message layer older services such as screenshotr speak over plists.
+// Every message is an array whose first element is its type.
+
+use log::warn;
+use plist::Value;
+
+use crate::{Idevice, IdeviceError};
+
+/// DeviceLink sends this in place of an empty string
+const EMPTY_PARAMETER: &str = "___EmptyParameterString___";
+
+pub struct DeviceLinkClient {
+    pub idevice: Idevice,
+}
+
+impl DeviceLinkClient {
+    /// Wraps a connection to a DeviceLink service.
+    /// Call `exchange_versions` before anything else unless it's already been done.
+    pub fn new(idevice: Idevice) -> Self {
+        Self { idevice }
+    }
+
+    /// Agrees on the protocol version and waits for the service to be ready
+    /// # Arguments
+    /// `version` - The major version the service is expected to speak
+    pub async fn exchange_versions(&mut self, version: u64) -> Result<(), IdeviceError> {
+        let res = self.read_message("DLMessageVersionExchange").await?;
+        match res.get(1).and_then(|v| v.as_unsigned_integer()) {
+            Some(v) if v == version => {}
+            v => warn!("Unexpected DeviceLink version {v:?}"),
+        }
+        self.send_message(vec![
+            "DLMessageVersionExchange".into(),
+            "DLVersionsOk".into(),
+            version.into(),
+        ])
+        .await?;
+        self.read_message("DLMessageDeviceReady").await?;
+        Ok(())
+    }
+
+    /// Sends a request to the service
+    pub async fn send_process_message(
+        &mut self,
+        message: plist::Dictionary,
+    ) -> Result<(), IdeviceError> {
+        self.send_message(vec![
+            "DLMessageProcessMessage".into(),
+            Value::Dictionary(message),
+        ])
+        .await
+    }
+
+    /// Reads a reply from the service
+    pub async fn read_process_message(&mut self) -> Result<plist::Dictionary, IdeviceError> {
+        let mut res = self.read_message("DLMessageProcessMessage").await?;
+        match res.pop() {
+            Some(Value::Dictionary(res)) => Ok(res),
+            _ => Err(IdeviceError::UnexpectedResponse),
+        }
+    }
+
+    /// Answers a request the service made of the host
+    /// # Arguments
+    /// `code` - 0 for success, or an errno
+    /// `status` - A description of the failure, if any
+    /// `info` - Anything the request asked for
+    pub async fn send_status_response(
+        &mut self,
+        code: i64,
+        status: Option<&str>,
+        info: Value,
+    ) -> Result<(), IdeviceError> {
+        self.send_message(vec![
+            "DLMessageStatusResponse".into(),
+            code.into(),
+            status.unwrap_or(EMPTY_PARAMETER).into(),
+            info,
+        ])
+        .await
+    }
+
+    /// Tells the service the host is done, after which it closes the connection
+    pub async fn disconnect(&mut self) -> Result<(), IdeviceError> {
+        self.send_message(vec!["DLMessageDisconnect".into(), EMPTY_PARAMETER.into()])
+            .await
+    }
+
+    pub async fn send_message(&mut self, message: Vec<Value>) -> Result<(), IdeviceError> {
+        self.idevice.send_plist(Value::Array(message)).await
+    }
+
+    /// Reads a message of any type
+    pub async fn read_any_message(&mut self) -> Result<Vec<Value>, IdeviceError> {
+        match self.idevice.read_plist_value().await? {
+            Value::Array(message) if message.first().and_then(|m| m.as_string()).is_some() => {
+                Ok(message)
+            }
+            m => {
+                warn!("Expected a DeviceLink message, got {m:?}");
+                Err(IdeviceError::UnexpectedResponse)
+            }
+        }
+    }
+
+    /// Reads a message, failing if it isn't of the expected type
+    pub async fn read_message(&mut self, expected: &str) -> Result<Vec<Value>, IdeviceError> {
+        let message = self.read_any_message().await?;
+        if message[0].as_string() == Some(expected) {
+            Ok(message)
+        } else {
+            warn!("Expected {expected}, got {message:?}");
+            Err(IdeviceError::UnexpectedResponse)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    use super::*;
+
+    async fn write(server: &mut DuplexStream, message: Vec<Value>) {
+        let mut bytes = Vec::new();
+        Value::Array(message).to_writer_xml(&mut bytes).unwrap();
+        server.write_u32(bytes.len() as u32).await.unwrap();
+        server.write_all(&bytes).await.unwrap();
+    }
+
+    async fn read(server: &mut DuplexStream) -> Vec<Value> {
+        let len = server.read_u32().await.unwrap();
+        let mut buf = vec![0; len as usize];
+        server.read_exact(&mut buf).await.unwrap();
+        plist::from_bytes(&buf).unwrap()
+    }
+
+    #[tokio::test]
+    async fn version_exchange_and_messages() {
+        let (client, mut server) = tokio::io::duplex(4096);
+        let mut link = DeviceLinkClient::new(Idevice::new(Box::new(client), "test"));
+
+        let device = tokio::spawn(async move {
+            write(
+                &mut server,
+                vec!["DLMessageVersionExchange".into(), 300.into(), 0.into()],
+            )
+            .await;
+            let res = read(&mut server).await;
+            assert_eq!(res[1], "DLVersionsOk".into());
+            write(&mut server, vec!["DLMessageDeviceReady".into()]).await;
+
+            let req = read(&mut server).await;
+            assert_eq!(req[0], "DLMessageProcessMessage".into());
+            write(&mut server, req).await;
+            write(&mut server, vec!["DLMessageDisconnect".into()]).await;
+        });
+
+        link.exchange_versions(300).await.unwrap();
+        let mut req = plist::Dictionary::new();
+        req.insert("MessageType".into(), "Echo".into());
+        link.send_process_message(req.clone()).await.unwrap();
+        assert_eq!(link.read_process_message().await.unwrap(), req);
+        assert!(link.read_process_message().await.is_err());
+        device.await.unwrap();
+    }
+}
@@ -1,10 +1,41 @@
 // Jackson Coxson
 // Abstractions for the heartbeat service on iOS
 
-use crate::{lockdownd::LockdowndClient, Idevice, IdeviceError, IdeviceService};
+use futures::Stream;
+
+use crate::{
+    lockdownd::LockdowndClient,
+    utils::stream::{until_stopped, StopHandle},
+    Idevice, IdeviceError, IdeviceService,
+};
 
 pub struct HeartbeatClient {
     pub idevice: Idevice,
+    on_sleep: Option<SleepCallback>,
+}
+
+/// Called with the interval the device sent along with `SleepyTime`, if any
+pub type SleepCallback = Box<dyn Fn(Option<u64>) + Send + Sync>;
+
+/// Timing for `HeartbeatClient::into_stream_with_config`
+#[derive(Debug, Clone, Copy)]
+pub struct HeartbeatConfig {
+    /// Seconds to wait for the first heartbeat
+    pub initial_interval: u64,
+    /// Extra seconds to wait beyond the interval before giving up on the device
+    pub grace: u64,
+    /// Waits this many seconds for every heartbeat instead of the interval the device asks for
+    pub fixed_interval: Option<u64>,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            initial_interval: 15,
+            grace: 0,
+            fixed_interval: None,
+        }
+    }
 }
 
 impl IdeviceService for HeartbeatClient {
@@ -29,13 +60,22 @@ impl IdeviceService for HeartbeatClient {
                 .await?;
         }
 
-        Ok(Self { idevice })
+        Ok(Self::new(idevice))
     }
 }
 
 impl HeartbeatClient {
     pub fn new(idevice: Idevice) -> Self {
-        Self { idevice }
+        Self {
+            idevice,
+            on_sleep: None,
+        }
+    }
+
+    /// Sets a function to call when the device says it's going to sleep,
+    /// such as to back off reconnecting until it wakes
+    pub fn set_sleep_callback(&mut self, on_sleep: impl Fn(Option<u64>) + Send + Sync + 'static) {
+        self.on_sleep = Some(Box::new(on_sleep));
     }
 
     pub async fn get_marco(&mut self, interval: u64) -> Result<u64, IdeviceError> {
@@ -46,24 +86,22 @@ impl HeartbeatClient {
                 return Err(IdeviceError::HeartbeatTimeout)
             }
         };
-        match rec.get("Interval") {
-            Some(plist::Value::Integer(interval)) => {
-                if let Some(interval) = interval.as_unsigned() {
-                    Ok(interval)
-                } else {
-                    Err(IdeviceError::UnexpectedResponse)
+        let interval = match rec.get("Interval") {
+            Some(plist::Value::Integer(interval)) => match interval.as_unsigned() {
+                Some(interval) => Some(interval),
+                None => return Err(IdeviceError::UnexpectedResponse),
+            },
+            _ => None,
+        };
+        match rec.get("Command") {
+            // SleepyTime can carry an interval too, so check the command first
+            Some(plist::Value::String(command)) if command.as_str() == "SleepyTime" => {
+                if let Some(on_sleep) = &self.on_sleep {
+                    on_sleep(interval);
                 }
+                Err(IdeviceError::HeartbeatSleepyTime)
             }
-            _ => match rec.get("Command") {
-                Some(plist::Value::String(command)) => {
-                    if command.as_str() == "SleepyTime" {
-                        Err(IdeviceError::HeartbeatSleepyTime)
-                    } else {
-                        Err(IdeviceError::UnexpectedResponse)
-                    }
-                }
-                _ => Err(IdeviceError::UnexpectedResponse),
-            },
+            _ => interval.ok_or(IdeviceError::UnexpectedResponse),
         }
     }
 
@@ -75,4 +113,41 @@ impl HeartbeatClient {
             .await?;
         Ok(())
     }
+
+    /// Answers heartbeats until stopped, yielding the interval the device asks for each time.
+    /// The stream ends after the first error, such as the device going to sleep.
+    /// # Arguments
+    /// `interval` - The number of seconds to wait for the first heartbeat
+    pub fn into_stream(
+        self,
+        interval: u64,
+    ) -> (impl Stream<Item = Result<u64, IdeviceError>>, StopHandle) {
+        self.into_stream_with_config(HeartbeatConfig {
+            initial_interval: interval,
+            ..Default::default()
+        })
+    }
+
+    /// Like `into_stream`, with control over how long each heartbeat is waited for
+    pub fn into_stream_with_config(
+        self,
+        config: HeartbeatConfig,
+    ) -> (impl Stream<Item = Result<u64, IdeviceError>>, StopHandle) {
+        let stop = StopHandle::new();
+        let stream = until_stopped(
+            (self, config.initial_interval),
+            stop.clone(),
+            move |(mut client, interval)| async move {
+                let res = match client.get_marco(interval + config.grace).await {
+                    Ok(interval) => client.send_polo().await.map(|_| interval),
+                    Err(e) => Err(e),
+                };
+                let next = config
+                    .fixed_interval
+                    .unwrap_or(*res.as_ref().unwrap_or(&interval));
+                (res, (client, next))
+            },
+        );
+        (stream, stop)
+    }
 }
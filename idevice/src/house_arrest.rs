@@ -0,0 +1,88 @@
+// Jackson Coxson
+// Abstractions for house_arrest, which opens AFC sessions inside an app's container
+
+use log::warn;
+
+use crate::{afc::AfcClient, lockdownd::LockdowndClient, Idevice, IdeviceError, IdeviceService};
+
+pub struct HouseArrestClient {
+    pub idevice: Idevice,
+}
+
+impl IdeviceService for HouseArrestClient {
+    fn service_name() -> &'static str {
+        "com.apple.mobile.house_arrest"
+    }
+
+    async fn connect(
+        provider: &dyn crate::provider::IdeviceProvider,
+    ) -> Result<Self, IdeviceError> {
+        let mut lockdown = LockdowndClient::connect(provider).await?;
+        lockdown
+            .start_session(&provider.get_pairing_file().await?)
+            .await?;
+
+        let (port, ssl) = lockdown.start_service(Self::service_name()).await?;
+
+        let mut idevice = provider.connect(port).await?;
+        if ssl {
+            idevice
+                .start_session(&provider.get_pairing_file().await?)
+                .await?;
+        }
+
+        Ok(Self::new(idevice))
+    }
+}
+
+impl HouseArrestClient {
+    pub fn new(idevice: Idevice) -> Self {
+        Self { idevice }
+    }
+
+    /// Opens the app's whole container, which only works for apps that are debuggable,
+    /// such as ones installed with a development profile
+    /// # Arguments
+    /// `bundle_id` - The app to open
+    /// # Returns
+    /// An AFC client rooted at the container
+    pub async fn vend_container(
+        self,
+        bundle_id: impl Into<String>,
+    ) -> Result<AfcClient, IdeviceError> {
+        self.vend("VendContainer", bundle_id.into()).await
+    }
+
+    /// Opens the app's Documents directory, which works for apps that share files
+    /// through `UIFileSharingEnabled`
+    /// # Arguments
+    /// `bundle_id` - The app to open
+    /// # Returns
+    /// An AFC client rooted at the container, with the documents under `/Documents`
+    pub async fn vend_documents(
+        self,
+        bundle_id: impl Into<String>,
+    ) -> Result<AfcClient, IdeviceError> {
+        self.vend("VendDocuments", bundle_id.into()).await
+    }
+
+    async fn vend(mut self, command: &str, bundle_id: String) -> Result<AfcClient, IdeviceError> {
+        let mut req = plist::Dictionary::new();
+        req.insert("Command".into(), command.into());
+        req.insert("Identifier".into(), bundle_id.into());
+        self.idevice
+            .send_plist(plist::Value::Dictionary(req))
+            .await?;
+
+        // Errors such as an unknown bundle ID come back under "Error"
+        let res = self.idevice.read_plist().await?;
+        match res.get("Status").and_then(|s| s.as_string()) {
+            // The connection speaks AFC from here on
+            Some("Complete") => Ok(AfcClient::new(self.idevice)),
+            s => {
+                warn!("{command} failed with status {s:?}");
+                Err(IdeviceError::UnexpectedResponse)
+            }
+        }
+    }
+}
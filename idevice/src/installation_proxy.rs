@@ -3,12 +3,303 @@
 
 use std::collections::HashMap;
 
-use crate::{lockdownd::LockdowndClient, Idevice, IdeviceError, IdeviceService};
+use futures::{Stream, StreamExt};
+use log::debug;
+
+use crate::{
+    lockdownd::LockdowndClient,
+    utils::schema::{FromPlist, SchemaError},
+    Idevice, IdeviceError, IdeviceService,
+};
 
 pub struct InstallationProxyClient {
     pub idevice: Idevice,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ApplicationType {
+    #[default]
+    Any,
+    /// Apps installed by the user
+    User,
+    /// Apps that ship with iOS
+    System,
+    /// Apps hidden from the home screen
+    Internal,
+}
+
+impl ApplicationType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Any => "Any",
+            Self::User => "User",
+            Self::System => "System",
+            Self::Internal => "Internal",
+        }
+    }
+}
+
+/// An attribute of an installed app that can be requested
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AppAttribute {
+    BundleIdentifier,
+    DisplayName,
+    BundleName,
+    /// `CFBundleShortVersionString`, such as 1.2.0
+    ShortVersion,
+    /// `CFBundleVersion`, the build number
+    BundleVersion,
+    ApplicationType,
+    Path,
+    Container,
+    Entitlements,
+    SignerIdentity,
+    /// `CFBundleIcons`, the icon names by size
+    Icons,
+    /// `CFBundleIcons~ipad`, the icon names used on iPad
+    IpadIcons,
+    /// `CFBundleIconFiles`, the icon names used before iOS 5
+    IconFiles,
+    /// Any other key, as the device names it
+    Other(String),
+}
+
+impl AppAttribute {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::BundleIdentifier => "CFBundleIdentifier",
+            Self::DisplayName => "CFBundleDisplayName",
+            Self::BundleName => "CFBundleName",
+            Self::ShortVersion => "CFBundleShortVersionString",
+            Self::BundleVersion => "CFBundleVersion",
+            Self::ApplicationType => "ApplicationType",
+            Self::Path => "Path",
+            Self::Container => "Container",
+            Self::Entitlements => "Entitlements",
+            Self::SignerIdentity => "SignerIdentity",
+            Self::Icons => "CFBundleIcons",
+            Self::IpadIcons => "CFBundleIcons~ipad",
+            Self::IconFiles => "CFBundleIconFiles",
+            Self::Other(s) => s,
+        }
+    }
+}
+
+/// Which attributes the device returns for each app.
+/// `CFBundleIdentifier` is always returned.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum ReturnAttributes {
+    /// The bundle identifier and names
+    Minimal,
+    /// The attributes `AppInfo` has fields for, other than the entitlements
+    Standard,
+    /// Every attribute. This is slow when listing all apps.
+    #[default]
+    Everything,
+    /// The names, path and icon names, enough to find an app's icons in its bundle
+    Icons,
+    Custom(Vec<AppAttribute>),
+}
+
+impl ReturnAttributes {
+    /// An empty set to add attributes to with `with`
+    pub fn custom() -> Self {
+        Self::Custom(Vec::new())
+    }
+
+    /// Adds an attribute. Presets are turned into a custom set with their attributes.
+    /// Adding to `Everything` leaves it unchanged.
+    pub fn with(self, attribute: AppAttribute) -> Self {
+        let mut attributes = match self.attributes() {
+            Some(a) => a,
+            None => return self,
+        };
+        if !attributes.iter().any(|a| a.as_str() == attribute.as_str()) {
+            attributes.push(attribute);
+        }
+        Self::Custom(attributes)
+    }
+
+    /// The attributes to request, or `None` for every attribute
+    pub fn attributes(&self) -> Option<Vec<AppAttribute>> {
+        use AppAttribute::*;
+        let mut attributes = match self {
+            Self::Minimal => vec![BundleIdentifier, DisplayName, BundleName],
+            Self::Standard => vec![
+                BundleIdentifier,
+                DisplayName,
+                BundleName,
+                ShortVersion,
+                BundleVersion,
+                ApplicationType,
+                Path,
+                Container,
+            ],
+            Self::Everything => return None,
+            Self::Icons => vec![
+                BundleIdentifier,
+                DisplayName,
+                BundleName,
+                Path,
+                Icons,
+                IpadIcons,
+                IconFiles,
+            ],
+            Self::Custom(a) => a.clone(),
+        };
+        if !attributes
+            .iter()
+            .any(|a| a.as_str() == BundleIdentifier.as_str())
+        {
+            attributes.insert(0, BundleIdentifier);
+        }
+        Some(attributes)
+    }
+
+    fn to_plist(&self) -> Option<plist::Value> {
+        self.attributes().map(|a| {
+            plist::Value::Array(
+                a.iter()
+                    .map(|a| plist::Value::String(a.as_str().to_string()))
+                    .collect(),
+            )
+        })
+    }
+}
+
+/// Filters and attribute selection for `browse` and `lookup`
+#[derive(Debug, Clone, Default)]
+pub struct ClientOptions {
+    application_type: ApplicationType,
+    bundle_ids: Option<Vec<String>>,
+    return_attributes: ReturnAttributes,
+}
+
+impl ClientOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn application_type(mut self, application_type: ApplicationType) -> Self {
+        self.application_type = application_type;
+        self
+    }
+
+    /// Only returns the apps with these bundle identifiers
+    pub fn bundle_ids(mut self, ids: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.bundle_ids = Some(ids.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Only returns these attributes, which makes listing all apps much faster
+    pub fn attributes(mut self, attributes: ReturnAttributes) -> Self {
+        self.return_attributes = attributes;
+        self
+    }
+
+    /// Only returns these attributes, such as `CFBundleVersion` or `Entitlements`, which makes
+    /// listing all apps much faster. `CFBundleIdentifier` is always returned.
+    pub fn return_attributes(
+        mut self,
+        attributes: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.return_attributes = ReturnAttributes::Custom(
+            attributes
+                .into_iter()
+                .map(|a| AppAttribute::Other(a.into()))
+                .collect(),
+        );
+        self
+    }
+
+    fn into_plist(self) -> plist::Dictionary {
+        let mut options = plist::Dictionary::new();
+        options.insert(
+            "ApplicationType".into(),
+            self.application_type.as_str().into(),
+        );
+        if let Some(ids) = self.bundle_ids {
+            options.insert(
+                "BundleIDs".into(),
+                plist::Value::Array(ids.into_iter().map(plist::Value::String).collect()),
+            );
+        }
+        if let Some(attributes) = self.return_attributes.to_plist() {
+            options.insert("ReturnAttributes".into(), attributes);
+        }
+        options
+    }
+}
+
+/// An update sent while a package installs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InstallProgress {
+    /// The step the install is at, such as `CreatingStagingDirectory` or `VerifyingApplication`.
+    /// The last update is `Complete`.
+    pub status: String,
+    /// How far along the whole install is, when the device says
+    pub percent_complete: Option<u64>,
+}
+
+/// An installed app. Fields are `None` when they weren't requested or the app doesn't have them.
+#[derive(Debug, Clone)]
+pub struct AppInfo {
+    pub bundle_id: String,
+    /// The name shown on the home screen
+    pub name: Option<String>,
+    /// `CFBundleShortVersionString`, such as 1.2.0
+    pub version: Option<String>,
+    /// `CFBundleVersion`, the build number
+    pub build_version: Option<String>,
+    pub application_type: Option<String>,
+    /// Where the app bundle is installed
+    pub path: Option<String>,
+    /// The app's data container
+    pub container: Option<String>,
+    pub entitlements: Option<plist::Dictionary>,
+    /// All of the attributes the device returned
+    pub raw: plist::Dictionary,
+}
+
+impl AppInfo {
+    pub fn from_plist(raw: plist::Dictionary) -> Result<Self, SchemaError> {
+        #[derive(FromPlist)]
+        struct Fields {
+            #[plist(rename = "CFBundleIdentifier")]
+            bundle_id: String,
+            #[plist(rename = "CFBundleDisplayName")]
+            display_name: Option<String>,
+            #[plist(rename = "CFBundleName")]
+            name: Option<String>,
+            #[plist(rename = "CFBundleShortVersionString")]
+            version: Option<String>,
+            #[plist(rename = "CFBundleVersion")]
+            build_version: Option<String>,
+            #[plist(rename = "ApplicationType")]
+            application_type: Option<String>,
+            #[plist(rename = "Path")]
+            path: Option<String>,
+            #[plist(rename = "Container")]
+            container: Option<String>,
+            #[plist(rename = "Entitlements")]
+            entitlements: Option<plist::Dictionary>,
+        }
+
+        let f = Fields::from_plist(&raw)?;
+        Ok(Self {
+            bundle_id: f.bundle_id,
+            name: f.display_name.or(f.name),
+            version: f.version,
+            build_version: f.build_version,
+            application_type: f.application_type,
+            path: f.path,
+            container: f.container,
+            entitlements: f.entitlements,
+            raw,
+        })
+    }
+}
+
 impl IdeviceService for InstallationProxyClient {
     fn service_name() -> &'static str {
         "com.apple.mobile.installation_proxy"
@@ -47,6 +338,21 @@ impl InstallationProxyClient {
         &mut self,
         application_type: Option<String>,
         bundle_identifiers: Option<Vec<String>>,
+    ) -> Result<HashMap<String, plist::Value>, IdeviceError> {
+        self.get_apps_with_attributes(application_type, bundle_identifiers, None)
+            .await
+    }
+
+    /// Gets installed apps on the device, with only the requested attributes
+    /// # Arguments
+    /// `application_type` - The application type to filter by
+    /// `bundle_identifiers` - The identifiers to filter by
+    /// `return_attributes` - The attributes to return, such as `Entitlements`, or all if `None`
+    pub async fn get_apps_with_attributes(
+        &mut self,
+        application_type: Option<String>,
+        bundle_identifiers: Option<Vec<String>>,
+        return_attributes: Option<Vec<String>>,
     ) -> Result<HashMap<String, plist::Value>, IdeviceError> {
         let application_type = application_type.unwrap_or("Any".to_string());
         let mut options = plist::Dictionary::new();
@@ -55,11 +361,19 @@ impl InstallationProxyClient {
                 .into_iter()
                 .map(plist::Value::String)
                 .collect::<Vec<plist::Value>>();
-            options.insert("BundleIDs".into(), ids.into()).unwrap();
+            options.insert("BundleIDs".into(), ids.into());
+        }
+        if let Some(attributes) = return_attributes {
+            let attributes = attributes
+                .into_iter()
+                .map(plist::Value::String)
+                .collect::<Vec<plist::Value>>();
+            options.insert("ReturnAttributes".into(), attributes.into());
         }
         options.insert("ApplicationType".into(), application_type.into());
 
         let mut req = plist::Dictionary::new();
+        req.insert("Label".into(), self.idevice.metadata.label.clone().into());
         req.insert("Command".into(), "Lookup".into());
         req.insert("ClientOptions".into(), plist::Value::Dictionary(options));
         self.idevice
@@ -74,4 +388,183 @@ impl InstallationProxyClient {
             _ => Err(IdeviceError::UnexpectedResponse),
         }
     }
+
+    /// Looks up installed apps, which is faster than `browse` when `bundle_ids` is set
+    /// # Arguments
+    /// `options` - Which apps and attributes to return
+    pub async fn lookup(
+        &mut self,
+        options: ClientOptions,
+    ) -> Result<HashMap<String, AppInfo>, IdeviceError> {
+        let mut req = plist::Dictionary::new();
+        req.insert("Label".into(), self.idevice.metadata.label.clone().into());
+        req.insert("Command".into(), "Lookup".into());
+        req.insert(
+            "ClientOptions".into(),
+            plist::Value::Dictionary(options.into_plist()),
+        );
+        self.idevice
+            .send_plist(plist::Value::Dictionary(req))
+            .await?;
+
+        let mut res = self.idevice.read_plist().await?;
+        match res.remove("LookupResult") {
+            Some(plist::Value::Dictionary(res)) => res
+                .into_iter()
+                .map(|(id, app)| match app {
+                    plist::Value::Dictionary(app) => Ok((id, AppInfo::from_plist(app)?)),
+                    _ => Err(IdeviceError::UnexpectedResponse),
+                })
+                .collect(),
+            _ => Err(IdeviceError::UnexpectedResponse),
+        }
+    }
+
+    /// Lists installed apps
+    /// # Arguments
+    /// `options` - Which apps and attributes to return
+    pub async fn browse(&mut self, options: ClientOptions) -> Result<Vec<AppInfo>, IdeviceError> {
+        let mut req = plist::Dictionary::new();
+        req.insert("Label".into(), self.idevice.metadata.label.clone().into());
+        req.insert("Command".into(), "Browse".into());
+        req.insert(
+            "ClientOptions".into(),
+            plist::Value::Dictionary(options.into_plist()),
+        );
+        self.idevice
+            .send_plist(plist::Value::Dictionary(req))
+            .await?;
+
+        // The apps come in batches until the status is Complete
+        let mut apps = Vec::new();
+        loop {
+            let mut res = self.idevice.read_plist().await?;
+            if let Some(plist::Value::Array(list)) = res.remove("CurrentList") {
+                for app in list {
+                    match app {
+                        plist::Value::Dictionary(app) => apps.push(AppInfo::from_plist(app)?),
+                        _ => return Err(IdeviceError::UnexpectedResponse),
+                    }
+                }
+            }
+            match res.get("Status").and_then(|s| s.as_string()) {
+                Some("Complete") => return Ok(apps),
+                Some(_) => {}
+                None => return Err(IdeviceError::UnexpectedResponse),
+            }
+        }
+    }
+
+    /// Installs a package that has already been uploaded to the device
+    /// # Arguments
+    /// `package_path` - The path to the package, relative to the AFC root, such as `PublicStaging/app.ipa`
+    /// `options` - Extra client options to send with the request
+    pub async fn install(
+        &mut self,
+        package_path: impl Into<String>,
+        options: Option<plist::Dictionary>,
+    ) -> Result<(), IdeviceError> {
+        let mut progress = Box::pin(self.install_stream(package_path, options).await?);
+        while let Some(p) = progress.next().await {
+            let p = p?;
+            debug!("Install status: {} {:?}", p.status, p.percent_complete);
+        }
+        Ok(())
+    }
+
+    /// Installs a package like `install`, reporting each step the device goes through.
+    /// The stream ends after the `Complete` update or the first error.
+    pub async fn install_stream(
+        &mut self,
+        package_path: impl Into<String>,
+        options: Option<plist::Dictionary>,
+    ) -> Result<impl Stream<Item = Result<InstallProgress, IdeviceError>> + '_, IdeviceError> {
+        let package_path = package_path.into();
+        let mut req = plist::Dictionary::new();
+        req.insert("Label".into(), self.idevice.metadata.label.clone().into());
+        req.insert("Command".into(), "Install".into());
+        req.insert("PackagePath".into(), package_path.into());
+        req.insert(
+            "ClientOptions".into(),
+            plist::Value::Dictionary(options.unwrap_or_default()),
+        );
+        self.idevice
+            .send_plist(plist::Value::Dictionary(req))
+            .await?;
+
+        Ok(futures::stream::unfold(Some(self), |client| async move {
+            let client = client?;
+            let res = match client.idevice.read_plist().await {
+                Ok(res) => res,
+                Err(e) => return Some((Err(e), None)),
+            };
+            let progress = match res.get("Status").and_then(|s| s.as_string()) {
+                Some(status) => InstallProgress {
+                    status: status.to_string(),
+                    percent_complete: res
+                        .get("PercentComplete")
+                        .and_then(|p| p.as_unsigned_integer()),
+                },
+                None => return Some((Err(IdeviceError::UnexpectedResponse), None)),
+            };
+            let next = match progress.status.as_str() {
+                "Complete" => None,
+                _ => Some(client),
+            };
+            Some((Ok(progress), next))
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn return_attributes_always_include_the_identifier() {
+        let names = |r: ReturnAttributes| {
+            r.attributes()
+                .unwrap()
+                .iter()
+                .map(|a| a.as_str().to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            names(ReturnAttributes::Minimal),
+            ["CFBundleIdentifier", "CFBundleDisplayName", "CFBundleName"]
+        );
+        assert_eq!(
+            names(ReturnAttributes::custom().with(AppAttribute::Entitlements)),
+            ["CFBundleIdentifier", "Entitlements"]
+        );
+        assert_eq!(
+            names(
+                ReturnAttributes::Minimal
+                    .with(AppAttribute::DisplayName)
+                    .with(AppAttribute::Path)
+            )
+            .len(),
+            4
+        );
+        assert!(ReturnAttributes::Everything
+            .with(AppAttribute::Path)
+            .attributes()
+            .is_none());
+
+        let options = ClientOptions::new()
+            .return_attributes(["CFBundleIdentifier", "CFBundleVersion"])
+            .into_plist();
+        assert_eq!(
+            options
+                .get("ReturnAttributes")
+                .unwrap()
+                .as_array()
+                .unwrap()
+                .len(),
+            2
+        );
+        assert!(!ClientOptions::new()
+            .into_plist()
+            .contains_key("ReturnAttributes"));
+    }
 }
@@ -1,29 +1,60 @@
 // Jackson Coxson
 
+// Lets the derive macros refer to this crate by name from inside it
+extern crate self as idevice;
+
+#[cfg(feature = "afc")]
+pub mod afc;
+pub mod capabilities;
 #[cfg(feature = "core_device_proxy")]
 pub mod core_device_proxy;
+pub mod crash_report;
+#[cfg(feature = "crashreportcopymobile")]
+pub mod crashreportcopymobile;
+#[cfg(feature = "devicelink")]
+pub mod devicelink;
 #[cfg(feature = "heartbeat")]
 pub mod heartbeat;
+#[cfg(feature = "house_arrest")]
+pub mod house_arrest;
 #[cfg(feature = "xpc")]
 pub mod http2;
 #[cfg(feature = "installation_proxy")]
 pub mod installation_proxy;
 pub mod lockdownd;
+pub mod middleware;
+#[cfg(feature = "misagent")]
+pub mod misagent;
 #[cfg(feature = "mounter")]
 pub mod mounter;
+#[cfg(feature = "notification_proxy")]
+pub mod notification_proxy;
+#[cfg(feature = "os_trace_relay")]
+pub mod os_trace_relay;
 pub mod pairing_file;
+#[cfg(feature = "pcapd")]
+pub mod pcapd;
 pub mod provider;
+#[cfg(feature = "screenshotr")]
+pub mod screenshotr;
+#[cfg(feature = "springboardservices")]
+pub mod springboardservices;
+#[cfg(feature = "syslog_relay")]
+pub mod syslog_relay;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 #[cfg(feature = "tss")]
 pub mod tss;
 #[cfg(feature = "usbmuxd")]
 pub mod usbmuxd;
-mod util;
+pub mod utils;
 #[cfg(feature = "xpc")]
 pub mod xpc;
 
-use log::{debug, error};
+use log::debug;
 use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
 use provider::IdeviceProvider;
+use serde::Serialize;
 use std::io::{self, BufWriter};
 use thiserror::Error;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
@@ -40,22 +71,144 @@ pub trait IdeviceService: Sized {
 
 pub type IdeviceSocket = Box<dyn ReadWrite>;
 
+/// Identifies the client to the muxer and the device's services
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientMetadata {
+    /// Sent as `Label` in lockdown and service requests
+    pub label: String,
+    /// Sent as `ClientVersionString` to usbmuxd
+    pub version: String,
+    /// Sent as `ProgName` to usbmuxd
+    pub program_name: String,
+    /// Run on every plist sent and received by connections using this metadata
+    pub middleware: middleware::Middlewares,
+    /// How connections encode the plists they send, unless changed on the connection
+    pub plist_format: PlistFormat,
+}
+
+/// The encoding of a plist sent over a connection
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PlistFormat {
+    #[default]
+    Xml,
+    Binary,
+}
+
+impl PlistFormat {
+    /// Binary plists start with `bplist`, and anything else is taken for XML
+    pub fn detect(bytes: &[u8]) -> Self {
+        if bytes.starts_with(b"bplist") {
+            Self::Binary
+        } else {
+            Self::Xml
+        }
+    }
+}
+
+impl ClientMetadata {
+    pub const DEFAULT_VERSION: &'static str = concat!("idevice-rs-", env!("CARGO_PKG_VERSION"));
+
+    /// Uses the label as the program name and this crate's version
+    pub fn new(label: impl Into<String>) -> Self {
+        let label = label.into();
+        Self {
+            program_name: label.clone(),
+            label,
+            version: Self::DEFAULT_VERSION.to_string(),
+            middleware: middleware::Middlewares::default(),
+            plist_format: PlistFormat::default(),
+        }
+    }
+
+    pub fn with_version(mut self, version: impl Into<String>) -> Self {
+        self.version = version.into();
+        self
+    }
+
+    pub fn with_program_name(mut self, program_name: impl Into<String>) -> Self {
+        self.program_name = program_name.into();
+        self
+    }
+
+    /// Adds a hook that runs after the ones already added
+    pub fn with_middleware(
+        mut self,
+        middleware: std::sync::Arc<dyn middleware::PlistMiddleware>,
+    ) -> Self {
+        self.middleware.push(middleware);
+        self
+    }
+
+    /// Sets how connections encode plists, for devices whose services misbehave with XML
+    pub fn with_plist_format(mut self, plist_format: PlistFormat) -> Self {
+        self.plist_format = plist_format;
+        self
+    }
+}
+
+impl From<&str> for ClientMetadata {
+    fn from(label: &str) -> Self {
+        Self::new(label)
+    }
+}
+
+impl From<String> for ClientMetadata {
+    fn from(label: String) -> Self {
+        Self::new(label)
+    }
+}
+
+impl From<&ClientMetadata> for ClientMetadata {
+    fn from(metadata: &ClientMetadata) -> Self {
+        metadata.clone()
+    }
+}
+
 pub struct Idevice {
     socket: Option<Box<dyn ReadWrite>>, // in a box for now to use the ReadWrite trait for further uses
-    label: String,
+    metadata: ClientMetadata,
+    plist_format: PlistFormat,
+    received_plist_format: Option<PlistFormat>,
 }
 
 impl Idevice {
-    pub fn new(socket: Box<dyn ReadWrite>, label: impl Into<String>) -> Self {
+    pub fn new(socket: Box<dyn ReadWrite>, metadata: impl Into<ClientMetadata>) -> Self {
+        let metadata = metadata.into();
         Self {
             socket: Some(socket),
-            label: label.into(),
+            plist_format: metadata.plist_format,
+            metadata,
+            received_plist_format: None,
         }
     }
 
+    pub fn label(&self) -> &str {
+        &self.metadata.label
+    }
+
+    pub fn metadata(&self) -> &ClientMetadata {
+        &self.metadata
+    }
+
+    /// How plists sent over this connection are encoded
+    pub fn plist_format(&self) -> PlistFormat {
+        self.plist_format
+    }
+
+    /// Changes how plists sent over this connection are encoded.
+    /// Service clients expose their connection, so each can be set on its own.
+    pub fn set_plist_format(&mut self, plist_format: PlistFormat) {
+        self.plist_format = plist_format;
+    }
+
+    /// The encoding of the last plist the device sent, which is `None` until one is read
+    pub fn received_plist_format(&self) -> Option<PlistFormat> {
+        self.received_plist_format
+    }
+
     pub async fn get_type(&mut self) -> Result<String, IdeviceError> {
         let mut req = plist::Dictionary::new();
-        req.insert("Label".into(), self.label.clone().into());
+        req.insert("Label".into(), self.metadata.label.clone().into());
         req.insert("Request".into(), "QueryType".into());
         let message = plist::to_value(&req)?;
         self.send_plist(message).await?;
@@ -67,16 +220,21 @@ impl Idevice {
     }
 
     /// Sends a plist to the socket
-    async fn send_plist(&mut self, message: plist::Value) -> Result<(), IdeviceError> {
+    async fn send_plist(&mut self, mut message: plist::Value) -> Result<(), IdeviceError> {
         if let Some(socket) = &mut self.socket {
+            self.metadata
+                .middleware
+                .on_send(&self.metadata.label, &mut message);
             let buf = Vec::new();
             let mut writer = BufWriter::new(buf);
-            message.to_writer_xml(&mut writer)?;
+            match self.plist_format {
+                PlistFormat::Xml => message.to_writer_xml(&mut writer)?,
+                PlistFormat::Binary => message.to_writer_binary(&mut writer)?,
+            }
             let message = writer.into_inner().unwrap();
-            let message = String::from_utf8(message)?;
             let len = message.len() as u32;
             socket.write_all(&len.to_be_bytes()).await?;
-            socket.write_all(message.as_bytes()).await?;
+            socket.write_all(&message).await?;
             Ok(())
         } else {
             Err(IdeviceError::NoEstablishedConnection)
@@ -104,6 +262,7 @@ impl Idevice {
     }
 
     /// Reads bytes from the socket until it doesn't
+    #[cfg(any(feature = "core_device_proxy", feature = "syslog_relay"))]
     async fn read_any(&mut self, max_size: u32) -> Result<Vec<u8>, IdeviceError> {
         if let Some(socket) = &mut self.socket {
             let mut buf = vec![0; max_size as usize];
@@ -116,23 +275,37 @@ impl Idevice {
 
     /// Read a plist from the socket
     async fn read_plist(&mut self) -> Result<plist::Dictionary, IdeviceError> {
+        let res = self.read_plist_unchecked().await?;
+        IdeviceError::check_response(&res)?;
+        Ok(res)
+    }
+
+    /// Reads a plist without turning an `Error` in it into an `IdeviceError`,
+    /// for responses that carry details next to the error
+    async fn read_plist_unchecked(&mut self) -> Result<plist::Dictionary, IdeviceError> {
         if let Some(socket) = &mut self.socket {
-            debug!("Reading response size");
+            debug!("[{}] Reading response size", self.metadata.label);
             let mut buf = [0u8; 4];
             socket.read_exact(&mut buf).await?;
             let len = u32::from_be_bytes(buf);
             let mut buf = vec![0; len as usize];
             socket.read_exact(&mut buf).await?;
-            let res: plist::Dictionary = plist::from_bytes(&buf)?;
-            debug!("Received plist: {res:#?}");
-
-            if let Some(e) = res.get("Error") {
-                let e: String = plist::from_value(e)?;
-                if let Some(e) = IdeviceError::from_device_error_type(e.as_str()) {
-                    return Err(e);
-                } else {
-                    return Err(IdeviceError::UnknownErrorType(e));
+            self.received_plist_format = Some(PlistFormat::detect(&buf));
+            let res: plist::Dictionary = if self.metadata.middleware.is_empty() {
+                plist::from_bytes(&buf)?
+            } else {
+                let mut res = plist::from_bytes(&buf)?;
+                self.metadata
+                    .middleware
+                    .on_receive(&self.metadata.label, &mut res);
+                match res {
+                    plist::Value::Dictionary(res) => res,
+                    _ => return Err(IdeviceError::UnexpectedResponse),
                 }
+            };
+            if log::log_enabled!(log::Level::Debug) {
+                let res = utils::redact::redacted(&res);
+                debug!("[{}] Received plist: {res:#?}", self.metadata.label);
             }
             Ok(res)
         } else {
@@ -140,6 +313,26 @@ impl Idevice {
         }
     }
 
+    /// Reads a plist that isn't necessarily a dictionary, such as DeviceLink's arrays
+    #[cfg(any(feature = "devicelink", feature = "springboardservices"))]
+    async fn read_plist_value(&mut self) -> Result<plist::Value, IdeviceError> {
+        if let Some(socket) = &mut self.socket {
+            let mut buf = [0u8; 4];
+            socket.read_exact(&mut buf).await?;
+            let len = u32::from_be_bytes(buf);
+            let mut buf = vec![0; len as usize];
+            socket.read_exact(&mut buf).await?;
+            self.received_plist_format = Some(PlistFormat::detect(&buf));
+            let mut res = plist::from_bytes(&buf)?;
+            self.metadata
+                .middleware
+                .on_receive(&self.metadata.label, &mut res);
+            Ok(res)
+        } else {
+            Err(IdeviceError::NoEstablishedConnection)
+        }
+    }
+
     /// Wraps current connection in TLS
     pub async fn start_session(
         &mut self,
@@ -168,6 +361,16 @@ impl Idevice {
     }
 }
 
+/// A machine readable description of an `IdeviceError`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ErrorReport {
+    pub code: u32,
+    pub name: &'static str,
+    pub message: String,
+    /// The messages of the underlying errors, outermost first
+    pub context: Vec<String>,
+}
+
 #[derive(Error, Debug)]
 pub enum IdeviceError {
     #[error("device socket io failed")]
@@ -178,6 +381,8 @@ pub enum IdeviceError {
     SslSetup(#[from] openssl::error::ErrorStack),
     #[error("io on plist")]
     Plist(#[from] plist::Error),
+    #[error("plist didn't match the expected schema")]
+    Schema(#[from] utils::schema::SchemaError),
     #[error("can't convert bytes to utf8")]
     Utf8(#[from] std::string::FromUtf8Error),
     #[error("unexpected response from device")]
@@ -188,6 +393,8 @@ pub enum IdeviceError {
     SessionInactive,
     #[error("device does not have pairing file")]
     InvalidHostID,
+    #[error("device is locked with a passcode")]
+    PasswordProtected,
     #[error("no established connection")]
     NoEstablishedConnection,
     #[error("device went to sleep")]
@@ -196,6 +403,12 @@ pub enum IdeviceError {
     HeartbeatTimeout,
     #[error("not found")]
     NotFound,
+    #[error("timed out")]
+    Timeout,
+    #[error("invalid argument")]
+    InvalidArgument,
+    #[error("service is not available")]
+    InvalidService,
     #[error("CDTunnel packet too short")]
     CdtunnelPacketTooShort,
     #[error("CDTunnel packet invalid magic")]
@@ -203,12 +416,13 @@ pub enum IdeviceError {
     #[error("Proclaimed packet size does not match actual size")]
     PacketSizeMismatch,
 
-    #[cfg(feature = "core_device_proxy")]
     #[error("JSON serialization failed")]
     Json(#[from] serde_json::Error),
 
     #[error("device not found")]
     DeviceNotFound,
+    #[error("expected device `{expected}` but `{found}` answered")]
+    UdidMismatch { expected: String, found: String },
 
     #[error("device refused connection")]
     UsbConnectionRefused,
@@ -218,25 +432,192 @@ pub enum IdeviceError {
     UsbBadDevice,
     #[error("usb bad version")]
     UsbBadVersion,
+    #[error("muxer answered request tag {expected} with tag {found}")]
+    UsbmuxdTagMismatch { expected: u32, found: u32 },
 
     #[error("bad build manifest")]
     BadBuildManifest,
 
+    #[error("misagent operation failed with status {0}")]
+    MisagentFailure(u64),
+
+    #[cfg(feature = "afc")]
+    #[error("afc error")]
+    Afc(#[from] afc::errors::AfcError),
+    #[cfg(feature = "afc")]
+    #[error("path `{0}` is outside the allowed root")]
+    PathOutsideRoot(String),
+
+    #[cfg(feature = "os_tun")]
+    #[error("TUN interface failed")]
+    Tun(#[from] tun_rs::Error),
+
     #[cfg(feature = "tss")]
     #[error("http reqwest error")]
     Reqwest(#[from] reqwest::Error),
 
+    #[cfg(feature = "screenshotr")]
+    #[error("image conversion failed")]
+    Image(#[from] image::ImageError),
+
     #[error("unknown error `{0}` returned from device")]
     UnknownErrorType(String),
 }
 
 impl IdeviceError {
+    /// A number for the kind of error, for consumers that can't match on the enum.
+    /// Codes aren't reused, so new kinds get new codes.
+    pub fn code(&self) -> u32 {
+        self.code_and_name().0
+    }
+
+    /// The name of the variant, such as `UnexpectedResponse`, which doesn't change
+    /// when the Display text is reworded
+    pub fn name(&self) -> &'static str {
+        self.code_and_name().1
+    }
+
+    /// The error with its code, name, message and the messages of the errors that caused it
+    pub fn report(&self) -> ErrorReport {
+        let mut context = Vec::new();
+        let mut source = std::error::Error::source(self);
+        while let Some(e) = source {
+            context.push(e.to_string());
+            source = e.source();
+        }
+        ErrorReport {
+            code: self.code(),
+            name: self.name(),
+            message: self.to_string(),
+            context,
+        }
+    }
+
+    /// `report` as a JSON object, for log pipelines and bindings
+    pub fn to_json(&self) -> serde_json::Value {
+        // The report only holds strings and numbers
+        serde_json::to_value(self.report()).unwrap()
+    }
+
+    fn code_and_name(&self) -> (u32, &'static str) {
+        match self {
+            Self::Socket(_) => (1, "Socket"),
+            Self::Ssl(_) => (2, "Ssl"),
+            Self::SslSetup(_) => (3, "SslSetup"),
+            Self::Plist(_) => (4, "Plist"),
+            Self::Schema(_) => (5, "Schema"),
+            Self::Utf8(_) => (6, "Utf8"),
+            Self::UnexpectedResponse => (7, "UnexpectedResponse"),
+            Self::GetProhibited => (8, "GetProhibited"),
+            Self::SessionInactive => (9, "SessionInactive"),
+            Self::InvalidHostID => (10, "InvalidHostID"),
+            Self::PasswordProtected => (11, "PasswordProtected"),
+            Self::NoEstablishedConnection => (12, "NoEstablishedConnection"),
+            Self::HeartbeatSleepyTime => (13, "HeartbeatSleepyTime"),
+            Self::HeartbeatTimeout => (14, "HeartbeatTimeout"),
+            Self::NotFound => (15, "NotFound"),
+            Self::Timeout => (16, "Timeout"),
+            Self::InvalidArgument => (17, "InvalidArgument"),
+            Self::InvalidService => (18, "InvalidService"),
+            Self::CdtunnelPacketTooShort => (19, "CdtunnelPacketTooShort"),
+            Self::CdtunnelPacketInvalidMagic => (20, "CdtunnelPacketInvalidMagic"),
+            Self::PacketSizeMismatch => (21, "PacketSizeMismatch"),
+            Self::Json(_) => (22, "Json"),
+            Self::DeviceNotFound => (23, "DeviceNotFound"),
+            Self::UdidMismatch { .. } => (24, "UdidMismatch"),
+            Self::UsbConnectionRefused => (25, "UsbConnectionRefused"),
+            Self::UsbBadCommand => (26, "UsbBadCommand"),
+            Self::UsbBadDevice => (27, "UsbBadDevice"),
+            Self::UsbBadVersion => (28, "UsbBadVersion"),
+            Self::UsbmuxdTagMismatch { .. } => (29, "UsbmuxdTagMismatch"),
+            Self::BadBuildManifest => (30, "BadBuildManifest"),
+            Self::MisagentFailure(_) => (31, "MisagentFailure"),
+            #[cfg(feature = "afc")]
+            Self::Afc(_) => (32, "Afc"),
+            #[cfg(feature = "afc")]
+            Self::PathOutsideRoot(_) => (33, "PathOutsideRoot"),
+            #[cfg(feature = "os_tun")]
+            Self::Tun(_) => (34, "Tun"),
+            #[cfg(feature = "tss")]
+            Self::Reqwest(_) => (35, "Reqwest"),
+            Self::UnknownErrorType(_) => (36, "UnknownErrorType"),
+            #[cfg(feature = "screenshotr")]
+            Self::Image(_) => (37, "Image"),
+        }
+    }
+
+    /// Returns the error in a response's `Error` key, if it has one
+    fn check_response(res: &plist::Dictionary) -> Result<(), Self> {
+        if let Some(e) = res.get("Error") {
+            let e: String = plist::from_value(e)?;
+            if let Some(e) = IdeviceError::from_device_error_type(e.as_str()) {
+                return Err(e);
+            } else {
+                return Err(IdeviceError::UnknownErrorType(e));
+            }
+        }
+        Ok(())
+    }
+
     fn from_device_error_type(e: &str) -> Option<Self> {
         match e {
             "GetProhibited" => Some(Self::GetProhibited),
             "InvalidHostID" => Some(Self::InvalidHostID),
             "SessionInactive" => Some(Self::SessionInactive),
+            "InvalidService" => Some(Self::InvalidService),
+            "PasswordProtected" => Some(Self::PasswordProtected),
             _ => None,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn plist_format() {
+        let metadata = ClientMetadata::new("test").with_plist_format(PlistFormat::Binary);
+        let (client, mut server) = tokio::io::duplex(4096);
+        let mut idevice = Idevice::new(Box::new(client), metadata);
+        assert_eq!(idevice.received_plist_format(), None);
+
+        let mut req = plist::Dictionary::new();
+        req.insert("Request".into(), "Ping".into());
+        let req = plist::Value::Dictionary(req);
+        idevice.send_plist(req.clone()).await.unwrap();
+        idevice.set_plist_format(PlistFormat::Xml);
+        idevice.send_plist(req).await.unwrap();
+        for expected in [PlistFormat::Binary, PlistFormat::Xml] {
+            let len = server.read_u32().await.unwrap();
+            let mut buf = vec![0; len as usize];
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(PlistFormat::detect(&buf), expected);
+        }
+
+        let mut res = Vec::new();
+        plist::Value::Dictionary(plist::Dictionary::new())
+            .to_writer_binary(&mut res)
+            .unwrap();
+        server.write_u32(res.len() as u32).await.unwrap();
+        server.write_all(&res).await.unwrap();
+        idevice.read_plist().await.unwrap();
+        assert_eq!(idevice.received_plist_format(), Some(PlistFormat::Binary));
+    }
+
+    #[test]
+    fn error_report() {
+        let e = IdeviceError::from(io::Error::new(io::ErrorKind::BrokenPipe, "pipe closed"));
+        let json = e.to_json();
+        assert_eq!(json["code"], 1);
+        assert_eq!(json["name"], "Socket");
+        assert_eq!(json["message"], "device socket io failed");
+        assert_eq!(json["context"], serde_json::json!(["pipe closed"]));
+        assert_eq!(
+            IdeviceError::UnexpectedResponse.name(),
+            "UnexpectedResponse"
+        );
+    }
+}
@@ -1,14 +1,37 @@
 // Jackson Coxson
 // Abstractions for lockdownd
 
-use log::error;
+use std::collections::HashMap;
+
+use log::{debug, error, warn};
+use openssl::{
+    pkcs7::{Pkcs7, Pkcs7Flags},
+    pkey::{PKey, Private},
+    stack::Stack,
+    x509::X509,
+};
 use plist::Value;
 use serde::{Deserialize, Serialize};
 
-use crate::{pairing_file, Idevice, IdeviceError, IdeviceService};
+#[cfg(feature = "notification_proxy")]
+use futures::{FutureExt, Stream, StreamExt};
+
+#[cfg(feature = "notification_proxy")]
+use crate::notification_proxy::NotificationName;
+use crate::{
+    capabilities::{DeviceCapabilities, DeviceClass},
+    pairing_file,
+    utils::{
+        models::{self, DeviceModel},
+        schema::FromPlist,
+    },
+    Idevice, IdeviceError, IdeviceService,
+};
 
 pub struct LockdowndClient {
     pub idevice: crate::Idevice,
+    /// Values already read, keyed by domain and key. `None` when caching is disabled.
+    cache: Option<HashMap<(Option<String>, String), Value>>,
 }
 
 impl IdeviceService for LockdowndClient {
@@ -29,41 +52,372 @@ impl IdeviceService for LockdowndClient {
 struct LockdowndRequest {
     label: String,
     key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    domain: Option<String>,
     request: String,
 }
 
+/// Lockdown domains with commonly used values.
+/// Converts into the `String` `get_value` and `set_value` take.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LockdownDomain {
+    Amfi,
+    Backup,
+    Battery,
+    DeveloperDomain,
+    DiskUsage,
+    DiskUsageFactory,
+    International,
+    ITunes,
+    Restriction,
+    WirelessLockdown,
+}
+
+impl LockdownDomain {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Amfi => "com.apple.security.mac.amfi",
+            Self::Backup => "com.apple.mobile.backup",
+            Self::Battery => "com.apple.mobile.battery",
+            Self::DeveloperDomain => "com.apple.xcode.developerdomain",
+            Self::DiskUsage => "com.apple.disk_usage",
+            Self::DiskUsageFactory => "com.apple.disk_usage.factory",
+            Self::International => "com.apple.international",
+            Self::ITunes => "com.apple.mobile.iTunes",
+            Self::Restriction => "com.apple.mobile.restriction",
+            Self::WirelessLockdown => "com.apple.mobile.wireless_lockdown",
+        }
+    }
+}
+
+impl From<LockdownDomain> for String {
+    fn from(domain: LockdownDomain) -> Self {
+        domain.as_str().to_string()
+    }
+}
+
+/// The `com.apple.mobile.battery` domain
+#[derive(FromPlist, Debug, Clone, PartialEq, Eq)]
+#[plist(rename_all = "PascalCase")]
+pub struct BatteryInfo {
+    /// The charge, in percent
+    #[plist(rename = "BatteryCurrentCapacity")]
+    pub current_capacity: u64,
+    #[plist(rename = "BatteryIsCharging", default)]
+    pub is_charging: bool,
+    pub external_connected: Option<bool>,
+    pub fully_charged: Option<bool>,
+}
+
+/// The `com.apple.disk_usage` domain, in bytes
+#[derive(FromPlist, Debug, Clone, PartialEq, Eq)]
+#[plist(rename_all = "PascalCase")]
+pub struct DiskUsage {
+    pub total_disk_capacity: u64,
+    pub total_data_capacity: u64,
+    pub total_data_available: u64,
+    pub total_system_capacity: Option<u64>,
+    pub total_system_available: Option<u64>,
+}
+
+/// The identity of the organization supervising a device, from its MDM or
+/// Apple Configurator profile. Supervised devices pair with it without a trust prompt.
+#[derive(Debug, Clone)]
+pub struct SupervisorIdentity {
+    pub certificate: X509,
+    pub key: PKey<Private>,
+}
+
+/// Options sent with Pair requests
+#[derive(Debug, Clone, Default)]
+pub struct PairingOptions {
+    /// Answers the device's supervision challenge with this identity
+    pub supervisor: Option<SupervisorIdentity>,
+}
+
+/// Whether a service can be started over lockdown, and if not, why
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServiceAvailability {
+    /// The service was started and is listening on `port`, using SSL if `ssl` is set
+    Available { port: u16, ssl: bool },
+    /// Developer mode must be enabled on the device before the service can be started
+    RequiresDeveloperMode,
+    /// A developer disk image must be mounted before the service can be started
+    RequiresDdi,
+    /// The service doesn't exist over lockdown on this iOS version
+    NotPresent,
+}
+
 impl LockdowndClient {
     pub const LOCKDOWND_PORT: u16 = 62078;
 
+    /// Services that are only available once a developer disk image is mounted
+    const DEVELOPER_SERVICES: &'static [&'static str] = &[
+        "com.apple.instruments.remoteserver",
+        "com.apple.instruments.remoteserver.DVTSecureSocketProxy",
+        "com.apple.debugserver",
+        "com.apple.debugserver.DVTSecureSocketProxy",
+        "com.apple.mobile.screenshotr",
+        "com.apple.dt.simulatelocation",
+        "com.apple.dt.fetchsymbols",
+        "com.apple.accessibility.axAuditDaemon.remoteserver",
+        "com.apple.testmanagerd.lockdown",
+        "com.apple.testmanagerd.lockdown.secure",
+    ];
+
     pub fn new(idevice: Idevice) -> Self {
-        Self { idevice }
+        Self {
+            idevice,
+            cache: None,
+        }
+    }
+
+    /// Remembers values returned by `get_value` for the life of this connection.
+    /// Useful for values like ProductVersion that are checked repeatedly.
+    /// Values are forgotten by `invalidate_cache`, `start_session`, and
+    /// `invalidate_from_notifications` with the notification_proxy feature.
+    pub fn enable_cache(&mut self) {
+        if self.cache.is_none() {
+            self.cache = Some(HashMap::new());
+        }
+    }
+
+    /// Forgets all cached values, such as after the device's state changed
+    pub fn invalidate_cache(&mut self) {
+        if let Some(cache) = &mut self.cache {
+            cache.clear();
+        }
+    }
+
+    /// Forgets the cached values a notification says changed.
+    /// Notifications that don't concern lockdown values clear nothing.
+    #[cfg(feature = "notification_proxy")]
+    pub fn invalidate_for_notification(&mut self, notification: &NotificationName) {
+        let cache = match &mut self.cache {
+            Some(c) => c,
+            None => return,
+        };
+        let (domain, key) = match notification {
+            NotificationName::DeviceNameChanged => (None, Some("DeviceName")),
+            NotificationName::PhoneNumberChanged => (None, Some("PhoneNumber")),
+            NotificationName::ActivationState => (None, Some("ActivationState")),
+            NotificationName::DiskUsageChanged => (Some(LockdownDomain::DiskUsage), None),
+            NotificationName::LanguageChanged => (Some(LockdownDomain::International), None),
+            NotificationName::BackupDomainChanged => (Some(LockdownDomain::Backup), None),
+            NotificationName::DeveloperImageMounted => {
+                (Some(LockdownDomain::DeveloperDomain), None)
+            }
+            _ => return,
+        };
+        let domain = domain.map(|d| d.as_str());
+        cache.retain(|(d, k), _| match key {
+            Some(key) => d.is_some() || k != key,
+            None => d.as_deref() != domain,
+        });
+    }
+
+    /// Forgets the cached values that notifications already received say changed,
+    /// without waiting for more. Call it before reading values that may have changed.
+    /// If the stream has ended, changes can no longer be seen, so the whole cache is cleared.
+    /// # Arguments
+    /// `notifications` - From `NotificationProxyClient::into_stream`, observing the
+    /// notifications of interest
+    #[cfg(feature = "notification_proxy")]
+    pub fn invalidate_from_notifications(
+        &mut self,
+        notifications: &mut (impl Stream<Item = Result<NotificationName, IdeviceError>> + Unpin),
+    ) {
+        while let Some(next) = notifications.next().now_or_never() {
+            match next {
+                Some(Ok(name)) => self.invalidate_for_notification(&name),
+                Some(Err(e)) => {
+                    warn!("Notifications stopped, clearing the lockdown cache: {e:?}");
+                    self.invalidate_cache();
+                    return;
+                }
+                None => {
+                    self.invalidate_cache();
+                    return;
+                }
+            }
+        }
     }
+
+    /// Gets a value from lockdownd
+    /// # Arguments
+    /// `value` - The key to get
     pub async fn get_value(&mut self, value: impl Into<String>) -> Result<Value, IdeviceError> {
+        self.get_cached_value(value.into(), None).await
+    }
+
+    /// Gets a value from a domain other than the global one
+    /// # Arguments
+    /// `value` - The key to get
+    /// `domain` - The domain the key lives in, such as a `LockdownDomain`
+    pub async fn get_value_in_domain(
+        &mut self,
+        value: impl Into<String>,
+        domain: impl Into<String>,
+    ) -> Result<Value, IdeviceError> {
+        self.get_cached_value(value.into(), Some(domain.into()))
+            .await
+    }
+
+    async fn get_cached_value(
+        &mut self,
+        value: String,
+        domain: Option<String>,
+    ) -> Result<Value, IdeviceError> {
+        let cache_key = (domain, value);
+        if let Some(v) = self.cache.as_ref().and_then(|c| c.get(&cache_key)) {
+            debug!("Using cached lockdown value for {}", cache_key.1);
+            return Ok(v.to_owned());
+        }
+
+        let req = LockdowndRequest {
+            label: self.idevice.metadata.label.clone(),
+            key: Some(cache_key.1.clone()),
+            domain: cache_key.0.clone(),
+            request: "GetValue".to_string(),
+        };
+        let message = plist::to_value(&req)?;
+        self.idevice.send_plist(message).await?;
+        let message: plist::Dictionary = self.idevice.read_plist().await?;
+        match message.get("Value") {
+            Some(m) => {
+                if let Some(cache) = &mut self.cache {
+                    cache.insert(cache_key, m.to_owned());
+                }
+                Ok(m.to_owned())
+            }
+            None => Err(IdeviceError::UnexpectedResponse),
+        }
+    }
+
+    /// Gets every value in a domain
+    pub async fn get_domain_values(
+        &mut self,
+        domain: impl Into<String>,
+    ) -> Result<plist::Dictionary, IdeviceError> {
+        let domain = domain.into();
         let req = LockdowndRequest {
-            label: self.idevice.label.clone(),
-            key: Some(value.into()),
+            label: self.idevice.metadata.label.clone(),
+            key: None,
+            domain: Some(domain.clone()),
             request: "GetValue".to_string(),
         };
         let message = plist::to_value(&req)?;
         self.idevice.send_plist(message).await?;
         let message: plist::Dictionary = self.idevice.read_plist().await?;
         match message.get("Value") {
-            Some(m) => Ok(m.to_owned()),
+            Some(m) => {
+                let values: plist::Dictionary = plist::from_value(m)?;
+                if let Some(cache) = &mut self.cache {
+                    for (k, v) in values.iter() {
+                        cache.insert((Some(domain.clone()), k.to_owned()), v.to_owned());
+                    }
+                }
+                Ok(values)
+            }
             None => Err(IdeviceError::UnexpectedResponse),
         }
     }
 
+    /// Gets the name the user gave the device
+    pub async fn device_name(&mut self) -> Result<String, IdeviceError> {
+        match self.get_value("DeviceName").await? {
+            Value::String(n) => Ok(n),
+            _ => Err(IdeviceError::UnexpectedResponse),
+        }
+    }
+
+    /// Gets the battery charge, in percent
+    pub async fn battery_level(&mut self) -> Result<u64, IdeviceError> {
+        self.get_value_in_domain("BatteryCurrentCapacity", LockdownDomain::Battery)
+            .await?
+            .as_unsigned_integer()
+            .ok_or(IdeviceError::UnexpectedResponse)
+    }
+
+    pub async fn battery(&mut self) -> Result<BatteryInfo, IdeviceError> {
+        let values = self.get_domain_values(LockdownDomain::Battery).await?;
+        Ok(BatteryInfo::from_plist(&values)?)
+    }
+
+    pub async fn disk_usage(&mut self) -> Result<DiskUsage, IdeviceError> {
+        let values = self.get_domain_values(LockdownDomain::DiskUsage).await?;
+        Ok(DiskUsage::from_plist(&values)?)
+    }
+
+    /// Gets the kind of device, such as an iPhone or iPad
+    pub async fn get_device_class(&mut self) -> Result<DeviceClass, IdeviceError> {
+        match self.get_value("DeviceClass").await? {
+            Value::String(c) => Ok(DeviceClass::from(c.as_str())),
+            _ => Err(IdeviceError::UnexpectedResponse),
+        }
+    }
+
+    /// Gets the device's model from its `ProductType`
+    /// # Returns
+    /// The model, or `None` if the product type isn't known to this version of the crate
+    pub async fn get_device_model(&mut self) -> Result<Option<&'static DeviceModel>, IdeviceError> {
+        match self.get_value("ProductType").await? {
+            Value::String(p) => Ok(models::lookup(&p)),
+            _ => Err(IdeviceError::UnexpectedResponse),
+        }
+    }
+
+    /// Sets a value in lockdownd. A session must already be started.
+    /// # Arguments
+    /// `key` - The key to set
+    /// `value` - The value to store
+    /// `domain` - The domain the key lives in, or the global domain if `None`
+    pub async fn set_value(
+        &mut self,
+        key: impl Into<String>,
+        value: Value,
+        domain: Option<String>,
+    ) -> Result<(), IdeviceError> {
+        let key = key.into();
+        let mut req = plist::Dictionary::new();
+        req.insert("Label".into(), self.idevice.metadata.label.clone().into());
+        req.insert("Request".into(), "SetValue".into());
+        req.insert("Key".into(), key.as_str().into());
+        req.insert("Value".into(), value.clone());
+        if let Some(domain) = &domain {
+            req.insert("Domain".into(), domain.as_str().into());
+        }
+        self.idevice
+            .send_plist(plist::Value::Dictionary(req))
+            .await?;
+        self.idevice.read_plist().await?;
+
+        if let Some(cache) = &mut self.cache {
+            cache.insert((domain, key), value);
+        }
+        Ok(())
+    }
+
     pub async fn get_all_values(&mut self) -> Result<plist::Dictionary, IdeviceError> {
         let req = LockdowndRequest {
-            label: self.idevice.label.clone(),
+            label: self.idevice.metadata.label.clone(),
             key: None,
+            domain: None,
             request: "GetValue".to_string(),
         };
         let message = plist::to_value(&req)?;
         self.idevice.send_plist(message).await?;
         let message: plist::Dictionary = self.idevice.read_plist().await?;
         match message.get("Value") {
-            Some(m) => Ok(plist::from_value(m)?),
+            Some(m) => {
+                let values: plist::Dictionary = plist::from_value(m)?;
+                if let Some(cache) = &mut self.cache {
+                    for (k, v) in values.iter() {
+                        cache.insert((None, k.to_owned()), v.to_owned());
+                    }
+                }
+                Ok(values)
+            }
             None => Err(IdeviceError::UnexpectedResponse),
         }
     }
@@ -80,7 +434,7 @@ impl LockdowndClient {
         let mut request = plist::Dictionary::new();
         request.insert(
             "Label".to_string(),
-            plist::Value::String(self.idevice.label.clone()),
+            plist::Value::String(self.idevice.metadata.label.clone()),
         );
 
         request.insert(
@@ -113,9 +467,165 @@ impl LockdowndClient {
         }
 
         self.idevice.start_session(pairing_file).await?;
+        // More values are readable once the session is up
+        self.invalidate_cache();
         Ok(())
     }
 
+    /// Checks whether the device still trusts a pairing record.
+    /// Devices forget their pairings after "Reset Location & Privacy" or when the user
+    /// removes trust, after which sessions fail with `InvalidHostID`.
+    /// # Returns
+    /// `false` if the device doesn't recognize the pairing
+    pub async fn validate_pair(
+        &mut self,
+        pairing_file: &pairing_file::PairingFile,
+    ) -> Result<bool, IdeviceError> {
+        let mut req = plist::Dictionary::new();
+        req.insert("Label".into(), self.idevice.metadata.label.clone().into());
+        req.insert("Request".into(), "ValidatePair".into());
+        req.insert("ProtocolVersion".into(), "2".into());
+        req.insert(
+            "PairRecord".into(),
+            plist::Value::Dictionary(Self::pair_record(pairing_file)?),
+        );
+        self.idevice
+            .send_plist(plist::Value::Dictionary(req))
+            .await?;
+
+        match self.idevice.read_plist().await {
+            Ok(_) => Ok(true),
+            Err(IdeviceError::InvalidHostID) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Gets a new escrow bag by pairing again with the keys the device already trusts.
+    /// The escrow bag lets services like backup run while the device is locked, and the
+    /// device stops accepting the old one after its passcode changes.
+    /// The device must be unlocked, and no trust prompt is shown.
+    /// # Arguments
+    /// `pairing_file` - A pairing the device still trusts
+    /// # Returns
+    /// The pairing file with the new escrow bag, to be saved in place of the old one
+    pub async fn refresh_escrow_bag(
+        &mut self,
+        pairing_file: &pairing_file::PairingFile,
+    ) -> Result<pairing_file::PairingFile, IdeviceError> {
+        self.refresh_escrow_bag_with_options(pairing_file, &PairingOptions::default())
+            .await
+    }
+
+    /// Like `refresh_escrow_bag`, with options for the Pair request.
+    /// With a supervisor identity, the device's challenge is signed with its key.
+    pub async fn refresh_escrow_bag_with_options(
+        &mut self,
+        pairing_file: &pairing_file::PairingFile,
+        options: &PairingOptions,
+    ) -> Result<pairing_file::PairingFile, IdeviceError> {
+        let mut pairing_options = plist::Dictionary::new();
+        if let Some(supervisor) = &options.supervisor {
+            pairing_options.insert(
+                "SupervisorCertificate".into(),
+                plist::Value::Data(supervisor.certificate.to_der()?),
+            );
+        }
+        let mut res = self.pair(pairing_file, pairing_options).await?;
+
+        if let (Some(supervisor), Some("MCChallengeRequired")) = (
+            &options.supervisor,
+            res.get("Error").and_then(|e| e.as_string()),
+        ) {
+            let challenge = match res
+                .get("ExtendedResponse")
+                .and_then(|r| r.as_dictionary())
+                .and_then(|r| r.get("PairingChallenge"))
+            {
+                Some(plist::Value::Data(c)) => c,
+                _ => {
+                    warn!("Supervision challenge didn't contain a PairingChallenge");
+                    return Err(IdeviceError::UnexpectedResponse);
+                }
+            };
+            let no_extra_certs = Stack::new()?;
+            let signed = Pkcs7::sign(
+                &supervisor.certificate,
+                &supervisor.key,
+                &no_extra_certs,
+                challenge,
+                Pkcs7Flags::BINARY,
+            )?;
+
+            let mut pairing_options = plist::Dictionary::new();
+            pairing_options.insert(
+                "ChallengeResponse".into(),
+                plist::Value::Data(signed.to_der()?),
+            );
+            res = self.pair(pairing_file, pairing_options).await?;
+        }
+        IdeviceError::check_response(&res)?;
+
+        match res.remove("EscrowBag") {
+            Some(plist::Value::Data(escrow_bag)) => {
+                let mut pairing_file = pairing_file.clone();
+                pairing_file.escrow_bag = escrow_bag;
+                Ok(pairing_file)
+            }
+            _ => {
+                warn!("Pair response didn't contain an escrow bag");
+                Err(IdeviceError::UnexpectedResponse)
+            }
+        }
+    }
+
+    /// Sends a Pair request for an existing pairing and returns the response, errors included
+    async fn pair(
+        &mut self,
+        pairing_file: &pairing_file::PairingFile,
+        mut options: plist::Dictionary,
+    ) -> Result<plist::Dictionary, IdeviceError> {
+        options.insert("ExtendedPairingErrors".into(), true.into());
+
+        let mut req = plist::Dictionary::new();
+        req.insert("Label".into(), self.idevice.metadata.label.clone().into());
+        req.insert("Request".into(), "Pair".into());
+        req.insert("ProtocolVersion".into(), "2".into());
+        req.insert(
+            "PairRecord".into(),
+            plist::Value::Dictionary(Self::pair_record(pairing_file)?),
+        );
+        req.insert("PairingOptions".into(), plist::Value::Dictionary(options));
+        self.idevice
+            .send_plist(plist::Value::Dictionary(req))
+            .await?;
+        self.idevice.read_plist_unchecked().await
+    }
+
+    /// The public half of a pairing, as lockdownd expects it in pairing requests
+    fn pair_record(
+        pairing_file: &pairing_file::PairingFile,
+    ) -> Result<plist::Dictionary, IdeviceError> {
+        let mut record = plist::Dictionary::new();
+        record.insert(
+            "DeviceCertificate".into(),
+            plist::Value::Data(pairing_file.device_certificate.to_pem()?),
+        );
+        record.insert(
+            "HostCertificate".into(),
+            plist::Value::Data(pairing_file.host_certificate.to_pem()?),
+        );
+        record.insert(
+            "RootCertificate".into(),
+            plist::Value::Data(pairing_file.root_certificate.to_pem()?),
+        );
+        record.insert("HostID".into(), pairing_file.host_id.as_str().into());
+        record.insert(
+            "SystemBUID".into(),
+            pairing_file.system_buid.as_str().into(),
+        );
+        Ok(record)
+    }
+
     /// Asks lockdownd to pretty please start a service for us
     /// # Arguments
     /// `identifier` - The identifier for the service you want to start
@@ -127,6 +637,7 @@ impl LockdowndClient {
     ) -> Result<(u16, bool), IdeviceError> {
         let identifier = identifier.into();
         let mut req = plist::Dictionary::new();
+        req.insert("Label".into(), self.idevice.metadata.label.clone().into());
         req.insert("Request".into(), "StartService".into());
         req.insert("Service".into(), identifier.into());
         self.idevice
@@ -154,6 +665,42 @@ impl LockdowndClient {
             }
         }
     }
+
+    /// Attempts to start a service and explains why it isn't available on failure.
+    /// Probing starts the service, so connect to the returned port rather than starting it again.
+    /// A session must already be started.
+    /// # Arguments
+    /// `identifier` - The identifier for the service to probe
+    pub async fn probe_service(
+        &mut self,
+        identifier: impl Into<String>,
+    ) -> Result<ServiceAvailability, IdeviceError> {
+        let identifier = identifier.into();
+        match self.start_service(identifier.as_str()).await {
+            Ok((port, ssl)) => return Ok(ServiceAvailability::Available { port, ssl }),
+            Err(IdeviceError::InvalidService) => {}
+            Err(e) => return Err(e),
+        }
+
+        if !Self::DEVELOPER_SERVICES.contains(&identifier.as_str()) {
+            return Ok(ServiceAvailability::NotPresent);
+        }
+
+        let capabilities = DeviceCapabilities::from_lockdown(self).await?;
+        if !capabilities.developer_services_over_lockdown() {
+            return Ok(ServiceAvailability::NotPresent);
+        }
+
+        // The key is missing before iOS 16, where developer mode doesn't exist
+        match self
+            .get_value_in_domain("DeveloperModeStatus", LockdownDomain::Amfi)
+            .await
+        {
+            Ok(Value::Boolean(false)) => Ok(ServiceAvailability::RequiresDeveloperMode),
+            Ok(_) | Err(IdeviceError::UnknownErrorType(_)) => Ok(ServiceAvailability::RequiresDdi),
+            Err(e) => Err(e),
+        }
+    }
 }
 
 impl From<Idevice> for LockdowndClient {
@@ -161,3 +708,147 @@ impl From<Idevice> for LockdowndClient {
         Self::new(value)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    use super::*;
+    use crate::pairing_file::tests::{certificate, pairing_file};
+
+    /// Answers each request with the next response, like lockdownd would
+    async fn serve(
+        mut device: DuplexStream,
+        responses: Vec<plist::Dictionary>,
+    ) -> Vec<plist::Dictionary> {
+        let mut requests = Vec::new();
+        for res in responses {
+            let mut len = [0; 4];
+            device.read_exact(&mut len).await.unwrap();
+            let mut buf = vec![0; u32::from_be_bytes(len) as usize];
+            device.read_exact(&mut buf).await.unwrap();
+            requests.push(plist::from_bytes(&buf).unwrap());
+
+            let mut buf = Vec::new();
+            plist::to_writer_xml(&mut buf, &res).unwrap();
+            device
+                .write_all(&(buf.len() as u32).to_be_bytes())
+                .await
+                .unwrap();
+            device.write_all(&buf).await.unwrap();
+        }
+        requests
+    }
+
+    #[tokio::test]
+    async fn probing_returns_the_started_port() {
+        let mut started = plist::Dictionary::new();
+        started.insert("Port".into(), Value::Integer(49152u64.into()));
+        started.insert("EnableServiceSSL".into(), Value::Boolean(true));
+
+        let (socket, device) = tokio::io::duplex(1 << 16);
+        let device = tokio::spawn(serve(device, vec![started]));
+        let mut lockdown = LockdowndClient::new(Idevice::new(Box::new(socket), "test"));
+        assert_eq!(
+            lockdown.probe_service("com.apple.afc").await.unwrap(),
+            ServiceAvailability::Available {
+                port: 49152,
+                ssl: true
+            }
+        );
+        assert_eq!(device.await.unwrap()[0]["Request"], "StartService".into());
+    }
+
+    #[tokio::test]
+    async fn answers_supervision_challenge() {
+        let pairing_file = pairing_file(None);
+        let key = pairing_file.host_private_key.clone();
+        let supervisor = SupervisorIdentity {
+            certificate: certificate(&key, 1),
+            key,
+        };
+
+        let mut extended = plist::Dictionary::new();
+        extended.insert(
+            "PairingChallenge".into(),
+            Value::Data(b"challenge".to_vec()),
+        );
+        let mut challenge = plist::Dictionary::new();
+        challenge.insert("Error".into(), "MCChallengeRequired".into());
+        challenge.insert("ExtendedResponse".into(), Value::Dictionary(extended));
+        let mut paired = plist::Dictionary::new();
+        paired.insert("EscrowBag".into(), Value::Data(b"bag".to_vec()));
+
+        let (socket, device) = tokio::io::duplex(1 << 16);
+        let device = tokio::spawn(serve(device, vec![challenge, paired]));
+        let mut lockdown = LockdowndClient::new(Idevice::new(Box::new(socket), "test"));
+        let options = PairingOptions {
+            supervisor: Some(supervisor.clone()),
+        };
+        let refreshed = lockdown
+            .refresh_escrow_bag_with_options(&pairing_file, &options)
+            .await
+            .unwrap();
+        assert_eq!(refreshed.escrow_bag, b"bag");
+
+        let requests = device.await.unwrap();
+        let options = |i: usize| requests[i]["PairingOptions"].as_dictionary().unwrap();
+        assert_eq!(
+            options(0)["SupervisorCertificate"].as_data().unwrap(),
+            supervisor.certificate.to_der().unwrap()
+        );
+        let response = Pkcs7::from_der(options(1)["ChallengeResponse"].as_data().unwrap()).unwrap();
+        let mut signed = Vec::new();
+        response
+            .verify(
+                &Stack::new().unwrap(),
+                &openssl::x509::store::X509StoreBuilder::new()
+                    .unwrap()
+                    .build(),
+                None,
+                Some(&mut signed),
+                Pkcs7Flags::NOVERIFY,
+            )
+            .unwrap();
+        assert_eq!(signed, b"challenge");
+    }
+
+    #[cfg(feature = "notification_proxy")]
+    #[test]
+    fn notifications_invalidate_cached_values() {
+        let (socket, _device) = tokio::io::duplex(64);
+        let mut lockdown = LockdowndClient::new(Idevice::new(Box::new(socket), "test"));
+        lockdown.enable_cache();
+        let international = Some(LockdownDomain::International.into());
+        for (domain, key) in [
+            (None, "DeviceName"),
+            (None, "ProductVersion"),
+            (international.clone(), "Language"),
+            (
+                Some(LockdownDomain::Battery.into()),
+                "BatteryCurrentCapacity",
+            ),
+        ] {
+            lockdown
+                .cache
+                .as_mut()
+                .unwrap()
+                .insert((domain, key.to_string()), Value::Boolean(true));
+        }
+
+        let mut notifications = futures::stream::iter([
+            Ok(NotificationName::DeviceNameChanged),
+            Ok(NotificationName::LanguageChanged),
+            Ok(NotificationName::SyncDidStart),
+        ])
+        .chain(futures::stream::pending());
+        lockdown.invalidate_from_notifications(&mut notifications);
+        let cache = lockdown.cache.as_ref().unwrap();
+        assert_eq!(cache.len(), 2);
+        assert!(cache.contains_key(&(None, "ProductVersion".to_string())));
+        assert!(!cache.contains_key(&(international, "Language".to_string())));
+
+        lockdown.invalidate_from_notifications(&mut futures::stream::empty());
+        assert!(lockdown.cache.as_ref().unwrap().is_empty());
+    }
+}
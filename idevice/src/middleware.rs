@@ -0,0 +1,169 @@
+// Jackson Coxson
+// Hooks that see every plist a connection sends and receives
+
+use std::{
+    fmt::Debug,
+    sync::{Arc, Mutex},
+};
+
+/// Observes or changes the plists sent and received over a connection.
+/// Hooks are registered on `ClientMetadata`, so they apply to every connection
+/// a provider makes, whichever service client it's for.
+pub trait PlistMiddleware: Send + Sync {
+    /// Called before a plist is sent. Changes to it are sent.
+    /// # Arguments
+    /// `label` - The label of the connection
+    /// `message` - The plist about to be sent
+    fn on_send(&self, label: &str, message: &mut plist::Value) {
+        let _ = (label, message);
+    }
+
+    /// Called after a plist is received, before the client looks at it.
+    /// Errors reported by the device are checked after this.
+    fn on_receive(&self, label: &str, message: &mut plist::Value) {
+        let _ = (label, message);
+    }
+}
+
+/// The hooks registered on a `ClientMetadata`, run in the order they were added
+#[derive(Clone, Default)]
+pub struct Middlewares(Vec<Arc<dyn PlistMiddleware>>);
+
+impl Middlewares {
+    pub fn push(&mut self, middleware: Arc<dyn PlistMiddleware>) {
+        self.0.push(middleware);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub(crate) fn on_send(&self, label: &str, message: &mut plist::Value) {
+        for m in &self.0 {
+            m.on_send(label, message);
+        }
+    }
+
+    pub(crate) fn on_receive(&self, label: &str, message: &mut plist::Value) {
+        for m in &self.0 {
+            m.on_receive(label, message);
+        }
+    }
+}
+
+impl Debug for Middlewares {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Middlewares({})", self.0.len())
+    }
+}
+
+/// Hooks only compare equal when they're the same instances
+impl PartialEq for Middlewares {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.len() == other.0.len() && self.0.iter().zip(&other.0).all(|(a, b)| Arc::ptr_eq(a, b))
+    }
+}
+
+impl Eq for Middlewares {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// Keeps a copy of every plist exchanged, for debugging or replaying a session
+#[derive(Debug, Default)]
+pub struct PlistRecorder {
+    messages: Mutex<Vec<(String, Direction, plist::Value)>>,
+}
+
+impl PlistRecorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The plists recorded so far, with the label of the connection they went over
+    pub fn messages(&self) -> Vec<(String, Direction, plist::Value)> {
+        self.messages.lock().unwrap().clone()
+    }
+
+    pub fn clear(&self) {
+        self.messages.lock().unwrap().clear();
+    }
+
+    fn record(&self, label: &str, direction: Direction, message: &plist::Value) {
+        self.messages
+            .lock()
+            .unwrap()
+            .push((label.to_string(), direction, message.clone()));
+    }
+}
+
+impl PlistMiddleware for PlistRecorder {
+    fn on_send(&self, label: &str, message: &mut plist::Value) {
+        self.record(label, Direction::Sent, message);
+    }
+
+    fn on_receive(&self, label: &str, message: &mut plist::Value) {
+        self.record(label, Direction::Received, message);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::{ClientMetadata, Idevice};
+
+    struct Inject;
+
+    impl PlistMiddleware for Inject {
+        fn on_send(&self, _: &str, message: &mut plist::Value) {
+            if let Some(d) = message.as_dictionary_mut() {
+                d.insert("Injected".into(), true.into());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn hooks_see_and_change_plists() {
+        let recorder = Arc::new(PlistRecorder::new());
+        let metadata = ClientMetadata::new("test")
+            .with_middleware(Arc::new(Inject))
+            .with_middleware(recorder.clone());
+        let (client, mut server) = tokio::io::duplex(4096);
+        let mut idevice = Idevice::new(Box::new(client), metadata);
+
+        let mut req = plist::Dictionary::new();
+        req.insert("Request".into(), "Ping".into());
+        idevice
+            .send_plist(plist::Value::Dictionary(req))
+            .await
+            .unwrap();
+        let len = server.read_u32().await.unwrap();
+        let mut buf = vec![0; len as usize];
+        server.read_exact(&mut buf).await.unwrap();
+        let sent: plist::Dictionary = plist::from_bytes(&buf).unwrap();
+        assert_eq!(sent.get("Injected"), Some(&true.into()));
+
+        let mut res = plist::Dictionary::new();
+        res.insert("Status".into(), "Pong".into());
+        let res = crate::utils::plist_to_bytes(&res);
+        server.write_u32(res.len() as u32).await.unwrap();
+        server.write_all(&res).await.unwrap();
+        idevice.read_plist().await.unwrap();
+
+        let messages = recorder.messages();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].1, Direction::Sent);
+        // The recorder runs after the injecting hook
+        assert!(messages[0]
+            .2
+            .as_dictionary()
+            .unwrap()
+            .contains_key("Injected"));
+        assert_eq!(messages[1].1, Direction::Received);
+    }
+}
@@ -0,0 +1,112 @@
+// Jackson Coxson
+// Abstractions for misagent, which manages provisioning profiles
+
+use log::warn;
+
+use crate::{lockdownd::LockdowndClient, Idevice, IdeviceError, IdeviceService};
+
+pub struct MisagentClient {
+    pub idevice: Idevice,
+}
+
+impl IdeviceService for MisagentClient {
+    fn service_name() -> &'static str {
+        "com.apple.misagent"
+    }
+
+    async fn connect(
+        provider: &dyn crate::provider::IdeviceProvider,
+    ) -> Result<Self, IdeviceError> {
+        let mut lockdown = LockdowndClient::connect(provider).await?;
+        lockdown
+            .start_session(&provider.get_pairing_file().await?)
+            .await?;
+
+        let (port, ssl) = lockdown.start_service(Self::service_name()).await?;
+
+        let mut idevice = provider.connect(port).await?;
+        if ssl {
+            idevice
+                .start_session(&provider.get_pairing_file().await?)
+                .await?;
+        }
+
+        Ok(Self::new(idevice))
+    }
+}
+
+impl MisagentClient {
+    pub fn new(idevice: Idevice) -> Self {
+        Self { idevice }
+    }
+
+    /// Installs a provisioning profile
+    /// # Arguments
+    /// `profile` - The raw bytes of the signed profile
+    pub async fn install(&mut self, profile: Vec<u8>) -> Result<(), IdeviceError> {
+        let mut req = plist::Dictionary::new();
+        req.insert("MessageType".into(), "Install".into());
+        req.insert("Profile".into(), plist::Value::Data(profile));
+        req.insert("ProfileType".into(), "Provisioning".into());
+        self.idevice
+            .send_plist(plist::Value::Dictionary(req))
+            .await?;
+
+        let res = self.idevice.read_plist().await?;
+        Self::check_status(&res)
+    }
+
+    /// Removes a provisioning profile
+    /// # Arguments
+    /// `id` - The UUID of the profile to remove
+    pub async fn remove(&mut self, id: impl Into<String>) -> Result<(), IdeviceError> {
+        let id = id.into();
+        let mut req = plist::Dictionary::new();
+        req.insert("MessageType".into(), "Remove".into());
+        req.insert("ProfileID".into(), id.into());
+        req.insert("ProfileType".into(), "Provisioning".into());
+        self.idevice
+            .send_plist(plist::Value::Dictionary(req))
+            .await?;
+
+        let res = self.idevice.read_plist().await?;
+        Self::check_status(&res)
+    }
+
+    /// Copies every provisioning profile installed on the device
+    pub async fn copy_all(&mut self) -> Result<Vec<Vec<u8>>, IdeviceError> {
+        let mut req = plist::Dictionary::new();
+        req.insert("MessageType".into(), "CopyAll".into());
+        req.insert("ProfileType".into(), "Provisioning".into());
+        self.idevice
+            .send_plist(plist::Value::Dictionary(req))
+            .await?;
+
+        let mut res = self.idevice.read_plist().await?;
+        Self::check_status(&res)?;
+        match res.remove("Payload") {
+            Some(plist::Value::Array(profiles)) => Ok(profiles
+                .into_iter()
+                .filter_map(|p| match p {
+                    plist::Value::Data(p) => Some(p),
+                    _ => {
+                        warn!("Profile payload wasn't data");
+                        None
+                    }
+                })
+                .collect()),
+            _ => Err(IdeviceError::UnexpectedResponse),
+        }
+    }
+
+    fn check_status(res: &plist::Dictionary) -> Result<(), IdeviceError> {
+        match res.get("Status") {
+            Some(plist::Value::Integer(status)) => match status.as_unsigned() {
+                Some(0) => Ok(()),
+                Some(code) => Err(IdeviceError::MisagentFailure(code)),
+                None => Err(IdeviceError::UnexpectedResponse),
+            },
+            _ => Err(IdeviceError::UnexpectedResponse),
+        }
+    }
+}
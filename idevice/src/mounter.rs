@@ -1,12 +1,11 @@
 // Jackson Coxson
 
-use crate::{
-    lockdownd::LockdowndClient, tss::TSSRequest, util::hashmap_to_dictionary, Idevice,
-    IdeviceError, IdeviceService,
-};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::{lockdownd::LockdowndClient, tss::TSSRequest, Idevice, IdeviceError, IdeviceService};
 
 pub struct ImageMounter {
-    idevice: Idevice,
+    pub idevice: Idevice,
 }
 
 impl IdeviceService for ImageMounter {
@@ -36,6 +35,10 @@ impl IdeviceService for ImageMounter {
 }
 
 impl ImageMounter {
+    const MOUNT_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(500);
+    /// How much of an image is read into memory at a time while uploading from a reader
+    const UPLOAD_CHUNK_SIZE: usize = 64 * 1024;
+
     pub fn new(idevice: Idevice) -> Self {
         Self { idevice }
     }
@@ -59,6 +62,23 @@ impl ImageMounter {
         &mut self,
         image_type: impl Into<String>,
     ) -> Result<Vec<u8>, IdeviceError> {
+        match self
+            .lookup_image_signatures(image_type)
+            .await?
+            .into_iter()
+            .next()
+        {
+            Some(signature) => Ok(signature),
+            None => Err(IdeviceError::NotFound),
+        }
+    }
+
+    /// Looks up the signatures of every mounted image of a type.
+    /// Newer versions answer with a list, which is empty when nothing is mounted.
+    pub async fn lookup_image_signatures(
+        &mut self,
+        image_type: impl Into<String>,
+    ) -> Result<Vec<Vec<u8>>, IdeviceError> {
         let image_type = image_type.into();
         let mut req = plist::Dictionary::new();
         req.insert("Command".into(), "LookupImage".into());
@@ -67,10 +87,44 @@ impl ImageMounter {
             .send_plist(plist::Value::Dictionary(req))
             .await?;
 
-        let res = self.idevice.read_plist().await?;
-        match res.get("ImageSignature") {
-            Some(plist::Value::Data(signature)) => Ok(signature.clone()),
-            _ => Err(IdeviceError::NotFound),
+        let mut res = self.idevice.read_plist().await?;
+        match res.remove("ImageSignature") {
+            Some(plist::Value::Data(signature)) => Ok(vec![signature]),
+            Some(plist::Value::Array(signatures)) => Ok(signatures
+                .into_iter()
+                .filter_map(|s| s.into_data())
+                .collect()),
+            _ => Ok(Vec::new()),
+        }
+    }
+
+    /// Waits for an image to be mounted, such as right after `mount_image` returns and before
+    /// starting services that the image provides.
+    /// On timeout, a request may have been cut off, so the connection should be reestablished.
+    /// # Arguments
+    /// `image_type` - The image type to wait for, such as `Developer` or `Personalized`
+    /// `timeout` - How long to wait before giving up with `IdeviceError::Timeout`
+    pub async fn wait_until_mounted(
+        &mut self,
+        image_type: impl Into<String>,
+        timeout: std::time::Duration,
+    ) -> Result<(), IdeviceError> {
+        let image_type = image_type.into();
+        let poll = async {
+            loop {
+                match self.lookup_image(image_type.as_str()).await {
+                    Ok(_) => return Ok(()),
+                    Err(IdeviceError::NotFound) => {
+                        log::debug!("{image_type} image isn't mounted yet");
+                        tokio::time::sleep(Self::MOUNT_POLL_INTERVAL).await;
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+        };
+        match tokio::time::timeout(timeout, poll).await {
+            Ok(res) => res,
+            Err(_) => Err(IdeviceError::Timeout),
         }
     }
 
@@ -79,13 +133,57 @@ impl ImageMounter {
         image_type: impl Into<String>,
         image: &[u8],
         signature: Vec<u8>,
+    ) -> Result<(), IdeviceError> {
+        self.upload_image_from_reader(image_type, image, image.len() as u64, signature)
+            .await
+    }
+
+    /// Uploads an image unless one with the same signature is already mounted,
+    /// in which case it doesn't need to be mounted again either.
+    /// The device can't resume an upload that was cut off, so those start over.
+    /// # Arguments
+    /// `image` - The image, such as an open file, read a chunk at a time
+    /// `size` - The length of the image in bytes
+    /// # Returns
+    /// Whether the image was uploaded
+    pub async fn upload_image_if_needed(
+        &mut self,
+        image_type: impl Into<String>,
+        image: impl AsyncRead + Unpin,
+        size: u64,
+        signature: Vec<u8>,
+    ) -> Result<bool, IdeviceError> {
+        let image_type = image_type.into();
+        if self
+            .lookup_image_signatures(image_type.as_str())
+            .await?
+            .contains(&signature)
+        {
+            log::debug!("{image_type} image with this signature is already mounted");
+            return Ok(false);
+        }
+        self.upload_image_from_reader(image_type, image, size, signature)
+            .await?;
+        Ok(true)
+    }
+
+    /// Uploads an image without holding all of it in memory
+    /// # Arguments
+    /// `image` - The image, such as an open file, read a chunk at a time
+    /// `size` - The length of the image in bytes
+    pub async fn upload_image_from_reader(
+        &mut self,
+        image_type: impl Into<String>,
+        mut image: impl AsyncRead + Unpin,
+        size: u64,
+        signature: Vec<u8>,
     ) -> Result<(), IdeviceError> {
         let image_type = image_type.into();
 
         let mut req = plist::Dictionary::new();
         req.insert("Command".into(), "ReceiveBytes".into());
         req.insert("ImageType".into(), image_type.into());
-        req.insert("ImageSize".into(), (image.len() as u64).into());
+        req.insert("ImageSize".into(), size.into());
         req.insert("ImageSignature".into(), plist::Value::Data(signature));
         self.idevice
             .send_plist(plist::Value::Dictionary(req))
@@ -102,7 +200,14 @@ impl ImageMounter {
             _ => return Err(IdeviceError::UnexpectedResponse),
         }
 
-        self.idevice.send_raw(image).await?;
+        let mut remaining = size;
+        let mut buf = vec![0; Self::UPLOAD_CHUNK_SIZE];
+        while remaining > 0 {
+            let len = remaining.min(buf.len() as u64) as usize;
+            image.read_exact(&mut buf[..len]).await?;
+            self.idevice.send_raw(&buf[..len]).await?;
+            remaining -= len as u64;
+        }
 
         let res = self.idevice.read_plist().await?;
         match res.get("Status") {
@@ -278,7 +383,7 @@ impl ImageMounter {
         image: &[u8],
         signature: Vec<u8>,
     ) -> Result<(), IdeviceError> {
-        self.upload_image("Developer", &image, signature.clone())
+        self.upload_image("Developer", image, signature.clone())
             .await?;
         self.mount_image(
             "Developer",
@@ -343,8 +448,17 @@ impl ImageMounter {
         build_manifest: &plist::Dictionary,
         unique_chip_id: u64,
     ) -> Result<Vec<u8>, IdeviceError> {
+        use crate::utils::schema::FromPlist;
         use log::{debug, warn};
 
+        #[derive(FromPlist)]
+        struct PersonalizationIdentifiers {
+            #[plist(rename = "BoardId")]
+            board_id: u64,
+            #[plist(rename = "ChipID")]
+            chip_id: u64,
+        }
+
         let mut request = TSSRequest::new();
 
         let personalization_identifiers = self.query_personalization_identifiers(None).await?;
@@ -354,24 +468,8 @@ impl ImageMounter {
             }
         }
 
-        let board_id = match personalization_identifiers.get("BoardId") {
-            Some(plist::Value::Integer(b)) => match b.as_unsigned() {
-                Some(b) => b,
-                None => return Err(IdeviceError::UnexpectedResponse),
-            },
-            _ => {
-                return Err(IdeviceError::UnexpectedResponse);
-            }
-        };
-        let chip_id = match personalization_identifiers.get("ChipID") {
-            Some(plist::Value::Integer(b)) => match b.as_unsigned() {
-                Some(b) => b,
-                None => return Err(IdeviceError::UnexpectedResponse),
-            },
-            _ => {
-                return Err(IdeviceError::UnexpectedResponse);
-            }
-        };
+        let PersonalizationIdentifiers { board_id, chip_id } =
+            PersonalizationIdentifiers::from_plist(&personalization_identifiers)?;
 
         request.insert("@ApImg4Ticket", true);
         request.insert("@BBTicket", true);
@@ -0,0 +1,132 @@
+// Jackson Coxson
+// Abstractions for notification_proxy, which relays system notifications
+
+use futures::Stream;
+use log::{debug, warn};
+
+use crate::{
+    lockdownd::LockdowndClient,
+    utils::stream::{until_stopped, StopHandle},
+    Idevice, IdeviceError, IdeviceService,
+};
+
+pub mod names;
+
+pub use names::NotificationName;
+
+pub struct NotificationProxyClient {
+    pub idevice: Idevice,
+}
+
+impl IdeviceService for NotificationProxyClient {
+    fn service_name() -> &'static str {
+        "com.apple.mobile.notification_proxy"
+    }
+
+    async fn connect(
+        provider: &dyn crate::provider::IdeviceProvider,
+    ) -> Result<Self, IdeviceError> {
+        let mut lockdown = LockdowndClient::connect(provider).await?;
+        lockdown
+            .start_session(&provider.get_pairing_file().await?)
+            .await?;
+
+        let (port, ssl) = lockdown.start_service(Self::service_name()).await?;
+
+        let mut idevice = provider.connect(port).await?;
+        if ssl {
+            idevice
+                .start_session(&provider.get_pairing_file().await?)
+                .await?;
+        }
+
+        Ok(Self::new(idevice))
+    }
+}
+
+impl NotificationProxyClient {
+    pub fn new(idevice: Idevice) -> Self {
+        Self { idevice }
+    }
+
+    /// Asks the device to relay a notification when it's posted.
+    /// The device doesn't answer, and unknown names are accepted but never relayed.
+    pub async fn observe(&mut self, name: impl Into<NotificationName>) -> Result<(), IdeviceError> {
+        self.send_command("ObserveNotification", Some(name.into()))
+            .await
+    }
+
+    /// Observes every notification in `NotificationName::ALL`
+    pub async fn observe_all(&mut self) -> Result<(), IdeviceError> {
+        for name in NotificationName::ALL {
+            self.observe(name.clone()).await?;
+        }
+        Ok(())
+    }
+
+    /// Posts a notification on the device
+    pub async fn post(&mut self, name: impl Into<NotificationName>) -> Result<(), IdeviceError> {
+        self.send_command("PostNotification", Some(name.into()))
+            .await
+    }
+
+    /// Waits for the next observed notification
+    pub async fn next_notification(&mut self) -> Result<NotificationName, IdeviceError> {
+        loop {
+            let res = self.idevice.read_plist().await?;
+            match res.get("Command").and_then(|c| c.as_string()) {
+                Some("RelayNotification") => match res.get("Name").and_then(|n| n.as_string()) {
+                    Some(name) => return Ok(name.into()),
+                    None => return Err(IdeviceError::UnexpectedResponse),
+                },
+                Some("ProxyDeath") => return Err(IdeviceError::NoEstablishedConnection),
+                c => {
+                    debug!("Ignoring notification_proxy command {c:?}");
+                }
+            }
+        }
+    }
+
+    /// Yields observed notifications until stopped.
+    /// The stream ends after the first error, such as the proxy shutting down.
+    pub fn into_stream(
+        self,
+    ) -> (
+        impl Stream<Item = Result<NotificationName, IdeviceError>>,
+        StopHandle,
+    ) {
+        let stop = StopHandle::new();
+        let stream = until_stopped(self, stop.clone(), |mut client| async move {
+            let res = client.next_notification().await;
+            (res, client)
+        });
+        (stream, stop)
+    }
+
+    /// Ends the session. The device answers with `ProxyDeath` once it stops relaying.
+    pub async fn shutdown(&mut self) -> Result<(), IdeviceError> {
+        self.send_command("Shutdown", None).await?;
+        loop {
+            let res = self.idevice.read_plist().await?;
+            match res.get("Command").and_then(|c| c.as_string()) {
+                Some("ProxyDeath") => return Ok(()),
+                c => {
+                    warn!("Expected ProxyDeath, got {c:?}");
+                }
+            }
+        }
+    }
+
+    async fn send_command(
+        &mut self,
+        command: &str,
+        name: Option<NotificationName>,
+    ) -> Result<(), IdeviceError> {
+        let mut req = plist::Dictionary::new();
+        req.insert("Command".into(), command.into());
+        if let Some(name) = name {
+            req.insert("Name".into(), name.as_str().into());
+        }
+        self.idevice.send_plist(plist::Value::Dictionary(req)).await
+    }
+}
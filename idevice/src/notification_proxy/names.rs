@@ -0,0 +1,115 @@
+// Jackson Coxson
+// Notifications known to be relayed by notification_proxy
+
+use std::fmt;
+
+macro_rules! names {
+    ($($(#[$doc:meta])* $variant:ident => $name:literal,)*) => {
+        /// A notification that can be observed or posted.
+        /// Names not listed here can still be used through `Other`.
+        #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+        pub enum NotificationName {
+            $($(#[$doc])* $variant,)*
+            Other(String),
+        }
+
+        impl NotificationName {
+            /// Every listed notification, such as to observe all of them
+            pub const ALL: &'static [NotificationName] = &[$(NotificationName::$variant,)*];
+
+            /// The string the device uses for this notification
+            pub fn as_str(&self) -> &str {
+                match self {
+                    $(NotificationName::$variant => $name,)*
+                    NotificationName::Other(name) => name,
+                }
+            }
+        }
+
+        impl From<&str> for NotificationName {
+            fn from(value: &str) -> Self {
+                match value {
+                    $($name => NotificationName::$variant,)*
+                    other => NotificationName::Other(other.to_string()),
+                }
+            }
+        }
+    };
+}
+
+names! {
+    /// A host is about to sync with the device
+    SyncWillStart => "com.apple.itunes-mobdev.syncWillStart",
+    /// A host started syncing
+    SyncDidStart => "com.apple.itunes-mobdev.syncDidStart",
+    /// A host finished syncing
+    SyncDidFinish => "com.apple.itunes-mobdev.syncDidFinish",
+    /// A host is asking for the sync lock
+    SyncLockRequest => "com.apple.itunes-mobdev.syncLockRequest",
+    /// Posted to ask the device to cancel a sync
+    SyncCancelRequest => "com.apple.itunes-client.syncCancelRequest",
+    /// Posted to ask the device to suspend a sync
+    SyncSuspendRequest => "com.apple.itunes-client.syncSuspendRequest",
+    /// Posted to ask the device to resume a suspended sync
+    SyncResumeRequest => "com.apple.itunes-client.syncResumeRequest",
+    /// The device's language was changed
+    LanguageChanged => "com.apple.language.changed",
+    /// A developer disk image was mounted
+    DeveloperImageMounted => "com.apple.mobile.developer_image_mounted",
+    /// An app was installed or updated
+    ApplicationInstalled => "com.apple.mobile.application_installed",
+    /// An app was removed
+    ApplicationUninstalled => "com.apple.mobile.application_uninstalled",
+    /// The set of installed apps changed
+    ApplicationsChanged => "com.apple.LaunchServices.ApplicationsChanged",
+    /// A backup domain's settings changed
+    BackupDomainChanged => "com.apple.mobile.backup.domain_changed",
+    /// A data sync domain's settings changed
+    DataSyncDomainChanged => "com.apple.mobile.data_sync.domain_changed",
+    /// The device was renamed
+    DeviceNameChanged => "com.apple.mobile.lockdown.device_name_changed",
+    /// A host connected
+    HostAttached => "com.apple.mobile.lockdown.host_attached",
+    /// A host disconnected
+    HostDetached => "com.apple.mobile.lockdown.host_detached",
+    /// A paired host connected
+    TrustedHostAttached => "com.apple.mobile.lockdown.trusted_host_attached",
+    /// The phone number changed
+    PhoneNumberChanged => "com.apple.mobile.lockdown.phone_number_changed",
+    /// Activation state changed
+    ActivationState => "com.apple.mobile.lockdown.activation_state",
+    /// Disk usage changed
+    DiskUsageChanged => "com.apple.mobile.lockdown.disk_usage_changed",
+    /// The address book preferences changed
+    AddressBookPreferenceChanged => "com.apple.AddressBook.PreferenceChanged",
+}
+
+impl From<String> for NotificationName {
+    fn from(value: String) -> Self {
+        match NotificationName::from(value.as_str()) {
+            NotificationName::Other(_) => NotificationName::Other(value),
+            n => n,
+        }
+    }
+}
+
+impl fmt::Display for NotificationName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_round_trip() {
+        for name in NotificationName::ALL {
+            assert_eq!(&NotificationName::from(name.as_str()), name);
+        }
+        let other = NotificationName::from("com.example.custom");
+        assert_eq!(other, NotificationName::Other("com.example.custom".into()));
+        assert_eq!(other.as_str(), "com.example.custom");
+    }
+}
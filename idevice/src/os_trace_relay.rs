@@ -0,0 +1,476 @@
+// Jackson Coxson
+// Abstractions for os_trace_relay, which relays the unified log and process metadata
+
+use std::{
+    collections::HashMap,
+    io::Write,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use futures::{Stream, StreamExt};
+use log::warn;
+
+use crate::{
+    lockdownd::LockdowndClient,
+    utils::stream::{until_stopped, StopHandle},
+    Idevice, IdeviceError, IdeviceService,
+};
+
+pub struct OsTraceRelayClient {
+    pub idevice: Idevice,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogLevel {
+    Notice,
+    Info,
+    Debug,
+    UserAction,
+    Error,
+    Fault,
+    Unknown(u8),
+}
+
+impl From<u8> for LogLevel {
+    fn from(value: u8) -> Self {
+        match value {
+            0x00 => Self::Notice,
+            0x01 => Self::Info,
+            0x02 => Self::Debug,
+            0x03 => Self::UserAction,
+            0x10 => Self::Error,
+            0x11 => Self::Fault,
+            v => Self::Unknown(v),
+        }
+    }
+}
+
+impl LogLevel {
+    /// The `messageType` `log show --style ndjson` uses for the level
+    pub fn message_type(&self) -> &'static str {
+        match self {
+            Self::Info => "Info",
+            Self::Debug => "Debug",
+            Self::Error => "Error",
+            Self::Fault => "Fault",
+            Self::Notice | Self::UserAction | Self::Unknown(_) => "Default",
+        }
+    }
+}
+
+/// A unified log message relayed from the device
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogEntry {
+    pub pid: u32,
+    pub timestamp: SystemTime,
+    pub level: LogLevel,
+    /// The executable of the process that logged the message
+    pub process_image_path: String,
+    /// The binary or library the message was logged from
+    pub sender_image_path: String,
+    pub message: String,
+    pub subsystem: Option<String>,
+    pub category: Option<String>,
+}
+
+impl LogEntry {
+    /// Offsets into the fixed part of an entry
+    const PID: usize = 9;
+    const SECONDS: usize = 55;
+    const MICROS: usize = 63;
+    const LEVEL: usize = 68;
+    const IMAGE_NAME_SIZE: usize = 107;
+    const MESSAGE_SIZE: usize = 109;
+    const SUBSYSTEM_SIZE: usize = 117;
+    const CATEGORY_SIZE: usize = 121;
+    const STRINGS: usize = 129;
+
+    /// Parses an entry from the activity stream, without its marker and length
+    pub fn parse(buf: &[u8]) -> Result<Self, IdeviceError> {
+        if buf.len() < Self::STRINGS {
+            return Err(IdeviceError::PacketSizeMismatch);
+        }
+        let u16_at = |i: usize| u16::from_le_bytes([buf[i], buf[i + 1]]) as usize;
+        let u32_at = |i: usize| u32::from_le_bytes(buf[i..i + 4].try_into().unwrap());
+
+        // The process path is nul terminated, and the rest are sized
+        let mut rest = &buf[Self::STRINGS..];
+        let nul = rest
+            .iter()
+            .position(|b| *b == 0)
+            .ok_or(IdeviceError::UnexpectedResponse)?;
+        let process_image_path = String::from_utf8_lossy(&rest[..nul]).to_string();
+        rest = &rest[nul + 1..];
+        let mut take = |len: usize| -> Result<String, IdeviceError> {
+            if rest.len() < len {
+                return Err(IdeviceError::PacketSizeMismatch);
+            }
+            let (s, r) = rest.split_at(len);
+            rest = r;
+            Ok(String::from_utf8_lossy(s)
+                .trim_end_matches('\0')
+                .to_string())
+        };
+        let sender_image_path = take(u16_at(Self::IMAGE_NAME_SIZE))?;
+        let message = take(u16_at(Self::MESSAGE_SIZE))?;
+        let (subsystem, category) = match u32_at(Self::SUBSYSTEM_SIZE) as usize {
+            0 => (None, None),
+            len => (
+                Some(take(len)?),
+                Some(take(u32_at(Self::CATEGORY_SIZE) as usize)?),
+            ),
+        };
+
+        let timestamp = UNIX_EPOCH
+            + Duration::from_secs(u32_at(Self::SECONDS) as u64)
+            + Duration::from_micros(u32_at(Self::MICROS) as u64);
+        Ok(Self {
+            pid: u32_at(Self::PID),
+            timestamp,
+            level: LogLevel::from(buf[Self::LEVEL]),
+            process_image_path,
+            sender_image_path,
+            message,
+            subsystem,
+            category,
+        })
+    }
+
+    /// The file name of the process's executable, such as `SpringBoard`
+    pub fn process_name(&self) -> &str {
+        self.process_image_path
+            .rsplit('/')
+            .next()
+            .unwrap_or_default()
+    }
+
+    /// Writes the entry as a line of NDJSON, with the keys `log show --style ndjson` uses,
+    /// so it can be read by the same tools.
+    /// The relay doesn't send activity or thread IDs, so those keys are left out.
+    pub fn write_ndjson(&self, mut writer: impl Write) -> std::io::Result<()> {
+        let json = serde_json::json!({
+            "timestamp": rfc3339(self.timestamp),
+            "messageType": self.level.message_type(),
+            "eventType": "logEvent",
+            "processID": self.pid,
+            "processImagePath": self.process_image_path,
+            "senderImagePath": self.sender_image_path,
+            "subsystem": self.subsystem.as_deref().unwrap_or_default(),
+            "category": self.category.as_deref().unwrap_or_default(),
+            "eventMessage": self.message,
+        });
+        serde_json::to_writer(&mut writer, &json)?;
+        writer.write_all(b"\n")
+    }
+}
+
+/// Which messages `start_activity_with` relays.
+/// The device only filters by pid, so the rest are checked as entries arrive.
+#[derive(Debug, Clone, Default)]
+pub struct ActivityOptions {
+    pid: Option<u32>,
+    process_name: Option<String>,
+    matches: Vec<String>,
+    ignore_case: bool,
+}
+
+impl ActivityOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only relay messages from this process
+    pub fn pid(mut self, pid: u32) -> Self {
+        self.pid = Some(pid);
+        self
+    }
+
+    /// Only relay messages from processes with this executable name, such as `SpringBoard`
+    pub fn process_name(mut self, name: impl Into<String>) -> Self {
+        self.process_name = Some(name.into());
+        self
+    }
+
+    /// Only relay messages containing this string. Every string added must appear.
+    pub fn matching(mut self, s: impl Into<String>) -> Self {
+        self.matches.push(s.into());
+        self
+    }
+
+    /// Compare match strings without regard to case
+    pub fn ignore_case(mut self, ignore_case: bool) -> Self {
+        self.ignore_case = ignore_case;
+        self
+    }
+
+    /// Whether an entry passes the filters that aren't sent to the device
+    pub fn matches(&self, entry: &LogEntry) -> bool {
+        if let Some(name) = &self.process_name {
+            if entry.process_name() != name {
+                return false;
+            }
+        }
+        if self.ignore_case {
+            let message = entry.message.to_lowercase();
+            self.matches
+                .iter()
+                .all(|m| message.contains(&m.to_lowercase()))
+        } else {
+            self.matches
+                .iter()
+                .all(|m| entry.message.contains(m.as_str()))
+        }
+    }
+}
+
+/// Formats a time as UTC with microseconds, such as 2025-01-31T12:00:00.000000Z
+fn rfc3339(time: SystemTime) -> String {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since.as_secs();
+    let (days, rem) = ((secs / 86400) as i64, secs % 86400);
+
+    // Howard Hinnant's days_from_civil, inverted
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:06}Z",
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60,
+        since.subsec_micros()
+    )
+}
+
+impl IdeviceService for OsTraceRelayClient {
+    fn service_name() -> &'static str {
+        "com.apple.os_trace_relay"
+    }
+
+    async fn connect(
+        provider: &dyn crate::provider::IdeviceProvider,
+    ) -> Result<Self, IdeviceError> {
+        let mut lockdown = LockdowndClient::connect(provider).await?;
+        lockdown
+            .start_session(&provider.get_pairing_file().await?)
+            .await?;
+
+        let (port, ssl) = lockdown.start_service(Self::service_name()).await?;
+
+        let mut idevice = provider.connect(port).await?;
+        if ssl {
+            idevice
+                .start_session(&provider.get_pairing_file().await?)
+                .await?;
+        }
+
+        Ok(Self::new(idevice))
+    }
+}
+
+impl OsTraceRelayClient {
+    pub fn new(idevice: Idevice) -> Self {
+        Self { idevice }
+    }
+
+    /// Gets the names of the running processes, keyed by PID.
+    /// Works without a developer disk image mounted.
+    pub async fn get_pid_list(&mut self) -> Result<HashMap<u64, String>, IdeviceError> {
+        let mut req = plist::Dictionary::new();
+        req.insert("Request".into(), "PidList".into());
+        self.idevice
+            .send_plist(plist::Value::Dictionary(req))
+            .await?;
+
+        let res = self.read_response().await?;
+        let payload = match res.get("Payload") {
+            Some(plist::Value::Dictionary(p)) => p,
+            _ => {
+                warn!("PidList response didn't contain a payload");
+                return Err(IdeviceError::UnexpectedResponse);
+            }
+        };
+
+        Ok(payload
+            .iter()
+            .filter_map(|(pid, info)| {
+                let pid = pid.parse::<u64>().ok()?;
+                let name = info
+                    .as_dictionary()
+                    .and_then(|i| i.get("ProcessName"))
+                    .and_then(|n| n.as_string())
+                    .unwrap_or_default();
+                Some((pid, name.to_string()))
+            })
+            .collect())
+    }
+
+    /// Starts relaying log messages. The connection is consumed, as the device only sends
+    /// messages on it afterwards.
+    /// # Arguments
+    /// `pid` - Only relay messages from this process, or from every process if `None`
+    pub async fn start_activity(
+        self,
+        pid: Option<u32>,
+    ) -> Result<
+        (
+            impl Stream<Item = Result<LogEntry, IdeviceError>>,
+            StopHandle,
+        ),
+        IdeviceError,
+    > {
+        let mut options = ActivityOptions::new();
+        options.pid = pid;
+        self.start_activity_with(options).await
+    }
+
+    /// Starts relaying the log messages that pass the filters.
+    /// Errors are always passed through, so the caller sees the stream end.
+    /// # Arguments
+    /// `options` - Which messages to relay
+    pub async fn start_activity_with(
+        mut self,
+        options: ActivityOptions,
+    ) -> Result<
+        (
+            impl Stream<Item = Result<LogEntry, IdeviceError>>,
+            StopHandle,
+        ),
+        IdeviceError,
+    > {
+        let mut req = plist::Dictionary::new();
+        req.insert("Request".into(), "StartActivity".into());
+        req.insert("MessageFilter".into(), 65535.into());
+        req.insert(
+            "Pid".into(),
+            options.pid.map(|p| p as i64).unwrap_or(-1).into(),
+        );
+        req.insert("StreamFlags".into(), 60.into());
+        self.idevice
+            .send_plist(plist::Value::Dictionary(req))
+            .await?;
+        self.read_response().await?;
+
+        let stop = StopHandle::new();
+        let stream = until_stopped(self, stop.clone(), |mut client| async move {
+            let res = client.read_entry().await;
+            (res, client)
+        })
+        .filter(move |res| {
+            let keep = match res {
+                Ok(entry) => options.matches(entry),
+                Err(_) => true,
+            };
+            std::future::ready(keep)
+        });
+        Ok((stream, stop))
+    }
+
+    /// Entries start with a marker byte, then a little endian length
+    async fn read_entry(&mut self) -> Result<LogEntry, IdeviceError> {
+        let marker = self.idevice.read_raw(1).await?;
+        if marker[0] != 0x02 {
+            warn!("Unexpected os_trace entry marker {:#x}", marker[0]);
+            return Err(IdeviceError::UnexpectedResponse);
+        }
+        let len = self.idevice.read_raw(4).await?;
+        let len = u32::from_le_bytes([len[0], len[1], len[2], len[3]]);
+        let entry = self.idevice.read_raw(len as usize).await?;
+        LogEntry::parse(&entry)
+    }
+
+    /// Responses start with a status byte, then a big endian length prefixed plist
+    async fn read_response(&mut self) -> Result<plist::Dictionary, IdeviceError> {
+        self.idevice.read_raw(1).await?;
+        let len = self.idevice.read_raw(4).await?;
+        let len = u32::from_be_bytes([len[0], len[1], len[2], len[3]]);
+        let res = self.idevice.read_raw(len as usize).await?;
+        let res: plist::Dictionary = plist::from_bytes(&res)?;
+
+        match res.get("Status").and_then(|s| s.as_string()) {
+            Some("RequestSuccessful") => Ok(res),
+            s => {
+                warn!("os_trace_relay request failed with status {s:?}");
+                Err(IdeviceError::UnexpectedResponse)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_and_exports_entries() {
+        let mut buf = vec![0; LogEntry::STRINGS];
+        buf[LogEntry::PID..LogEntry::PID + 4].copy_from_slice(&42u32.to_le_bytes());
+        buf[LogEntry::SECONDS..LogEntry::SECONDS + 4]
+            .copy_from_slice(&1_700_000_000u32.to_le_bytes());
+        buf[LogEntry::MICROS..LogEntry::MICROS + 4].copy_from_slice(&5u32.to_le_bytes());
+        buf[LogEntry::LEVEL] = 0x10;
+        buf[LogEntry::IMAGE_NAME_SIZE..LogEntry::IMAGE_NAME_SIZE + 2]
+            .copy_from_slice(&8u16.to_le_bytes());
+        buf[LogEntry::MESSAGE_SIZE..LogEntry::MESSAGE_SIZE + 2]
+            .copy_from_slice(&6u16.to_le_bytes());
+        buf[LogEntry::SUBSYSTEM_SIZE..LogEntry::SUBSYSTEM_SIZE + 4]
+            .copy_from_slice(&10u32.to_le_bytes());
+        buf[LogEntry::CATEGORY_SIZE..LogEntry::CATEGORY_SIZE + 4]
+            .copy_from_slice(&4u32.to_le_bytes());
+        buf.extend(b"/bin/a\0libx.so\0hello\0com.a.sub\0cat\0");
+
+        let entry = LogEntry::parse(&buf).unwrap();
+        assert_eq!(entry.pid, 42);
+        assert_eq!(entry.level, LogLevel::Error);
+        assert_eq!(entry.process_image_path, "/bin/a");
+        assert_eq!(entry.sender_image_path, "libx.so");
+        assert_eq!(entry.message, "hello");
+        assert_eq!(entry.subsystem.as_deref(), Some("com.a.sub"));
+        assert_eq!(entry.category.as_deref(), Some("cat"));
+        assert!(LogEntry::parse(&buf[..buf.len() - 3]).is_err());
+
+        let mut out = Vec::new();
+        entry.write_ndjson(&mut out).unwrap();
+        let line: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(line["timestamp"], "2023-11-14T22:13:20.000005Z");
+        assert_eq!(line["messageType"], "Error");
+        assert_eq!(line["subsystem"], "com.a.sub");
+    }
+
+    #[test]
+    fn activity_options_filter_entries() {
+        let entry = LogEntry {
+            pid: 1,
+            timestamp: UNIX_EPOCH,
+            level: LogLevel::Info,
+            process_image_path: "/System/Library/CoreServices/SpringBoard.app/SpringBoard"
+                .to_string(),
+            sender_image_path: String::new(),
+            message: "Icon Layout Changed".to_string(),
+            subsystem: None,
+            category: None,
+        };
+        assert_eq!(entry.process_name(), "SpringBoard");
+        assert!(ActivityOptions::new().matches(&entry));
+        assert!(ActivityOptions::new()
+            .process_name("SpringBoard")
+            .matching("Icon")
+            .matching("Layout")
+            .matches(&entry));
+        assert!(!ActivityOptions::new()
+            .process_name("backboardd")
+            .matches(&entry));
+        assert!(!ActivityOptions::new().matching("icon").matches(&entry));
+        assert!(ActivityOptions::new()
+            .matching("icon")
+            .ignore_case(true)
+            .matches(&entry));
+    }
+}
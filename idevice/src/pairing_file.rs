@@ -1,9 +1,13 @@
 // Jackson Coxson
 
-use std::path::Path;
+use std::{
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use log::warn;
 use openssl::{
+    asn1::{Asn1Time, Asn1TimeRef},
     pkey::{PKey, Private},
     x509::X509,
 };
@@ -24,6 +28,31 @@ pub struct PairingFile {
     pub udid: Option<String>,
 }
 
+/// Loading a pairing file with a certificate that expires sooner than this logs a warning
+pub const EXPIRY_WARNING: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CertificateKind {
+    Device,
+    Host,
+    Root,
+}
+
+/// When one of the pairing file's certificates is valid
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CertificateValidity {
+    pub kind: CertificateKind,
+    pub not_before: SystemTime,
+    pub not_after: SystemTime,
+}
+
+impl CertificateValidity {
+    /// Whether the certificate has expired, or will within the duration from now
+    pub fn expires_within(&self, within: Duration) -> bool {
+        self.not_after <= SystemTime::now() + within
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "PascalCase")]
 struct RawPairingFile {
@@ -39,7 +68,7 @@ struct RawPairingFile {
     escrow_bag: Data,
     #[serde(rename = "WiFiMACAddress")]
     wifi_mac_address: String,
-    #[serde(rename = "UDID")]
+    #[serde(rename = "UDID", skip_serializing_if = "Option::is_none")]
     udid: Option<String>,
 }
 
@@ -59,7 +88,10 @@ impl PairingFile {
         };
 
         match r.try_into() {
-            Ok(r) => Ok(r),
+            Ok(r) => {
+                Self::warn_if_expiring(&r);
+                Ok(r)
+            }
             Err(e) => {
                 warn!("Unable to convert raw pairing file into pairing file: {e:?}");
                 Err(crate::IdeviceError::UnexpectedResponse)
@@ -70,8 +102,112 @@ impl PairingFile {
     pub fn from_value(v: &plist::Value) -> Result<Self, crate::IdeviceError> {
         let raw: RawPairingFile = plist::from_value(v)?;
         let p = raw.try_into()?;
+        Self::warn_if_expiring(&p);
         Ok(p)
     }
+
+    /// The validity periods of the device, host and root certificates
+    pub fn certificate_validity(&self) -> Result<Vec<CertificateValidity>, crate::IdeviceError> {
+        [
+            (CertificateKind::Device, &self.device_certificate),
+            (CertificateKind::Host, &self.host_certificate),
+            (CertificateKind::Root, &self.root_certificate),
+        ]
+        .into_iter()
+        .map(|(kind, cert)| {
+            Ok(CertificateValidity {
+                kind,
+                not_before: asn1_to_system_time(cert.not_before())?,
+                not_after: asn1_to_system_time(cert.not_after())?,
+            })
+        })
+        .collect()
+    }
+
+    /// When the first of the certificates expires, after which the device has to be paired again
+    pub fn expires_at(&self) -> Result<SystemTime, crate::IdeviceError> {
+        Ok(self
+            .certificate_validity()?
+            .into_iter()
+            .map(|v| v.not_after)
+            .min()
+            .unwrap_or(UNIX_EPOCH))
+    }
+
+    /// The certificates that have expired, or will within the duration from now
+    pub fn expiring_within(
+        &self,
+        within: Duration,
+    ) -> Result<Vec<CertificateValidity>, crate::IdeviceError> {
+        Ok(self
+            .certificate_validity()?
+            .into_iter()
+            .filter(|v| v.expires_within(within))
+            .collect())
+    }
+
+    fn warn_if_expiring(&self) {
+        let expiring = match self.expiring_within(EXPIRY_WARNING) {
+            Ok(e) => e,
+            Err(e) => {
+                warn!("Unable to read pairing file certificate validity: {e:?}");
+                return;
+            }
+        };
+        let now = SystemTime::now();
+        for v in expiring {
+            match v.not_after.duration_since(now) {
+                Ok(left) => warn!(
+                    "The {:?} certificate of the pairing file for {} expires in {} days",
+                    v.kind,
+                    self.udid.as_deref().unwrap_or(&self.host_id),
+                    left.as_secs() / 86400
+                ),
+                Err(_) => warn!(
+                    "The {:?} certificate of the pairing file for {} has expired",
+                    v.kind,
+                    self.udid.as_deref().unwrap_or(&self.host_id)
+                ),
+            }
+        }
+    }
+
+    /// Serializes the pairing file as an XML plist, as it's stored on disk
+    pub fn serialize(&self) -> Result<Vec<u8>, crate::IdeviceError> {
+        let raw = RawPairingFile::try_from(self)?;
+        let mut buf = Vec::new();
+        plist::to_writer_xml(&mut buf, &raw)?;
+        Ok(buf)
+    }
+}
+
+fn asn1_to_system_time(time: &Asn1TimeRef) -> Result<SystemTime, openssl::error::ErrorStack> {
+    let diff = Asn1Time::from_unix(0)?.diff(time)?;
+    let secs = diff.days as i64 * 86400 + diff.secs as i64;
+    Ok(if secs >= 0 {
+        UNIX_EPOCH + Duration::from_secs(secs as u64)
+    } else {
+        UNIX_EPOCH - Duration::from_secs(secs.unsigned_abs())
+    })
+}
+
+impl TryFrom<&PairingFile> for RawPairingFile {
+    type Error = openssl::error::ErrorStack;
+
+    fn try_from(value: &PairingFile) -> Result<Self, Self::Error> {
+        Ok(Self {
+            device_certificate: Data::new(value.device_certificate.to_pem()?),
+            host_private_key: Data::new(value.host_private_key.private_key_to_pem_pkcs8()?),
+            host_certificate: Data::new(value.host_certificate.to_pem()?),
+            root_private_key: Data::new(value.root_private_key.private_key_to_pem_pkcs8()?),
+            root_certificate: Data::new(value.root_certificate.to_pem()?),
+            system_buid: value.system_buid.clone(),
+            host_id: value.host_id.clone(),
+            escrow_bag: Data::new(value.escrow_bag.clone()),
+            wifi_mac_address: value.wifi_mac_address.clone(),
+            udid: value.udid.clone(),
+        })
+    }
 }
 
 impl TryFrom<RawPairingFile> for PairingFile {
@@ -96,3 +232,73 @@ impl TryFrom<RawPairingFile> for PairingFile {
         })
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use openssl::{hash::MessageDigest, rsa::Rsa, x509::X509Builder};
+
+    use super::*;
+
+    pub(crate) fn certificate(key: &PKey<Private>, days: u32) -> X509 {
+        let mut builder = X509Builder::new().unwrap();
+        builder.set_pubkey(key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(days).unwrap())
+            .unwrap();
+        builder.sign(key, MessageDigest::sha256()).unwrap();
+        builder.build()
+    }
+
+    /// A pairing file whose keys and certificates are all one throwaway key
+    pub(crate) fn pairing_file(udid: Option<&str>) -> PairingFile {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        PairingFile {
+            device_certificate: certificate(&key, 3650),
+            host_private_key: key.clone(),
+            host_certificate: certificate(&key, 3650),
+            root_private_key: key.clone(),
+            root_certificate: certificate(&key, 3650),
+            system_buid: "buid".into(),
+            host_id: "host".into(),
+            escrow_bag: Vec::new(),
+            wifi_mac_address: "00:00:00:00:00:00".into(),
+            udid: udid.map(String::from),
+        }
+    }
+
+    #[test]
+    fn certificate_expiry() {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let pairing_file = PairingFile {
+            device_certificate: certificate(&key, 3650),
+            host_private_key: key.clone(),
+            host_certificate: certificate(&key, 10),
+            root_private_key: key.clone(),
+            root_certificate: certificate(&key, 3650),
+            system_buid: "buid".into(),
+            host_id: "host".into(),
+            escrow_bag: Vec::new(),
+            wifi_mac_address: "00:00:00:00:00:00".into(),
+            udid: None,
+        };
+        let pairing_file = PairingFile::from_bytes(&pairing_file.serialize().unwrap()).unwrap();
+
+        let expiring = pairing_file.expiring_within(EXPIRY_WARNING).unwrap();
+        assert_eq!(expiring.len(), 1);
+        assert_eq!(expiring[0].kind, CertificateKind::Host);
+        assert!(pairing_file
+            .expiring_within(Duration::from_secs(86400))
+            .unwrap()
+            .is_empty());
+
+        let left = pairing_file
+            .expires_at()
+            .unwrap()
+            .duration_since(SystemTime::now())
+            .unwrap();
+        assert!(left > Duration::from_secs(9 * 86400) && left <= Duration::from_secs(10 * 86400));
+    }
+}
@@ -0,0 +1,290 @@
+// Jackson Coxson
+// Keeping long captures bounded, in memory or on disk
+
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::PathBuf,
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+use futures::{Stream, StreamExt};
+use log::{debug, warn};
+
+use super::{pcapng::PcapngWriter, DevicePacket};
+use crate::IdeviceError;
+
+/// Somewhere captured packets can be recorded to with `record`
+pub trait PacketSink: Send {
+    fn push_packet(&mut self, packet: DevicePacket) -> io::Result<()>;
+
+    /// Called when recording ends
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Records packets from a stream, such as `PcapdClient::into_stream`, until it ends.
+/// Returns the error that ended the stream or the sink, if any.
+pub async fn record(
+    stream: impl Stream<Item = Result<DevicePacket, IdeviceError>>,
+    sink: &mut impl PacketSink,
+) -> Result<(), IdeviceError> {
+    let mut stream = Box::pin(stream);
+    while let Some(packet) = stream.next().await {
+        let res = packet.and_then(|p| Ok(sink.push_packet(p)?));
+        if let Err(e) = res {
+            warn!("Packet capture stopped: {e:?}");
+            sink.flush()?;
+            return Err(e);
+        }
+    }
+    Ok(sink.flush()?)
+}
+
+/// Keeps the most recent packets up to a byte budget, so the traffic around an event
+/// can be saved after the fact
+#[derive(Debug, Clone)]
+pub struct PacketRing {
+    max_bytes: usize,
+    bytes: usize,
+    packets: VecDeque<DevicePacket>,
+}
+
+impl PacketRing {
+    pub fn new(max_bytes: usize) -> Self {
+        Self {
+            max_bytes,
+            bytes: 0,
+            packets: VecDeque::new(),
+        }
+    }
+
+    /// Adds a packet, dropping the oldest ones to stay within the budget
+    pub fn push(&mut self, packet: DevicePacket) {
+        self.bytes += packet.data.len();
+        self.packets.push_back(packet);
+        while self.bytes > self.max_bytes {
+            match self.packets.pop_front() {
+                Some(p) => self.bytes -= p.data.len(),
+                None => break,
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.packets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.packets.is_empty()
+    }
+
+    /// The bytes of packet data currently held
+    pub fn size(&self) -> usize {
+        self.bytes
+    }
+
+    /// Writes the held packets as a pcapng file, leaving the ring as it was
+    pub fn snapshot(&self, writer: impl Write) -> io::Result<()> {
+        let mut writer = PcapngWriter::new(writer)?;
+        for packet in &self.packets {
+            writer.write_packet(packet)?;
+        }
+        writer.flush()
+    }
+
+    pub fn clear(&mut self) {
+        self.packets.clear();
+        self.bytes = 0;
+    }
+}
+
+impl PacketSink for PacketRing {
+    fn push_packet(&mut self, packet: DevicePacket) -> io::Result<()> {
+        self.push(packet);
+        Ok(())
+    }
+}
+
+/// Records into a shared ring, so it can be snapshotted while the capture goes on
+impl PacketSink for Arc<Mutex<PacketRing>> {
+    fn push_packet(&mut self, packet: DevicePacket) -> io::Result<()> {
+        self.lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(packet);
+        Ok(())
+    }
+}
+
+/// When a `RotatingWriter` starts a new file
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RotationPolicy {
+    /// Start a new file once this many bytes of packets were written
+    pub max_bytes: Option<u64>,
+    /// Start a new file once the current one is this old
+    pub max_age: Option<Duration>,
+    /// Delete the oldest files beyond this many
+    pub max_files: Option<usize>,
+}
+
+/// Writes packets to numbered pcapng files in a directory, starting new ones per the policy
+pub struct RotatingWriter {
+    dir: PathBuf,
+    prefix: String,
+    policy: RotationPolicy,
+    current: Option<CurrentFile>,
+    next_index: u64,
+    files: VecDeque<PathBuf>,
+}
+
+struct CurrentFile {
+    writer: PcapngWriter<BufWriter<File>>,
+    started: Instant,
+    written: u64,
+}
+
+impl RotatingWriter {
+    /// # Arguments
+    /// `dir` - The directory to write files to
+    /// `prefix` - File names are this, then an index, then `.pcapng`
+    pub fn new(dir: impl Into<PathBuf>, prefix: impl Into<String>, policy: RotationPolicy) -> Self {
+        Self {
+            dir: dir.into(),
+            prefix: prefix.into(),
+            policy,
+            current: None,
+            next_index: 0,
+            files: VecDeque::new(),
+        }
+    }
+
+    pub fn write_packet(&mut self, packet: &DevicePacket) -> io::Result<()> {
+        if self.should_rotate() {
+            self.rotate()?;
+        }
+        // rotate always leaves a file open
+        let current = self.current.as_mut().unwrap();
+        current.writer.write_packet(packet)?;
+        current.written += packet.data.len() as u64;
+        Ok(())
+    }
+
+    /// The files written so far, oldest first
+    pub fn files(&self) -> impl Iterator<Item = &PathBuf> {
+        self.files.iter()
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        match &mut self.current {
+            Some(c) => c.writer.flush(),
+            None => Ok(()),
+        }
+    }
+
+    fn should_rotate(&self) -> bool {
+        let current = match &self.current {
+            Some(c) => c,
+            None => return true,
+        };
+        self.policy
+            .max_bytes
+            .is_some_and(|max| current.written >= max)
+            || self
+                .policy
+                .max_age
+                .is_some_and(|max| current.started.elapsed() >= max)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.flush()?;
+        let path = self
+            .dir
+            .join(format!("{}{}.pcapng", self.prefix, self.next_index));
+        self.next_index += 1;
+        debug!("Starting capture file {path:?}");
+
+        let file = BufWriter::new(File::create(&path)?);
+        self.current = Some(CurrentFile {
+            writer: PcapngWriter::new(file)?,
+            started: Instant::now(),
+            written: 0,
+        });
+        self.files.push_back(path);
+
+        if let Some(max) = self.policy.max_files {
+            while self.files.len() > max.max(1) {
+                if let Some(old) = self.files.pop_front() {
+                    std::fs::remove_file(old)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl PacketSink for RotatingWriter {
+    fn push_packet(&mut self, packet: DevicePacket) -> io::Result<()> {
+        self.write_packet(&packet)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        RotatingWriter::flush(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(len: usize) -> DevicePacket {
+        DevicePacket {
+            interface_type: 0,
+            unit: 0,
+            io: 0,
+            protocol_family: 2,
+            interface_name: "en0".to_string(),
+            pid: 0,
+            comm: String::new(),
+            epid: 0,
+            ecomm: String::new(),
+            seconds: 1,
+            microseconds: 2,
+            data: vec![0; len],
+        }
+    }
+
+    #[test]
+    fn ring_keeps_newest_within_budget() {
+        let mut ring = PacketRing::new(100);
+        for _ in 0..5 {
+            ring.push(packet(30));
+        }
+        assert_eq!(ring.len(), 3);
+        assert_eq!(ring.size(), 90);
+
+        let mut out = Vec::new();
+        ring.snapshot(&mut out).unwrap();
+        // Section header, interface description, then three 64 byte packet blocks
+        assert_eq!(out.len(), 28 + 20 + 3 * 64);
+    }
+
+    #[tokio::test]
+    async fn records_until_the_stream_ends() {
+        let ring = Arc::new(Mutex::new(PacketRing::new(100)));
+        let packets = (0..4).map(|_| Ok(packet(30)));
+        record(futures::stream::iter(packets), &mut ring.clone())
+            .await
+            .unwrap();
+        assert_eq!(ring.lock().unwrap().len(), 3);
+
+        let packets = vec![Ok(packet(10)), Err(IdeviceError::UnexpectedResponse)];
+        let mut ring = PacketRing::new(100);
+        assert!(record(futures::stream::iter(packets), &mut ring)
+            .await
+            .is_err());
+        assert_eq!(ring.len(), 1);
+    }
+}
@@ -0,0 +1,158 @@
+// Jackson Coxson
+// Abstractions for pcapd, which captures the device's network traffic
+
+use futures::Stream;
+use log::warn;
+
+use crate::{
+    lockdownd::LockdowndClient,
+    utils::stream::{until_stopped, StopHandle},
+    Idevice, IdeviceError, IdeviceService,
+};
+
+pub mod capture;
+pub mod pcapng;
+
+pub struct PcapdClient {
+    pub idevice: Idevice,
+}
+
+/// A packet seen on one of the device's interfaces
+#[derive(Debug, Clone)]
+pub struct DevicePacket {
+    pub interface_type: u8,
+    pub unit: u16,
+    /// 0 for received packets, 1 for sent
+    pub io: u8,
+    pub protocol_family: u32,
+    pub interface_name: String,
+    pub pid: u32,
+    pub comm: String,
+    pub epid: u32,
+    pub ecomm: String,
+    pub seconds: u32,
+    pub microseconds: u32,
+    /// The packet as an Ethernet frame. A fake link header is added for interfaces without one.
+    pub data: Vec<u8>,
+}
+
+impl IdeviceService for PcapdClient {
+    fn service_name() -> &'static str {
+        "com.apple.pcapd"
+    }
+
+    async fn connect(
+        provider: &dyn crate::provider::IdeviceProvider,
+    ) -> Result<Self, IdeviceError> {
+        let mut lockdown = LockdowndClient::connect(provider).await?;
+        lockdown
+            .start_session(&provider.get_pairing_file().await?)
+            .await?;
+
+        let (port, ssl) = lockdown.start_service(Self::service_name()).await?;
+
+        let mut idevice = provider.connect(port).await?;
+        if ssl {
+            idevice
+                .start_session(&provider.get_pairing_file().await?)
+                .await?;
+        }
+
+        Ok(Self::new(idevice))
+    }
+}
+
+impl PcapdClient {
+    pub fn new(idevice: Idevice) -> Self {
+        Self { idevice }
+    }
+
+    /// Waits for the next captured packet. Capturing starts as soon as the service connects.
+    pub async fn next_packet(&mut self) -> Result<DevicePacket, IdeviceError> {
+        let len = self.idevice.read_raw(4).await?;
+        let len = u32::from_be_bytes([len[0], len[1], len[2], len[3]]);
+        let res = self.idevice.read_raw(len as usize).await?;
+        match plist::from_bytes::<plist::Value>(&res)? {
+            plist::Value::Data(d) => DevicePacket::parse(&d),
+            _ => {
+                warn!("pcapd sent a plist that wasn't data");
+                Err(IdeviceError::UnexpectedResponse)
+            }
+        }
+    }
+
+    /// Turns the client into a stream of captured packets.
+    /// The stream ends when the handle is stopped or after the first error.
+    pub fn into_stream(
+        self,
+    ) -> (
+        impl Stream<Item = Result<DevicePacket, IdeviceError>>,
+        StopHandle,
+    ) {
+        let stop = StopHandle::new();
+        let stream = until_stopped(self, stop.clone(), |mut client| async move {
+            let res = client.next_packet().await;
+            (res, client)
+        });
+        (stream, stop)
+    }
+}
+
+impl DevicePacket {
+    /// The fixed size of the header before any extensions
+    const MIN_HEADER_LEN: usize = 95;
+
+    /// Parses a packet header and its data
+    pub fn parse(buf: &[u8]) -> Result<Self, IdeviceError> {
+        if buf.len() < Self::MIN_HEADER_LEN {
+            return Err(IdeviceError::UnexpectedResponse);
+        }
+        let be32 = |at: usize| u32::from_be_bytes(buf[at..at + 4].try_into().unwrap());
+        let le32 = |at: usize| u32::from_le_bytes(buf[at..at + 4].try_into().unwrap());
+        let string = |at: usize, len: usize| {
+            let s = &buf[at..at + len];
+            let end = s.iter().position(|b| *b == 0).unwrap_or(len);
+            String::from_utf8_lossy(&s[..end]).to_string()
+        };
+
+        let header_len = be32(0) as usize;
+        let packet_len = be32(5) as usize;
+        let protocol_family = be32(13);
+        let frame_pre_length = be32(17);
+        if header_len < Self::MIN_HEADER_LEN || buf.len() < header_len + packet_len {
+            return Err(IdeviceError::PacketSizeMismatch);
+        }
+
+        let mut data = Vec::with_capacity(packet_len + 14);
+        if frame_pre_length == 0 {
+            // Interfaces like pdp_ip have no link header, so make up an Ethernet one
+            data.extend_from_slice(&[0xbe, 0xfe].repeat(6));
+            data.extend_from_slice(match protocol_family {
+                // AF_INET6
+                30 => &[0x86, 0xdd],
+                _ => &[0x08, 0x00],
+            });
+        }
+        data.extend_from_slice(&buf[header_len..header_len + packet_len]);
+
+        Ok(Self {
+            interface_type: buf[9],
+            unit: u16::from_be_bytes([buf[10], buf[11]]),
+            io: buf[12],
+            protocol_family,
+            interface_name: string(25, 16),
+            pid: le32(41),
+            comm: string(45, 17),
+            epid: le32(66),
+            ecomm: string(70, 17),
+            seconds: be32(87),
+            microseconds: be32(91),
+            data,
+        })
+    }
+
+    /// Microseconds since the Unix epoch
+    pub fn timestamp_micros(&self) -> u64 {
+        self.seconds as u64 * 1_000_000 + self.microseconds as u64
+    }
+}
@@ -2,17 +2,38 @@
 
 use std::{
     future::Future,
-    net::{IpAddr, SocketAddr},
+    net::IpAddr,
+    path::PathBuf,
     pin::Pin,
+    sync::{Arc, Mutex, PoisonError},
+    time::SystemTime,
 };
 
+#[cfg(feature = "tcp")]
+use std::net::SocketAddr;
+
+#[cfg(any(feature = "tcp", feature = "usbmuxd"))]
+use log::warn;
 #[cfg(feature = "tcp")]
 use tokio::net::TcpStream;
 
-use crate::{pairing_file::PairingFile, Idevice, IdeviceError};
+use crate::{
+    lockdownd::{LockdowndClient, ServiceAvailability},
+    pairing_file::PairingFile,
+    ClientMetadata, Idevice, IdeviceError, IdeviceService,
+};
 
 #[cfg(feature = "usbmuxd")]
-use crate::usbmuxd::UsbmuxdAddr;
+use crate::usbmuxd::{Connection, UsbmuxdAddr};
+
+/// How a provider reaches its device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionKind {
+    Usb,
+    /// Over the network, which is much slower for large transfers
+    Network,
+    Unknown,
+}
 
 /// A provider for connecting to the iOS device
 /// This is an ugly trait until async traits are stabilized
@@ -22,19 +43,141 @@ pub trait IdeviceProvider: Unpin + Send + Sync + std::fmt::Debug {
         port: u16,
     ) -> Pin<Box<dyn Future<Output = Result<Idevice, IdeviceError>> + Send>>;
 
-    fn label(&self) -> &str;
+    /// The client metadata attached to every connection made by this provider
+    fn metadata(&self) -> &ClientMetadata;
+
+    fn label(&self) -> &str {
+        &self.metadata().label
+    }
+
+    /// The device's UDID, if the provider knows it
+    fn udid(&self) -> Option<String> {
+        None
+    }
+
+    fn connection_kind(&self) -> ConnectionKind {
+        ConnectionKind::Unknown
+    }
+
+    /// The device's IP address, for providers that connect over the network
+    fn address(&self) -> Option<IpAddr> {
+        None
+    }
 
     fn get_pairing_file(
         &self,
     ) -> Pin<Box<dyn Future<Output = Result<PairingFile, IdeviceError>> + Send>>;
 }
 
+impl dyn IdeviceProvider {
+    /// Attempts to start a service over lockdown to find out whether it can be used.
+    /// Tools can use this to explain why a feature is unavailable.
+    /// A service that is available is left running on the returned port.
+    /// # Arguments
+    /// `name` - The identifier for the service to probe
+    pub async fn probe_service(
+        &self,
+        name: impl Into<String>,
+    ) -> Result<ServiceAvailability, IdeviceError> {
+        let mut lockdown = LockdowndClient::connect(self).await?;
+        lockdown
+            .start_session(&self.get_pairing_file().await?)
+            .await?;
+        lockdown.probe_service(name).await
+    }
+}
+
+/// Supplies up to date pairing files to a provider, such as after the user trusts the host again
+pub trait PairingFileSource: Send + Sync + std::fmt::Debug {
+    fn load(&self) -> Pin<Box<dyn Future<Output = Result<PairingFile, IdeviceError>> + Send>>;
+
+    /// The UDID in the pairing file loaded last, for sources that keep it
+    fn loaded_udid(&self) -> Option<String> {
+        None
+    }
+}
+
+/// Loads a pairing file from disk, reading it again whenever it's modified
+#[derive(Debug)]
+pub struct PairingFileWatcher {
+    path: PathBuf,
+    cached: Arc<Mutex<Option<(SystemTime, PairingFile)>>>,
+}
+
+impl PairingFileWatcher {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            cached: Arc::new(Mutex::new(None)),
+        }
+    }
+}
+
+impl PairingFileWatcher {
+    async fn load_cached(
+        path: PathBuf,
+        cached: Arc<Mutex<Option<(SystemTime, PairingFile)>>>,
+    ) -> Result<PairingFile, IdeviceError> {
+        let modified = tokio::fs::metadata(&path).await?.modified()?;
+        // A panic elsewhere can't leave the cache half written, so a poisoned lock is usable
+        if let Some((m, p)) = cached
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .as_ref()
+        {
+            if *m == modified {
+                return Ok(p.clone());
+            }
+        }
+        let pairing_file = PairingFile::from_bytes(&tokio::fs::read(&path).await?)?;
+        *cached.lock().unwrap_or_else(PoisonError::into_inner) =
+            Some((modified, pairing_file.clone()));
+        Ok(pairing_file)
+    }
+}
+
+impl PairingFileSource for PairingFileWatcher {
+    fn load(&self) -> Pin<Box<dyn Future<Output = Result<PairingFile, IdeviceError>> + Send>> {
+        Box::pin(Self::load_cached(self.path.clone(), self.cached.clone()))
+    }
+
+    fn loaded_udid(&self) -> Option<String> {
+        self.cached
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .as_ref()
+            .and_then(|(_, p)| p.udid.clone())
+    }
+}
+
+/// Checks that the device on the other end of a lockdown connection is the one expected
+#[cfg(any(feature = "tcp", feature = "usbmuxd"))]
+async fn verify_udid(idevice: Idevice, expected: &str) -> Result<Idevice, IdeviceError> {
+    let mut lockdown = LockdowndClient::new(idevice);
+    let found = match lockdown.get_value("UniqueDeviceID").await? {
+        plist::Value::String(u) => u,
+        _ => return Err(IdeviceError::UnexpectedResponse),
+    };
+    if found != expected {
+        return Err(IdeviceError::UdidMismatch {
+            expected: expected.to_string(),
+            found,
+        });
+    }
+    Ok(lockdown.idevice)
+}
+
 #[cfg(feature = "tcp")]
 #[derive(Debug)]
 pub struct TcpProvider {
     pub addr: IpAddr,
     pub pairing_file: PairingFile,
-    pub label: String,
+    pub metadata: ClientMetadata,
+    /// Check that lockdown connections reach the device the pairing file in use belongs to.
+    /// Connecting to lockdown fails with `InvalidArgument` if that pairing file has no UDID.
+    pub verify_udid: bool,
+    /// Used instead of `pairing_file` when set, so regenerated records are picked up
+    pub pairing_file_source: Option<Arc<dyn PairingFileSource>>,
 }
 
 #[cfg(feature = "tcp")]
@@ -44,21 +187,59 @@ impl IdeviceProvider for TcpProvider {
         port: u16,
     ) -> Pin<Box<dyn Future<Output = Result<Idevice, IdeviceError>> + Send>> {
         let addr = self.addr;
-        let label = self.label.clone();
+        let metadata = self.metadata.clone();
+        // Checked against the pairing file in use, which may be newer than `pairing_file`
+        let pairing_file = match self.verify_udid && port == LockdowndClient::LOCKDOWND_PORT {
+            true => Some(self.get_pairing_file()),
+            false => None,
+        };
         Box::pin(async move {
+            let expected_udid = match pairing_file {
+                Some(pairing_file) => match pairing_file.await?.udid {
+                    Some(udid) => Some(udid),
+                    None => {
+                        warn!("Can't verify the device's UDID, the pairing file has none");
+                        return Err(IdeviceError::InvalidArgument);
+                    }
+                },
+                None => None,
+            };
             let socket_addr = SocketAddr::new(addr, port);
             let stream = TcpStream::connect(socket_addr).await?;
-            Ok(Idevice::new(Box::new(stream), label))
+            let idevice = Idevice::new(Box::new(stream), metadata);
+            match expected_udid {
+                Some(udid) => verify_udid(idevice, &udid).await,
+                None => Ok(idevice),
+            }
         })
     }
 
-    fn label(&self) -> &str {
-        &self.label
+    fn metadata(&self) -> &ClientMetadata {
+        &self.metadata
+    }
+
+    /// Taken from the source's pairing file once it has loaded one, since that's the one in use
+    fn udid(&self) -> Option<String> {
+        self.pairing_file_source
+            .as_ref()
+            .and_then(|s| s.loaded_udid())
+            .or_else(|| self.pairing_file.udid.clone())
+    }
+
+    fn connection_kind(&self) -> ConnectionKind {
+        ConnectionKind::Network
+    }
+
+    fn address(&self) -> Option<IpAddr> {
+        Some(self.addr)
     }
 
     fn get_pairing_file(
         &self,
     ) -> Pin<Box<dyn Future<Output = Result<PairingFile, IdeviceError>> + Send>> {
+        if let Some(source) = &self.pairing_file_source {
+            return source.load();
+        }
         let pairing_file = self.pairing_file.clone();
         Box::pin(async move { Ok(pairing_file) })
     }
@@ -71,11 +252,20 @@ pub struct UsbmuxdProvider {
     pub tag: u32,
     pub udid: String,
     pub device_id: u32,
-    pub label: String,
+    /// How the muxer reaches the device
+    pub connection_type: Connection,
+    pub metadata: ClientMetadata,
+    /// Check that lockdown connections reach the device with this provider's UDID.
+    /// Connecting to lockdown fails with `InvalidArgument` if the UDID is empty.
+    pub verify_udid: bool,
+    /// Used instead of the muxer's pair record when set
+    pub pairing_file_source: Option<Arc<dyn PairingFileSource>>,
 }
 
 #[cfg(feature = "usbmuxd")]
 impl IdeviceProvider for UsbmuxdProvider {
+    /// Every connection opens its own socket to the muxer,
+    /// so services can be connected to concurrently through one provider
     fn connect(
         &self,
         port: u16,
@@ -83,28 +273,143 @@ impl IdeviceProvider for UsbmuxdProvider {
         let addr = self.addr.clone();
         let tag = self.tag;
         let device_id = self.device_id;
-        let label = self.label.clone();
+        let metadata = self.metadata.clone();
+        let expected_udid = match self.verify_udid && port == LockdowndClient::LOCKDOWND_PORT {
+            true => Some(self.udid.clone()),
+            false => None,
+        };
 
         Box::pin(async move {
-            let usbmuxd = addr.connect(tag).await?;
-            usbmuxd.connect_to_device(device_id, port, &label).await
+            if expected_udid.as_ref().is_some_and(|u| u.is_empty()) {
+                warn!("Can't verify the device's UDID, the provider has none");
+                return Err(IdeviceError::InvalidArgument);
+            }
+            let usbmuxd = addr.connect(tag).await?.with_metadata(metadata.clone());
+            let idevice = usbmuxd.connect_to_device(device_id, port, metadata).await?;
+            match expected_udid {
+                Some(udid) => verify_udid(idevice, &udid).await,
+                None => Ok(idevice),
+            }
         })
     }
 
-    fn label(&self) -> &str {
-        &self.label
+    fn metadata(&self) -> &ClientMetadata {
+        &self.metadata
+    }
+
+    fn udid(&self) -> Option<String> {
+        Some(self.udid.clone())
+    }
+
+    fn connection_kind(&self) -> ConnectionKind {
+        match self.connection_type {
+            Connection::Usb => ConnectionKind::Usb,
+            Connection::Network(_) => ConnectionKind::Network,
+            Connection::Unknown(_) => ConnectionKind::Unknown,
+        }
+    }
+
+    fn address(&self) -> Option<IpAddr> {
+        match self.connection_type {
+            Connection::Network(addr) => Some(addr),
+            _ => None,
+        }
     }
 
     fn get_pairing_file(
         &self,
     ) -> Pin<Box<dyn Future<Output = Result<PairingFile, IdeviceError>> + Send>> {
+        if let Some(source) = &self.pairing_file_source {
+            return source.load();
+        }
         let addr = self.addr.clone();
         let tag = self.tag;
         let udid = self.udid.clone();
+        let metadata = self.metadata.clone();
 
         Box::pin(async move {
-            let mut usbmuxd = addr.connect(tag).await?;
+            let mut usbmuxd = addr.connect(tag).await?.with_metadata(metadata);
             usbmuxd.get_pair_record(&udid).await
         })
     }
 }
+
+#[cfg(all(test, feature = "tcp"))]
+mod tests {
+    use super::*;
+    use crate::pairing_file::tests::pairing_file;
+
+    #[tokio::test]
+    async fn tcp_udid_follows_the_source() {
+        let path = std::env::temp_dir().join(format!("idevice-pairing-{}", fastrand::u64(..)));
+        tokio::fs::write(&path, pairing_file(Some("new")).serialize().unwrap())
+            .await
+            .unwrap();
+        let provider = TcpProvider {
+            addr: IpAddr::V4(std::net::Ipv4Addr::LOCALHOST),
+            pairing_file: pairing_file(Some("old")),
+            metadata: ClientMetadata::new("test"),
+            verify_udid: false,
+            pairing_file_source: Some(Arc::new(PairingFileWatcher::new(&path))),
+        };
+
+        // Nothing has been loaded yet, so only the initial pairing file is known
+        assert_eq!(provider.udid().as_deref(), Some("old"));
+        provider.get_pairing_file().await.unwrap();
+        assert_eq!(provider.udid().as_deref(), Some("new"));
+        tokio::fs::remove_file(path).await.unwrap();
+    }
+
+    #[cfg(feature = "usbmuxd_server")]
+    #[tokio::test]
+    async fn usbmuxd_only_needs_a_udid_for_lockdown() {
+        use crate::usbmuxd::server::{
+            ConnectFuture, DeviceTransport, MemoryPairRecords, UsbmuxdServer,
+        };
+
+        #[derive(Debug)]
+        struct Sink;
+
+        impl DeviceTransport for Sink {
+            fn connect(&self, _port: u16) -> ConnectFuture<'_> {
+                Box::pin(async {
+                    let (client, device) = tokio::io::duplex(1024);
+                    tokio::spawn(async move {
+                        let _device = device;
+                        std::future::pending::<()>().await
+                    });
+                    Ok(Box::new(client) as Box<dyn crate::ReadWrite>)
+                })
+            }
+        }
+
+        let server = UsbmuxdServer::new("BUID", Arc::new(MemoryPairRecords::default()));
+        let device_id = server.attach("", Connection::Usb, Arc::new(Sink));
+        let listener = tokio::net::TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                let server = server.clone();
+                tokio::spawn(async move { server.handle(Box::new(socket)).await });
+            }
+        });
+        let provider = UsbmuxdProvider {
+            addr: UsbmuxdAddr::TcpSocket(addr),
+            tag: 1,
+            udid: String::new(),
+            device_id,
+            connection_type: Connection::Usb,
+            metadata: ClientMetadata::new("test"),
+            verify_udid: true,
+            pairing_file_source: None,
+        };
+
+        provider.connect(1234).await.unwrap();
+        assert!(matches!(
+            provider.connect(LockdowndClient::LOCKDOWND_PORT).await,
+            Err(IdeviceError::InvalidArgument)
+        ));
+    }
+}
@@ -0,0 +1,228 @@
+// Jackson Coxson
+// Abstractions for screenshotr, which captures the device's screen.
+// Requires the developer disk image to be mounted.
+
+use std::{
+    io::Cursor,
+    time::{Duration, Instant},
+};
+
+use futures::Stream;
+use log::warn;
+use plist::Value;
+use tokio::time::MissedTickBehavior;
+
+use crate::{
+    capabilities::DeviceCapabilities,
+    devicelink::DeviceLinkClient,
+    lockdownd::LockdowndClient,
+    provider::IdeviceProvider,
+    utils::stream::{until_stopped, StopHandle},
+    Idevice, IdeviceError, IdeviceService,
+};
+
+/// The DeviceLink protocol version screenshotr speaks
+const DL_VERSION: u64 = 300;
+
+pub struct ScreenshotClient {
+    pub link: DeviceLinkClient,
+}
+
+/// A capture from `ScreenshotClient::stream`
+#[derive(Debug, Clone)]
+pub struct Frame {
+    /// The image, usually a PNG, or a TIFF on older versions
+    pub data: Vec<u8>,
+    /// When the capture was requested
+    pub captured: Instant,
+    /// Captures skipped since the last frame because the device took longer than the interval
+    pub skipped: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScreenshotFormat {
+    Png,
+    Tiff,
+    /// Neither PNG nor TIFF
+    Unknown,
+}
+
+impl ScreenshotFormat {
+    /// Detects the format from the image's magic bytes
+    pub fn detect(data: &[u8]) -> Self {
+        if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+            Self::Png
+        } else if data.starts_with(b"II*\0") || data.starts_with(b"MM\0*") {
+            Self::Tiff
+        } else {
+            Self::Unknown
+        }
+    }
+}
+
+/// A capture from `screenshot`
+#[derive(Debug, Clone)]
+pub struct ScreenshotResult {
+    pub format: ScreenshotFormat,
+    pub data: Vec<u8>,
+}
+
+impl ScreenshotResult {
+    pub fn new(data: Vec<u8>) -> Self {
+        Self {
+            format: ScreenshotFormat::detect(&data),
+            data,
+        }
+    }
+
+    /// Re-encodes the image as a PNG, unless it already is one
+    pub fn into_png(self) -> Result<Self, IdeviceError> {
+        if self.format == ScreenshotFormat::Png {
+            return Ok(self);
+        }
+        let image = image::load_from_memory(&self.data)?;
+        let mut data = Vec::new();
+        image.write_to(&mut Cursor::new(&mut data), image::ImageFormat::Png)?;
+        Ok(Self {
+            format: ScreenshotFormat::Png,
+            data,
+        })
+    }
+}
+
+/// Captures the screen with whichever service the device's iOS version offers.
+/// Before iOS 17 this is screenshotr. iOS 17 moved it behind RemoteXPC, where only the
+/// instruments screenshot channel is left, which isn't supported yet.
+/// # Arguments
+/// `png` - Whether to re-encode the capture as a PNG if the device sent another format
+pub async fn screenshot(
+    provider: &dyn IdeviceProvider,
+    png: bool,
+) -> Result<ScreenshotResult, IdeviceError> {
+    let mut lockdown = LockdowndClient::connect(provider).await?;
+    let capabilities = DeviceCapabilities::from_lockdown(&mut lockdown).await?;
+    if !capabilities.developer_services_over_lockdown() {
+        warn!(
+            "iOS {} only offers screenshots over instruments, which isn't supported",
+            capabilities.major()
+        );
+        return Err(IdeviceError::InvalidService);
+    }
+
+    let mut client = ScreenshotClient::connect(provider).await?;
+    let res = ScreenshotResult::new(client.take_screenshot().await?);
+    if png {
+        res.into_png()
+    } else {
+        Ok(res)
+    }
+}
+
+impl IdeviceService for ScreenshotClient {
+    fn service_name() -> &'static str {
+        "com.apple.mobile.screenshotr"
+    }
+
+    async fn connect(
+        provider: &dyn crate::provider::IdeviceProvider,
+    ) -> Result<Self, IdeviceError> {
+        let mut lockdown = LockdowndClient::connect(provider).await?;
+        lockdown
+            .start_session(&provider.get_pairing_file().await?)
+            .await?;
+
+        let (port, ssl) = lockdown.start_service(Self::service_name()).await?;
+
+        let mut idevice = provider.connect(port).await?;
+        if ssl {
+            idevice
+                .start_session(&provider.get_pairing_file().await?)
+                .await?;
+        }
+
+        let mut client = Self::new(idevice);
+        client.link.exchange_versions(DL_VERSION).await?;
+        Ok(client)
+    }
+}
+
+impl ScreenshotClient {
+    /// Wraps a connection that already finished the DeviceLink version exchange
+    pub fn new(idevice: Idevice) -> Self {
+        Self {
+            link: DeviceLinkClient::new(idevice),
+        }
+    }
+
+    /// Captures the screen once
+    pub async fn take_screenshot(&mut self) -> Result<Vec<u8>, IdeviceError> {
+        let mut req = plist::Dictionary::new();
+        req.insert("MessageType".into(), "ScreenShotRequest".into());
+        self.link.send_process_message(req).await?;
+
+        let mut res = self.link.read_process_message().await?;
+        match res.remove("ScreenShotData") {
+            Some(Value::Data(data)) => Ok(data),
+            _ => Err(IdeviceError::UnexpectedResponse),
+        }
+    }
+
+    /// Captures the screen every `interval` until stopped.
+    /// When a capture takes longer than the interval, the missed captures are skipped
+    /// instead of being taken back to back, and counted in the next frame.
+    /// The stream ends after the first error.
+    pub fn stream(
+        self,
+        interval: Duration,
+    ) -> (impl Stream<Item = Result<Frame, IdeviceError>>, StopHandle) {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+        let stop = StopHandle::new();
+        let stream = until_stopped(
+            (self, ticker, None::<Instant>),
+            stop.clone(),
+            move |(mut client, mut ticker, last)| async move {
+                ticker.tick().await;
+                let captured = Instant::now();
+                let skipped = match last {
+                    Some(last) => {
+                        let elapsed = captured.duration_since(last).as_nanos();
+                        (elapsed / interval.as_nanos().max(1)).saturating_sub(1) as u64
+                    }
+                    None => 0,
+                };
+                let res = client.take_screenshot().await.map(|data| Frame {
+                    data,
+                    captured,
+                    skipped,
+                });
+                (res, (client, ticker, Some(captured)))
+            },
+        );
+        (stream, stop)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_tiff_to_png() {
+        let image = image::RgbaImage::from_pixel(2, 3, image::Rgba([255, 0, 0, 255]));
+        let mut tiff = Vec::new();
+        image
+            .write_to(&mut Cursor::new(&mut tiff), image::ImageFormat::Tiff)
+            .unwrap();
+
+        let res = ScreenshotResult::new(tiff);
+        assert_eq!(res.format, ScreenshotFormat::Tiff);
+        let res = res.into_png().unwrap();
+        assert_eq!(res.format, ScreenshotFormat::Png);
+        assert_eq!(ScreenshotFormat::detect(&res.data), ScreenshotFormat::Png);
+
+        let decoded = image::load_from_memory(&res.data).unwrap().to_rgba8();
+        assert_eq!(decoded, image);
+    }
+}
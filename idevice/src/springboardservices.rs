@@ -0,0 +1,443 @@
+// Jackson Coxson
+// Abstractions for springboardservices, which serves home screen icons and layout
+
+use std::collections::{HashMap, VecDeque};
+
+use log::warn;
+
+use crate::{lockdownd::LockdowndClient, Idevice, IdeviceError, IdeviceService};
+
+pub struct SpringBoardServicesClient {
+    pub idevice: Idevice,
+}
+
+impl IdeviceService for SpringBoardServicesClient {
+    fn service_name() -> &'static str {
+        "com.apple.springboardservices"
+    }
+
+    async fn connect(
+        provider: &dyn crate::provider::IdeviceProvider,
+    ) -> Result<Self, IdeviceError> {
+        let mut lockdown = LockdowndClient::connect(provider).await?;
+        lockdown
+            .start_session(&provider.get_pairing_file().await?)
+            .await?;
+
+        let (port, ssl) = lockdown.start_service(Self::service_name()).await?;
+
+        let mut idevice = provider.connect(port).await?;
+        if ssl {
+            idevice
+                .start_session(&provider.get_pairing_file().await?)
+                .await?;
+        }
+
+        Ok(Self::new(idevice))
+    }
+}
+
+impl SpringBoardServicesClient {
+    pub fn new(idevice: Idevice) -> Self {
+        Self { idevice }
+    }
+
+    /// Gets an app's home screen icon
+    /// # Arguments
+    /// `bundle_id` - The bundle identifier of the app
+    /// # Returns
+    /// The icon as PNG bytes
+    pub async fn get_icon_pngdata(
+        &mut self,
+        bundle_id: impl Into<String>,
+    ) -> Result<Vec<u8>, IdeviceError> {
+        self.send_icon_request(bundle_id.into()).await?;
+        self.read_png_response().await
+    }
+
+    /// Gets the icons of many apps over this connection.
+    /// Up to `max_concurrent` requests are sent ahead of their responses so the device
+    /// isn't left idle between icons.
+    /// # Returns
+    /// PNG bytes by bundle identifier. Apps the device has no icon for are left out.
+    pub async fn get_icons(
+        &mut self,
+        bundle_ids: impl IntoIterator<Item = impl Into<String>>,
+        max_concurrent: usize,
+    ) -> Result<HashMap<String, Vec<u8>>, IdeviceError> {
+        let max_concurrent = max_concurrent.max(1);
+        let mut bundle_ids = bundle_ids.into_iter().map(Into::into);
+        let mut in_flight = VecDeque::with_capacity(max_concurrent);
+        let mut icons = HashMap::new();
+
+        loop {
+            while in_flight.len() < max_concurrent {
+                match bundle_ids.next() {
+                    Some(bundle_id) => {
+                        self.send_icon_request(bundle_id.clone()).await?;
+                        in_flight.push_back(bundle_id);
+                    }
+                    None => break,
+                }
+            }
+
+            // The device answers in the order it was asked
+            let bundle_id = match in_flight.pop_front() {
+                Some(b) => b,
+                None => break,
+            };
+            match self.read_png_response().await {
+                Ok(png) => {
+                    icons.insert(bundle_id, png);
+                }
+                Err(IdeviceError::NotFound) | Err(IdeviceError::UnknownErrorType(_)) => {
+                    warn!("No icon for {bundle_id}");
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(icons)
+    }
+
+    /// Gets the layout of the home screen
+    pub async fn get_icon_state(&mut self) -> Result<IconState, IdeviceError> {
+        let mut req = plist::Dictionary::new();
+        req.insert("command".into(), "getIconState".into());
+        req.insert("formatVersion".into(), "2".into());
+        self.idevice
+            .send_plist(plist::Value::Dictionary(req))
+            .await?;
+
+        match self.idevice.read_plist_value().await? {
+            plist::Value::Dictionary(res) => match res.get("Error").and_then(|e| e.as_string()) {
+                Some(e) => Err(IdeviceError::from_device_error_type(e)
+                    .unwrap_or_else(|| IdeviceError::UnknownErrorType(e.to_string()))),
+                None => Err(IdeviceError::UnexpectedResponse),
+            },
+            res => IconState::from_plist(res),
+        }
+    }
+
+    /// Rearranges the home screen.
+    /// The device doesn't reply, and ignores states it can't apply,
+    /// so start from `get_icon_state` and only move icons around.
+    pub async fn set_icon_state(&mut self, state: &IconState) -> Result<(), IdeviceError> {
+        let mut req = plist::Dictionary::new();
+        req.insert("command".into(), "setIconState".into());
+        req.insert("iconState".into(), state.to_plist());
+        self.idevice.send_plist(plist::Value::Dictionary(req)).await
+    }
+
+    async fn send_icon_request(&mut self, bundle_id: String) -> Result<(), IdeviceError> {
+        let mut req = plist::Dictionary::new();
+        req.insert("command".into(), "getIconPNGData".into());
+        req.insert("bundleId".into(), bundle_id.into());
+        self.idevice.send_plist(plist::Value::Dictionary(req)).await
+    }
+
+    /// Gets the home screen wallpaper
+    /// # Returns
+    /// The wallpaper as PNG bytes
+    pub async fn get_home_screen_wallpaper_pngdata(&mut self) -> Result<Vec<u8>, IdeviceError> {
+        let mut req = plist::Dictionary::new();
+        req.insert("command".into(), "getHomeScreenWallpaperPNGData".into());
+        self.idevice
+            .send_plist(plist::Value::Dictionary(req))
+            .await?;
+        self.read_png_response().await
+    }
+
+    /// Gets a preview of a wallpaper, as shown in Settings
+    /// # Returns
+    /// The preview as PNG bytes
+    pub async fn get_wallpaper_preview_image(
+        &mut self,
+        wallpaper: Wallpaper,
+    ) -> Result<Vec<u8>, IdeviceError> {
+        let mut req = plist::Dictionary::new();
+        req.insert("command".into(), "getWallpaperPreviewImage".into());
+        req.insert("wallpaperName".into(), wallpaper.as_str().into());
+        self.idevice
+            .send_plist(plist::Value::Dictionary(req))
+            .await?;
+        self.read_png_response().await
+    }
+
+    async fn read_png_response(&mut self) -> Result<Vec<u8>, IdeviceError> {
+        let mut res = self.idevice.read_plist().await?;
+        match res.remove("pngData") {
+            Some(plist::Value::Data(png)) if !png.is_empty() => Ok(png),
+            _ => Err(IdeviceError::NotFound),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Wallpaper {
+    HomeScreen,
+    LockScreen,
+}
+
+impl Wallpaper {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::HomeScreen => "homescreen",
+            Self::LockScreen => "lockscreen",
+        }
+    }
+}
+
+/// The layout of the home screen
+#[derive(Debug, Clone, PartialEq)]
+pub struct IconState {
+    pub dock: Vec<Icon>,
+    pub pages: Vec<Vec<Icon>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Icon {
+    App(AppIcon),
+    Folder(Folder),
+    WebClip(WebClip),
+    /// Widgets and anything else this doesn't model, kept as the device sent it
+    Other(plist::Dictionary),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AppIcon {
+    pub bundle_id: String,
+    pub name: Option<String>,
+    /// The remaining keys, sent back unchanged
+    pub extra: plist::Dictionary,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Folder {
+    pub name: String,
+    pub pages: Vec<Vec<Icon>>,
+    /// The remaining keys, sent back unchanged
+    pub extra: plist::Dictionary,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WebClip {
+    pub identifier: String,
+    pub name: Option<String>,
+    /// The remaining keys, sent back unchanged
+    pub extra: plist::Dictionary,
+}
+
+impl IconState {
+    /// Parses the array `getIconState` returns, whose first list is the dock
+    pub fn from_plist(value: plist::Value) -> Result<Self, IdeviceError> {
+        let mut lists = parse_lists(value)?.into_iter();
+        Ok(Self {
+            dock: lists.next().unwrap_or_default(),
+            pages: lists.collect(),
+        })
+    }
+
+    pub fn to_plist(&self) -> plist::Value {
+        plist::Value::Array(
+            std::iter::once(&self.dock)
+                .chain(&self.pages)
+                .map(|l| list_to_plist(l))
+                .collect(),
+        )
+    }
+
+    /// Every icon in the dock, on the pages and in folders
+    pub fn icons(&self) -> Vec<&Icon> {
+        let mut res = Vec::new();
+        let mut lists: Vec<&Vec<Icon>> = std::iter::once(&self.dock).chain(&self.pages).collect();
+        while let Some(list) = lists.pop() {
+            for icon in list {
+                if let Icon::Folder(f) = icon {
+                    lists.extend(&f.pages);
+                }
+                res.push(icon);
+            }
+        }
+        res
+    }
+
+    /// Takes an app's icon out of wherever it is, so it can be placed elsewhere.
+    /// Folders left empty are kept.
+    pub fn remove_app(&mut self, bundle_id: &str) -> Option<Icon> {
+        let mut lists: Vec<&mut Vec<Icon>> = std::iter::once(&mut self.dock)
+            .chain(&mut self.pages)
+            .collect();
+        while let Some(list) = lists.pop() {
+            if let Some(i) = list
+                .iter()
+                .position(|i| matches!(i, Icon::App(a) if a.bundle_id == bundle_id))
+            {
+                return Some(list.remove(i));
+            }
+            for icon in list.iter_mut() {
+                if let Icon::Folder(f) = icon {
+                    lists.extend(&mut f.pages);
+                }
+            }
+        }
+        None
+    }
+}
+
+impl Icon {
+    pub fn from_plist(value: plist::Value) -> Result<Self, IdeviceError> {
+        let mut d = match value {
+            plist::Value::Dictionary(d) => d,
+            _ => return Err(IdeviceError::UnexpectedResponse),
+        };
+        if d.get("listType").and_then(|t| t.as_string()) == Some("folder") {
+            let pages = match d.remove("iconLists") {
+                Some(l) => parse_lists(l)?,
+                None => Vec::new(),
+            };
+            let name = take_string(&mut d, "displayName").unwrap_or_default();
+            return Ok(Self::Folder(Folder {
+                name,
+                pages,
+                extra: d,
+            }));
+        }
+        if let Some(identifier) = take_string(&mut d, "webClipIdentifier") {
+            let name = take_string(&mut d, "displayName");
+            return Ok(Self::WebClip(WebClip {
+                identifier,
+                name,
+                extra: d,
+            }));
+        }
+        // Older versions only send displayIdentifier
+        let bundle_id = take_string(&mut d, "bundleIdentifier");
+        let display_id = take_string(&mut d, "displayIdentifier");
+        if let Some(bundle_id) = bundle_id.or(display_id) {
+            let name = take_string(&mut d, "displayName");
+            return Ok(Self::App(AppIcon {
+                bundle_id,
+                name,
+                extra: d,
+            }));
+        }
+        Ok(Self::Other(d))
+    }
+
+    pub fn to_plist(&self) -> plist::Value {
+        let mut d;
+        match self {
+            Self::App(a) => {
+                d = a.extra.clone();
+                d.insert("bundleIdentifier".into(), a.bundle_id.clone().into());
+                d.insert("displayIdentifier".into(), a.bundle_id.clone().into());
+                if let Some(name) = &a.name {
+                    d.insert("displayName".into(), name.clone().into());
+                }
+            }
+            Self::Folder(f) => {
+                d = f.extra.clone();
+                d.insert("listType".into(), "folder".into());
+                d.insert("displayName".into(), f.name.clone().into());
+                d.insert(
+                    "iconLists".into(),
+                    plist::Value::Array(f.pages.iter().map(|l| list_to_plist(l)).collect()),
+                );
+            }
+            Self::WebClip(w) => {
+                d = w.extra.clone();
+                d.insert("webClipIdentifier".into(), w.identifier.clone().into());
+                if let Some(name) = &w.name {
+                    d.insert("displayName".into(), name.clone().into());
+                }
+            }
+            Self::Other(o) => d = o.clone(),
+        }
+        plist::Value::Dictionary(d)
+    }
+}
+
+fn parse_lists(value: plist::Value) -> Result<Vec<Vec<Icon>>, IdeviceError> {
+    let lists = match value {
+        plist::Value::Array(a) => a,
+        _ => return Err(IdeviceError::UnexpectedResponse),
+    };
+    lists
+        .into_iter()
+        .map(|list| match list {
+            plist::Value::Array(icons) => icons.into_iter().map(Icon::from_plist).collect(),
+            _ => Err(IdeviceError::UnexpectedResponse),
+        })
+        .collect()
+}
+
+fn list_to_plist(list: &[Icon]) -> plist::Value {
+    plist::Value::Array(list.iter().map(Icon::to_plist).collect())
+}
+
+fn take_string(d: &mut plist::Dictionary, key: &str) -> Option<String> {
+    match d.remove(key) {
+        Some(plist::Value::String(s)) => Some(s),
+        Some(v) => {
+            d.insert(key.into(), v);
+            None
+        }
+        None => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn app(bundle_id: &str) -> plist::Value {
+        let mut d = plist::Dictionary::new();
+        d.insert("bundleIdentifier".into(), bundle_id.into());
+        d.insert("displayIdentifier".into(), bundle_id.into());
+        d.insert("displayName".into(), bundle_id.into());
+        d.insert("iconModDate".into(), plist::Value::Integer(1.into()));
+        plist::Value::Dictionary(d)
+    }
+
+    #[test]
+    fn parse_move_and_serialize_icon_state() {
+        let mut folder = plist::Dictionary::new();
+        folder.insert("listType".into(), "folder".into());
+        folder.insert("displayName".into(), "Tools".into());
+        folder.insert(
+            "iconLists".into(),
+            plist::Value::Array(vec![plist::Value::Array(vec![app("com.example.c")])]),
+        );
+        let mut widget = plist::Dictionary::new();
+        widget.insert("elementType".into(), "widget".into());
+        let raw = plist::Value::Array(vec![
+            plist::Value::Array(vec![app("com.example.a")]),
+            plist::Value::Array(vec![
+                app("com.example.b"),
+                plist::Value::Dictionary(folder),
+                plist::Value::Dictionary(widget),
+            ]),
+        ]);
+
+        let mut state = IconState::from_plist(raw.clone()).unwrap();
+        assert_eq!(state.to_plist(), raw);
+        assert_eq!(state.icons().len(), 5);
+        assert!(matches!(&state.pages[0][2], Icon::Other(_)));
+
+        let icon = state.remove_app("com.example.c").unwrap();
+        assert!(state.remove_app("com.example.c").is_none());
+        state.dock.push(icon);
+        match &state.pages[0][1] {
+            Icon::Folder(f) => assert!(f.pages[0].is_empty()),
+            i => panic!("expected a folder, got {i:?}"),
+        }
+        match &state.dock[1] {
+            Icon::App(a) => {
+                assert_eq!(a.bundle_id, "com.example.c");
+                assert!(a.extra.contains_key("iconModDate"));
+            }
+            i => panic!("expected an app, got {i:?}"),
+        }
+    }
+}
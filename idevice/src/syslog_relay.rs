@@ -0,0 +1,104 @@
+// Jackson Coxson
+// Abstractions for syslog_relay, which relays the device's syslog as text
+
+use futures::Stream;
+
+use crate::{
+    lockdownd::LockdowndClient,
+    utils::stream::{until_stopped, StopHandle},
+    Idevice, IdeviceError, IdeviceService,
+};
+
+pub struct SyslogRelayClient {
+    pub idevice: Idevice,
+    buf: Vec<u8>,
+}
+
+impl IdeviceService for SyslogRelayClient {
+    fn service_name() -> &'static str {
+        "com.apple.syslog_relay"
+    }
+
+    async fn connect(
+        provider: &dyn crate::provider::IdeviceProvider,
+    ) -> Result<Self, IdeviceError> {
+        let mut lockdown = LockdowndClient::connect(provider).await?;
+        lockdown
+            .start_session(&provider.get_pairing_file().await?)
+            .await?;
+
+        let (port, ssl) = lockdown.start_service(Self::service_name()).await?;
+
+        let mut idevice = provider.connect(port).await?;
+        if ssl {
+            idevice
+                .start_session(&provider.get_pairing_file().await?)
+                .await?;
+        }
+
+        Ok(Self::new(idevice))
+    }
+}
+
+impl SyslogRelayClient {
+    pub fn new(idevice: Idevice) -> Self {
+        Self {
+            idevice,
+            buf: Vec::new(),
+        }
+    }
+
+    /// Reads the next message. Messages are separated by nul bytes, and their
+    /// trailing newline is removed.
+    pub async fn next_message(&mut self) -> Result<String, IdeviceError> {
+        loop {
+            if let Some(i) = self.buf.iter().position(|b| *b == 0) {
+                let message = self.buf.drain(..=i).collect::<Vec<u8>>();
+                let message = String::from_utf8_lossy(&message[..i]);
+                let message = message.trim_end_matches('\n');
+                if message.is_empty() {
+                    continue;
+                }
+                return Ok(message.to_string());
+            }
+            let read = self.idevice.read_any(4096).await?;
+            if read.is_empty() {
+                return Err(IdeviceError::NoEstablishedConnection);
+            }
+            self.buf.extend_from_slice(&read);
+        }
+    }
+
+    /// Turns the client into a stream of messages
+    pub fn into_stream(self) -> (impl Stream<Item = Result<String, IdeviceError>>, StopHandle) {
+        let stop = StopHandle::new();
+        let stream = until_stopped(self, stop.clone(), |mut client| async move {
+            let res = client.next_message().await;
+            (res, client)
+        });
+        (stream, stop)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn splits_messages() {
+        let (client, mut server) = tokio::io::duplex(1024);
+        let mut client = SyslogRelayClient::new(Idevice::new(Box::new(client), "test"));
+        server
+            .write_all(b"Oct 14 12:00:00 SpringBoard[1] <Notice>: one\n\0\0two")
+            .await
+            .unwrap();
+        assert_eq!(
+            client.next_message().await.unwrap(),
+            "Oct 14 12:00:00 SpringBoard[1] <Notice>: one"
+        );
+        server.write_all(b" halves\n\0").await.unwrap();
+        assert_eq!(client.next_message().await.unwrap(), "two halves");
+    }
+}
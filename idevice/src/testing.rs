@@ -0,0 +1,256 @@
+// Jackson Coxson
+// Randomized but well-formed protocol packets for property tests of the parsers.
+// A seeded Rng makes any failure reproducible.
+
+pub use fastrand::Rng;
+
+/// Nested containers stop at this depth so generated values stay small
+pub const MAX_DEPTH: usize = 3;
+
+/// A short string of ASCII letters and digits, which every format can carry
+pub fn string(rng: &mut Rng) -> String {
+    let len = rng.usize(0..16);
+    std::iter::repeat_with(|| rng.alphanumeric())
+        .take(len)
+        .collect()
+}
+
+pub fn bytes(rng: &mut Rng, max_len: usize) -> Vec<u8> {
+    let len = rng.usize(0..=max_len);
+    std::iter::repeat_with(|| rng.u8(..)).take(len).collect()
+}
+
+/// A plist value without reals or dates, so it compares equal after a round trip
+pub fn plist_value(rng: &mut Rng, depth: usize) -> plist::Value {
+    let kinds = if depth >= MAX_DEPTH { 4 } else { 6 };
+    match rng.usize(0..kinds) {
+        0 => plist::Value::Boolean(rng.bool()),
+        1 => plist::Value::Integer(rng.i64(..).into()),
+        2 => plist::Value::String(string(rng)),
+        3 => plist::Value::Data(bytes(rng, 64)),
+        4 => plist::Value::Array(
+            (0..rng.usize(0..4))
+                .map(|_| plist_value(rng, depth + 1))
+                .collect(),
+        ),
+        _ => plist::Value::Dictionary(plist_dictionary(rng, depth + 1)),
+    }
+}
+
+pub fn plist_dictionary(rng: &mut Rng, depth: usize) -> plist::Dictionary {
+    (0..rng.usize(0..4))
+        .map(|_| (string(rng), plist_value(rng, depth)))
+        .collect()
+}
+
+/// Damages an encoded packet the way a misbehaving device or a cut connection might,
+/// for checking that parsers fail without panicking
+pub fn mutate(rng: &mut Rng, buf: &mut Vec<u8>) {
+    if buf.is_empty() {
+        buf.extend(bytes(rng, 8));
+        return;
+    }
+    match rng.usize(0..4) {
+        0 => {
+            let i = rng.usize(..buf.len());
+            buf[i] ^= 1 << rng.u8(0..8);
+        }
+        1 => buf.truncate(rng.usize(..buf.len())),
+        2 => {
+            // Length fields are the most likely to be trusted, so clobber a word
+            let i = rng.usize(..buf.len());
+            for (b, r) in buf[i..].iter_mut().zip(rng.u32(..).to_le_bytes()) {
+                *b = r;
+            }
+        }
+        _ => {
+            let i = rng.usize(..=buf.len());
+            let junk = bytes(rng, 8);
+            buf.splice(i..i, junk);
+        }
+    }
+}
+
+/// An AFC packet with a random operation and payloads, and lengths that match them
+#[cfg(feature = "afc")]
+pub fn afc_packet(rng: &mut Rng) -> crate::afc::packet::AfcPacket {
+    use crate::afc::{
+        opcode::AfcOpcode,
+        packet::{AfcPacket, AfcPacketHeader},
+    };
+
+    let operation = loop {
+        if let Ok(o) = AfcOpcode::try_from(rng.u64(0..=0x40)) {
+            break o;
+        }
+    };
+    let header_payload = bytes(rng, 64);
+    let payload = bytes(rng, 256);
+    let header_payload_len = AfcPacketHeader::LEN + header_payload.len() as u64;
+    AfcPacket {
+        header: AfcPacketHeader {
+            magic: crate::afc::MAGIC,
+            entire_len: header_payload_len + payload.len() as u64,
+            header_payload_len,
+            packet_num: rng.u64(..),
+            operation,
+        },
+        header_payload,
+        payload,
+    }
+}
+
+#[cfg(feature = "xpc")]
+pub fn xpc_object(rng: &mut Rng, depth: usize) -> crate::xpc::format::XPCObject {
+    use crate::xpc::format::XPCObject;
+
+    let kinds = if depth >= MAX_DEPTH { 6 } else { 8 };
+    match rng.usize(0..kinds) {
+        0 => XPCObject::Bool(rng.bool()),
+        1 => XPCObject::Int64(rng.i64(..)),
+        2 => XPCObject::UInt64(rng.u64(..)),
+        3 => XPCObject::String(string(rng)),
+        4 => XPCObject::Data(bytes(rng, 64)),
+        5 => XPCObject::Uuid(uuid::Uuid::from_u128(rng.u128(..))),
+        6 => XPCObject::Array(
+            (0..rng.usize(0..4))
+                .map(|_| xpc_object(rng, depth + 1))
+                .collect(),
+        ),
+        _ => XPCObject::Dictionary(
+            (0..rng.usize(0..4))
+                .map(|_| (string(rng), xpc_object(rng, depth + 1)))
+                .collect(),
+        ),
+    }
+}
+
+/// An XPC frame with random flags, carrying a dictionary or nothing
+#[cfg(feature = "xpc")]
+pub fn xpc_message(rng: &mut Rng) -> crate::xpc::format::XPCMessage {
+    use crate::xpc::format::{XPCMessage, XPCObject};
+
+    let message = match rng.bool() {
+        true => Some(XPCObject::Dictionary(
+            (0..rng.usize(0..4))
+                .map(|_| (string(rng), xpc_object(rng, 1)))
+                .collect(),
+        )),
+        false => None,
+    };
+    XPCMessage {
+        flags: rng.u32(..),
+        message,
+        message_id: Some(rng.u64(..)),
+    }
+}
+
+/// An encoded usbmuxd plist packet, along with the dictionary it carries
+#[cfg(feature = "usbmuxd")]
+pub fn usbmuxd_packet(rng: &mut Rng) -> (Vec<u8>, plist::Dictionary) {
+    use crate::usbmuxd::raw_packet::RawPacket;
+
+    let plist = plist_dictionary(rng, 0);
+    let packet = RawPacket::new(plist.clone(), 1, 8, rng.u32(..));
+    (packet.into(), plist)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CASES: u64 = 256;
+
+    /// A socket that yields `bytes` and then hangs up, like a device sending a
+    /// damaged packet before the connection drops
+    #[cfg(any(feature = "afc", feature = "usbmuxd"))]
+    async fn socket(bytes: &[u8]) -> tokio::io::DuplexStream {
+        use tokio::io::AsyncWriteExt;
+
+        let (client, mut server) = tokio::io::duplex(bytes.len().max(1));
+        server.write_all(bytes).await.unwrap();
+        client
+    }
+
+    #[cfg(feature = "afc")]
+    #[tokio::test]
+    async fn afc_round_trip() {
+        use crate::afc::packet::{AfcPacket, AfcPacketHeader};
+
+        for seed in 0..CASES {
+            let mut rng = Rng::with_seed(seed);
+            let packet = afc_packet(&mut rng);
+            let mut bytes = packet.serialize();
+            let header = AfcPacketHeader::parse(&bytes).unwrap();
+            assert_eq!(header, packet.header, "seed {seed}");
+            let split = header.header_payload_len as usize;
+            assert_eq!(
+                bytes[AfcPacketHeader::LEN as usize..split],
+                packet.header_payload
+            );
+            assert_eq!(bytes[split..header.entire_len as usize], packet.payload);
+
+            mutate(&mut rng, &mut bytes);
+            let _ = AfcPacketHeader::parse(&bytes);
+            let mut idevice = crate::Idevice::new(Box::new(socket(&bytes).await), "test");
+            // Damage can leave a valid packet, but never one made of bytes that weren't sent
+            if let Ok(read) = AfcPacket::read(&mut idevice).await {
+                assert!(bytes.starts_with(&read.serialize()), "seed {seed}");
+            }
+        }
+    }
+
+    #[cfg(feature = "xpc")]
+    #[test]
+    fn xpc_round_trip() {
+        use crate::xpc::format::XPCMessage;
+
+        for seed in 0..CASES {
+            let mut rng = Rng::with_seed(seed);
+            let message = xpc_message(&mut rng);
+            let (flags, body, id) = (message.flags, message.message.clone(), message.message_id);
+            let mut bytes = message.encode(id.unwrap()).unwrap();
+            let decoded = XPCMessage::decode(&bytes).unwrap();
+            assert_eq!(decoded.flags, flags, "seed {seed}");
+            assert_eq!(decoded.message, body, "seed {seed}");
+            assert_eq!(decoded.message_id, id, "seed {seed}");
+
+            mutate(&mut rng, &mut bytes);
+            let _ = XPCMessage::decode(&bytes);
+        }
+    }
+
+    #[cfg(feature = "usbmuxd")]
+    #[tokio::test]
+    async fn usbmuxd_round_trip() {
+        use crate::usbmuxd::{raw_packet::RawPacket, UsbmuxdConnection};
+
+        for seed in 0..CASES {
+            let mut rng = Rng::with_seed(seed);
+            let (mut bytes, plist) = usbmuxd_packet(&mut rng);
+            let packet = RawPacket::try_from(bytes.as_slice()).unwrap();
+            assert_eq!(packet.plist, plist, "seed {seed}");
+
+            mutate(&mut rng, &mut bytes);
+            let _ = RawPacket::try_from(bytes.as_slice());
+            let mut conn = UsbmuxdConnection::new(Box::new(socket(&bytes).await), 1);
+            let _ = conn.read_packet().await;
+        }
+    }
+
+    #[cfg(feature = "usbmuxd")]
+    #[tokio::test]
+    async fn usbmuxd_refuses_oversized_packets() {
+        use crate::usbmuxd::UsbmuxdConnection;
+
+        let mut header = Vec::new();
+        for n in [u32::MAX, 1, UsbmuxdConnection::PLIST_MESSAGE_TYPE, 0] {
+            header.extend_from_slice(&n.to_le_bytes());
+        }
+        let mut conn = UsbmuxdConnection::new(Box::new(socket(&header).await), 1);
+        assert!(matches!(
+            conn.read_packet().await,
+            Err(crate::IdeviceError::PacketSizeMismatch)
+        ));
+    }
+}
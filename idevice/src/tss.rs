@@ -4,7 +4,7 @@
 use log::{debug, warn};
 use plist::Value;
 
-use crate::{util::plist_to_bytes, IdeviceError};
+use crate::{utils::plist_to_bytes, IdeviceError};
 
 const TSS_CLIENT_VERSION_STRING: &str = "libauthinstall-1033.0.2";
 const TSS_CONTROLLER_ACTION_URL: &str = "http://gs.apple.com/TSS/controller?action=2";
@@ -25,3 +25,25 @@ pub struct DevicePropertiesResponse {
     #[serde(rename = "SerialNumber")]
     pub serial_number: String,
 }
+
+#[derive(Deserialize)]
+pub struct ListListenersResponse {
+    #[serde(rename = "ListenerList")]
+    pub listener_list: Vec<ListenerResponse>,
+}
+
+#[derive(Deserialize)]
+pub struct ListenerResponse {
+    #[serde(rename = "ID String")]
+    pub id: Option<String>,
+    #[serde(rename = "ProgName")]
+    pub program_name: Option<String>,
+    #[serde(rename = "BundleID")]
+    pub bundle_id: Option<String>,
+    #[serde(rename = "ConnType")]
+    pub connection_type: Option<u64>,
+    #[serde(rename = "kLibUSBMuxVersion")]
+    pub lib_usbmux_version: Option<u64>,
+    #[serde(rename = "Blacklisted")]
+    pub blacklisted: Option<bool>,
+}
@@ -2,15 +2,21 @@
 
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4};
 
-use log::debug;
+use futures::Stream;
+use log::{debug, warn};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
 use crate::{
-    pairing_file::PairingFile, provider::UsbmuxdProvider, Idevice, IdeviceError, ReadWrite,
+    pairing_file::PairingFile,
+    provider::UsbmuxdProvider,
+    utils::stream::{until_stopped, StopHandle},
+    ClientMetadata, Idevice, IdeviceError, ReadWrite,
 };
 
 mod des;
-mod raw_packet;
+pub(crate) mod raw_packet;
+#[cfg(feature = "usbmuxd_server")]
+pub mod server;
 
 #[derive(Debug, Clone)]
 pub enum Connection {
@@ -26,9 +32,44 @@ pub struct UsbmuxdDevice {
     pub device_id: u32,
 }
 
+/// A client of the muxer that holds a listen socket
+#[derive(Debug, Clone)]
+pub struct UsbmuxdListener {
+    /// The muxer's identifier for the client connection
+    pub id: Option<String>,
+    pub program_name: Option<String>,
+    pub bundle_id: Option<String>,
+    pub connection_type: Option<u64>,
+    pub lib_usbmux_version: Option<u64>,
+    pub blacklisted: Option<bool>,
+}
+
+/// The muxer a connection talks to, and the clients listening on it
+#[derive(Debug, Clone)]
+pub struct UsbmuxdInstance {
+    /// The muxer's host identifier, which differs between muxers on the same host
+    pub buid: String,
+    pub listeners: Vec<UsbmuxdListener>,
+}
+
+#[derive(Debug, Clone)]
+pub enum UsbmuxdListenEvent {
+    Connected(UsbmuxdDevice),
+    /// The muxer's device ID of the device that was removed
+    Disconnected(u32),
+    /// The muxer's device ID of a device that was just paired with this host
+    Paired(u32),
+}
+
 pub struct UsbmuxdConnection {
     socket: Box<dyn ReadWrite>,
+    /// The tag for the next request. Each request gets its own, so replies can be matched.
     tag: u32,
+    /// The tag the reply to the last request should carry
+    sent_tag: u32,
+    metadata: ClientMetadata,
+    /// Where to reconnect to if the muxer closes the socket
+    addr: Option<UsbmuxdAddr>,
 }
 
 #[derive(Clone, Debug)]
@@ -50,7 +91,9 @@ impl UsbmuxdAddr {
 
     pub async fn connect(&self, tag: u32) -> Result<UsbmuxdConnection, IdeviceError> {
         let socket = self.to_socket().await?;
-        Ok(UsbmuxdConnection::new(socket, tag))
+        let mut conn = UsbmuxdConnection::new(socket, tag);
+        conn.addr = Some(self.clone());
+        Ok(conn)
     }
 }
 
@@ -76,81 +119,100 @@ impl UsbmuxdConnection {
     pub const RESULT_MESSAGE_TYPE: u32 = 1;
     pub const PLIST_MESSAGE_TYPE: u32 = 8;
 
-    pub async fn default() -> Result<Self, IdeviceError> {
-        let socket = UsbmuxdAddr::default().to_socket().await?;
+    /// The largest packet body accepted from either side. Packets are small plists;
+    /// the biggest, pair records and device lists, are a few KB each.
+    pub const MAX_PACKET_SIZE: u32 = 4 * 1024 * 1024;
 
-        Ok(Self {
-            socket: Box::new(socket),
-            tag: 0,
-        })
+    pub async fn default() -> Result<Self, IdeviceError> {
+        UsbmuxdAddr::default().connect(0).await
     }
 
+    /// Wraps an existing socket to the muxer.
+    /// Connections made this way can't reconnect, as the address isn't known.
     pub fn new(socket: Box<dyn ReadWrite>, tag: u32) -> Self {
-        Self { socket, tag }
+        Self {
+            socket,
+            tag,
+            sent_tag: tag,
+            metadata: ClientMetadata::new("idevice-rs"),
+            addr: None,
+        }
     }
 
-    pub async fn get_devices(&mut self) -> Result<Vec<UsbmuxdDevice>, IdeviceError> {
+    /// Sets the client version and program name sent with every request
+    pub fn with_metadata(mut self, metadata: impl Into<ClientMetadata>) -> Self {
+        self.metadata = metadata.into();
+        self
+    }
+
+    /// The tag the next request will be sent with
+    pub fn tag(&self) -> u32 {
+        self.tag
+    }
+
+    /// Checks that the muxer is still answering on this connection, without reconnecting
+    pub async fn health_check(&mut self) -> Result<(), IdeviceError> {
         let mut req = plist::Dictionary::new();
-        req.insert("MessageType".into(), "ListDevices".into());
-        req.insert("ClientVersionString".into(), "idevice-rs".into());
-        req.insert("kLibUSBMuxVersion".into(), 3.into());
+        req.insert("MessageType".into(), "ReadBUID".into());
         self.write_plist(req).await?;
-        let res = self.read_plist().await?;
-        let res = plist::to_value(&res)?;
-        let res = plist::from_value::<des::ListDevicesResponse>(&res)?;
+        self.read_plist().await?;
+        Ok(())
+    }
 
-        let mut devs = Vec::new();
-        for dev in res.device_list {
-            let connection_type = match dev.properties.connection_type.as_str() {
-                "Network" => {
-                    if let Some(addr) = dev.properties.network_address {
-                        let addr = &Into::<Vec<u8>>::into(addr);
-                        if addr.len() < 8 {
-                            return Err(IdeviceError::UnexpectedResponse);
-                        }
+    /// Opens a new socket to the muxer, keeping the tag and metadata
+    pub async fn reconnect(&mut self) -> Result<(), IdeviceError> {
+        match &self.addr {
+            Some(addr) => {
+                self.socket = addr.to_socket().await?;
+                Ok(())
+            }
+            None => Err(IdeviceError::NoEstablishedConnection),
+        }
+    }
 
-                        let addr = match addr[0] {
-                            0x02 => {
-                                // ipv4
-                                IpAddr::V4(Ipv4Addr::new(addr[4], addr[5], addr[6], addr[7]))
-                            }
-                            0x1E => {
-                                // ipv6
-                                if addr.len() < 24 {
-                                    return Err(IdeviceError::UnexpectedResponse);
-                                }
-
-                                IpAddr::V6(Ipv6Addr::new(
-                                    u16::from_be_bytes([addr[8], addr[9]]),
-                                    u16::from_be_bytes([addr[10], addr[11]]),
-                                    u16::from_be_bytes([addr[12], addr[13]]),
-                                    u16::from_be_bytes([addr[14], addr[15]]),
-                                    u16::from_be_bytes([addr[16], addr[17]]),
-                                    u16::from_be_bytes([addr[18], addr[19]]),
-                                    u16::from_be_bytes([addr[20], addr[21]]),
-                                    u16::from_be_bytes([addr[22], addr[23]]),
-                                ))
-                            }
-                            _ => {
-                                return Err(IdeviceError::UnexpectedResponse);
-                            }
-                        };
-                        Connection::Network(addr)
-                    } else {
-                        return Err(IdeviceError::UnexpectedResponse);
-                    }
-                }
-                "USB" => Connection::Usb,
-                _ => Connection::Unknown(dev.properties.connection_type),
-            };
-            devs.push(UsbmuxdDevice {
-                connection_type,
-                udid: dev.properties.serial_number,
-                device_id: dev.device_id,
-            })
+    /// Sends a request that is safe to repeat, reconnecting once if the muxer went away.
+    /// usbmuxd closes client sockets when it restarts.
+    async fn idempotent_request(
+        &mut self,
+        req: plist::Dictionary,
+    ) -> Result<plist::Dictionary, IdeviceError> {
+        let res = match self.write_plist(req.clone()).await {
+            Ok(_) => self.read_plist().await,
+            Err(e) => Err(e),
+        };
+        match res {
+            Err(IdeviceError::Socket(e)) if self.addr.is_some() && Self::is_closed(&e) => {
+                warn!("Muxer socket closed, reconnecting: {e:?}");
+                self.reconnect().await?;
+                self.write_plist(req).await?;
+                self.read_plist().await
+            }
+            res => res,
         }
+    }
+
+    fn is_closed(e: &std::io::Error) -> bool {
+        matches!(
+            e.kind(),
+            std::io::ErrorKind::BrokenPipe
+                | std::io::ErrorKind::ConnectionReset
+                | std::io::ErrorKind::ConnectionAborted
+                | std::io::ErrorKind::NotConnected
+                | std::io::ErrorKind::UnexpectedEof
+        )
+    }
+
+    pub async fn get_devices(&mut self) -> Result<Vec<UsbmuxdDevice>, IdeviceError> {
+        let mut req = plist::Dictionary::new();
+        req.insert("MessageType".into(), "ListDevices".into());
+        let res = self.idempotent_request(req).await?;
+        let res = plist::to_value(&res)?;
+        let res = plist::from_value::<des::ListDevicesResponse>(&res)?;
 
-        Ok(devs)
+        res.device_list
+            .into_iter()
+            .map(UsbmuxdDevice::from_response)
+            .collect()
     }
 
     pub async fn get_device(&mut self, udid: &str) -> Result<UsbmuxdDevice, IdeviceError> {
@@ -166,20 +228,78 @@ impl UsbmuxdConnection {
         let mut req = plist::Dictionary::new();
         req.insert("MessageType".into(), "ReadPairRecord".into());
         req.insert("PairRecordID".into(), udid.into());
-        self.write_plist(req).await?;
-        let res = self.read_plist().await?;
+        let res = self.idempotent_request(req).await?;
 
         match res.get("PairRecordData") {
             Some(plist::Value::Data(d)) => PairingFile::from_bytes(d),
-            _ => Err(IdeviceError::UnexpectedResponse),
+            // The muxer answers with an error result when it has no record
+            _ => Self::check_result(&res).and(Err(IdeviceError::UnexpectedResponse)),
+        }
+    }
+
+    /// Gets the pairing files the muxer has for the connected devices.
+    /// The muxer can't list records by itself, so ones for disconnected devices are left out.
+    /// # Returns
+    /// Pairing files by UDID
+    pub async fn list_pair_records(&mut self) -> Result<Vec<(String, PairingFile)>, IdeviceError> {
+        let mut res: Vec<(String, PairingFile)> = Vec::new();
+        for device in self.get_devices().await? {
+            // Devices connected over both USB and the network are listed twice
+            if res.iter().any(|(udid, _)| *udid == device.udid) {
+                continue;
+            }
+            match self.get_pair_record(&device.udid).await {
+                Ok(p) => res.push((device.udid, p)),
+                Err(IdeviceError::UsbBadDevice) => debug!("No pair record for {}", device.udid),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(res)
+    }
+
+    /// Deletes the muxer's pairing file for a device, so it has to be trusted again.
+    /// The device keeps its side of the pairing until it's reset or the host is untrusted.
+    pub async fn delete_pair_record(&mut self, udid: &str) -> Result<(), IdeviceError> {
+        debug!("Deleting pair record for {udid}");
+        let mut req = plist::Dictionary::new();
+        req.insert("MessageType".into(), "DeletePairRecord".into());
+        req.insert("PairRecordID".into(), udid.into());
+        let res = self.idempotent_request(req).await?;
+        Self::check_result(&res)
+    }
+
+    /// Stores a pairing file for a device, replacing the muxer's record for it
+    /// # Arguments
+    /// `udid` - The device the pairing file is for
+    /// `device_id` - The muxer's ID for the device while it's connected, which has
+    ///   the muxer start using the record right away
+    /// `pairing_file` - The pairing file to store
+    pub async fn save_pair_record(
+        &mut self,
+        udid: &str,
+        device_id: Option<u32>,
+        pairing_file: &PairingFile,
+    ) -> Result<(), IdeviceError> {
+        debug!("Saving pair record for {udid}");
+        let mut req = plist::Dictionary::new();
+        req.insert("MessageType".into(), "SavePairRecord".into());
+        req.insert("PairRecordID".into(), udid.into());
+        req.insert(
+            "PairRecordData".into(),
+            plist::Value::Data(pairing_file.serialize()?),
+        );
+        if let Some(device_id) = device_id {
+            req.insert("DeviceID".into(), device_id.into());
         }
+        let res = self.idempotent_request(req).await?;
+        Self::check_result(&res)
     }
 
+    /// Reads the muxer's host identifier, which devices paired through it will know
     pub async fn get_buid(&mut self) -> Result<String, IdeviceError> {
         let mut req = plist::Dictionary::new();
         req.insert("MessageType".into(), "ReadBUID".into());
-        self.write_plist(req).await?;
-        let mut res = self.read_plist().await?;
+        let mut res = self.idempotent_request(req).await?;
 
         match res.remove("BUID") {
             Some(plist::Value::String(s)) => Ok(s),
@@ -187,11 +307,43 @@ impl UsbmuxdConnection {
         }
     }
 
+    /// Lists the clients currently listening for device events.
+    /// Useful for finding other tools holding the muxer.
+    pub async fn list_listeners(&mut self) -> Result<Vec<UsbmuxdListener>, IdeviceError> {
+        let mut req = plist::Dictionary::new();
+        req.insert("MessageType".into(), "ListListeners".into());
+        let res = self.idempotent_request(req).await?;
+        let res = plist::to_value(&res)?;
+        let res = plist::from_value::<des::ListListenersResponse>(&res)?;
+
+        Ok(res
+            .listener_list
+            .into_iter()
+            .map(|l| UsbmuxdListener {
+                id: l.id,
+                program_name: l.program_name,
+                bundle_id: l.bundle_id,
+                connection_type: l.connection_type,
+                lib_usbmux_version: l.lib_usbmux_version,
+                blacklisted: l.blacklisted,
+            })
+            .collect())
+    }
+
+    /// Reads which muxer this is and who else is listening on it.
+    /// Telling muxers apart by BUID helps when several run on one host.
+    pub async fn get_instance(&mut self) -> Result<UsbmuxdInstance, IdeviceError> {
+        Ok(UsbmuxdInstance {
+            buid: self.get_buid().await?,
+            listeners: self.list_listeners().await?,
+        })
+    }
+
     pub async fn connect_to_device(
         mut self,
         device_id: u32,
         port: u16,
-        label: impl Into<String>,
+        metadata: impl Into<ClientMetadata>,
     ) -> Result<Idevice, IdeviceError> {
         debug!("Connecting to device {device_id} on port {port}");
         let port = port.to_be();
@@ -201,9 +353,75 @@ impl UsbmuxdConnection {
         req.insert("DeviceID".into(), device_id.into());
         req.insert("PortNumber".into(), port.into());
         self.write_plist(req).await?;
-        match self.read_plist().await?.get("Number") {
+        if let Err(e) = Self::check_result(&self.read_plist().await?) {
+            warn!(
+                "Muxer refused connecting to device {device_id} for request tag {}: {e:?}",
+                self.sent_tag
+            );
+            return Err(e);
+        }
+        Ok(Idevice::new(self.socket, metadata))
+    }
+
+    /// Subscribes to devices being connected and disconnected.
+    /// The connection is consumed, as the muxer only sends events on it afterwards.
+    pub async fn listen(
+        mut self,
+    ) -> Result<
+        (
+            impl Stream<Item = Result<UsbmuxdListenEvent, IdeviceError>>,
+            StopHandle,
+        ),
+        IdeviceError,
+    > {
+        let mut req = plist::Dictionary::new();
+        req.insert("MessageType".into(), "Listen".into());
+        self.write_plist(req).await?;
+        Self::check_result(&self.read_plist().await?)?;
+
+        let stop = StopHandle::new();
+        let stream = until_stopped(self, stop.clone(), |mut conn| async move {
+            let res = conn.read_event().await;
+            (res, conn)
+        });
+        Ok((stream, stop))
+    }
+
+    async fn read_event(&mut self) -> Result<UsbmuxdListenEvent, IdeviceError> {
+        loop {
+            // Events aren't replies, so they can carry any tag
+            let (_, res) = self.read_packet().await?;
+            match res.get("MessageType") {
+                Some(plist::Value::String(t)) if t.as_str() == "Attached" => {
+                    let res = plist::to_value(&res)?;
+                    let dev = plist::from_value::<des::DeviceListResponse>(&res)?;
+                    return Ok(UsbmuxdListenEvent::Connected(UsbmuxdDevice::from_response(
+                        dev,
+                    )?));
+                }
+                Some(plist::Value::String(t)) if t.as_str() == "Detached" => {
+                    match res.get("DeviceID").and_then(|d| d.as_unsigned_integer()) {
+                        Some(id) => return Ok(UsbmuxdListenEvent::Disconnected(id as u32)),
+                        None => return Err(IdeviceError::UnexpectedResponse),
+                    }
+                }
+                Some(plist::Value::String(t)) if t.as_str() == "Paired" => {
+                    match res.get("DeviceID").and_then(|d| d.as_unsigned_integer()) {
+                        Some(id) => return Ok(UsbmuxdListenEvent::Paired(id as u32)),
+                        None => return Err(IdeviceError::UnexpectedResponse),
+                    }
+                }
+                t => {
+                    debug!("Ignoring muxer event {t:?}");
+                }
+            }
+        }
+    }
+
+    fn check_result(res: &plist::Dictionary) -> Result<(), IdeviceError> {
+        match res.get("Number") {
             Some(plist::Value::Integer(i)) => match i.as_unsigned() {
-                Some(0) => Ok(Idevice::new(self.socket, label)),
+                Some(0) => Ok(()),
                 Some(1) => Err(IdeviceError::UsbBadCommand),
                 Some(2) => Err(IdeviceError::UsbBadDevice),
                 Some(3) => Err(IdeviceError::UsbConnectionRefused),
@@ -214,13 +432,22 @@ impl UsbmuxdConnection {
         }
     }
 
-    async fn write_plist(&mut self, req: plist::Dictionary) -> Result<(), IdeviceError> {
+    async fn write_plist(&mut self, mut req: plist::Dictionary) -> Result<(), IdeviceError> {
+        req.insert(
+            "ClientVersionString".into(),
+            self.metadata.version.clone().into(),
+        );
+        req.insert("ProgName".into(), self.metadata.program_name.clone().into());
+        req.insert("kLibUSBMuxVersion".into(), 3.into());
+
         let raw = raw_packet::RawPacket::new(
             req,
             Self::XML_PLIST_VERSION,
             Self::PLIST_MESSAGE_TYPE,
             self.tag,
         );
+        self.sent_tag = self.tag;
+        self.tag = self.tag.wrapping_add(1);
 
         let raw: Vec<u8> = raw.into();
         self.socket.write_all(&raw).await?;
@@ -228,39 +455,119 @@ impl UsbmuxdConnection {
         Ok(())
     }
 
+    /// Reads the reply to the last request
     async fn read_plist(&mut self) -> Result<plist::Dictionary, IdeviceError> {
+        let (tag, res) = self.read_packet().await?;
+        if tag != self.sent_tag {
+            return Err(IdeviceError::UsbmuxdTagMismatch {
+                expected: self.sent_tag,
+                found: tag,
+            });
+        }
+        Ok(res)
+    }
+
+    pub(crate) async fn read_packet(&mut self) -> Result<(u32, plist::Dictionary), IdeviceError> {
         let mut header_buffer = [0; 16];
         self.socket.read_exact(&mut header_buffer).await?;
 
         // We are safe to unwrap as it only panics if the buffer isn't 4
-        let packet_size = u32::from_le_bytes(header_buffer[..4].try_into().unwrap()) - 16;
-        debug!("Reading {packet_size} bytes from muxer");
+        let packet_size = u32::from_le_bytes(header_buffer[..4].try_into().unwrap());
+        let packet_size = match packet_size.checked_sub(16) {
+            Some(s) => s,
+            None => return Err(IdeviceError::PacketSizeMismatch),
+        };
+        let tag = u32::from_le_bytes(header_buffer[12..].try_into().unwrap());
+        if packet_size > Self::MAX_PACKET_SIZE {
+            warn!(
+                "Muxer sent a {packet_size} byte packet, larger than {}",
+                Self::MAX_PACKET_SIZE
+            );
+            return Err(IdeviceError::PacketSizeMismatch);
+        }
+        debug!("Reading {packet_size} bytes from muxer for tag {tag}");
 
         let mut body_buffer = vec![0; packet_size as usize];
         self.socket.read_exact(&mut body_buffer).await?;
 
         let res = plist::from_bytes(&body_buffer)?;
-        debug!("Read from muxer: {res:#?}");
+        if log::log_enabled!(log::Level::Debug) {
+            debug!(
+                "Read from muxer: {:#?}",
+                crate::utils::redact::redacted(&res)
+            );
+        }
 
-        Ok(res)
+        Ok((tag, res))
     }
 }
 
 impl UsbmuxdDevice {
+    fn from_response(dev: des::DeviceListResponse) -> Result<Self, IdeviceError> {
+        let connection_type = match dev.properties.connection_type.as_str() {
+            "Network" => {
+                if let Some(addr) = dev.properties.network_address {
+                    let addr = &Into::<Vec<u8>>::into(addr);
+                    if addr.len() < 8 {
+                        return Err(IdeviceError::UnexpectedResponse);
+                    }
+
+                    let addr = match addr[0] {
+                        0x02 => {
+                            // ipv4
+                            IpAddr::V4(Ipv4Addr::new(addr[4], addr[5], addr[6], addr[7]))
+                        }
+                        0x1E => {
+                            // ipv6
+                            if addr.len() < 24 {
+                                return Err(IdeviceError::UnexpectedResponse);
+                            }
+
+                            IpAddr::V6(Ipv6Addr::new(
+                                u16::from_be_bytes([addr[8], addr[9]]),
+                                u16::from_be_bytes([addr[10], addr[11]]),
+                                u16::from_be_bytes([addr[12], addr[13]]),
+                                u16::from_be_bytes([addr[14], addr[15]]),
+                                u16::from_be_bytes([addr[16], addr[17]]),
+                                u16::from_be_bytes([addr[18], addr[19]]),
+                                u16::from_be_bytes([addr[20], addr[21]]),
+                                u16::from_be_bytes([addr[22], addr[23]]),
+                            ))
+                        }
+                        _ => {
+                            return Err(IdeviceError::UnexpectedResponse);
+                        }
+                    };
+                    Connection::Network(addr)
+                } else {
+                    return Err(IdeviceError::UnexpectedResponse);
+                }
+            }
+            "USB" => Connection::Usb,
+            _ => Connection::Unknown(dev.properties.connection_type),
+        };
+        Ok(UsbmuxdDevice {
+            connection_type,
+            udid: dev.properties.serial_number,
+            device_id: dev.device_id,
+        })
+    }
+
     pub fn to_provider(
         &self,
         addr: UsbmuxdAddr,
         tag: u32,
-        label: impl Into<String>,
+        metadata: impl Into<ClientMetadata>,
     ) -> UsbmuxdProvider {
-        let label = label.into();
-
         UsbmuxdProvider {
             addr,
             tag,
             udid: self.udid.clone(),
             device_id: self.device_id,
-            label,
+            connection_type: self.connection_type.clone(),
+            metadata: metadata.into(),
+            verify_udid: false,
+            pairing_file_source: None,
         }
     }
 }
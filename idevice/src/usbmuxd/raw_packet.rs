@@ -1,6 +1,6 @@
 // Jackson Coxson
 
-use crate::util::plist_to_bytes;
+use crate::utils::plist_to_bytes;
 use log::warn;
 
 #[derive(Debug)]
@@ -64,6 +64,11 @@ impl TryFrom<&[u8]> for RawPacket {
             }
         });
 
+        if packet_size < 16 {
+            warn!("Raw packet size is smaller than its header");
+            return Err(());
+        }
+
         // Determine if we have enough data to parse
         if packet.len() < packet_size as usize {
             warn!("Not enough data to parse a raw packet body");
@@ -0,0 +1,643 @@
+// Jackson Coxson
+// The muxer side of the usbmuxd protocol, for serving devices to usbmuxd clients.
+// Devices are reached through transports the embedder provides, such as USB or TCP.
+
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    future::Future,
+    net::IpAddr,
+    path::PathBuf,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use log::{debug, warn};
+use plist::{Dictionary, Value};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::broadcast,
+};
+
+use crate::{IdeviceError, ReadWrite};
+
+use super::{raw_packet::RawPacket, Connection, UsbmuxdConnection};
+
+/// Events that listeners haven't read yet are dropped past this many
+const EVENT_CAPACITY: usize = 64;
+
+/// Result numbers the muxer answers requests with
+const RESULT_OK: u64 = 0;
+const RESULT_BAD_COMMAND: u64 = 1;
+const RESULT_BAD_DEVICE: u64 = 2;
+const RESULT_CONNECTION_REFUSED: u64 = 3;
+
+pub type ConnectFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<Box<dyn ReadWrite>, IdeviceError>> + Send + 'a>>;
+
+/// Reaches the services of one device
+pub trait DeviceTransport: Send + Sync + Debug {
+    /// Opens a connection to a port on the device
+    fn connect(&self, port: u16) -> ConnectFuture<'_>;
+}
+
+/// Reaches a device over the network, as usbmuxd does for WiFi devices
+#[cfg(feature = "tcp")]
+#[derive(Debug, Clone)]
+pub struct TcpTransport {
+    pub addr: IpAddr,
+}
+
+#[cfg(feature = "tcp")]
+impl DeviceTransport for TcpTransport {
+    fn connect(&self, port: u16) -> ConnectFuture<'_> {
+        Box::pin(async move {
+            let stream = tokio::net::TcpStream::connect((self.addr, port)).await?;
+            Ok(Box::new(stream) as Box<dyn ReadWrite>)
+        })
+    }
+}
+
+/// Where the server keeps the pairing files clients save, as serialized plists by UDID.
+/// The server calls it on a blocking thread, so implementations may do blocking IO.
+pub trait PairRecordStore: Send + Sync + Debug {
+    fn get(&self, udid: &str) -> Result<Option<Vec<u8>>, IdeviceError>;
+    fn save(&self, udid: &str, record: Vec<u8>) -> Result<(), IdeviceError>;
+    fn delete(&self, udid: &str) -> Result<(), IdeviceError>;
+}
+
+/// Keeps pair records until the server is dropped
+#[derive(Debug, Default)]
+pub struct MemoryPairRecords {
+    records: Mutex<HashMap<String, Vec<u8>>>,
+}
+
+impl PairRecordStore for MemoryPairRecords {
+    fn get(&self, udid: &str) -> Result<Option<Vec<u8>>, IdeviceError> {
+        Ok(self.records.lock().unwrap().get(udid).cloned())
+    }
+
+    fn save(&self, udid: &str, record: Vec<u8>) -> Result<(), IdeviceError> {
+        self.records
+            .lock()
+            .unwrap()
+            .insert(udid.to_string(), record);
+        Ok(())
+    }
+
+    fn delete(&self, udid: &str) -> Result<(), IdeviceError> {
+        self.records.lock().unwrap().remove(udid);
+        Ok(())
+    }
+}
+
+/// Keeps pair records as `<udid>.plist` files in a directory, like usbmuxd's `/var/lib/lockdown`
+#[derive(Debug, Clone)]
+pub struct DirectoryPairRecords {
+    pub dir: PathBuf,
+}
+
+impl DirectoryPairRecords {
+    fn path(&self, udid: &str) -> Result<PathBuf, IdeviceError> {
+        // The UDID comes from the client, so it mustn't name another file
+        if udid.is_empty() || !udid.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+            return Err(IdeviceError::InvalidArgument);
+        }
+        Ok(self.dir.join(format!("{udid}.plist")))
+    }
+}
+
+impl PairRecordStore for DirectoryPairRecords {
+    fn get(&self, udid: &str) -> Result<Option<Vec<u8>>, IdeviceError> {
+        match std::fs::read(self.path(udid)?) {
+            Ok(r) => Ok(Some(r)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn save(&self, udid: &str, record: Vec<u8>) -> Result<(), IdeviceError> {
+        std::fs::create_dir_all(&self.dir)?;
+        Ok(std::fs::write(self.path(udid)?, record)?)
+    }
+
+    fn delete(&self, udid: &str) -> Result<(), IdeviceError> {
+        match std::fs::remove_file(self.path(udid)?) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Serves usbmuxd clients, such as this crate's `UsbmuxdConnection` or libusbmuxd.
+///
+/// The embedder accepts client sockets and passes each to `handle`, and reports
+/// devices with `attach` and `detach` as its transports find and lose them.
+/// Clones share the same devices and listeners.
+#[derive(Clone, Debug)]
+pub struct UsbmuxdServer {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    buid: String,
+    records: Arc<dyn PairRecordStore>,
+    state: Mutex<State>,
+    events: broadcast::Sender<Event>,
+    next_listener: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    devices: HashMap<u32, ServedDevice>,
+    next_device_id: u32,
+    listeners: HashMap<u64, Dictionary>,
+}
+
+#[derive(Debug, Clone)]
+struct ServedDevice {
+    udid: String,
+    connection: Connection,
+    transport: Arc<dyn DeviceTransport>,
+}
+
+#[derive(Debug, Clone)]
+enum Event {
+    Attached(u32),
+    Detached(u32),
+    Paired(u32),
+}
+
+impl UsbmuxdServer {
+    /// # Arguments
+    /// `buid` - The host's identifier, which pairing files made through the server record
+    /// `records` - Where pairing files are kept
+    pub fn new(buid: impl Into<String>, records: Arc<dyn PairRecordStore>) -> Self {
+        let (events, _) = broadcast::channel(EVENT_CAPACITY);
+        Self {
+            inner: Arc::new(Inner {
+                buid: buid.into(),
+                records,
+                state: Mutex::new(State {
+                    next_device_id: 1,
+                    ..Default::default()
+                }),
+                events,
+                next_listener: AtomicU64::new(1),
+            }),
+        }
+    }
+
+    /// Makes a device available to clients
+    /// # Arguments
+    /// `udid` - The device's UDID
+    /// `connection` - How the device is reached, which clients see
+    /// `transport` - Opens connections to the device
+    /// # Returns
+    /// The device ID clients use for it
+    pub fn attach(
+        &self,
+        udid: impl Into<String>,
+        connection: Connection,
+        transport: Arc<dyn DeviceTransport>,
+    ) -> u32 {
+        let mut state = self.inner.state.lock().unwrap();
+        let id = state.next_device_id;
+        state.next_device_id += 1;
+        state.devices.insert(
+            id,
+            ServedDevice {
+                udid: udid.into(),
+                connection,
+                transport,
+            },
+        );
+        // Nobody listening isn't an error
+        let _ = self.inner.events.send(Event::Attached(id));
+        id
+    }
+
+    /// Removes a device. Connections already made to it are left to its transport.
+    pub fn detach(&self, device_id: u32) {
+        let mut state = self.inner.state.lock().unwrap();
+        if state.devices.remove(&device_id).is_some() {
+            let _ = self.inner.events.send(Event::Detached(device_id));
+        }
+    }
+
+    /// Serves one client until it disconnects, or for the lifetime of a device
+    /// connection it asks for
+    pub async fn handle(&self, mut socket: Box<dyn ReadWrite>) -> Result<(), IdeviceError> {
+        loop {
+            let (version, tag, req) = match read_request(&mut socket).await? {
+                Some(r) => r,
+                None => return Ok(()),
+            };
+            let message_type = req
+                .get("MessageType")
+                .and_then(|m| m.as_string())
+                .unwrap_or_default()
+                .to_string();
+            debug!("Muxer client sent {message_type}");
+
+            match message_type.as_str() {
+                "Listen" => {
+                    write_packet(&mut socket, version, tag, result(RESULT_OK)).await?;
+                    return self.listen(socket, version, req).await;
+                }
+                "Connect" => {
+                    let device_id = req.get("DeviceID").and_then(|d| d.as_unsigned_integer());
+                    // Clients send the port in network byte order
+                    let port = req
+                        .get("PortNumber")
+                        .and_then(|p| p.as_unsigned_integer())
+                        .map(|p| u16::from_be(p as u16));
+                    let device = device_id.and_then(|id| self.device(id as u32));
+                    let (device, port) = match (device, port) {
+                        (Some(d), Some(p)) => (d, p),
+                        _ => {
+                            write_packet(&mut socket, version, tag, result(RESULT_BAD_DEVICE))
+                                .await?;
+                            continue;
+                        }
+                    };
+                    let mut stream = match device.transport.connect(port).await {
+                        Ok(s) => s,
+                        Err(e) => {
+                            warn!("Unable to connect to port {port} on {}: {e:?}", device.udid);
+                            let res = result(RESULT_CONNECTION_REFUSED);
+                            write_packet(&mut socket, version, tag, res).await?;
+                            continue;
+                        }
+                    };
+                    write_packet(&mut socket, version, tag, result(RESULT_OK)).await?;
+                    // The socket belongs to the device connection from now on
+                    tokio::io::copy_bidirectional(&mut socket, &mut stream).await?;
+                    return Ok(());
+                }
+                _ => {
+                    let res = self.respond(&message_type, &req).await;
+                    write_packet(&mut socket, version, tag, res).await?;
+                }
+            }
+        }
+    }
+
+    /// Answers the requests that don't take over the connection
+    async fn respond(&self, message_type: &str, req: &Dictionary) -> Dictionary {
+        let udid = req
+            .get("PairRecordID")
+            .and_then(|u| u.as_string())
+            .map(|u| u.to_string());
+        let res = match (message_type, udid) {
+            ("ListDevices", _) => {
+                let state = self.inner.state.lock().unwrap();
+                let list = state
+                    .devices
+                    .iter()
+                    .map(|(id, d)| Value::Dictionary(attached(*id, d)))
+                    .collect();
+                let mut res = Dictionary::new();
+                res.insert("DeviceList".into(), Value::Array(list));
+                Ok(res)
+            }
+            ("ListListeners", _) => {
+                let state = self.inner.state.lock().unwrap();
+                let list = state
+                    .listeners
+                    .values()
+                    .cloned()
+                    .map(Value::Dictionary)
+                    .collect();
+                let mut res = Dictionary::new();
+                res.insert("ListenerList".into(), Value::Array(list));
+                Ok(res)
+            }
+            ("ReadBUID", _) => {
+                let mut res = Dictionary::new();
+                res.insert("BUID".into(), self.inner.buid.clone().into());
+                Ok(res)
+            }
+            ("ReadPairRecord", Some(udid)) => {
+                let record = self.with_records(move |r| r.get(&udid)).await;
+                record.map(|r| match r {
+                    Some(r) => {
+                        let mut res = Dictionary::new();
+                        res.insert("PairRecordData".into(), Value::Data(r));
+                        res
+                    }
+                    None => result(RESULT_BAD_DEVICE),
+                })
+            }
+            ("SavePairRecord", Some(udid)) => match req.get("PairRecordData") {
+                Some(Value::Data(record)) => {
+                    let record = record.clone();
+                    let saved = self.with_records(move |r| r.save(&udid, record)).await;
+                    saved.map(|_| {
+                        let device_id = req.get("DeviceID").and_then(|d| d.as_unsigned_integer());
+                        if let Some(id) = device_id {
+                            if self.device(id as u32).is_some() {
+                                let _ = self.inner.events.send(Event::Paired(id as u32));
+                            }
+                        }
+                        result(RESULT_OK)
+                    })
+                }
+                _ => Ok(result(RESULT_BAD_COMMAND)),
+            },
+            ("DeletePairRecord", Some(udid)) => self
+                .with_records(move |r| r.delete(&udid))
+                .await
+                .map(|_| result(RESULT_OK)),
+            _ => {
+                warn!("Unsupported muxer request {message_type}");
+                Ok(result(RESULT_BAD_COMMAND))
+            }
+        };
+        res.unwrap_or_else(|e| {
+            warn!("Failed to answer {message_type}: {e:?}");
+            result(RESULT_BAD_DEVICE)
+        })
+    }
+
+    /// Sends the attached devices and then every change, until the client goes away
+    async fn listen(
+        &self,
+        socket: Box<dyn ReadWrite>,
+        version: u32,
+        req: Dictionary,
+    ) -> Result<(), IdeviceError> {
+        let id = self.inner.next_listener.fetch_add(1, Ordering::Relaxed);
+        let mut listener = Dictionary::new();
+        listener.insert("ID String".into(), id.to_string().into());
+        for key in ["ProgName", "BundleID", "kLibUSBMuxVersion"] {
+            if let Some(v) = req.get(key) {
+                listener.insert(key.into(), v.clone());
+            }
+        }
+        listener.insert("ConnType".into(), 0.into());
+
+        // Subscribe before reading the devices so none are missed in between
+        let mut events = self.inner.events.subscribe();
+        let current = {
+            let mut state = self.inner.state.lock().unwrap();
+            state.listeners.insert(id, listener);
+            state
+                .devices
+                .iter()
+                .map(|(id, d)| attached(*id, d))
+                .collect::<Vec<_>>()
+        };
+
+        let (mut reader, mut writer) = tokio::io::split(socket);
+        let res = async {
+            for dev in current {
+                write_packet(&mut writer, version, 0, dev).await?;
+            }
+            let mut buf = [0; 1];
+            loop {
+                let event = tokio::select! {
+                    e = events.recv() => e,
+                    // Listeners don't send anything, so this only returns when they leave
+                    _ = reader.read(&mut buf) => return Ok(()),
+                };
+                let message = match event {
+                    Ok(Event::Attached(id)) => match self.device(id) {
+                        Some(d) => attached(id, &d),
+                        None => continue,
+                    },
+                    Ok(Event::Detached(id)) => device_event("Detached", id),
+                    Ok(Event::Paired(id)) => device_event("Paired", id),
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        warn!("Listener {id} missed {n} device events");
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                };
+                write_packet(&mut writer, version, 0, message).await?;
+            }
+        }
+        .await;
+
+        self.inner.state.lock().unwrap().listeners.remove(&id);
+        res
+    }
+
+    /// Uses the pair record store off the async runtime, as stores may do blocking IO
+    async fn with_records<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&dyn PairRecordStore) -> Result<T, IdeviceError> + Send + 'static,
+    ) -> Result<T, IdeviceError> {
+        let records = self.inner.records.clone();
+        tokio::task::spawn_blocking(move || f(records.as_ref()))
+            .await
+            .map_err(std::io::Error::other)?
+    }
+
+    fn device(&self, device_id: u32) -> Option<ServedDevice> {
+        self.inner
+            .state
+            .lock()
+            .unwrap()
+            .devices
+            .get(&device_id)
+            .cloned()
+    }
+}
+
+fn result(number: u64) -> Dictionary {
+    let mut res = Dictionary::new();
+    res.insert("MessageType".into(), "Result".into());
+    res.insert("Number".into(), number.into());
+    res
+}
+
+fn device_event(message_type: &str, device_id: u32) -> Dictionary {
+    let mut res = Dictionary::new();
+    res.insert("MessageType".into(), message_type.into());
+    res.insert("DeviceID".into(), device_id.into());
+    res
+}
+
+/// A device as `ListDevices` and `Attached` events describe it
+fn attached(device_id: u32, device: &ServedDevice) -> Dictionary {
+    let mut properties = Dictionary::new();
+    properties.insert("DeviceID".into(), device_id.into());
+    properties.insert("SerialNumber".into(), device.udid.clone().into());
+    let connection_type = match &device.connection {
+        Connection::Usb => "USB".to_string(),
+        Connection::Network(addr) => {
+            properties.insert("NetworkAddress".into(), Value::Data(network_address(*addr)));
+            "Network".to_string()
+        }
+        Connection::Unknown(t) => t.clone(),
+    };
+    properties.insert("ConnectionType".into(), connection_type.into());
+
+    let mut res = device_event("Attached", device_id);
+    res.insert("Properties".into(), Value::Dictionary(properties));
+    res
+}
+
+/// A sockaddr with the family first, as the muxer sends it
+fn network_address(addr: IpAddr) -> Vec<u8> {
+    match addr {
+        IpAddr::V4(v4) => {
+            let mut res = vec![0x02, 0, 0, 0];
+            res.extend_from_slice(&v4.octets());
+            res.extend_from_slice(&[0; 8]);
+            res
+        }
+        IpAddr::V6(v6) => {
+            let mut res = vec![0x1E, 0, 0, 0, 0, 0, 0, 0];
+            res.extend_from_slice(&v6.octets());
+            res.extend_from_slice(&[0; 4]);
+            res
+        }
+    }
+}
+
+/// Reads a request, or `None` when the client disconnects between requests
+async fn read_request(
+    socket: &mut Box<dyn ReadWrite>,
+) -> Result<Option<(u32, u32, Dictionary)>, IdeviceError> {
+    let mut header = [0; 16];
+    match socket.read_exact(&mut header).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let size = u32::from_le_bytes(header[..4].try_into().unwrap());
+    let version = u32::from_le_bytes(header[4..8].try_into().unwrap());
+    let message = u32::from_le_bytes(header[8..12].try_into().unwrap());
+    let tag = u32::from_le_bytes(header[12..].try_into().unwrap());
+    let size = size
+        .checked_sub(16)
+        .ok_or(IdeviceError::PacketSizeMismatch)?;
+    if message != UsbmuxdConnection::PLIST_MESSAGE_TYPE {
+        warn!("Muxer client sent message type {message}, which isn't a plist");
+        return Err(IdeviceError::UsbBadVersion);
+    }
+
+    if size > UsbmuxdConnection::MAX_PACKET_SIZE {
+        warn!(
+            "Muxer client sent a {size} byte packet, larger than {}",
+            UsbmuxdConnection::MAX_PACKET_SIZE
+        );
+        return Err(IdeviceError::PacketSizeMismatch);
+    }
+
+    let mut body = vec![0; size as usize];
+    socket.read_exact(&mut body).await?;
+    Ok(Some((version, tag, plist::from_bytes(&body)?)))
+}
+
+async fn write_packet(
+    socket: &mut (impl tokio::io::AsyncWrite + Unpin),
+    version: u32,
+    tag: u32,
+    message: Dictionary,
+) -> Result<(), IdeviceError> {
+    let raw: Vec<u8> =
+        RawPacket::new(message, version, UsbmuxdConnection::PLIST_MESSAGE_TYPE, tag).into();
+    socket.write_all(&raw).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::usbmuxd::UsbmuxdListenEvent;
+
+    /// Echoes whatever is written to any port
+    #[derive(Debug)]
+    struct Echo;
+
+    impl DeviceTransport for Echo {
+        fn connect(&self, _port: u16) -> ConnectFuture<'_> {
+            Box::pin(async {
+                let (client, mut device) = tokio::io::duplex(1024);
+                tokio::spawn(async move {
+                    let (mut r, mut w) = tokio::io::split(&mut device);
+                    let _ = tokio::io::copy(&mut r, &mut w).await;
+                });
+                Ok(Box::new(client) as Box<dyn ReadWrite>)
+            })
+        }
+    }
+
+    fn client(server: &UsbmuxdServer) -> UsbmuxdConnection {
+        let (client, socket) = tokio::io::duplex(1 << 16);
+        let server = server.clone();
+        tokio::spawn(async move { server.handle(Box::new(socket)).await });
+        UsbmuxdConnection::new(Box::new(client), 1)
+    }
+
+    #[tokio::test]
+    async fn serves_the_client() {
+        let server = UsbmuxdServer::new("BUID", Arc::new(MemoryPairRecords::default()));
+        let (events, _stop) = client(&server).listen().await.unwrap();
+        let mut events = Box::pin(events);
+
+        let usb = server.attach("usb-device", Connection::Usb, Arc::new(Echo));
+        let network = Connection::Network(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)));
+        server.attach("network-device", network, Arc::new(Echo));
+
+        let mut conn = client(&server);
+        assert_eq!(conn.get_buid().await.unwrap(), "BUID");
+        let mut devices = conn.get_devices().await.unwrap();
+        devices.sort_by_key(|d| d.device_id);
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[0].udid, "usb-device");
+        assert!(matches!(
+            devices[1].connection_type,
+            Connection::Network(IpAddr::V4(a)) if a == Ipv4Addr::new(10, 0, 0, 2)
+        ));
+        assert!(matches!(
+            conn.get_pair_record("usb-device").await,
+            Err(IdeviceError::UsbBadDevice)
+        ));
+        let instance = conn.get_instance().await.unwrap();
+        assert_eq!(instance.buid, "BUID");
+        assert_eq!(instance.listeners.len(), 1);
+
+        let mut idevice = conn.connect_to_device(usb, 62078, "test").await.unwrap();
+        idevice.send_raw(b"ping").await.unwrap();
+        assert_eq!(idevice.read_raw(4).await.unwrap(), b"ping");
+
+        assert!(matches!(
+            events.next().await,
+            Some(Ok(UsbmuxdListenEvent::Connected(d))) if d.device_id == usb
+        ));
+        events.next().await.unwrap().unwrap();
+        server.detach(usb);
+        assert!(matches!(
+            events.next().await,
+            Some(Ok(UsbmuxdListenEvent::Disconnected(id))) if id == usb
+        ));
+    }
+
+    #[tokio::test]
+    async fn refuses_oversized_requests() {
+        let (client, server) = tokio::io::duplex(1024);
+        let mut server: Box<dyn ReadWrite> = Box::new(server);
+        let mut client = client;
+        let mut header = Vec::new();
+        for n in [u32::MAX, 1, UsbmuxdConnection::PLIST_MESSAGE_TYPE, 0] {
+            header.extend_from_slice(&n.to_le_bytes());
+        }
+        client.write_all(&header).await.unwrap();
+        assert!(matches!(
+            read_request(&mut server).await,
+            Err(IdeviceError::PacketSizeMismatch)
+        ));
+    }
+}
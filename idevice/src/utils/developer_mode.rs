@@ -0,0 +1,74 @@
+// Jackson Coxson
+// Whether developer mode is turned on, from whichever source the device answers
+
+use log::debug;
+use plist::Value;
+
+use crate::{
+    capabilities::DeviceCapabilities,
+    lockdownd::{LockdownDomain, LockdowndClient},
+    mounter::ImageMounter,
+    provider::IdeviceProvider,
+    IdeviceError, IdeviceService,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeveloperModeStatus {
+    Enabled,
+    Disabled,
+    /// Developer mode doesn't exist before iOS 16, so nothing needs enabling
+    Unsupported,
+}
+
+impl DeveloperModeStatus {
+    /// Whether developer services can be used as far as developer mode is concerned
+    pub fn allows_developer_services(&self) -> bool {
+        !matches!(self, Self::Disabled)
+    }
+}
+
+impl From<bool> for DeveloperModeStatus {
+    fn from(value: bool) -> Self {
+        match value {
+            true => Self::Enabled,
+            false => Self::Disabled,
+        }
+    }
+}
+
+/// Gets the developer mode status of the device
+pub async fn status(provider: &dyn IdeviceProvider) -> Result<DeveloperModeStatus, IdeviceError> {
+    let mut lockdown = LockdowndClient::connect(provider).await?;
+    lockdown
+        .start_session(&provider.get_pairing_file().await?)
+        .await?;
+    let capabilities = DeviceCapabilities::from_lockdown(&mut lockdown).await?;
+    status_from_lockdown(&mut lockdown, &capabilities, provider).await
+}
+
+/// Reads the lockdown value, which AMFI publishes, and asks the image mounter
+/// if lockdown doesn't have it
+/// # Arguments
+/// `lockdown` - A client with a session started
+pub(crate) async fn status_from_lockdown(
+    lockdown: &mut LockdowndClient,
+    capabilities: &DeviceCapabilities,
+    provider: &dyn IdeviceProvider,
+) -> Result<DeveloperModeStatus, IdeviceError> {
+    if !capabilities.has_developer_mode() {
+        return Ok(DeveloperModeStatus::Unsupported);
+    }
+    match lockdown
+        .get_value_in_domain("DeveloperModeStatus", LockdownDomain::Amfi)
+        .await
+    {
+        Ok(Value::Boolean(enabled)) => return Ok(enabled.into()),
+        Ok(v) => debug!("Unexpected DeveloperModeStatus {v:?}, asking the image mounter"),
+        Err(IdeviceError::UnknownErrorType(e)) => {
+            debug!("Lockdown has no DeveloperModeStatus ({e}), asking the image mounter")
+        }
+        Err(e) => return Err(e),
+    }
+    let mut mounter = ImageMounter::connect(provider).await?;
+    Ok(mounter.query_developer_mode_status().await?.into())
+}
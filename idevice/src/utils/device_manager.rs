@@ -0,0 +1,546 @@
+// Jackson Coxson
+// Tracks the devices attached to usbmuxd and runs work against each of them in order
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::{Stream, StreamExt};
+use log::{debug, warn};
+use tokio::sync::{broadcast, mpsc, oneshot};
+
+use crate::{
+    provider::UsbmuxdProvider,
+    usbmuxd::{Connection, UsbmuxdAddr, UsbmuxdDevice, UsbmuxdListenEvent},
+    utils::stream::StopHandle,
+    ClientMetadata, IdeviceError,
+};
+
+/// How long to wait before listening again after the muxer connection is lost
+pub const RECONNECT_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Events that are sent before a subscriber catches up are dropped past this many
+const EVENT_CAPACITY: usize = 64;
+
+/// A change to the set of attached devices
+#[derive(Debug, Clone)]
+pub enum DeviceEvent {
+    /// A device that wasn't attached before, with its preferred connection
+    Attached(UsbmuxdDevice),
+    /// An attached device gained or lost a connection, so its preferred one changed.
+    /// Tasks started afterwards use the new connection.
+    ConnectionChanged(UsbmuxdDevice),
+    /// The device's last connection went away. Tasks still queued for it are dropped.
+    Detached(String),
+    /// The attached device with this UDID was paired with this host
+    Paired(String),
+}
+
+/// The result of a task that was submitted to a device's queue.
+/// Resolves to `DeviceNotFound` if the device detached before the task ran.
+pub struct TaskHandle<T> {
+    rx: oneshot::Receiver<T>,
+}
+
+impl<T> Future for TaskHandle<T> {
+    type Output = Result<T, IdeviceError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.rx)
+            .poll(cx)
+            .map(|r| r.map_err(|_| IdeviceError::DeviceNotFound))
+    }
+}
+
+type Events = Pin<Box<dyn Stream<Item = Result<UsbmuxdListenEvent, IdeviceError>> + Send>>;
+
+type Job = Box<dyn FnOnce(Arc<UsbmuxdProvider>) -> Pin<Box<dyn Future<Output = ()> + Send>> + Send>;
+
+/// Watches usbmuxd and keeps a provider and a task queue for each attached device.
+///
+/// A device attached over both USB and the network is tracked once, by UDID, and its
+/// provider prefers USB. Tasks for one device run one at a time in the order they were
+/// submitted, while tasks for different devices run concurrently.
+/// If the muxer connection is lost, such as when usbmuxd restarts, every device is detached
+/// and the manager listens again until it's dropped. The devices still attached are then
+/// reported again.
+pub struct DeviceManager {
+    shared: Arc<Shared>,
+    stop: StopHandle,
+}
+
+struct Shared {
+    addr: UsbmuxdAddr,
+    metadata: ClientMetadata,
+    state: Mutex<State>,
+    events: broadcast::Sender<DeviceEvent>,
+}
+
+#[derive(Default)]
+struct State {
+    registry: Registry,
+    queues: HashMap<String, Queue>,
+}
+
+struct Queue {
+    provider: Arc<Mutex<Arc<UsbmuxdProvider>>>,
+    jobs: mpsc::UnboundedSender<Job>,
+}
+
+impl DeviceManager {
+    /// Starts listening to the muxer.
+    /// The devices that are already attached are reported as the first events.
+    /// # Arguments
+    /// `addr` - The muxer to watch and connect to devices through
+    /// `metadata` - Identifies the providers' connections
+    pub async fn new(
+        addr: UsbmuxdAddr,
+        metadata: impl Into<ClientMetadata>,
+    ) -> Result<Self, IdeviceError> {
+        let metadata = metadata.into();
+        // Fail here rather than in the background if the muxer isn't running
+        let events = listen(&addr, &metadata).await?;
+
+        let (tx, _) = broadcast::channel(EVENT_CAPACITY);
+        let shared = Arc::new(Shared {
+            addr,
+            metadata,
+            state: Mutex::new(State::default()),
+            events: tx,
+        });
+        let stop = StopHandle::new();
+        tokio::spawn(run(shared.clone(), stop.clone(), events));
+
+        Ok(Self { shared, stop })
+    }
+
+    /// Subscribes to devices being attached and detached from now on.
+    /// Combine with `devices` to also see the ones already attached.
+    pub fn subscribe(&self) -> broadcast::Receiver<DeviceEvent> {
+        self.shared.events.subscribe()
+    }
+
+    /// The attached devices, each with its preferred connection
+    pub fn devices(&self) -> Vec<UsbmuxdDevice> {
+        let state = self.shared.state.lock().unwrap();
+        state.registry.preferred_all()
+    }
+
+    /// The provider for an attached device, using its preferred connection
+    pub fn provider(&self, udid: &str) -> Option<Arc<UsbmuxdProvider>> {
+        let state = self.shared.state.lock().unwrap();
+        let provider = state.queues.get(udid)?.provider.lock().unwrap().clone();
+        Some(provider)
+    }
+
+    /// Waits for a device to be attached, returning right away if it already is
+    /// # Arguments
+    /// `udid` - The device to wait for
+    /// `timeout` - How long to wait before giving up with `Timeout`
+    /// # Returns
+    /// The device with its preferred connection
+    pub async fn wait_for_device(
+        &self,
+        udid: &str,
+        timeout: Duration,
+    ) -> Result<UsbmuxdDevice, IdeviceError> {
+        // Subscribe first so an attach between the check and the wait isn't missed
+        let mut events = self.subscribe();
+        let find = || self.devices().into_iter().find(|d| d.udid == udid);
+        if let Some(dev) = find() {
+            return Ok(dev);
+        }
+        let wait = async {
+            loop {
+                match events.recv().await {
+                    Ok(DeviceEvent::Attached(dev)) | Ok(DeviceEvent::ConnectionChanged(dev))
+                        if dev.udid == udid =>
+                    {
+                        return Ok(dev);
+                    }
+                    Ok(_) => {}
+                    // Events were dropped, one of which may have been the attach
+                    Err(broadcast::error::RecvError::Lagged(_)) => {
+                        if let Some(dev) = find() {
+                            return Ok(dev);
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Closed) => {
+                        return Err(IdeviceError::NoEstablishedConnection)
+                    }
+                }
+            }
+        };
+        tokio::time::timeout(timeout, wait)
+            .await
+            .map_err(|_| IdeviceError::Timeout)?
+    }
+
+    /// Queues a task for a device. It runs after the device's earlier tasks finish.
+    /// # Arguments
+    /// `udid` - The device to run against
+    /// `task` - Called with the device's provider when its turn comes
+    /// # Returns
+    /// A handle that resolves to the task's output
+    pub fn submit<F, Fut, T>(&self, udid: &str, task: F) -> Result<TaskHandle<T>, IdeviceError>
+    where
+        F: FnOnce(Arc<UsbmuxdProvider>) -> Fut + Send + 'static,
+        Fut: Future<Output = T> + Send + 'static,
+        T: Send + 'static,
+    {
+        self.shared.submit(udid, task)
+    }
+}
+
+impl Drop for DeviceManager {
+    fn drop(&mut self) {
+        self.stop.stop();
+    }
+}
+
+impl Shared {
+    fn submit<F, Fut, T>(&self, udid: &str, task: F) -> Result<TaskHandle<T>, IdeviceError>
+    where
+        F: FnOnce(Arc<UsbmuxdProvider>) -> Fut + Send + 'static,
+        Fut: Future<Output = T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        let job: Job = Box::new(move |provider| {
+            Box::pin(async move {
+                // The submitter may have stopped waiting, which is fine
+                let _ = tx.send(task(provider).await);
+            })
+        });
+
+        let state = self.state.lock().unwrap();
+        let queue = state.queues.get(udid).ok_or(IdeviceError::DeviceNotFound)?;
+        queue
+            .jobs
+            .send(job)
+            .map_err(|_| IdeviceError::DeviceNotFound)?;
+        Ok(TaskHandle { rx })
+    }
+
+    fn handle(&self, event: UsbmuxdListenEvent) {
+        let mut state = self.state.lock().unwrap();
+        let event = match event {
+            UsbmuxdListenEvent::Connected(dev) => state.registry.attach(dev),
+            UsbmuxdListenEvent::Disconnected(id) => state.registry.detach(id),
+            UsbmuxdListenEvent::Paired(id) => state.registry.udid(id).map(DeviceEvent::Paired),
+        };
+        if let Some(event) = event {
+            self.apply(&mut state, &event);
+            // Nobody listening isn't an error
+            let _ = self.events.send(event);
+        }
+    }
+
+    fn detach_all(&self) {
+        let mut state = self.state.lock().unwrap();
+        for event in state.registry.clear() {
+            self.apply(&mut state, &event);
+            let _ = self.events.send(event);
+        }
+    }
+
+    fn apply(&self, state: &mut State, event: &DeviceEvent) {
+        match event {
+            DeviceEvent::Attached(dev) => {
+                let provider = Arc::new(Mutex::new(Arc::new(self.provider_for(dev))));
+                let (tx, rx) = mpsc::unbounded_channel();
+                tokio::spawn(work(dev.udid.clone(), provider.clone(), rx));
+                state
+                    .queues
+                    .insert(dev.udid.clone(), Queue { provider, jobs: tx });
+            }
+            DeviceEvent::ConnectionChanged(dev) => {
+                if let Some(queue) = state.queues.get(&dev.udid) {
+                    *queue.provider.lock().unwrap() = Arc::new(self.provider_for(dev));
+                }
+            }
+            DeviceEvent::Detached(udid) => {
+                // Closing the queue stops the worker after its current task
+                state.queues.remove(udid);
+            }
+            DeviceEvent::Paired(_) => {}
+        }
+    }
+
+    fn provider_for(&self, dev: &UsbmuxdDevice) -> UsbmuxdProvider {
+        dev.to_provider(self.addr.clone(), 0, self.metadata.clone())
+    }
+}
+
+async fn listen(addr: &UsbmuxdAddr, metadata: &ClientMetadata) -> Result<Events, IdeviceError> {
+    let conn = addr.connect(0).await?.with_metadata(metadata.clone());
+    // The manager has its own stop handle
+    let (events, _) = conn.listen().await?;
+    Ok(Box::pin(events))
+}
+
+async fn run(shared: Arc<Shared>, stop: StopHandle, mut events: Events) {
+    loop {
+        loop {
+            let event = tokio::select! {
+                _ = stop.stopped() => None,
+                e = events.next() => e,
+            };
+            match event {
+                Some(Ok(event)) => shared.handle(event),
+                Some(Err(e)) => {
+                    warn!("Lost the muxer connection: {e:?}");
+                    break;
+                }
+                None => break,
+            }
+        }
+        shared.detach_all();
+
+        events = loop {
+            if stop.is_stopped() {
+                return;
+            }
+            match listen(&shared.addr, &shared.metadata).await {
+                Ok(events) => break events,
+                Err(e) => debug!("Failed to listen to the muxer: {e:?}"),
+            }
+            tokio::select! {
+                _ = stop.stopped() => return,
+                _ = tokio::time::sleep(RECONNECT_INTERVAL) => {}
+            }
+        };
+    }
+}
+
+async fn work(
+    udid: String,
+    provider: Arc<Mutex<Arc<UsbmuxdProvider>>>,
+    mut jobs: mpsc::UnboundedReceiver<Job>,
+) {
+    while let Some(job) = jobs.recv().await {
+        // Jobs still buffered after a detach belong to a device that's gone
+        if jobs.is_closed() {
+            break;
+        }
+        let provider = provider.lock().unwrap().clone();
+        job(provider).await;
+    }
+    debug!("Task queue for {udid} closed");
+}
+
+/// The connections the muxer reports for each device, grouped by UDID
+#[derive(Default)]
+struct Registry {
+    devices: HashMap<String, Vec<UsbmuxdDevice>>,
+}
+
+impl Registry {
+    fn attach(&mut self, dev: UsbmuxdDevice) -> Option<DeviceEvent> {
+        let connections = self.devices.entry(dev.udid.clone()).or_default();
+        let first = connections.is_empty();
+        let before = preferred(connections).map(|d| d.device_id);
+        connections.retain(|d| d.device_id != dev.device_id);
+        connections.push(dev);
+
+        let now = preferred(connections)?;
+        match (first, before == Some(now.device_id)) {
+            (true, _) => Some(DeviceEvent::Attached(now.clone())),
+            (false, false) => Some(DeviceEvent::ConnectionChanged(now.clone())),
+            (false, true) => None,
+        }
+    }
+
+    /// The UDID of the device the connection belongs to
+    fn udid(&self, device_id: u32) -> Option<String> {
+        self.devices
+            .iter()
+            .find(|(_, c)| c.iter().any(|d| d.device_id == device_id))
+            .map(|(u, _)| u.clone())
+    }
+
+    fn detach(&mut self, device_id: u32) -> Option<DeviceEvent> {
+        let udid = self.udid(device_id)?;
+        let connections = self.devices.get_mut(&udid)?;
+        let before = preferred(connections).map(|d| d.device_id);
+        connections.retain(|d| d.device_id != device_id);
+
+        match preferred(connections) {
+            None => {
+                self.devices.remove(&udid);
+                Some(DeviceEvent::Detached(udid))
+            }
+            Some(now) if before != Some(now.device_id) => {
+                Some(DeviceEvent::ConnectionChanged(now.clone()))
+            }
+            Some(_) => None,
+        }
+    }
+
+    fn clear(&mut self) -> Vec<DeviceEvent> {
+        self.devices
+            .drain()
+            .map(|(udid, _)| DeviceEvent::Detached(udid))
+            .collect()
+    }
+
+    fn preferred_all(&self) -> Vec<UsbmuxdDevice> {
+        self.devices
+            .values()
+            .filter_map(|c| preferred(c).cloned())
+            .collect()
+    }
+}
+
+/// USB first, as it's faster and doesn't drop when the device sleeps
+fn preferred(connections: &[UsbmuxdDevice]) -> Option<&UsbmuxdDevice> {
+    connections.iter().min_by_key(|d| match d.connection_type {
+        Connection::Usb => 0,
+        Connection::Network(_) => 1,
+        Connection::Unknown(_) => 2,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use super::*;
+
+    fn device(udid: &str, device_id: u32, connection_type: Connection) -> UsbmuxdDevice {
+        UsbmuxdDevice {
+            connection_type,
+            udid: udid.to_string(),
+            device_id,
+        }
+    }
+
+    fn network() -> Connection {
+        Connection::Network(IpAddr::V4(Ipv4Addr::LOCALHOST))
+    }
+
+    #[test]
+    fn registry_prefers_usb() {
+        let mut registry = Registry::default();
+        assert!(matches!(
+            registry.attach(device("a", 1, network())),
+            Some(DeviceEvent::Attached(d)) if d.device_id == 1
+        ));
+        assert!(matches!(
+            registry.attach(device("a", 2, Connection::Usb)),
+            Some(DeviceEvent::ConnectionChanged(d)) if d.device_id == 2
+        ));
+        // Losing the network doesn't change anything while USB is there
+        assert!(registry.detach(1).is_none());
+        assert!(registry.attach(device("a", 3, network())).is_none());
+        assert!(matches!(
+            registry.detach(2),
+            Some(DeviceEvent::ConnectionChanged(d)) if d.device_id == 3
+        ));
+        assert!(matches!(registry.detach(3), Some(DeviceEvent::Detached(u)) if u == "a"));
+        assert!(registry.detach(3).is_none());
+    }
+
+    fn shared() -> Shared {
+        let (tx, _) = broadcast::channel(EVENT_CAPACITY);
+        Shared {
+            addr: UsbmuxdAddr::default(),
+            metadata: ClientMetadata::new("test"),
+            state: Mutex::new(State::default()),
+            events: tx,
+        }
+    }
+
+    #[tokio::test]
+    async fn tasks_run_in_order_and_stop_on_detach() {
+        let shared = shared();
+        let mut events = shared.events.subscribe();
+        assert!(shared.submit("a", |_| async {}).is_err());
+
+        shared.handle(UsbmuxdListenEvent::Connected(device(
+            "a",
+            1,
+            Connection::Usb,
+        )));
+        assert!(matches!(events.recv().await, Ok(DeviceEvent::Attached(_))));
+
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let (release, gate) = oneshot::channel::<()>();
+        let first = {
+            let order = order.clone();
+            shared
+                .submit("a", move |p| async move {
+                    let _ = gate.await;
+                    order.lock().unwrap().push(1);
+                    p.udid.clone()
+                })
+                .unwrap()
+        };
+        let second = {
+            let order = order.clone();
+            shared
+                .submit("a", move |_| async move { order.lock().unwrap().push(2) })
+                .unwrap()
+        };
+        release.send(()).unwrap();
+        assert_eq!(first.await.unwrap(), "a");
+        second.await.unwrap();
+        assert_eq!(*order.lock().unwrap(), vec![1, 2]);
+
+        // A task still queued when the device goes away never runs
+        let (started, running) = oneshot::channel::<()>();
+        let (release, gate) = oneshot::channel::<()>();
+        let busy = shared
+            .submit("a", |_| async move {
+                started.send(()).unwrap();
+                gate.await
+            })
+            .unwrap();
+        let queued = shared.submit("a", |_| async {}).unwrap();
+        running.await.unwrap();
+        shared.handle(UsbmuxdListenEvent::Disconnected(1));
+        assert!(matches!(events.recv().await, Ok(DeviceEvent::Detached(_))));
+        release.send(()).unwrap();
+        assert!(busy.await.is_ok());
+        assert!(matches!(queued.await, Err(IdeviceError::DeviceNotFound)));
+        assert!(shared.submit("a", |_| async {}).is_err());
+    }
+
+    #[tokio::test]
+    async fn wait_for_device_and_paired() {
+        let manager = DeviceManager {
+            shared: Arc::new(shared()),
+            stop: StopHandle::new(),
+        };
+        let mut events = manager.subscribe();
+        assert!(matches!(
+            manager
+                .wait_for_device("a", Duration::from_millis(10))
+                .await,
+            Err(IdeviceError::Timeout)
+        ));
+
+        let waiting = manager.wait_for_device("a", Duration::from_secs(5));
+        let attach = async {
+            tokio::task::yield_now().await;
+            manager
+                .shared
+                .handle(UsbmuxdListenEvent::Connected(device("a", 1, network())));
+        };
+        let (dev, _) = tokio::join!(waiting, attach);
+        assert_eq!(dev.unwrap().device_id, 1);
+        // Already attached
+        assert!(manager.wait_for_device("a", Duration::ZERO).await.is_ok());
+
+        manager.shared.handle(UsbmuxdListenEvent::Paired(1));
+        manager.shared.handle(UsbmuxdListenEvent::Paired(7));
+        assert!(matches!(events.recv().await, Ok(DeviceEvent::Attached(_))));
+        assert!(matches!(events.recv().await, Ok(DeviceEvent::Paired(u)) if u == "a"));
+        assert!(events.try_recv().is_err());
+    }
+}
@@ -0,0 +1,330 @@
+// Jackson Coxson
+// Places to forward device log messages to, for long running collection
+
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use flate2::{write::GzEncoder, Compression};
+use futures::{Stream, StreamExt};
+use log::warn;
+use tokio::sync::mpsc;
+
+use crate::IdeviceError;
+
+/// A log message relayed from the device
+pub trait LogRecord: Send + 'static {
+    fn level(&self) -> log::Level;
+
+    /// The name of the process that logged the message, if known
+    fn process(&self) -> Option<&str>;
+
+    fn message(&self) -> &str;
+
+    /// The message as a single line, as it's written to files
+    fn to_line(&self) -> String;
+}
+
+#[cfg(feature = "os_trace_relay")]
+impl LogRecord for crate::os_trace_relay::LogEntry {
+    fn level(&self) -> log::Level {
+        use crate::os_trace_relay::LogLevel;
+        match self.level {
+            LogLevel::Error | LogLevel::Fault => log::Level::Error,
+            LogLevel::Debug => log::Level::Debug,
+            LogLevel::Notice | LogLevel::Info | LogLevel::UserAction | LogLevel::Unknown(_) => {
+                log::Level::Info
+            }
+        }
+    }
+
+    fn process(&self) -> Option<&str> {
+        Some(self.process_name())
+    }
+
+    fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// The entry as NDJSON
+    fn to_line(&self) -> String {
+        let mut line = Vec::new();
+        // Writing to a Vec can't fail
+        self.write_ndjson(&mut line).unwrap();
+        line.pop();
+        String::from_utf8_lossy(&line).to_string()
+    }
+}
+
+/// Syslog lines, such as `Oct 14 12:00:00 iPhone SpringBoard[1] <Notice>: message`
+impl LogRecord for String {
+    fn level(&self) -> log::Level {
+        if self.contains("<Error>") || self.contains("<Fault>") {
+            log::Level::Error
+        } else if self.contains("<Warning>") {
+            log::Level::Warn
+        } else if self.contains("<Debug>") {
+            log::Level::Debug
+        } else {
+            log::Level::Info
+        }
+    }
+
+    fn process(&self) -> Option<&str> {
+        // The process is the fifth field, followed by the library and pid, as in
+        // `SpringBoard(UIKitCore)[58]`
+        let field = self.split_whitespace().nth(4)?;
+        field.split(['[', '(']).next().filter(|p| !p.is_empty())
+    }
+
+    fn message(&self) -> &str {
+        self
+    }
+
+    fn to_line(&self) -> String {
+        self.clone()
+    }
+}
+
+/// Somewhere log messages can be forwarded to with `forward`
+pub trait LogSink<R: LogRecord>: Send {
+    fn write(&mut self, record: &R) -> Result<(), IdeviceError>;
+
+    /// Called when forwarding ends
+    fn flush(&mut self) -> Result<(), IdeviceError> {
+        Ok(())
+    }
+}
+
+/// Writes log messages to a sink until the stream ends.
+/// Returns the error that ended the stream or the sink, if any.
+pub async fn forward<R: LogRecord>(
+    stream: impl Stream<Item = Result<R, IdeviceError>>,
+    mut sink: impl LogSink<R>,
+) -> Result<(), IdeviceError> {
+    let mut stream = Box::pin(stream);
+    while let Some(record) = stream.next().await {
+        let res = record.and_then(|r| sink.write(&r));
+        if let Err(e) = res {
+            warn!("Log forwarding stopped: {e:?}");
+            sink.flush()?;
+            return Err(e);
+        }
+    }
+    sink.flush()
+}
+
+/// When and how `RotatingFileSink` rotates its file
+#[derive(Debug, Clone, Copy)]
+pub struct RotationConfig {
+    /// The file is rotated once it's at least this many bytes
+    pub max_size: u64,
+    /// How many rotated files to keep, named `<path>.1` for the newest up to `<path>.<max_files>`
+    pub max_files: usize,
+    /// Whether to gzip rotated files, which adds `.gz` to their names
+    pub compress: bool,
+}
+
+impl Default for RotationConfig {
+    fn default() -> Self {
+        Self {
+            max_size: 10 * 1024 * 1024,
+            max_files: 5,
+            compress: true,
+        }
+    }
+}
+
+/// Appends messages to a file, one per line, rotating it as it grows.
+/// Writes are buffered, and flushed when rotating and when forwarding ends.
+pub struct RotatingFileSink {
+    path: PathBuf,
+    config: RotationConfig,
+    file: BufWriter<File>,
+    size: u64,
+}
+
+impl RotatingFileSink {
+    /// Opens the file, appending to it if it exists
+    /// # Arguments
+    /// `path` - The file to write to. Rotated files are written next to it.
+    /// `config` - When to rotate the file
+    pub fn new(path: impl Into<PathBuf>, config: RotationConfig) -> Result<Self, IdeviceError> {
+        let path = path.into();
+        let file = open_append(&path)?;
+        let size = file.get_ref().metadata()?.len();
+        Ok(Self {
+            path,
+            config,
+            file,
+            size,
+        })
+    }
+}
+
+impl<R: LogRecord> LogSink<R> for RotatingFileSink {
+    fn write(&mut self, record: &R) -> Result<(), IdeviceError> {
+        let line = record.to_line();
+        self.file.write_all(line.as_bytes())?;
+        self.file.write_all(b"\n")?;
+        self.size += line.len() as u64 + 1;
+
+        if self.size >= self.config.max_size {
+            self.file.flush()?;
+            rotate(&self.path, &self.config)?;
+            self.file = open_append(&self.path)?;
+            self.size = 0;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), IdeviceError> {
+        Ok(self.file.flush()?)
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<BufWriter<File>> {
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    Ok(BufWriter::new(file))
+}
+
+/// The name of the `n`th rotated file
+pub(crate) fn rotated_path(path: &Path, n: usize, compress: bool) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{n}"));
+    if compress {
+        name.push(".gz");
+    }
+    PathBuf::from(name)
+}
+
+/// Shifts the rotated files up by one, dropping the oldest, and moves the file to `<path>.1`
+fn rotate(path: &Path, config: &RotationConfig) -> io::Result<()> {
+    if config.max_files == 0 {
+        return std::fs::remove_file(path);
+    }
+    let rotated = |n| rotated_path(path, n, config.compress);
+    match std::fs::remove_file(rotated(config.max_files)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    for n in (1..config.max_files).rev() {
+        match std::fs::rename(rotated(n), rotated(n + 1)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+
+    if config.compress {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut encoder = GzEncoder::new(
+            BufWriter::new(File::create(rotated(1))?),
+            Compression::default(),
+        );
+        io::copy(&mut reader, &mut encoder)?;
+        encoder.finish()?.into_inner().map_err(|e| e.into_error())?;
+        std::fs::remove_file(path)
+    } else {
+        std::fs::rename(path, rotated(1))
+    }
+}
+
+/// Sends messages to a channel. When the channel is full, messages are dropped
+/// rather than holding up the device's connection.
+pub struct ChannelSink<R> {
+    sender: mpsc::Sender<R>,
+    dropped: u64,
+}
+
+impl<R> ChannelSink<R> {
+    pub fn new(sender: mpsc::Sender<R>) -> Self {
+        Self { sender, dropped: 0 }
+    }
+
+    /// How many messages were dropped because the channel was full
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
+
+impl<R: LogRecord + Clone> LogSink<R> for ChannelSink<R> {
+    fn write(&mut self, record: &R) -> Result<(), IdeviceError> {
+        match self.sender.try_send(record.clone()) {
+            Ok(()) => Ok(()),
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                if self.dropped == 0 {
+                    warn!("Log channel is full, dropping messages");
+                }
+                self.dropped += 1;
+                Ok(())
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                Err(io::Error::new(io::ErrorKind::BrokenPipe, "log receiver was dropped").into())
+            }
+        }
+    }
+}
+
+/// Emits messages as `tracing` events with the target `idevice::device_log`,
+/// recording the process as a field
+#[cfg(feature = "tracing")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TracingSink;
+
+#[cfg(feature = "tracing")]
+impl<R: LogRecord> LogSink<R> for TracingSink {
+    fn write(&mut self, record: &R) -> Result<(), IdeviceError> {
+        let process = record.process().unwrap_or_default();
+        let message = record.message();
+        match record.level() {
+            log::Level::Error => {
+                tracing::error!(target: "idevice::device_log", process, "{message}")
+            }
+            log::Level::Warn => {
+                tracing::warn!(target: "idevice::device_log", process, "{message}")
+            }
+            log::Level::Info => {
+                tracing::info!(target: "idevice::device_log", process, "{message}")
+            }
+            log::Level::Debug => {
+                tracing::debug!(target: "idevice::device_log", process, "{message}")
+            }
+            log::Level::Trace => {
+                tracing::trace!(target: "idevice::device_log", process, "{message}")
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn channel_sink_drops_when_full() {
+        let (tx, mut rx) = mpsc::channel(2);
+        let mut sink = ChannelSink::new(tx);
+        for line in ["a", "b", "c"] {
+            sink.write(&line.to_string()).unwrap();
+        }
+        assert_eq!(sink.dropped(), 1);
+        assert_eq!(rx.recv().await.as_deref(), Some("a"));
+
+        drop(rx);
+        assert!(sink.write(&"d".to_string()).is_err());
+    }
+
+    #[test]
+    fn syslog_lines() {
+        let line = "Oct  4 12:00:00 iPhone SpringBoard(UIKitCore)[58] <Error>: oh no".to_string();
+        assert_eq!(line.process(), Some("SpringBoard"));
+        assert_eq!(line.level(), log::Level::Error);
+        assert_eq!("garbage".to_string().process(), None);
+    }
+}
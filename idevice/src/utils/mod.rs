@@ -0,0 +1,50 @@
+// Jackson Coxson
+
+#[cfg(feature = "mounter")]
+pub mod developer_mode;
+#[cfg(feature = "device_manager")]
+pub mod device_manager;
+#[cfg(any(feature = "os_trace_relay", feature = "syslog_relay"))]
+pub mod log_sink;
+pub mod models;
+pub mod network_identity;
+#[cfg(feature = "mounter")]
+pub mod preflight;
+#[cfg(feature = "os_trace_relay")]
+pub mod processes;
+pub mod redact;
+pub mod schema;
+pub mod settings;
+#[cfg(feature = "sideload")]
+pub mod sideload;
+#[cfg(all(feature = "installation_proxy", feature = "misagent"))]
+pub mod signing;
+#[cfg(any(
+    feature = "crashreportcopymobile",
+    feature = "heartbeat",
+    feature = "notification_proxy",
+    feature = "os_trace_relay",
+    feature = "pcapd",
+    feature = "screenshotr",
+    feature = "syslog_relay",
+    feature = "usbmuxd"
+))]
+pub mod stream;
+#[cfg(feature = "crashreportcopymobile")]
+pub mod sysdiagnose;
+#[cfg(feature = "syslog_relay")]
+pub mod syslog;
+#[cfg(feature = "transfer")]
+pub mod transfer;
+#[cfg(feature = "usbmuxd")]
+pub mod trust;
+#[cfg(feature = "usbmuxd")]
+pub mod wifi;
+
+pub fn plist_to_bytes(p: &plist::Dictionary) -> Vec<u8> {
+    let buf = Vec::new();
+    let mut writer = std::io::BufWriter::new(buf);
+    plist::to_writer_xml(&mut writer, &p).unwrap();
+
+    writer.into_inner().unwrap()
+}
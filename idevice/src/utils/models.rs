@@ -0,0 +1,128 @@
+// Jackson Coxson
+// Human readable names for the ProductType lockdown reports, such as iPhone15,3
+
+use crate::capabilities::DeviceClass;
+
+/// The native resolution of a display, in pixels with the device held upright
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Screen {
+    pub width: u32,
+    pub height: u32,
+    /// Pixels per point
+    pub scale: u8,
+    pub ppi: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceModel {
+    pub product_type: &'static str,
+    /// The marketing name, such as "iPhone 14 Pro Max"
+    pub name: &'static str,
+    pub chip: &'static str,
+    pub screen: Screen,
+}
+
+impl DeviceModel {
+    /// The class this product type belongs to, taken from its prefix
+    pub fn class(&self) -> DeviceClass {
+        let prefix = self
+            .product_type
+            .trim_end_matches(|c: char| c.is_ascii_digit() || c == ',');
+        DeviceClass::from(prefix)
+    }
+}
+
+/// Looks up a product type, such as the `ProductType` lockdown value.
+/// Product types newer than this table return `None`.
+pub fn lookup(product_type: &str) -> Option<&'static DeviceModel> {
+    MODELS.iter().find(|m| m.product_type == product_type)
+}
+
+macro_rules! models {
+    ($($product_type:literal => $name:literal, $chip:literal, $width:literal x $height:literal @ $scale:literal, $ppi:literal;)*) => {
+        const MODELS: &[DeviceModel] = &[$(DeviceModel {
+            product_type: $product_type,
+            name: $name,
+            chip: $chip,
+            screen: Screen {
+                width: $width,
+                height: $height,
+                scale: $scale,
+                ppi: $ppi,
+            },
+        }),*];
+    };
+}
+
+// Versions of a model for different carriers or regions share a name
+models! {
+    "iPhone10,1" => "iPhone 8", "A11 Bionic", 750 x 1334 @ 2, 326;
+    "iPhone10,4" => "iPhone 8", "A11 Bionic", 750 x 1334 @ 2, 326;
+    "iPhone10,2" => "iPhone 8 Plus", "A11 Bionic", 1080 x 1920 @ 3, 401;
+    "iPhone10,5" => "iPhone 8 Plus", "A11 Bionic", 1080 x 1920 @ 3, 401;
+    "iPhone10,3" => "iPhone X", "A11 Bionic", 1125 x 2436 @ 3, 458;
+    "iPhone10,6" => "iPhone X", "A11 Bionic", 1125 x 2436 @ 3, 458;
+    "iPhone11,2" => "iPhone XS", "A12 Bionic", 1125 x 2436 @ 3, 458;
+    "iPhone11,4" => "iPhone XS Max", "A12 Bionic", 1242 x 2688 @ 3, 458;
+    "iPhone11,6" => "iPhone XS Max", "A12 Bionic", 1242 x 2688 @ 3, 458;
+    "iPhone11,8" => "iPhone XR", "A12 Bionic", 828 x 1792 @ 2, 326;
+    "iPhone12,1" => "iPhone 11", "A13 Bionic", 828 x 1792 @ 2, 326;
+    "iPhone12,3" => "iPhone 11 Pro", "A13 Bionic", 1125 x 2436 @ 3, 458;
+    "iPhone12,5" => "iPhone 11 Pro Max", "A13 Bionic", 1242 x 2688 @ 3, 458;
+    "iPhone12,8" => "iPhone SE (2nd generation)", "A13 Bionic", 750 x 1334 @ 2, 326;
+    "iPhone13,1" => "iPhone 12 mini", "A14 Bionic", 1080 x 2340 @ 3, 476;
+    "iPhone13,2" => "iPhone 12", "A14 Bionic", 1170 x 2532 @ 3, 460;
+    "iPhone13,3" => "iPhone 12 Pro", "A14 Bionic", 1170 x 2532 @ 3, 460;
+    "iPhone13,4" => "iPhone 12 Pro Max", "A14 Bionic", 1284 x 2778 @ 3, 458;
+    "iPhone14,4" => "iPhone 13 mini", "A15 Bionic", 1080 x 2340 @ 3, 476;
+    "iPhone14,5" => "iPhone 13", "A15 Bionic", 1170 x 2532 @ 3, 460;
+    "iPhone14,2" => "iPhone 13 Pro", "A15 Bionic", 1170 x 2532 @ 3, 460;
+    "iPhone14,3" => "iPhone 13 Pro Max", "A15 Bionic", 1284 x 2778 @ 3, 458;
+    "iPhone14,6" => "iPhone SE (3rd generation)", "A15 Bionic", 750 x 1334 @ 2, 326;
+    "iPhone14,7" => "iPhone 14", "A15 Bionic", 1170 x 2532 @ 3, 460;
+    "iPhone14,8" => "iPhone 14 Plus", "A15 Bionic", 1284 x 2778 @ 3, 458;
+    "iPhone15,2" => "iPhone 14 Pro", "A16 Bionic", 1179 x 2556 @ 3, 460;
+    "iPhone15,3" => "iPhone 14 Pro Max", "A16 Bionic", 1290 x 2796 @ 3, 460;
+    "iPhone15,4" => "iPhone 15", "A16 Bionic", 1179 x 2556 @ 3, 460;
+    "iPhone15,5" => "iPhone 15 Plus", "A16 Bionic", 1290 x 2796 @ 3, 460;
+    "iPhone16,1" => "iPhone 15 Pro", "A17 Pro", 1179 x 2556 @ 3, 460;
+    "iPhone16,2" => "iPhone 15 Pro Max", "A17 Pro", 1290 x 2796 @ 3, 460;
+    "iPhone17,3" => "iPhone 16", "A18", 1179 x 2556 @ 3, 460;
+    "iPhone17,4" => "iPhone 16 Plus", "A18", 1290 x 2796 @ 3, 460;
+    "iPhone17,1" => "iPhone 16 Pro", "A18 Pro", 1206 x 2622 @ 3, 460;
+    "iPhone17,2" => "iPhone 16 Pro Max", "A18 Pro", 1320 x 2868 @ 3, 460;
+    "iPhone17,5" => "iPhone 16e", "A18", 1170 x 2532 @ 3, 460;
+    "iPod9,1" => "iPod touch (7th generation)", "A10 Fusion", 640 x 1136 @ 2, 326;
+    "iPad12,1" => "iPad (9th generation)", "A13 Bionic", 1620 x 2160 @ 2, 264;
+    "iPad12,2" => "iPad (9th generation)", "A13 Bionic", 1620 x 2160 @ 2, 264;
+    "iPad13,18" => "iPad (10th generation)", "A14 Bionic", 1640 x 2360 @ 2, 264;
+    "iPad13,19" => "iPad (10th generation)", "A14 Bionic", 1640 x 2360 @ 2, 264;
+    "iPad13,1" => "iPad Air (4th generation)", "A14 Bionic", 1640 x 2360 @ 2, 264;
+    "iPad13,2" => "iPad Air (4th generation)", "A14 Bionic", 1640 x 2360 @ 2, 264;
+    "iPad13,16" => "iPad Air (5th generation)", "M1", 1640 x 2360 @ 2, 264;
+    "iPad13,17" => "iPad Air (5th generation)", "M1", 1640 x 2360 @ 2, 264;
+    "iPad14,1" => "iPad mini (6th generation)", "A15 Bionic", 1488 x 2266 @ 2, 326;
+    "iPad14,2" => "iPad mini (6th generation)", "A15 Bionic", 1488 x 2266 @ 2, 326;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookup_and_class() {
+        let model = lookup("iPhone15,3").unwrap();
+        assert_eq!(model.name, "iPhone 14 Pro Max");
+        assert_eq!(model.class(), DeviceClass::IPhone);
+        assert_eq!(lookup("iPad14,1").unwrap().class(), DeviceClass::IPad);
+        assert!(lookup("iPhone99,1").is_none());
+
+        for (i, m) in MODELS.iter().enumerate() {
+            assert!(
+                !MODELS[..i].iter().any(|o| o.product_type == m.product_type),
+                "{} is listed twice",
+                m.product_type
+            );
+        }
+    }
+}
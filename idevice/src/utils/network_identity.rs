@@ -0,0 +1,166 @@
+// Jackson Coxson
+// The locale, region and carrier a device is set up with, for inventory tooling.
+// A lockdown session must already be started, as most carrier values are hidden without one.
+
+use crate::{
+    lockdownd::{LockdownDomain, LockdowndClient},
+    utils::schema::FromPlist,
+    IdeviceError,
+};
+
+/// The state of the SIM, from the `SIMStatus` value
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SimStatus {
+    Ready,
+    NotInserted,
+    /// Waiting for the SIM's PIN or PUK
+    Locked,
+    Unknown(String),
+}
+
+impl From<&str> for SimStatus {
+    fn from(value: &str) -> Self {
+        match value {
+            "kCTSIMSupportSIMStatusReady" => Self::Ready,
+            "kCTSIMSupportSIMStatusNotInserted" => Self::NotInserted,
+            "kCTSIMSupportSIMStatusPINLocked" | "kCTSIMSupportSIMStatusPUKLocked" => Self::Locked,
+            _ => Self::Unknown(value.to_string()),
+        }
+    }
+}
+
+/// An entry of `CarrierBundleInfoArray`, one per SIM the device has a carrier bundle for
+#[derive(FromPlist, Debug, Clone, PartialEq, Eq)]
+pub struct CarrierBundle {
+    /// Such as `com.apple.Verizon_US`
+    #[plist(rename = "CFBundleIdentifier")]
+    pub bundle_id: Option<String>,
+    #[plist(rename = "CFBundleVersion")]
+    pub version: Option<String>,
+    /// Mobile country code
+    #[plist(rename = "MCC")]
+    pub mcc: Option<String>,
+    /// Mobile network code
+    #[plist(rename = "MNC")]
+    pub mnc: Option<String>,
+    #[plist(rename = "IntegratedCircuitCardIdentity")]
+    pub iccid: Option<String>,
+    #[plist(rename = "InternationalMobileSubscriberIdentity")]
+    pub imsi: Option<String>,
+}
+
+/// The values read from the root domain
+#[derive(FromPlist)]
+#[plist(rename_all = "PascalCase")]
+struct RootValues {
+    region_info: Option<String>,
+    #[plist(rename = "SIMStatus")]
+    sim_status: Option<String>,
+    phone_number: Option<String>,
+    #[plist(rename = "InternationalMobileEquipmentIdentity")]
+    imei: Option<String>,
+    #[plist(rename = "CarrierBundleInfoArray", default)]
+    carrier_bundles: Vec<CarrierBundle>,
+}
+
+/// The values read from the `com.apple.international` domain
+#[derive(FromPlist)]
+#[plist(rename_all = "PascalCase")]
+struct InternationalValues {
+    locale: Option<String>,
+    language: Option<String>,
+}
+
+/// Devices without a cellular modem have no SIM or carrier values
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NetworkIdentity {
+    /// The region format, such as `en_US`
+    pub locale: Option<String>,
+    /// Such as `en`
+    pub language: Option<String>,
+    /// The region the device was sold for, such as `LL/A`
+    pub region_info: Option<String>,
+    pub sim_status: Option<SimStatus>,
+    pub phone_number: Option<String>,
+    pub imei: Option<String>,
+    pub carrier_bundles: Vec<CarrierBundle>,
+}
+
+impl NetworkIdentity {
+    fn from_values(
+        root: &plist::Dictionary,
+        international: &plist::Dictionary,
+    ) -> Result<Self, IdeviceError> {
+        let root = RootValues::from_plist(root)?;
+        let international = InternationalValues::from_plist(international)?;
+        Ok(Self {
+            locale: international.locale,
+            language: international.language,
+            region_info: root.region_info,
+            sim_status: root.sim_status.as_deref().map(SimStatus::from),
+            phone_number: root.phone_number,
+            imei: root.imei,
+            carrier_bundles: root.carrier_bundles,
+        })
+    }
+
+    /// The region of the locale, such as `US` for `en_US` or `CN` for `zh-Hans_CN`
+    pub fn region_code(&self) -> Option<&str> {
+        self.locale
+            .as_deref()?
+            .rsplit_once('_')
+            .map(|(_, region)| region)
+            .filter(|r| !r.is_empty())
+    }
+}
+
+/// Reads the device's locale, region and carrier values
+/// # Arguments
+/// `lockdown` - A client with a session started
+pub async fn network_identity(
+    lockdown: &mut LockdowndClient,
+) -> Result<NetworkIdentity, IdeviceError> {
+    let root = lockdown.get_all_values().await?;
+    let international = lockdown
+        .get_domain_values(LockdownDomain::International)
+        .await?;
+    NetworkIdentity::from_values(&root, &international)
+}
+
+#[cfg(test)]
+mod tests {
+    use plist::{Dictionary, Value};
+
+    use super::*;
+
+    #[test]
+    fn reads_identity() {
+        let mut bundle = Dictionary::new();
+        bundle.insert("CFBundleIdentifier".into(), "com.apple.Verizon_US".into());
+        bundle.insert("MCC".into(), "311".into());
+        bundle.insert("MNC".into(), "480".into());
+
+        let mut root = Dictionary::new();
+        root.insert("RegionInfo".into(), "LL/A".into());
+        root.insert("SIMStatus".into(), "kCTSIMSupportSIMStatusReady".into());
+        root.insert(
+            "CarrierBundleInfoArray".into(),
+            Value::Array(vec![bundle.into()]),
+        );
+        let mut international = Dictionary::new();
+        international.insert("Locale".into(), "zh-Hans_CN".into());
+
+        let identity = NetworkIdentity::from_values(&root, &international).unwrap();
+        assert_eq!(identity.region_info.as_deref(), Some("LL/A"));
+        assert_eq!(identity.region_code(), Some("CN"));
+        assert_eq!(identity.sim_status, Some(SimStatus::Ready));
+        assert_eq!(identity.carrier_bundles[0].mcc.as_deref(), Some("311"));
+        assert_eq!(identity.carrier_bundles[0].iccid, None);
+        assert_eq!(identity.phone_number, None);
+
+        let identity =
+            NetworkIdentity::from_values(&Dictionary::new(), &Dictionary::new()).unwrap();
+        assert!(identity.carrier_bundles.is_empty());
+        assert_eq!(identity.region_code(), None);
+    }
+}
@@ -0,0 +1,107 @@
+// Jackson Coxson
+// Reports what's left to set up before developer services can be used
+
+use log::debug;
+
+use crate::{
+    capabilities::DeviceCapabilities,
+    lockdownd::LockdowndClient,
+    mounter::ImageMounter,
+    provider::IdeviceProvider,
+    utils::developer_mode::{status_from_lockdown, DeveloperModeStatus},
+    IdeviceError, IdeviceService,
+};
+
+/// The state of one step of setup
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Check {
+    Ready,
+    NotReady,
+    /// The step doesn't exist on this device
+    NotApplicable,
+    /// An earlier step must be completed before this one can be checked
+    Unknown,
+}
+
+impl From<bool> for Check {
+    fn from(value: bool) -> Self {
+        match value {
+            true => Self::Ready,
+            false => Self::NotReady,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DeveloperReadiness {
+    /// Whether the device accepts the provider's pairing file
+    pub paired: Check,
+    pub developer_mode: Check,
+    pub ddi_mounted: Check,
+    /// Whether developer services are only reachable through a CoreDevice tunnel.
+    /// `None` until the device is paired and its version can be read.
+    pub tunnel_required: Option<bool>,
+    pub capabilities: Option<DeviceCapabilities>,
+}
+
+impl DeveloperReadiness {
+    /// Whether every applicable step is done
+    pub fn is_ready(&self) -> bool {
+        [self.paired, self.developer_mode, self.ddi_mounted]
+            .iter()
+            .all(|c| matches!(c, Check::Ready | Check::NotApplicable))
+    }
+}
+
+/// Checks each setup step in order, stopping at the first that can't be checked yet
+pub async fn developer_ready(
+    provider: &dyn IdeviceProvider,
+) -> Result<DeveloperReadiness, IdeviceError> {
+    let mut readiness = DeveloperReadiness {
+        paired: Check::Unknown,
+        developer_mode: Check::Unknown,
+        ddi_mounted: Check::Unknown,
+        tunnel_required: None,
+        capabilities: None,
+    };
+
+    let mut lockdown = LockdowndClient::connect(provider).await?;
+    let pairing_file = match provider.get_pairing_file().await {
+        Ok(p) => p,
+        Err(e) => {
+            debug!("No pairing file available: {e:?}");
+            readiness.paired = Check::NotReady;
+            return Ok(readiness);
+        }
+    };
+    readiness.paired = lockdown.validate_pair(&pairing_file).await?.into();
+    if readiness.paired != Check::Ready {
+        return Ok(readiness);
+    }
+    lockdown.start_session(&pairing_file).await?;
+
+    let capabilities = DeviceCapabilities::from_lockdown(&mut lockdown).await?;
+    readiness.tunnel_required = Some(!capabilities.developer_services_over_lockdown());
+    readiness.developer_mode =
+        match status_from_lockdown(&mut lockdown, &capabilities, provider).await? {
+            DeveloperModeStatus::Enabled => Check::Ready,
+            DeveloperModeStatus::Disabled => Check::NotReady,
+            DeveloperModeStatus::Unsupported => Check::NotApplicable,
+        };
+
+    if readiness.developer_mode != Check::NotReady {
+        let image_type = match capabilities.needs_personalized_ddi() {
+            true => "Personalized",
+            false => "Developer",
+        };
+        let mut mounter = ImageMounter::connect(provider).await?;
+        readiness.ddi_mounted = match mounter.lookup_image(image_type).await {
+            Ok(_) => Check::Ready,
+            Err(IdeviceError::NotFound) => Check::NotReady,
+            Err(e) => return Err(e),
+        };
+    }
+
+    readiness.capabilities = Some(capabilities);
+    Ok(readiness)
+}
@@ -0,0 +1,53 @@
+// Jackson Coxson
+// Process listing that degrades gracefully when instruments can't be used
+
+use crate::{
+    os_trace_relay::OsTraceRelayClient, provider::IdeviceProvider, IdeviceError, IdeviceService,
+};
+
+/// Where a process table came from, which decides how much of it can be relied on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessSource {
+    /// os_trace_relay's PID list, which only has PIDs and names
+    OsTrace,
+}
+
+impl ProcessSource {
+    /// Whether fields other than the PID and name are missing or guessed
+    pub fn is_approximate(&self) -> bool {
+        match self {
+            Self::OsTrace => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProcessEntry {
+    pub pid: u64,
+    /// The executable name. Empty if the device didn't report one.
+    pub name: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct ProcessList {
+    pub source: ProcessSource,
+    /// Sorted by PID
+    pub processes: Vec<ProcessEntry>,
+}
+
+/// Lists running processes without needing a developer disk image
+pub async fn list_processes(provider: &dyn IdeviceProvider) -> Result<ProcessList, IdeviceError> {
+    let mut os_trace = OsTraceRelayClient::connect(provider).await?;
+    let mut processes = os_trace
+        .get_pid_list()
+        .await?
+        .into_iter()
+        .map(|(pid, name)| ProcessEntry { pid, name })
+        .collect::<Vec<_>>();
+    processes.sort_by_key(|p| p.pid);
+
+    Ok(ProcessList {
+        source: ProcessSource::OsTrace,
+        processes,
+    })
+}
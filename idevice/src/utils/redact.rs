@@ -0,0 +1,90 @@
+// Jackson Coxson
+// Masks secrets in plists before they're logged
+
+use std::sync::RwLock;
+
+use plist::{Dictionary, Value};
+
+/// Decides whether the value of a key should be masked in logs
+pub type RedactionHook = Box<dyn Fn(&str, &Value) -> bool + Send + Sync>;
+
+static HOOK: RwLock<Option<RedactionHook>> = RwLock::new(None);
+
+/// Keys masked when no hook is set
+pub const SENSITIVE_KEYS: &[&str] = &[
+    "AppleID",
+    "DSPersonID",
+    "DeviceCertificate",
+    "EscrowBag",
+    "HostCertificate",
+    "HostPrivateKey",
+    "PairRecordData",
+    "Password",
+    "RootCertificate",
+    "RootPrivateKey",
+];
+
+const MASK: &str = "<redacted>";
+
+/// Whether a key is one of the `SENSITIVE_KEYS`.
+/// Custom hooks can call this to extend the defaults instead of replacing them.
+pub fn is_sensitive_key(key: &str) -> bool {
+    SENSITIVE_KEYS.contains(&key)
+}
+
+/// Replaces how keys are chosen for masking, or restores the defaults with `None`
+pub fn set_redaction_hook(hook: Option<RedactionHook>) {
+    *HOOK.write().unwrap() = hook;
+}
+
+/// Copies a dictionary with sensitive values masked, at any depth
+pub fn redacted(dict: &Dictionary) -> Dictionary {
+    let hook = HOOK.read().unwrap();
+    let should_mask = |k: &str, v: &Value| match hook.as_ref() {
+        Some(hook) => hook(k, v),
+        None => is_sensitive_key(k),
+    };
+    redact_dict(dict, &should_mask)
+}
+
+fn redact_dict(dict: &Dictionary, should_mask: &dyn Fn(&str, &Value) -> bool) -> Dictionary {
+    dict.iter()
+        .map(|(k, v)| {
+            let v = match should_mask(k, v) {
+                true => Value::String(MASK.to_string()),
+                false => redact_value(v, should_mask),
+            };
+            (k.clone(), v)
+        })
+        .collect()
+}
+
+fn redact_value(value: &Value, should_mask: &dyn Fn(&str, &Value) -> bool) -> Value {
+    match value {
+        Value::Dictionary(d) => Value::Dictionary(redact_dict(d, should_mask)),
+        Value::Array(a) => Value::Array(a.iter().map(|v| redact_value(v, should_mask)).collect()),
+        v => v.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn masks_nested_keys() {
+        let mut record = Dictionary::new();
+        record.insert("HostPrivateKey".into(), Value::Data(vec![1, 2, 3]));
+        record.insert("HostID".into(), "host".into());
+        let mut dict = Dictionary::new();
+        dict.insert("PairRecord".into(), Value::Dictionary(record));
+
+        let res = redacted(&dict);
+        let record = res.get("PairRecord").unwrap().as_dictionary().unwrap();
+        assert_eq!(
+            record.get("HostPrivateKey").unwrap().as_string(),
+            Some(MASK)
+        );
+        assert_eq!(record.get("HostID").unwrap().as_string(), Some("host"));
+    }
+}
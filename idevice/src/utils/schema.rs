@@ -0,0 +1,286 @@
+// Jackson Coxson
+// Typed conversions from the plist dictionaries services respond with
+
+use std::fmt::Display;
+
+use thiserror::Error;
+
+pub use idevice_macros::FromPlist;
+pub use plist::{Dictionary, Value};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SchemaErrorKind {
+    /// A required key wasn't present
+    Missing,
+    /// The value was present but was the wrong plist type
+    WrongType {
+        expected: &'static str,
+        found: &'static str,
+    },
+}
+
+impl Display for SchemaErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Missing => write!(f, "missing key"),
+            Self::WrongType { expected, found } => {
+                write!(f, "expected {expected}, found {found}")
+            }
+        }
+    }
+}
+
+/// A failure converting a plist into a typed struct.
+/// The path is the chain of keys and array indices leading to the bad value.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("{kind} at `{path}`")]
+pub struct SchemaError {
+    pub path: String,
+    pub kind: SchemaErrorKind,
+}
+
+impl SchemaError {
+    pub fn missing(key: &str) -> Self {
+        Self {
+            path: key.to_string(),
+            kind: SchemaErrorKind::Missing,
+        }
+    }
+
+    pub fn wrong_type(expected: &'static str, found: &Value) -> Self {
+        Self {
+            path: String::new(),
+            kind: SchemaErrorKind::WrongType {
+                expected,
+                found: type_name(found),
+            },
+        }
+    }
+
+    /// Prefixes the path with the key of the containing dictionary
+    pub fn within(mut self, key: &str) -> Self {
+        self.path = if self.path.is_empty() {
+            key.to_string()
+        } else if self.path.starts_with('[') {
+            format!("{key}{}", self.path)
+        } else {
+            format!("{key}.{}", self.path)
+        };
+        self
+    }
+}
+
+/// A type that can be built from a plist dictionary.
+/// Derive it with `#[derive(FromPlist)]`.
+pub trait FromPlist: Sized {
+    fn from_plist(dict: &Dictionary) -> Result<Self, SchemaError>;
+}
+
+/// A type that can be built from a single plist value
+pub trait FromPlistValue: Sized {
+    const TYPE_NAME: &'static str;
+
+    fn from_plist_value(value: &Value) -> Result<Self, SchemaError>;
+
+    /// Called when the key for this value is missing from the dictionary
+    fn missing(key: &str) -> Result<Self, SchemaError> {
+        Err(SchemaError::missing(key))
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Array(_) => "array",
+        Value::Dictionary(_) => "dictionary",
+        Value::Boolean(_) => "boolean",
+        Value::Data(_) => "data",
+        Value::Date(_) => "date",
+        Value::Real(_) => "real",
+        Value::Integer(_) => "integer",
+        Value::String(_) => "string",
+        Value::Uid(_) => "uid",
+        _ => "unknown",
+    }
+}
+
+impl FromPlistValue for String {
+    const TYPE_NAME: &'static str = "string";
+
+    fn from_plist_value(value: &Value) -> Result<Self, SchemaError> {
+        match value {
+            Value::String(s) => Ok(s.clone()),
+            v => Err(SchemaError::wrong_type(Self::TYPE_NAME, v)),
+        }
+    }
+}
+
+impl FromPlistValue for bool {
+    const TYPE_NAME: &'static str = "boolean";
+
+    fn from_plist_value(value: &Value) -> Result<Self, SchemaError> {
+        match value {
+            Value::Boolean(b) => Ok(*b),
+            v => Err(SchemaError::wrong_type(Self::TYPE_NAME, v)),
+        }
+    }
+}
+
+macro_rules! unsigned {
+    ($($t:ty),*) => {$(
+        impl FromPlistValue for $t {
+            const TYPE_NAME: &'static str = "unsigned integer";
+
+            fn from_plist_value(value: &Value) -> Result<Self, SchemaError> {
+                match value {
+                    Value::Integer(i) => match i.as_unsigned().map(<$t>::try_from) {
+                        Some(Ok(i)) => Ok(i),
+                        _ => Err(SchemaError::wrong_type(Self::TYPE_NAME, value)),
+                    },
+                    v => Err(SchemaError::wrong_type(Self::TYPE_NAME, v)),
+                }
+            }
+        }
+    )*};
+}
+unsigned!(u16, u32, u64);
+
+impl FromPlistValue for i64 {
+    const TYPE_NAME: &'static str = "integer";
+
+    fn from_plist_value(value: &Value) -> Result<Self, SchemaError> {
+        match value {
+            Value::Integer(i) => match i.as_signed() {
+                Some(i) => Ok(i),
+                None => Err(SchemaError::wrong_type(Self::TYPE_NAME, value)),
+            },
+            v => Err(SchemaError::wrong_type(Self::TYPE_NAME, v)),
+        }
+    }
+}
+
+impl FromPlistValue for f64 {
+    const TYPE_NAME: &'static str = "real";
+
+    fn from_plist_value(value: &Value) -> Result<Self, SchemaError> {
+        match value {
+            Value::Real(r) => Ok(*r),
+            Value::Integer(i) => match i.as_signed() {
+                Some(i) => Ok(i as f64),
+                None => Err(SchemaError::wrong_type(Self::TYPE_NAME, value)),
+            },
+            v => Err(SchemaError::wrong_type(Self::TYPE_NAME, v)),
+        }
+    }
+}
+
+impl FromPlistValue for Vec<u8> {
+    const TYPE_NAME: &'static str = "data";
+
+    fn from_plist_value(value: &Value) -> Result<Self, SchemaError> {
+        match value {
+            Value::Data(d) => Ok(d.clone()),
+            v => Err(SchemaError::wrong_type(Self::TYPE_NAME, v)),
+        }
+    }
+}
+
+impl FromPlistValue for Dictionary {
+    const TYPE_NAME: &'static str = "dictionary";
+
+    fn from_plist_value(value: &Value) -> Result<Self, SchemaError> {
+        match value {
+            Value::Dictionary(d) => Ok(d.clone()),
+            v => Err(SchemaError::wrong_type(Self::TYPE_NAME, v)),
+        }
+    }
+}
+
+impl FromPlistValue for Value {
+    const TYPE_NAME: &'static str = "value";
+
+    fn from_plist_value(value: &Value) -> Result<Self, SchemaError> {
+        Ok(value.clone())
+    }
+}
+
+impl<T: FromPlistValue> FromPlistValue for Option<T> {
+    const TYPE_NAME: &'static str = T::TYPE_NAME;
+
+    fn from_plist_value(value: &Value) -> Result<Self, SchemaError> {
+        T::from_plist_value(value).map(Some)
+    }
+
+    fn missing(_key: &str) -> Result<Self, SchemaError> {
+        Ok(None)
+    }
+}
+
+impl<T: FromPlistValue> FromPlistValue for Vec<T> {
+    const TYPE_NAME: &'static str = "array";
+
+    fn from_plist_value(value: &Value) -> Result<Self, SchemaError> {
+        match value {
+            Value::Array(a) => a
+                .iter()
+                .enumerate()
+                .map(|(i, v)| T::from_plist_value(v).map_err(|e| e.within(&format!("[{i}]"))))
+                .collect(),
+            v => Err(SchemaError::wrong_type(Self::TYPE_NAME, v)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(FromPlist, Debug, PartialEq)]
+    #[plist(rename_all = "PascalCase")]
+    struct App {
+        bundle_identifier: String,
+        #[plist(rename = "CFBundleVersion")]
+        version: Option<String>,
+        #[plist(default)]
+        entitlements: Dictionary,
+    }
+
+    #[derive(FromPlist, Debug)]
+    struct Lookup {
+        #[plist(rename = "Apps")]
+        apps: Vec<App>,
+    }
+
+    #[test]
+    fn reports_path_to_bad_value() {
+        let mut good = Dictionary::new();
+        good.insert("BundleIdentifier".into(), "com.apple.Preferences".into());
+        let mut bad = Dictionary::new();
+        bad.insert("BundleIdentifier".into(), 7.into());
+
+        let mut dict = Dictionary::new();
+        dict.insert(
+            "Apps".into(),
+            Value::Array(vec![good.clone().into(), bad.into()]),
+        );
+        let err = Lookup::from_plist(&dict).unwrap_err();
+        assert_eq!(err.path, "Apps[1].BundleIdentifier");
+        assert_eq!(
+            err.kind,
+            SchemaErrorKind::WrongType {
+                expected: "string",
+                found: "integer"
+            }
+        );
+
+        let mut dict = Dictionary::new();
+        dict.insert("Apps".into(), Value::Array(vec![good.clone().into()]));
+        assert_eq!(Lookup::from_plist(&dict).unwrap().apps.len(), 1);
+
+        let app = App::from_plist(&good).unwrap();
+        assert_eq!(app.version, None);
+        assert!(app.entitlements.is_empty());
+
+        let err = App::from_plist(&Dictionary::new()).unwrap_err();
+        assert_eq!(err, SchemaError::missing("BundleIdentifier"));
+    }
+}
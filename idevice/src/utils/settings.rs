@@ -0,0 +1,158 @@
+// Jackson Coxson
+// Wrappers for the device settings lockdown allows writing.
+// A lockdown session must already be started for any of these to succeed.
+
+use log::debug;
+use plist::Value;
+
+use crate::{lockdownd::LockdowndClient, pairing_file::PairingFile, IdeviceError};
+
+const ACCESSIBILITY_DOMAIN: &str = "com.apple.Accessibility";
+const INTERNATIONAL_DOMAIN: &str = "com.apple.international";
+const WIRELESS_LOCKDOWN_DOMAIN: &str = "com.apple.mobile.wireless_lockdown";
+
+/// The longest name the device will display in full
+const MAX_DEVICE_NAME_LEN: usize = 255;
+
+/// Renames the device, as shown in Settings and Finder
+pub async fn set_device_name(
+    lockdown: &mut LockdowndClient,
+    name: impl Into<String>,
+) -> Result<(), IdeviceError> {
+    let name = name.into();
+    if name.trim().is_empty() || name.len() > MAX_DEVICE_NAME_LEN {
+        return Err(IdeviceError::InvalidArgument);
+    }
+    lockdown.set_value("DeviceName", name.into(), None).await
+}
+
+pub async fn set_assistive_touch_enabled(
+    lockdown: &mut LockdowndClient,
+    enabled: bool,
+) -> Result<(), IdeviceError> {
+    set_accessibility(lockdown, "AssistiveTouchEnabledByiTunes", enabled).await
+}
+
+pub async fn set_voice_over_enabled(
+    lockdown: &mut LockdowndClient,
+    enabled: bool,
+) -> Result<(), IdeviceError> {
+    set_accessibility(lockdown, "VoiceOverTouchEnabledByiTunes", enabled).await
+}
+
+pub async fn set_zoom_enabled(
+    lockdown: &mut LockdowndClient,
+    enabled: bool,
+) -> Result<(), IdeviceError> {
+    set_accessibility(lockdown, "ZoomTouchEnabledByiTunes", enabled).await
+}
+
+pub async fn set_invert_display_enabled(
+    lockdown: &mut LockdowndClient,
+    enabled: bool,
+) -> Result<(), IdeviceError> {
+    set_accessibility(lockdown, "InvertDisplayEnabledByiTunes", enabled).await
+}
+
+/// Allows lockdown connections over the network once the device is paired
+pub async fn set_wifi_connections_enabled(
+    lockdown: &mut LockdowndClient,
+    enabled: bool,
+) -> Result<(), IdeviceError> {
+    lockdown
+        .set_value(
+            "EnableWifiConnections",
+            enabled.into(),
+            Some(WIRELESS_LOCKDOWN_DOMAIN.to_string()),
+        )
+        .await
+}
+
+/// Lets the device be reached over the network for development, like Xcode's
+/// "Connect via network". The device pairs the network with this host's BUID.
+pub async fn enable_wifi_debugging(
+    lockdown: &mut LockdowndClient,
+    pairing_file: &PairingFile,
+) -> Result<(), IdeviceError> {
+    let domain = Some(WIRELESS_LOCKDOWN_DOMAIN.to_string());
+    let buddy = lockdown
+        .get_value_in_domain("WirelessBuddyID", WIRELESS_LOCKDOWN_DOMAIN)
+        .await;
+    if !matches!(buddy, Ok(Value::String(b)) if b == pairing_file.system_buid) {
+        debug!("Setting WirelessBuddyID to {}", pairing_file.system_buid);
+        lockdown
+            .set_value(
+                "WirelessBuddyID",
+                pairing_file.system_buid.as_str().into(),
+                domain.clone(),
+            )
+            .await?;
+    }
+    lockdown
+        .set_value("EnableWifiConnections", true.into(), domain.clone())
+        .await?;
+    lockdown
+        .set_value("EnableWifiDebugging", true.into(), domain)
+        .await
+}
+
+/// Sets the system language
+/// # Arguments
+/// `language` - A language identifier such as `en` or `zh-Hans`
+pub async fn set_language(
+    lockdown: &mut LockdowndClient,
+    language: impl Into<String>,
+) -> Result<(), IdeviceError> {
+    let language = language.into();
+    if !is_identifier(&language) {
+        return Err(IdeviceError::InvalidArgument);
+    }
+    lockdown
+        .set_value(
+            "Language",
+            language.into(),
+            Some(INTERNATIONAL_DOMAIN.to_string()),
+        )
+        .await
+}
+
+/// Sets the region format
+/// # Arguments
+/// `locale` - A locale identifier such as `en_US`
+pub async fn set_locale(
+    lockdown: &mut LockdowndClient,
+    locale: impl Into<String>,
+) -> Result<(), IdeviceError> {
+    let locale = locale.into();
+    if !is_identifier(&locale) {
+        return Err(IdeviceError::InvalidArgument);
+    }
+    lockdown
+        .set_value(
+            "Locale",
+            locale.into(),
+            Some(INTERNATIONAL_DOMAIN.to_string()),
+        )
+        .await
+}
+
+async fn set_accessibility(
+    lockdown: &mut LockdowndClient,
+    key: &str,
+    enabled: bool,
+) -> Result<(), IdeviceError> {
+    lockdown
+        .set_value(
+            key,
+            Value::Boolean(enabled),
+            Some(ACCESSIBILITY_DOMAIN.to_string()),
+        )
+        .await
+}
+
+/// Whether a language or locale identifier is well formed
+fn is_identifier(s: &str) -> bool {
+    !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
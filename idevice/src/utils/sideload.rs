@@ -0,0 +1,223 @@
+// Jackson Coxson
+// Coordinates the services needed to sideload an app
+
+use futures::StreamExt;
+use log::{debug, warn};
+use thiserror::Error;
+
+use crate::{
+    afc::{errors::AfcError, opcode::AfcFopenMode, AfcClient},
+    capabilities::DeviceCapabilities,
+    heartbeat::HeartbeatClient,
+    installation_proxy::InstallationProxyClient,
+    lockdownd::{LockdownDomain, LockdowndClient},
+    misagent::MisagentClient,
+    provider::{ConnectionKind, IdeviceProvider},
+    utils::stream::StopHandle,
+    Idevice, IdeviceError, IdeviceService,
+};
+
+/// The stage of the sideload sequence that failed
+#[derive(Error, Debug)]
+pub enum SideloadError {
+    #[error("failed to start the session")]
+    Session(#[source] IdeviceError),
+    #[error("failed to install the provisioning profile")]
+    ProfileInstall(#[source] IdeviceError),
+    #[error("failed to upload the package")]
+    Upload(#[source] IdeviceError),
+    #[error("failed to install the app")]
+    AppInstall(#[source] IdeviceError),
+    #[error("failed to verify the installed app")]
+    Verify(#[source] IdeviceError),
+    #[error("app `{0}` wasn't installed")]
+    NotInstalled(String),
+}
+
+/// Keeps a device awake and reuses one lockdown session while sideloading.
+/// The heartbeat task is stopped when the session is dropped.
+pub struct SideloadSession<'a> {
+    provider: &'a dyn IdeviceProvider,
+    lockdown: LockdowndClient,
+    heartbeat: StopHandle,
+    misagent: Option<MisagentClient>,
+    afc: Option<AfcClient>,
+    instproxy: Option<InstallationProxyClient>,
+}
+
+impl<'a> SideloadSession<'a> {
+    /// The directory packages are staged in before installing
+    pub const STAGING_DIR: &'static str = "PublicStaging";
+
+    /// Starts the heartbeat keepalive and a lockdown session
+    pub async fn new(provider: &'a dyn IdeviceProvider) -> Result<Self, SideloadError> {
+        let (stream, heartbeat) = HeartbeatClient::connect(provider)
+            .await
+            .map_err(SideloadError::Session)?
+            .into_stream(15);
+        tokio::spawn(async move {
+            let mut stream = Box::pin(stream);
+            while let Some(res) = stream.next().await {
+                if let Err(e) = res {
+                    warn!("Heartbeat stopped: {e:?}");
+                }
+            }
+        });
+
+        let mut lockdown = LockdowndClient::connect(provider)
+            .await
+            .map_err(SideloadError::Session)?;
+        let pairing_file = provider
+            .get_pairing_file()
+            .await
+            .map_err(SideloadError::Session)?;
+        lockdown
+            .start_session(&pairing_file)
+            .await
+            .map_err(SideloadError::Session)?;
+        lockdown.enable_cache();
+
+        Ok(Self {
+            provider,
+            lockdown,
+            heartbeat,
+            misagent: None,
+            afc: None,
+            instproxy: None,
+        })
+    }
+
+    /// Installs the profile, then uploads and installs the package and checks it's present
+    /// # Arguments
+    /// `profile` - The provisioning profile the app was signed with
+    /// `package` - The signed IPA
+    /// `bundle_id` - The bundle identifier of the app, used to verify the install
+    pub async fn sideload(
+        &mut self,
+        profile: Vec<u8>,
+        package: &[u8],
+        bundle_id: impl Into<String>,
+    ) -> Result<(), SideloadError> {
+        let bundle_id = bundle_id.into();
+        self.install_profile(profile).await?;
+        self.install_app(package, &bundle_id).await?;
+        self.verify(&bundle_id).await
+    }
+
+    /// Installs a provisioning profile with misagent
+    pub async fn install_profile(&mut self, profile: Vec<u8>) -> Result<(), SideloadError> {
+        if self.misagent.is_none() {
+            let idevice = self
+                .start_service(MisagentClient::service_name())
+                .await
+                .map_err(SideloadError::ProfileInstall)?;
+            self.misagent = Some(MisagentClient::new(idevice));
+        }
+        // We are safe to unwrap as it was set above
+        self.misagent
+            .as_mut()
+            .unwrap()
+            .install(profile)
+            .await
+            .map_err(SideloadError::ProfileInstall)
+    }
+
+    /// Uploads a package to the staging directory and installs it
+    pub async fn install_app(
+        &mut self,
+        package: &[u8],
+        bundle_id: &str,
+    ) -> Result<(), SideloadError> {
+        if self.afc.is_none() {
+            let idevice = self
+                .start_service(AfcClient::service_name())
+                .await
+                .map_err(SideloadError::Upload)?;
+            self.afc = Some(AfcClient::new(idevice));
+        }
+        let afc = self.afc.as_mut().unwrap();
+
+        match afc.mk_dir(Self::STAGING_DIR).await {
+            Ok(_) | Err(IdeviceError::Afc(AfcError::ObjectExists)) => {}
+            Err(e) => return Err(SideloadError::Upload(e)),
+        }
+        let package_path = format!("{}/{bundle_id}.ipa", Self::STAGING_DIR);
+        debug!("Uploading package to {package_path}");
+        if self.provider.connection_kind() == ConnectionKind::Network {
+            warn!(
+                "Uploading {} bytes over the network, which may take a while",
+                package.len()
+            );
+        }
+        let mut file = afc
+            .open(&package_path, AfcFopenMode::WrOnly)
+            .await
+            .map_err(SideloadError::Upload)?;
+        file.write(package).await.map_err(SideloadError::Upload)?;
+        file.close().await.map_err(SideloadError::Upload)?;
+
+        self.instproxy()
+            .await
+            .map_err(SideloadError::AppInstall)?
+            .install(package_path, None)
+            .await
+            .map_err(SideloadError::AppInstall)
+    }
+
+    /// Checks that an app is installed
+    pub async fn verify(&mut self, bundle_id: &str) -> Result<(), SideloadError> {
+        let apps = self
+            .instproxy()
+            .await
+            .map_err(SideloadError::Verify)?
+            .get_apps(None, Some(vec![bundle_id.to_string()]))
+            .await
+            .map_err(SideloadError::Verify)?;
+        if !apps.contains_key(bundle_id) {
+            return Err(SideloadError::NotInstalled(bundle_id.to_string()));
+        }
+
+        let capabilities = DeviceCapabilities::from_lockdown(&mut self.lockdown)
+            .await
+            .map_err(SideloadError::Verify)?;
+        if capabilities.has_developer_mode() {
+            let enabled = self
+                .lockdown
+                .get_value_in_domain("DeveloperModeStatus", LockdownDomain::Amfi)
+                .await
+                .map_err(SideloadError::Verify)?;
+            if enabled.as_boolean() == Some(false) {
+                warn!("{bundle_id} is installed but won't launch until developer mode is enabled");
+            }
+        }
+        Ok(())
+    }
+
+    async fn instproxy(&mut self) -> Result<&mut InstallationProxyClient, IdeviceError> {
+        if self.instproxy.is_none() {
+            let idevice = self
+                .start_service(InstallationProxyClient::service_name())
+                .await?;
+            self.instproxy = Some(InstallationProxyClient::new(idevice));
+        }
+        Ok(self.instproxy.as_mut().unwrap())
+    }
+
+    /// Starts a service over the cached lockdown session
+    async fn start_service(&mut self, name: &str) -> Result<Idevice, IdeviceError> {
+        let (port, ssl) = self.lockdown.start_service(name).await?;
+        let mut idevice = self.provider.connect(port).await?;
+        if ssl {
+            idevice
+                .start_session(&self.provider.get_pairing_file().await?)
+                .await?;
+        }
+        Ok(idevice)
+    }
+}
+
+impl Drop for SideloadSession<'_> {
+    fn drop(&mut self) {
+        self.heartbeat.stop();
+    }
+}
@@ -0,0 +1,174 @@
+// Jackson Coxson
+// Finds when the provisioning profiles of sideloaded apps expire
+
+use std::time::{Duration, SystemTime};
+
+use log::warn;
+
+use crate::{
+    installation_proxy::InstallationProxyClient, misagent::MisagentClient,
+    provider::IdeviceProvider, IdeviceError, IdeviceService,
+};
+
+/// The signing state of an installed app
+#[derive(Debug, Clone)]
+pub struct SigningStatus {
+    pub bundle_id: String,
+    /// The `application-identifier` entitlement, such as `TEAMID.com.example.app`
+    pub application_identifier: Option<String>,
+    /// The certificate the app was signed with, such as `Apple Development: ...`
+    pub signer_identity: Option<String>,
+    /// The installed profile that covers the app, if any
+    pub profile: Option<ProfileInfo>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ProfileInfo {
+    pub name: String,
+    pub uuid: String,
+    pub expiration: SystemTime,
+}
+
+impl SigningStatus {
+    /// Whether the app is signed by a developer rather than the App Store
+    pub fn is_developer_signed(&self) -> bool {
+        self.profile.is_some()
+    }
+
+    /// Whether the covering profile expires before `within` from now.
+    /// Apps without a profile never expire.
+    pub fn expires_within(&self, within: Duration) -> bool {
+        match &self.profile {
+            Some(p) => p.expiration <= SystemTime::now() + within,
+            None => false,
+        }
+    }
+}
+
+/// Gets the signing state of installed apps, such as to warn before free profiles
+/// expire after 7 days.
+/// # Arguments
+/// `bundle_ids` - The apps to check
+pub async fn signing_status(
+    provider: &dyn IdeviceProvider,
+    bundle_ids: Vec<String>,
+) -> Result<Vec<SigningStatus>, IdeviceError> {
+    let mut instproxy = InstallationProxyClient::connect(provider).await?;
+    let apps = instproxy
+        .get_apps_with_attributes(
+            None,
+            Some(bundle_ids.clone()),
+            Some(vec![
+                "CFBundleIdentifier".to_string(),
+                "Entitlements".to_string(),
+                "SignerIdentity".to_string(),
+            ]),
+        )
+        .await?;
+
+    let mut misagent = MisagentClient::connect(provider).await?;
+    let profiles = misagent
+        .copy_all()
+        .await?
+        .iter()
+        .filter_map(|p| match parse_profile(p) {
+            Some(p) => Some(p),
+            None => {
+                warn!("Unable to parse an installed provisioning profile");
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+
+    Ok(bundle_ids
+        .into_iter()
+        .filter_map(|bundle_id| {
+            let app = apps.get(&bundle_id)?.as_dictionary()?;
+            let application_identifier = app
+                .get("Entitlements")
+                .and_then(|e| e.as_dictionary())
+                .and_then(|e| e.get("application-identifier"))
+                .and_then(|a| a.as_string())
+                .map(ToString::to_string);
+            let signer_identity = app
+                .get("SignerIdentity")
+                .and_then(|s| s.as_string())
+                .map(ToString::to_string);
+
+            let profile = application_identifier.as_deref().and_then(|id| {
+                profiles
+                    .iter()
+                    .filter(|(pattern, _)| identifier_matches(pattern, id))
+                    .max_by_key(|(_, p)| p.expiration)
+                    .map(|(_, p)| p.clone())
+            });
+
+            Some(SigningStatus {
+                bundle_id,
+                application_identifier,
+                signer_identity,
+                profile,
+            })
+        })
+        .collect())
+}
+
+/// Reads the plist out of a signed profile
+/// # Returns
+/// The profile's application identifier pattern and its details
+fn parse_profile(profile: &[u8]) -> Option<(String, ProfileInfo)> {
+    let start = find(profile, b"<?xml")?;
+    let end = find(&profile[start..], b"</plist>")? + start + b"</plist>".len();
+    let plist: plist::Dictionary = plist::from_bytes(&profile[start..end]).ok()?;
+
+    let pattern = plist
+        .get("Entitlements")?
+        .as_dictionary()?
+        .get("application-identifier")?
+        .as_string()?
+        .to_string();
+    Some((
+        pattern,
+        ProfileInfo {
+            name: plist.get("Name")?.as_string()?.to_string(),
+            uuid: plist.get("UUID")?.as_string()?.to_string(),
+            expiration: plist.get("ExpirationDate")?.as_date()?.into(),
+        },
+    ))
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+/// Profiles may cover a team's apps with a trailing wildcard, such as `TEAMID.*`
+fn identifier_matches(pattern: &str, identifier: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => identifier.starts_with(prefix),
+        None => pattern == identifier,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_profile_from_signed_blob() {
+        let xml = br#"<?xml version="1.0" encoding="UTF-8"?>
+<plist version="1.0"><dict>
+<key>Name</key><string>Dev</string>
+<key>UUID</key><string>1234</string>
+<key>ExpirationDate</key><date>2030-01-01T00:00:00Z</date>
+<key>Entitlements</key><dict><key>application-identifier</key><string>ABCDE.*</string></dict>
+</dict></plist>"#;
+        let mut blob = vec![0x30, 0x82, 0x01];
+        blob.extend_from_slice(xml);
+        blob.extend_from_slice(&[0xa0, 0x00]);
+
+        let (pattern, info) = parse_profile(&blob).unwrap();
+        assert_eq!(info.name, "Dev");
+        assert!(identifier_matches(&pattern, "ABCDE.com.example.app"));
+        assert!(!identifier_matches(&pattern, "FGHIJ.com.example.app"));
+    }
+}
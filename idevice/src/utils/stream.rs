@@ -0,0 +1,113 @@
+// Jackson Coxson
+// Shared pieces for services that produce events until they're stopped
+
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use futures::Stream;
+use tokio::sync::Notify;
+
+use crate::IdeviceError;
+
+/// Stops a long-running service stream.
+/// Clones of the handle all stop the same stream.
+#[derive(Clone, Debug, Default)]
+pub struct StopHandle {
+    inner: Arc<StopInner>,
+}
+
+#[derive(Debug, Default)]
+struct StopInner {
+    stopped: AtomicBool,
+    notify: Notify,
+}
+
+impl StopHandle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ends the stream. It will yield `None` on its next poll.
+    pub fn stop(&self) {
+        self.inner.stopped.store(true, Ordering::SeqCst);
+        self.inner.notify.notify_waiters();
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.inner.stopped.load(Ordering::SeqCst)
+    }
+
+    /// Waits until `stop` is called
+    pub async fn stopped(&self) {
+        loop {
+            let notified = self.inner.notify.notified();
+            if self.is_stopped() {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+/// Builds a stream that calls `next` with the service state for each item.
+/// The stream ends when the handle is stopped or after the first error.
+pub(crate) fn until_stopped<S, T, F, Fut>(
+    state: S,
+    stop: StopHandle,
+    next: F,
+) -> impl Stream<Item = Result<T, IdeviceError>>
+where
+    F: FnMut(S) -> Fut,
+    Fut: Future<Output = (Result<T, IdeviceError>, S)>,
+{
+    futures::stream::unfold(
+        (Some(state), stop, next),
+        |(state, stop, mut next)| async move {
+            let state = state?;
+            if stop.is_stopped() {
+                return None;
+            }
+            tokio::select! {
+                _ = stop.stopped() => None,
+                (item, state) = next(state) => {
+                    let state = if item.is_ok() { Some(state) } else { None };
+                    Some((item, (state, stop, next)))
+                }
+            }
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn ends_on_error_and_stop() {
+        let stop = StopHandle::new();
+        let stream = until_stopped(0u8, stop.clone(), |n| async move {
+            match n {
+                2 => (Err(IdeviceError::HeartbeatTimeout), n),
+                n => (Ok(n), n + 1),
+            }
+        });
+        let items = stream.collect::<Vec<_>>().await;
+        assert_eq!(items.len(), 3);
+        assert!(items[2].is_err());
+
+        let stop = StopHandle::new();
+        let mut stream = Box::pin(until_stopped((), stop.clone(), |_| async {
+            std::future::pending::<()>().await;
+            (Ok(()), ())
+        }));
+        stop.stop();
+        assert!(stream.next().await.is_none());
+    }
+}
@@ -0,0 +1,128 @@
+// Jackson Coxson
+// Waits for a sysdiagnose to finish on the device and downloads it
+
+use std::{
+    collections::HashSet,
+    io::Write,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use log::debug;
+
+use crate::{
+    afc::{opcode::AfcFopenMode, path},
+    crashreportcopymobile::CrashReportCopyMobileClient,
+    provider::IdeviceProvider,
+    IdeviceError, IdeviceService,
+};
+
+/// How often the sysdiagnose directory is checked for a new archive
+pub const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Archives are written under this prefix and renamed once complete
+const IN_PROGRESS_PREFIX: &str = "IN_PROGRESS_";
+
+/// Reported by `capture_sysdiagnose` as it goes
+#[derive(Debug, Clone)]
+pub enum SysdiagnoseProgress {
+    /// No new archive has finished yet
+    Waiting,
+    /// A new archive was found and is being downloaded
+    Downloading {
+        name: String,
+        downloaded: u64,
+        total: u64,
+    },
+}
+
+/// Waits for a new sysdiagnose archive and downloads it.
+///
+/// There's no lockdown service that starts a sysdiagnose, so it has to be triggered on the
+/// device by holding both volume buttons and the side button for about a second.
+/// Collecting takes several minutes, so the timeout should be generous.
+/// Starting a sysdiagnose through CoreDevice isn't supported here.
+/// # Arguments
+/// `provider` - The device to watch
+/// `host_dir` - The directory to save the archive to
+/// `timeout` - How long to wait for the archive to finish, not counting the download
+/// `progress` - Called while waiting and after each downloaded chunk
+/// # Returns
+/// The path the archive was saved to
+pub async fn capture_sysdiagnose(
+    provider: &dyn IdeviceProvider,
+    host_dir: impl AsRef<Path>,
+    timeout: Duration,
+    mut progress: impl FnMut(SysdiagnoseProgress),
+) -> Result<PathBuf, IdeviceError> {
+    let mut client = CrashReportCopyMobileClient::connect(provider).await?;
+    // Archives already there are from earlier runs
+    let existing = finished_archives(&mut client).await?;
+    debug!("Ignoring {} existing sysdiagnose archives", existing.len());
+
+    let started = Instant::now();
+    let name = loop {
+        progress(SysdiagnoseProgress::Waiting);
+        let new = finished_archives(&mut client)
+            .await?
+            .into_iter()
+            .find(|n| !existing.contains(n));
+        if let Some(name) = new {
+            break name;
+        }
+        if started.elapsed() >= timeout {
+            return Err(IdeviceError::Timeout);
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    };
+
+    let device_path = path::join(CrashReportCopyMobileClient::SYSDIAGNOSE_DIR, &name);
+    let total = client.afc.get_file_info(device_path.as_str()).await?.size;
+    let host_path = host_dir.as_ref().join(&name);
+    let mut out = std::fs::File::create(&host_path)?;
+
+    let mut file = client.afc.open(device_path, AfcFopenMode::RdOnly).await?;
+    let mut downloaded = 0;
+    loop {
+        let chunk = file.read_chunk().await?;
+        if chunk.is_empty() {
+            break;
+        }
+        out.write_all(&chunk)?;
+        downloaded += chunk.len() as u64;
+        progress(SysdiagnoseProgress::Downloading {
+            name: name.clone(),
+            downloaded,
+            total,
+        });
+    }
+    file.close().await?;
+    out.flush()?;
+
+    Ok(host_path)
+}
+
+async fn finished_archives(
+    client: &mut CrashReportCopyMobileClient,
+) -> Result<HashSet<String>, IdeviceError> {
+    // The directory is only created by the first sysdiagnose
+    if client
+        .afc
+        .exists(CrashReportCopyMobileClient::SYSDIAGNOSE_DIR)
+        .await?
+        .is_none()
+    {
+        return Ok(HashSet::new());
+    }
+    Ok(client
+        .afc
+        .list_dir(CrashReportCopyMobileClient::SYSDIAGNOSE_DIR)
+        .await?
+        .into_iter()
+        .filter(|n| is_finished_archive(n))
+        .collect())
+}
+
+fn is_finished_archive(name: &str) -> bool {
+    name.ends_with(".tar.gz") && !name.starts_with(IN_PROGRESS_PREFIX)
+}
@@ -0,0 +1,97 @@
+// Jackson Coxson
+// Writes syslog_relay messages to disk, rotating the file as it grows
+
+use std::{io, path::PathBuf};
+
+use tokio::task::JoinHandle;
+
+use crate::{
+    syslog_relay::SyslogRelayClient,
+    utils::{
+        log_sink::{forward, RotatingFileSink},
+        stream::StopHandle,
+    },
+    IdeviceError,
+};
+
+pub use crate::utils::log_sink::RotationConfig;
+
+/// Collects the syslog into a file in the background until it's stopped
+pub struct SyslogCollector {
+    stop: StopHandle,
+    task: JoinHandle<Result<(), IdeviceError>>,
+}
+
+impl SyslogCollector {
+    /// Starts appending messages to the file, one per line.
+    /// # Arguments
+    /// `client` - The relay to read messages from
+    /// `path` - The file to write to. Rotated files are written next to it.
+    /// `config` - When to rotate the file
+    pub fn start(
+        client: SyslogRelayClient,
+        path: impl Into<PathBuf>,
+        config: RotationConfig,
+    ) -> Result<Self, IdeviceError> {
+        let sink = RotatingFileSink::new(path, config)?;
+        let (stream, stop) = client.into_stream();
+        let task = tokio::spawn(forward(stream, sink));
+        Ok(Self { stop, task })
+    }
+
+    /// A handle that stops collection when it's stopped
+    pub fn stop_handle(&self) -> StopHandle {
+        self.stop.clone()
+    }
+
+    /// Whether collection has ended, because it was stopped or the connection failed
+    pub fn is_finished(&self) -> bool {
+        self.task.is_finished()
+    }
+
+    /// Stops collecting and waits for the file to be flushed.
+    /// Returns the error that ended collection early, if any.
+    pub async fn stop(self) -> Result<(), IdeviceError> {
+        self.stop.stop();
+        self.task.await.map_err(io::Error::other)?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::File, io::Read};
+
+    use flate2::read::GzDecoder;
+
+    use super::*;
+    use crate::utils::log_sink::rotated_path;
+
+    #[tokio::test]
+    async fn rotates_and_compresses() {
+        let dir = std::env::temp_dir().join(format!("idevice-syslog-{}", fastrand::u64(..)));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("syslog.txt");
+        let config = RotationConfig {
+            max_size: 8,
+            max_files: 2,
+            compress: true,
+        };
+
+        let messages = ["first 1", "second 2", "third 3", "fourth"];
+        let stream = futures::stream::iter(messages.map(|m| Ok(m.to_string())));
+        let sink = RotatingFileSink::new(&path, config).unwrap();
+        forward(stream, sink).await.unwrap();
+
+        // Each of the first three messages fills a file, and only two are kept
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "fourth\n");
+        let mut newest = String::new();
+        GzDecoder::new(File::open(rotated_path(&path, 1, true)).unwrap())
+            .read_to_string(&mut newest)
+            .unwrap();
+        assert_eq!(newest, "third 3\n");
+        assert!(rotated_path(&path, 2, true).exists());
+        assert!(!rotated_path(&path, 3, true).exists());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,194 @@
+// Jackson Coxson
+// Copies files between two devices' AFC services without staging them on the host
+
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::SystemTime,
+};
+
+use log::warn;
+
+use crate::{
+    afc::{opcode::AfcFopenMode, path, AfcClient},
+    provider::IdeviceProvider,
+    IdeviceError, IdeviceService,
+};
+
+/// Reported by a transfer after each chunk is written
+#[derive(Debug, Clone)]
+pub struct TransferProgress {
+    /// The file the chunk belonged to
+    pub path: String,
+    pub files_done: u64,
+    pub files_total: u64,
+    pub bytes_done: u64,
+    pub bytes_total: u64,
+}
+
+/// Copies a file or directory from one device's media directory to the same path on another.
+/// Files are streamed a chunk at a time, so nothing is buffered on disk and memory use
+/// doesn't depend on file sizes. Modification times are kept.
+/// # Arguments
+/// `src` - The device to copy from
+/// `dst` - The device to copy to
+/// `path` - What to copy. Parent directories must already exist on the destination.
+/// `concurrency` - How many files to copy at once, each over its own pair of connections
+/// `progress` - Called after each chunk, from whichever file it belonged to
+pub async fn device_to_device(
+    src: &dyn IdeviceProvider,
+    dst: &dyn IdeviceProvider,
+    path: &str,
+    concurrency: usize,
+    progress: impl Fn(TransferProgress) + Sync,
+) -> Result<(), IdeviceError> {
+    let mut sources = Vec::new();
+    let mut destinations = Vec::new();
+    for _ in 0..concurrency.max(1) {
+        sources.push(AfcClient::connect(src).await?);
+        destinations.push(AfcClient::connect(dst).await?);
+    }
+    copy_between(sources, destinations, path, progress).await
+}
+
+/// Like `device_to_device`, but over clients that are already connected,
+/// such as ones vended by house_arrest for an app's documents.
+/// A file is copied for each pair of clients at a time.
+pub async fn copy_between(
+    mut sources: Vec<AfcClient>,
+    mut destinations: Vec<AfcClient>,
+    path: &str,
+    progress: impl Fn(TransferProgress) + Sync,
+) -> Result<(), IdeviceError> {
+    let (src, dst) = match (sources.first_mut(), destinations.first_mut()) {
+        (Some(s), Some(d)) => (s, d),
+        _ => return Err(IdeviceError::InvalidArgument),
+    };
+    let plan = plan(src, dst, path).await?;
+
+    let state = Shared {
+        files: Mutex::new(plan.files.iter().cloned().collect()),
+        files_total: plan.files.len() as u64,
+        bytes_total: plan.files.iter().map(|f| f.size).sum(),
+        files_done: AtomicU64::new(0),
+        bytes_done: AtomicU64::new(0),
+        progress: &progress,
+    };
+    futures::future::try_join_all(
+        sources
+            .iter_mut()
+            .zip(destinations.iter_mut())
+            .map(|(src, dst)| copy_files(src, dst, &state)),
+    )
+    .await?;
+
+    // Filling a directory changes its time, so they're set last, deepest first
+    for (dir, modified) in plan.dirs.iter().rev() {
+        destinations[0]
+            .set_file_time(dir.as_str(), *modified)
+            .await?;
+    }
+    Ok(())
+}
+
+#[derive(Clone)]
+struct PlannedFile {
+    path: String,
+    size: u64,
+    modified: SystemTime,
+}
+
+struct Plan {
+    /// Parents come before their children
+    dirs: Vec<(String, SystemTime)>,
+    files: Vec<PlannedFile>,
+}
+
+struct Shared<'a> {
+    files: Mutex<VecDeque<PlannedFile>>,
+    files_total: u64,
+    bytes_total: u64,
+    files_done: AtomicU64,
+    bytes_done: AtomicU64,
+    progress: &'a (dyn Fn(TransferProgress) + Sync),
+}
+
+/// Walks the source and creates the directory tree on the destination
+async fn plan(src: &mut AfcClient, dst: &mut AfcClient, root: &str) -> Result<Plan, IdeviceError> {
+    let mut plan = Plan {
+        dirs: Vec::new(),
+        files: Vec::new(),
+    };
+    let mut pending = vec![root.to_string()];
+    while let Some(device_path) = pending.pop() {
+        let info = src.get_file_info(device_path.as_str()).await?;
+        match info.st_ifmt.as_str() {
+            "S_IFDIR" => {
+                if dst.exists(device_path.as_str()).await?.is_none() {
+                    dst.mk_dir(device_path.as_str()).await?;
+                }
+                for name in src.list_dir(device_path.as_str()).await? {
+                    if name == "." || name == ".." {
+                        continue;
+                    }
+                    if !path::is_plain_name(&name) {
+                        warn!("Skipping unsafe entry name {name:?} in {device_path}");
+                        continue;
+                    }
+                    pending.push(path::join(&device_path, &name));
+                }
+                plan.dirs.push((device_path, info.modified));
+            }
+            "S_IFREG" => plan.files.push(PlannedFile {
+                path: device_path,
+                size: info.size,
+                modified: info.modified,
+            }),
+            t => warn!("Skipping {device_path} of type {t}"),
+        }
+    }
+    Ok(plan)
+}
+
+/// Takes files off the shared queue until it's empty
+async fn copy_files(
+    src: &mut AfcClient,
+    dst: &mut AfcClient,
+    state: &Shared<'_>,
+) -> Result<(), IdeviceError> {
+    loop {
+        let file = match state.files.lock().unwrap().pop_front() {
+            Some(f) => f,
+            None => return Ok(()),
+        };
+
+        let mut reader = src.open(file.path.as_str(), AfcFopenMode::RdOnly).await?;
+        let mut writer = dst.open(file.path.as_str(), AfcFopenMode::WrOnly).await?;
+        loop {
+            let chunk = reader.read_chunk().await?;
+            if chunk.is_empty() {
+                break;
+            }
+            writer.write(&chunk).await?;
+            let bytes_done = state
+                .bytes_done
+                .fetch_add(chunk.len() as u64, Ordering::SeqCst)
+                + chunk.len() as u64;
+            (state.progress)(TransferProgress {
+                path: file.path.clone(),
+                files_done: state.files_done.load(Ordering::SeqCst),
+                files_total: state.files_total,
+                bytes_done,
+                bytes_total: state.bytes_total,
+            });
+        }
+        reader.close().await?;
+        writer.close().await?;
+
+        dst.set_file_time(file.path.as_str(), file.modified).await?;
+        state.files_done.fetch_add(1, Ordering::SeqCst);
+    }
+}
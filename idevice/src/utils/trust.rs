@@ -0,0 +1,182 @@
+// Jackson Coxson
+// Watches devices for changes in whether they trust this host
+
+use std::{
+    collections::{HashMap, VecDeque},
+    pin::Pin,
+    time::Duration,
+};
+
+use futures::{Stream, StreamExt};
+use log::{debug, warn};
+
+use crate::{
+    lockdownd::LockdowndClient,
+    usbmuxd::{UsbmuxdAddr, UsbmuxdDevice, UsbmuxdListenEvent},
+    utils::stream::{until_stopped, StopHandle},
+    ClientMetadata, IdeviceError, IdeviceService,
+};
+
+#[derive(Debug, Clone)]
+pub enum TrustEvent {
+    /// The device has a pairing record that it accepts
+    Trusted(UsbmuxdDevice),
+    /// The device has no pairing record, or no longer accepts it.
+    /// The user must trust this host again before services can be used.
+    Untrusted(UsbmuxdDevice),
+    /// The muxer's device ID of the device that was removed
+    Disconnected(u32),
+}
+
+/// Reports devices as they connect, and again whenever their trust of this host changes
+#[derive(Debug, Clone)]
+pub struct TrustMonitor {
+    addr: UsbmuxdAddr,
+    metadata: ClientMetadata,
+    poll_interval: Duration,
+}
+
+type ListenStream = Pin<Box<dyn Stream<Item = Result<UsbmuxdListenEvent, IdeviceError>> + Send>>;
+
+struct TrustState {
+    monitor: TrustMonitor,
+    listen: ListenStream,
+    /// Connected devices by muxer ID, and whether they were trusted when last checked
+    devices: HashMap<u32, (UsbmuxdDevice, bool)>,
+    pending: VecDeque<TrustEvent>,
+}
+
+enum Wake {
+    Muxer(Option<Result<UsbmuxdListenEvent, IdeviceError>>),
+    Poll,
+}
+
+impl TrustMonitor {
+    pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+    pub fn new(addr: UsbmuxdAddr, metadata: impl Into<ClientMetadata>) -> Self {
+        Self {
+            addr,
+            metadata: metadata.into(),
+            poll_interval: Self::DEFAULT_POLL_INTERVAL,
+        }
+    }
+
+    /// Sets how often connected devices are checked for trust changes
+    pub fn with_poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    /// Starts watching. The stream ends when stopped or when the muxer connection fails.
+    pub async fn watch(
+        self,
+    ) -> Result<
+        (
+            impl Stream<Item = Result<TrustEvent, IdeviceError>>,
+            StopHandle,
+        ),
+        IdeviceError,
+    > {
+        let (listen, _) = self
+            .addr
+            .connect(0)
+            .await?
+            .with_metadata(&self.metadata)
+            .listen()
+            .await?;
+        let state = TrustState {
+            monitor: self,
+            listen: Box::pin(listen),
+            devices: HashMap::new(),
+            pending: VecDeque::new(),
+        };
+
+        let stop = StopHandle::new();
+        let stream = until_stopped(state, stop.clone(), |mut state| async move {
+            let res = state.next_event().await;
+            (res, state)
+        });
+        Ok((stream, stop))
+    }
+
+    /// Checks whether the device accepts the pairing record the muxer has for it
+    async fn is_trusted(&self, device: &UsbmuxdDevice) -> Result<bool, IdeviceError> {
+        let mut muxer = self.addr.connect(0).await?.with_metadata(&self.metadata);
+        let pairing_file = match muxer.get_pair_record(&device.udid).await {
+            Ok(p) => p,
+            // The muxer answers without a record when the device was never paired
+            Err(IdeviceError::UnexpectedResponse) => return Ok(false),
+            Err(e) => return Err(e),
+        };
+
+        let provider = device.to_provider(self.addr.clone(), 0, &self.metadata);
+        let mut lockdown = LockdowndClient::connect(&provider).await?;
+        lockdown.validate_pair(&pairing_file).await
+    }
+}
+
+impl TrustState {
+    async fn next_event(&mut self) -> Result<TrustEvent, IdeviceError> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Ok(event);
+            }
+
+            let wake = tokio::select! {
+                event = self.listen.next() => Wake::Muxer(event),
+                _ = tokio::time::sleep(self.monitor.poll_interval) => Wake::Poll,
+            };
+
+            match wake {
+                Wake::Muxer(Some(Ok(UsbmuxdListenEvent::Connected(device)))) => {
+                    match self.monitor.is_trusted(&device).await {
+                        Ok(trusted) => {
+                            self.devices
+                                .insert(device.device_id, (device.clone(), trusted));
+                            self.pending.push_back(Self::event(device, trusted));
+                        }
+                        // Commonly the device left again before it could be checked
+                        Err(e) => warn!("Unable to check trust of {}: {e:?}", device.udid),
+                    }
+                }
+                Wake::Muxer(Some(Ok(UsbmuxdListenEvent::Disconnected(id)))) => {
+                    if self.devices.remove(&id).is_some() {
+                        self.pending.push_back(TrustEvent::Disconnected(id));
+                    }
+                }
+                // The muxer saves the record before telling anyone, so it can be trusted as is
+                Wake::Muxer(Some(Ok(UsbmuxdListenEvent::Paired(id)))) => {
+                    if let Some((device, trusted)) = self.devices.get_mut(&id) {
+                        if !*trusted {
+                            *trusted = true;
+                            self.pending.push_back(TrustEvent::Trusted(device.clone()));
+                        }
+                    }
+                }
+                Wake::Muxer(Some(Err(e))) => return Err(e),
+                Wake::Muxer(None) => return Err(IdeviceError::NoEstablishedConnection),
+                Wake::Poll => {
+                    for (device, trusted) in self.devices.values_mut() {
+                        match self.monitor.is_trusted(device).await {
+                            Ok(t) if t != *trusted => {
+                                debug!("Trust of {} changed to {t}", device.udid);
+                                *trusted = t;
+                                self.pending.push_back(Self::event(device.clone(), t));
+                            }
+                            Ok(_) => {}
+                            Err(e) => warn!("Unable to check trust of {}: {e:?}", device.udid),
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn event(device: UsbmuxdDevice, trusted: bool) -> TrustEvent {
+        match trusted {
+            true => TrustEvent::Trusted(device),
+            false => TrustEvent::Untrusted(device),
+        }
+    }
+}
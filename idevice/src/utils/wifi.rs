@@ -0,0 +1,53 @@
+// Jackson Coxson
+// Makes paired devices reachable through the muxer over the network
+
+use log::{debug, warn};
+
+use crate::{
+    lockdownd::{LockdownDomain, LockdowndClient},
+    provider::{IdeviceProvider, UsbmuxdProvider},
+    IdeviceError, IdeviceService,
+};
+
+/// Turns on network connections on the device and registers its pairing file with usbmuxd.
+/// Afterwards the muxer also lists the device over the network whenever it's on the same one,
+/// so it can be used without a cable.
+/// # Arguments
+/// `provider` - A provider for the device, usually over USB
+pub async fn enable_wifi_sync(provider: &UsbmuxdProvider) -> Result<(), IdeviceError> {
+    let pairing_file = provider.get_pairing_file().await?;
+    if pairing_file.escrow_bag.is_empty() {
+        warn!(
+            "The pairing file for {} has no escrow bag, so the device can't be reached while locked",
+            provider.udid
+        );
+    }
+
+    let mut lockdown = LockdowndClient::connect(provider).await?;
+    lockdown.start_session(&pairing_file).await?;
+    lockdown
+        .set_value(
+            "EnableWifiConnections",
+            true.into(),
+            Some(LockdownDomain::WirelessLockdown.into()),
+        )
+        .await?;
+    debug!("Enabled WiFi connections on {}", provider.udid);
+
+    let mut muxer = provider
+        .addr
+        .connect(provider.tag)
+        .await?
+        .with_metadata(provider.metadata.clone());
+    // The muxer only pairs over the network with records made for its own host
+    let buid = muxer.get_buid().await?;
+    if pairing_file.system_buid != buid {
+        warn!(
+            "The pairing file for {} was made for SystemBUID {}, not the muxer's {buid}",
+            provider.udid, pairing_file.system_buid
+        );
+    }
+    muxer
+        .save_pair_record(&provider.udid, Some(provider.device_id), &pairing_file)
+        .await
+}
@@ -12,6 +12,32 @@ pub enum XPCError {
     Http2Error(Http2Error),
     ParseError(ParseError),
     Custom(String),
+    /// A dictionary didn't have a required key
+    MissingKey(String),
+    /// A dictionary value wasn't the type asked for
+    WrongType {
+        key: String,
+        expected: &'static str,
+    },
+    /// A message was bigger than the connection's limit
+    MessageTooLarge {
+        size: u64,
+        max: usize,
+    },
+    /// The rest of a message didn't arrive in time
+    Timeout,
+    /// An earlier `MessageTooLarge` or `Timeout` left the connection partway through a
+    /// message, so it can't be read from or written to again and must be reopened
+    Desynced,
+}
+
+impl XPCError {
+    pub(crate) fn wrong_type(key: &str, expected: &'static str) -> Self {
+        Self::WrongType {
+            key: key.to_string(),
+            expected,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -80,6 +106,13 @@ impl std::fmt::Display for XPCError {
                 Self::Http2Error(http2) => http2.to_string(),
                 Self::ParseError(e) => e.to_string(),
                 Self::Custom(s) => s.clone(),
+                Self::MissingKey(key) => format!("missing key `{key}`"),
+                Self::WrongType { key, expected } => format!("`{key}` isn't a {expected}"),
+                Self::MessageTooLarge { size, max } => {
+                    format!("message of {size} bytes is larger than {max}")
+                }
+                Self::Timeout => "timed out reassembling a message".to_string(),
+                Self::Desynced => "connection is partway through a discarded message".to_string(),
             }
         )
     }
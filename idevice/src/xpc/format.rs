@@ -88,7 +88,7 @@ impl TryFrom<u32> for XPCType {
 
 pub type Dictionary = IndexMap<String, XPCObject>;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum XPCObject {
     Bool(bool),
     Dictionary(Dictionary),
@@ -166,7 +166,7 @@ impl XPCObject {
         match self {
             XPCObject::Bool(val) => {
                 buf.extend_from_slice(&(XPCType::Bool as u32).to_le_bytes());
-                buf.push(if *val { 0 } else { 1 });
+                buf.push(if *val { 1 } else { 0 });
                 buf.extend_from_slice(&[0].repeat(3));
             }
             XPCObject::Dictionary(dict) => {
@@ -216,8 +216,8 @@ impl XPCObject {
                 buf.extend_from_slice(&[0].repeat(padding));
             }
             XPCObject::Uuid(uuid) => {
+                // UUIDs are always 16 bytes, so no length is sent
                 buf.extend_from_slice(&(XPCType::Uuid as u32).to_le_bytes());
-                buf.extend_from_slice(&16_u32.to_le_bytes());
                 buf.extend_from_slice(uuid.as_bytes());
             }
         }
@@ -225,6 +225,9 @@ impl XPCObject {
     }
 
     pub fn decode(buf: &[u8]) -> Result<Self, XPCError> {
+        if buf.len() < 8 {
+            Err("XPCObject must be at least 8 bytes")?
+        }
         let magic = u32::from_le_bytes(buf[0..4].try_into()?);
         if magic != 0x42133742 {
             Err("Invalid magic for XPCObject")?
@@ -289,6 +292,7 @@ impl XPCObject {
                 cursor.read_exact(&mut buf_32)?;
                 let l = u32::from_le_bytes(buf_32) as usize;
                 let padding = Self::calculate_padding(l);
+                Self::check_remaining(cursor, l)?;
 
                 let mut key_buf = vec![0; l];
                 cursor.read_exact(&mut key_buf)?;
@@ -305,6 +309,7 @@ impl XPCObject {
                 cursor.read_exact(&mut buf_32)?;
                 let l = u32::from_le_bytes(buf_32) as usize;
                 let padding = Self::calculate_padding(l);
+                Self::check_remaining(cursor, l)?;
 
                 let mut data = vec![0; l];
                 cursor.read_exact(&mut data)?;
@@ -356,6 +361,15 @@ impl XPCObject {
         }
     }
 
+    /// Lengths come from the device, so they're checked before allocating for them
+    fn check_remaining(cursor: &Cursor<&[u8]>, len: usize) -> Result<(), XPCError> {
+        let remaining = (cursor.get_ref().len() as u64).saturating_sub(cursor.position());
+        if len as u64 > remaining {
+            Err("XPCObject length runs past the end of the buffer")?
+        }
+        Ok(())
+    }
+
     fn calculate_padding(len: usize) -> usize {
         let c = ((len as f64) / 4.0).ceil();
         (c * 4.0 - (len as f64)) as usize
@@ -368,6 +382,142 @@ impl From<Dictionary> for XPCObject {
     }
 }
 
+macro_rules! from_value {
+    ($($t:ty => $variant:ident),* $(,)?) => {
+        $(impl From<$t> for XPCObject {
+            fn from(value: $t) -> Self {
+                XPCObject::$variant(value.into())
+            }
+        })*
+    };
+}
+
+from_value! {
+    bool => Bool,
+    i64 => Int64,
+    i32 => Int64,
+    u64 => UInt64,
+    u32 => UInt64,
+    String => String,
+    &str => String,
+    Vec<u8> => Data,
+    &[u8] => Data,
+    uuid::Uuid => Uuid,
+    Vec<XPCObject> => Array,
+}
+
+/// Builds an `XPCObject` from a JSON-like literal.
+/// Keys and values that aren't literals, like variables or negative numbers,
+/// must be wrapped in parentheses.
+/// ```
+/// let name = "com.apple.coredevice.feature.listprocesses";
+/// let req = idevice::xpc!({
+///     "CoreDevice.featureIdentifier": (name),
+///     "CoreDevice.input": {},
+///     "CoreDevice.invocationIdentifier": (uuid::Uuid::new_v4()),
+///     "tries": [1u64, 2u64],
+/// });
+/// ```
+#[macro_export]
+macro_rules! xpc {
+    ({ $($key:tt : $value:tt),* $(,)? }) => {{
+        #[allow(unused_mut)]
+        let mut dict = $crate::xpc::format::Dictionary::new();
+        $(dict.insert(::std::string::String::from($key), $crate::xpc!($value));)*
+        $crate::xpc::format::XPCObject::Dictionary(dict)
+    }};
+    ([ $($value:tt),* $(,)? ]) => {
+        $crate::xpc::format::XPCObject::Array(::std::vec![$($crate::xpc!($value)),*])
+    };
+    ($value:expr) => {
+        $crate::xpc::format::XPCObject::from($value)
+    };
+}
+
+/// Typed lookups on XPC dictionaries that fail with the key that was missing or mistyped
+pub trait DictionaryExt {
+    fn get_object(&self, key: &str) -> Result<&XPCObject, XPCError>;
+    fn get_str(&self, key: &str) -> Result<&str, XPCError>;
+    fn get_bool(&self, key: &str) -> Result<bool, XPCError>;
+    /// Accepts unsigned values that fit
+    fn get_i64(&self, key: &str) -> Result<i64, XPCError>;
+    /// Accepts non-negative signed values
+    fn get_u64(&self, key: &str) -> Result<u64, XPCError>;
+    fn get_data(&self, key: &str) -> Result<&[u8], XPCError>;
+    fn get_uuid(&self, key: &str) -> Result<uuid::Uuid, XPCError>;
+    fn get_array(&self, key: &str) -> Result<&[XPCObject], XPCError>;
+    fn get_dict(&self, key: &str) -> Result<&Dictionary, XPCError>;
+}
+
+impl DictionaryExt for Dictionary {
+    fn get_object(&self, key: &str) -> Result<&XPCObject, XPCError> {
+        self.get(key)
+            .ok_or_else(|| XPCError::MissingKey(key.to_string()))
+    }
+
+    fn get_str(&self, key: &str) -> Result<&str, XPCError> {
+        match self.get_object(key)? {
+            XPCObject::String(s) => Ok(s),
+            _ => Err(XPCError::wrong_type(key, "string")),
+        }
+    }
+
+    fn get_bool(&self, key: &str) -> Result<bool, XPCError> {
+        match self.get_object(key)? {
+            XPCObject::Bool(b) => Ok(*b),
+            _ => Err(XPCError::wrong_type(key, "bool")),
+        }
+    }
+
+    fn get_i64(&self, key: &str) -> Result<i64, XPCError> {
+        match self.get_object(key)? {
+            XPCObject::Int64(v) => Ok(*v),
+            XPCObject::UInt64(v) => {
+                i64::try_from(*v).map_err(|_| XPCError::wrong_type(key, "int64"))
+            }
+            _ => Err(XPCError::wrong_type(key, "int64")),
+        }
+    }
+
+    fn get_u64(&self, key: &str) -> Result<u64, XPCError> {
+        match self.get_object(key)? {
+            XPCObject::UInt64(v) => Ok(*v),
+            XPCObject::Int64(v) => {
+                u64::try_from(*v).map_err(|_| XPCError::wrong_type(key, "uint64"))
+            }
+            _ => Err(XPCError::wrong_type(key, "uint64")),
+        }
+    }
+
+    fn get_data(&self, key: &str) -> Result<&[u8], XPCError> {
+        match self.get_object(key)? {
+            XPCObject::Data(d) => Ok(d),
+            _ => Err(XPCError::wrong_type(key, "data")),
+        }
+    }
+
+    fn get_uuid(&self, key: &str) -> Result<uuid::Uuid, XPCError> {
+        match self.get_object(key)? {
+            XPCObject::Uuid(u) => Ok(*u),
+            _ => Err(XPCError::wrong_type(key, "uuid")),
+        }
+    }
+
+    fn get_array(&self, key: &str) -> Result<&[XPCObject], XPCError> {
+        match self.get_object(key)? {
+            XPCObject::Array(a) => Ok(a),
+            _ => Err(XPCError::wrong_type(key, "array")),
+        }
+    }
+
+    fn get_dict(&self, key: &str) -> Result<&Dictionary, XPCError> {
+        match self.get_object(key)? {
+            XPCObject::Dictionary(d) => Ok(d),
+            _ => Err(XPCError::wrong_type(key, "dictionary")),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct XPCMessage {
     pub flags: u32,
@@ -401,7 +551,10 @@ impl XPCMessage {
         let flags = u32::from_le_bytes(data[4..8].try_into()?);
         let body_len = u64::from_le_bytes(data[8..16].try_into()?);
         let message_id = u64::from_le_bytes(data[16..24].try_into()?);
-        if body_len + 24 > data.len().try_into()? {
+        if body_len
+            .checked_add(24)
+            .is_none_or(|l| l > data.len() as u64)
+        {
             Err("XPCMessage body length given is incorrect.")?
         }
 
@@ -421,6 +574,14 @@ impl XPCMessage {
         })
     }
 
+    /// The length of the whole message according to its header, once the header has arrived
+    pub fn declared_size(data: &[u8]) -> Option<u64> {
+        if data.len() < 24 || data[0..4] != 0x29b00b92_u32.to_le_bytes() {
+            return None;
+        }
+        u64::from_le_bytes(data[8..16].try_into().ok()?).checked_add(24)
+    }
+
     pub fn encode(self, message_id: u64) -> Result<Vec<u8>, XPCError> {
         let mut out = 0x29b00b92_u32.to_le_bytes().to_vec();
         out.extend_from_slice(&self.flags.to_le_bytes());
@@ -439,3 +600,30 @@ impl XPCMessage {
         Ok(out)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn macro_and_getters() {
+        let count = -3i64;
+        let obj = crate::xpc!({
+            "name": "ping",
+            "count": (count),
+            "inner": { "ok": true },
+            "list": [1u64, "two"],
+        });
+        let dict = obj.as_dictionary().unwrap();
+        assert_eq!(dict.get_str("name").unwrap(), "ping");
+        assert_eq!(dict.get_i64("count").unwrap(), -3);
+        assert!(dict.get_dict("inner").unwrap().get_bool("ok").unwrap());
+        assert_eq!(dict.get_array("list").unwrap().len(), 2);
+
+        assert!(matches!(
+            dict.get_u64("count"),
+            Err(XPCError::WrongType { .. })
+        ));
+        assert!(matches!(dict.get_str("nope"), Err(XPCError::MissingKey(_))));
+    }
+}
@@ -6,7 +6,8 @@ use crate::http2::{
 };
 use error::XPCError;
 use format::{XPCFlag, XPCMessage, XPCObject};
-use log::debug;
+use log::{debug, warn};
+use std::time::Duration;
 use tokio::net::{TcpStream, ToSocketAddrs};
 
 pub mod cdtunnel;
@@ -15,12 +16,19 @@ pub mod format;
 
 pub struct XPCConnection {
     inner: http2::Connection,
+    max_message_size: usize,
+    reassembly_timeout: Option<Duration>,
+    discarded: u64,
+    /// Set when a message was abandoned partway through
+    desynced: bool,
 }
 
 impl XPCConnection {
     pub const ROOT_CHANNEL: u32 = http2::Connection::ROOT_CHANNEL;
     pub const REPLY_CHANNEL: u32 = http2::Connection::REPLY_CHANNEL;
     const INIT_STREAM: u32 = http2::Connection::INIT_STREAM;
+    pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+    pub const DEFAULT_REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(30);
 
     pub async fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self, XPCError> {
         Self::new(Box::new(TcpStream::connect(addr).await?)).await
@@ -42,7 +50,13 @@ impl XPCConnection {
         client
             .send_frame(WindowUpdateFrame::new(Self::INIT_STREAM, 983041))
             .await?;
-        let mut xpc_client = Self { inner: client };
+        let mut xpc_client = Self {
+            inner: client,
+            max_message_size: Self::DEFAULT_MAX_MESSAGE_SIZE,
+            reassembly_timeout: Some(Self::DEFAULT_REASSEMBLY_TIMEOUT),
+            discarded: 0,
+            desynced: false,
+        };
         xpc_client
             .send_recv_message(
                 Self::ROOT_CHANNEL,
@@ -90,23 +104,102 @@ impl XPCConnection {
         stream_id: u32,
         message: XPCMessage,
     ) -> Result<(), XPCError> {
+        if self.desynced {
+            return Err(XPCError::Desynced);
+        }
         self.inner
             .write_streamid(stream_id, message.encode(0)?)
             .await
             .map_err(|err| err.into())
     }
 
+    /// Sets the largest message `read_message` will reassemble. Bigger messages are
+    /// discarded with an error instead of being buffered.
+    pub fn set_max_message_size(&mut self, size: usize) {
+        self.max_message_size = size;
+    }
+
+    /// Sets how long `read_message` waits for the rest of a message once part of it
+    /// has arrived, or `None` to wait forever
+    pub fn set_reassembly_timeout(&mut self, timeout: Option<Duration>) {
+        self.reassembly_timeout = timeout;
+    }
+
+    /// How many empty frames and incomplete or oversized messages have been discarded
+    pub fn discarded_messages(&self) -> u64 {
+        self.discarded
+    }
+
+    /// Whether an abandoned message left the connection unusable, see `read_message`
+    pub fn is_desynced(&self) -> bool {
+        self.desynced
+    }
+
+    /// Reads a message, reassembling it from as many frames as it was split into.
+    /// A message that is too large or doesn't finish arriving in time isn't drained,
+    /// as the rest of it may never come, and a timeout can stop partway through a frame.
+    /// The connection is marked unusable instead, and every later call returns
+    /// `XPCError::Desynced` rather than decoding from the middle of that message.
     pub async fn read_message(&mut self, stream_id: u32) -> Result<XPCMessage, XPCError> {
+        if self.desynced {
+            return Err(XPCError::Desynced);
+        }
         let mut buf = self.inner.read_streamid(stream_id).await?;
         loop {
+            if buf.is_empty() {
+                debug!("Discarding empty frame on stream {stream_id}");
+                self.discarded += 1;
+                buf = self.inner.read_streamid(stream_id).await?;
+                continue;
+            }
             match XPCMessage::decode(&buf) {
                 Ok(decoded) => {
                     debug!("Decoded message: {:?}", decoded);
                     return Ok(decoded);
                 }
                 Err(err) => {
-                    log::error!("Error decoding message: {:?}", err);
-                    buf.extend_from_slice(&self.inner.read_streamid(stream_id).await?);
+                    // More data won't fix a message that's all here or has a bad header
+                    match XPCMessage::declared_size(&buf) {
+                        Some(size) if size <= buf.len() as u64 => {
+                            self.discarded += 1;
+                            return Err(err);
+                        }
+                        None if buf.len() >= 24 => {
+                            self.discarded += 1;
+                            return Err(err);
+                        }
+                        _ => {}
+                    }
+                    let size = XPCMessage::declared_size(&buf)
+                        .unwrap_or_default()
+                        .max(buf.len() as u64);
+                    if size > self.max_message_size as u64 {
+                        warn!(
+                            "Discarding a {size} byte message, larger than {}",
+                            self.max_message_size
+                        );
+                        self.discarded += 1;
+                        self.desynced = true;
+                        return Err(XPCError::MessageTooLarge {
+                            size,
+                            max: self.max_message_size,
+                        });
+                    }
+                    debug!("Waiting for the rest of a message: {:?}", err);
+                    let next = self.inner.read_streamid(stream_id);
+                    let next = match self.reassembly_timeout {
+                        Some(timeout) => match tokio::time::timeout(timeout, next).await {
+                            Ok(next) => next,
+                            Err(_) => {
+                                warn!("Timed out reassembling a message on stream {stream_id}");
+                                self.discarded += 1;
+                                self.desynced = true;
+                                return Err(XPCError::Timeout);
+                            }
+                        },
+                        None => next.await,
+                    };
+                    buf.extend_from_slice(&next?);
                 }
             }
         }
@@ -116,6 +209,91 @@ impl XPCConnection {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::http2::h2::{DataFrame, Framable};
+    use tokio::io::AsyncWriteExt;
+
+    async fn test_connection() -> (XPCConnection, tokio::io::DuplexStream) {
+        let (client, server) = tokio::io::duplex(1 << 16);
+        let client = XPCConnection {
+            inner: http2::Connection::new(Box::new(client)).await.unwrap(),
+            max_message_size: 1024,
+            reassembly_timeout: Some(Duration::from_millis(50)),
+            discarded: 0,
+            desynced: false,
+        };
+        (client, server)
+    }
+
+    fn frame(data: Vec<u8>) -> Vec<u8> {
+        DataFrame::new(1, data, Default::default()).serialize()
+    }
+
+    fn test_message() -> Vec<u8> {
+        XPCMessage::new(
+            Some(XPCFlag::AlwaysSet),
+            Some(XPCObject::Dictionary(Default::default())),
+            None,
+        )
+        .encode(0)
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn reassembles_and_limits_messages() {
+        let (mut client, mut server) = test_connection().await;
+        let message = test_message();
+        let (a, b) = message.split_at(20);
+        server.write_all(&frame(Vec::new())).await.unwrap();
+        server.write_all(&frame(a.to_vec())).await.unwrap();
+        server.write_all(&frame(b.to_vec())).await.unwrap();
+        let decoded = client.read_message(1).await.unwrap();
+        assert!(decoded.message.is_some());
+        assert_eq!(client.discarded_messages(), 1);
+
+        let mut huge = message[..24].to_vec();
+        huge[8..16].copy_from_slice(&(1u64 << 20).to_le_bytes());
+        server.write_all(&frame(huge)).await.unwrap();
+        assert!(matches!(
+            client.read_message(1).await,
+            Err(XPCError::MessageTooLarge { .. })
+        ));
+        assert_eq!(client.discarded_messages(), 2);
+
+        let (mut client, mut server) = test_connection().await;
+        server.write_all(&frame(a.to_vec())).await.unwrap();
+        assert!(matches!(
+            client.read_message(1).await,
+            Err(XPCError::Timeout)
+        ));
+        assert_eq!(client.discarded_messages(), 1);
+    }
+
+    #[tokio::test]
+    async fn unusable_after_abandoned_message() {
+        let (mut client, mut server) = test_connection().await;
+        let message = test_message();
+        let (a, b) = message.split_at(20);
+        server.write_all(&frame(a.to_vec())).await.unwrap();
+        assert!(matches!(
+            client.read_message(1).await,
+            Err(XPCError::Timeout)
+        ));
+        assert!(client.is_desynced());
+
+        // The rest of the abandoned message and a whole one arrive late
+        server.write_all(&frame(b.to_vec())).await.unwrap();
+        server.write_all(&frame(message.clone())).await.unwrap();
+        assert!(matches!(
+            client.read_message(1).await,
+            Err(XPCError::Desynced)
+        ));
+        assert!(matches!(
+            client
+                .send_message(1, XPCMessage::new(Some(XPCFlag::AlwaysSet), None, None))
+                .await,
+            Err(XPCError::Desynced)
+        ));
+    }
 
     #[tokio::test]
     async fn it_works() {
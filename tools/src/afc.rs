@@ -0,0 +1,310 @@
+// Jackson Coxson
+// Browse and transfer files over AFC
+
+use std::{io::Read, time::Instant};
+
+use clap::{Arg, Command};
+use idevice::{
+    afc::{opcode::AfcFopenMode, AfcClient},
+    house_arrest::HouseArrestClient,
+    IdeviceError, IdeviceService,
+};
+
+mod common;
+
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+
+    let matches = Command::new("afc")
+        .about("Manage files on the device")
+        .arg(
+            Arg::new("host")
+                .long("host")
+                .value_name("HOST")
+                .help("IP address of the device"),
+        )
+        .arg(
+            Arg::new("pairing_file")
+                .long("pairing-file")
+                .value_name("PATH")
+                .help("Path to the pairing file"),
+        )
+        .arg(
+            Arg::new("udid")
+                .long("udid")
+                .value_name("UDID")
+                .help("UDID of the device (overrides host/pairing file)"),
+        )
+        .arg(
+            Arg::new("afc2")
+                .long("afc2")
+                .help("Use afc2 for full filesystem access on jailbroken devices")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("documents")
+                .long("documents")
+                .value_name("BUNDLE_ID")
+                .help("Access an app's Documents directory")
+                .conflicts_with_all(["afc2", "container"]),
+        )
+        .arg(
+            Arg::new("container")
+                .long("container")
+                .value_name("BUNDLE_ID")
+                .help("Access a debuggable app's whole container")
+                .conflicts_with("afc2"),
+        )
+        .arg(
+            Arg::new("about")
+                .long("about")
+                .help("Show about information")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .subcommand(
+            Command::new("list")
+                .about("Lists the items in the directory")
+                .arg(Arg::new("path").required(true).index(1)),
+        )
+        .subcommand(
+            Command::new("download")
+                .about("Downloads a file")
+                .arg(Arg::new("path").required(true).index(1))
+                .arg(Arg::new("save").required(true).index(2)),
+        )
+        .subcommand(
+            Command::new("upload")
+                .about("Uploads a file")
+                .arg(Arg::new("file").required(true).index(1))
+                .arg(Arg::new("path").required(true).index(2)),
+        )
+        .subcommand(
+            Command::new("mkdir")
+                .about("Creates a directory")
+                .arg(Arg::new("path").required(true).index(1)),
+        )
+        .subcommand(
+            Command::new("remove")
+                .about("Removes a file or empty directory")
+                .arg(Arg::new("path").required(true).index(1)),
+        )
+        .subcommand(
+            Command::new("info")
+                .about("Gets info about a file")
+                .arg(Arg::new("path").required(true).index(1)),
+        )
+        .subcommand(Command::new("device_info").about("Gets info about the device"))
+        .subcommand(
+            Command::new("batch")
+                .about("Runs put, get, rm and mkdir operations read from stdin, one per line")
+                .arg(
+                    Arg::new("stop_on_error")
+                        .long("stop-on-error")
+                        .help("Stop at the first failed operation")
+                        .action(clap::ArgAction::SetTrue),
+                ),
+        )
+        .get_matches();
+
+    if matches.get_flag("about") {
+        println!("afc - manage files on the device");
+        println!("Copyright (c) 2025 Jackson Coxson");
+        return;
+    }
+
+    let udid = matches.get_one::<String>("udid");
+    let host = matches.get_one::<String>("host");
+    let pairing_file = matches.get_one::<String>("pairing_file");
+
+    let provider = match common::get_provider(udid, host, pairing_file, "afc-jkcoxson").await {
+        Ok(p) => p,
+        Err(e) => {
+            eprintln!("{e}");
+            return;
+        }
+    };
+
+    let mut afc_client = if let Some(bundle_id) = matches.get_one::<String>("documents") {
+        HouseArrestClient::connect(&*provider)
+            .await
+            .expect("Unable to connect to house_arrest")
+            .vend_documents(bundle_id)
+            .await
+    } else if let Some(bundle_id) = matches.get_one::<String>("container") {
+        HouseArrestClient::connect(&*provider)
+            .await
+            .expect("Unable to connect to house_arrest")
+            .vend_container(bundle_id)
+            .await
+    } else if matches.get_flag("afc2") {
+        AfcClient::connect_with_service_name(&*provider, AfcClient::AFC2_SERVICE_NAME).await
+    } else {
+        AfcClient::connect(&*provider).await
+    }
+    .expect("Unable to connect to afc");
+
+    if let Some(matches) = matches.subcommand_matches("list") {
+        let path = matches.get_one::<String>("path").expect("No path passed");
+        let res = afc_client.list_dir(path).await.expect("Failed to read dir");
+        println!("{path}\n{res:#?}");
+    } else if let Some(matches) = matches.subcommand_matches("download") {
+        let path = matches.get_one::<String>("path").expect("No path passed");
+        let save = matches.get_one::<String>("save").expect("No path passed");
+
+        let mut file = afc_client
+            .open(path, AfcFopenMode::RdOnly)
+            .await
+            .expect("Failed to open");
+        let res = file.read().await.expect("Failed to read file");
+        file.close().await.expect("Failed to close file");
+        tokio::fs::write(save, res)
+            .await
+            .expect("Failed to write to file");
+    } else if let Some(matches) = matches.subcommand_matches("upload") {
+        let file = matches.get_one::<String>("file").expect("No path passed");
+        let path = matches.get_one::<String>("path").expect("No path passed");
+
+        let bytes = tokio::fs::read(file).await.expect("Failed to read file");
+        let mut file = afc_client
+            .open(path, AfcFopenMode::WrOnly)
+            .await
+            .expect("Failed to open");
+        file.write(&bytes).await.expect("Failed to upload bytes");
+        file.close().await.expect("Failed to close file");
+    } else if let Some(matches) = matches.subcommand_matches("mkdir") {
+        let path = matches.get_one::<String>("path").expect("No path passed");
+        afc_client.mk_dir(path).await.expect("Failed to mkdir");
+    } else if let Some(matches) = matches.subcommand_matches("remove") {
+        let path = matches.get_one::<String>("path").expect("No path passed");
+        afc_client.remove(path).await.expect("Failed to remove");
+    } else if let Some(matches) = matches.subcommand_matches("info") {
+        let path = matches.get_one::<String>("path").expect("No path passed");
+        let res = afc_client
+            .get_file_info(path)
+            .await
+            .expect("Failed to get file info");
+        println!("{res:#?}");
+    } else if matches.subcommand_matches("device_info").is_some() {
+        let res = afc_client
+            .get_device_info()
+            .await
+            .expect("Failed to get device info");
+        println!("{res:#?}");
+    } else if let Some(matches) = matches.subcommand_matches("batch") {
+        let mut script = String::new();
+        std::io::stdin()
+            .read_to_string(&mut script)
+            .expect("Failed to read stdin");
+        run_batch(&mut afc_client, &script, matches.get_flag("stop_on_error")).await;
+    } else {
+        eprintln!("Invalid usage, pass -h for help");
+    }
+}
+
+/// Runs each line of the script over the one connection:
+/// `put <local> <remote>`, `get <remote> <local>`, `rm <path>` or `mkdir <path>`.
+/// Paths with spaces can be quoted, and lines starting with `#` are skipped.
+async fn run_batch(afc_client: &mut AfcClient, script: &str, stop_on_error: bool) {
+    let ops: Vec<(usize, Vec<String>)> = script
+        .lines()
+        .enumerate()
+        .map(|(i, l)| (i + 1, split_line(l)))
+        .filter(|(_, words)| !words.is_empty() && !words[0].starts_with('#'))
+        .collect();
+
+    let started = Instant::now();
+    let (mut succeeded, mut failed, mut bytes) = (0, 0, 0);
+    for (n, (line, words)) in ops.iter().enumerate() {
+        let res = run_op(afc_client, words).await;
+        println!(
+            "[{}/{}] {} ... {}",
+            n + 1,
+            ops.len(),
+            words.join(" "),
+            match &res {
+                Ok(_) => "ok".to_string(),
+                Err(e) => format!("failed on line {line}: {e}"),
+            }
+        );
+        match res {
+            Ok(b) => {
+                succeeded += 1;
+                bytes += b;
+            }
+            Err(_) => {
+                failed += 1;
+                if stop_on_error {
+                    break;
+                }
+            }
+        }
+    }
+    println!(
+        "{succeeded} succeeded, {failed} failed, {bytes} bytes transferred in {:.1}s",
+        started.elapsed().as_secs_f64()
+    );
+}
+
+/// Runs one operation, returning the bytes transferred
+async fn run_op(afc_client: &mut AfcClient, words: &[String]) -> Result<usize, String> {
+    let words: Vec<&str> = words.iter().map(|w| w.as_str()).collect();
+    let err = |e: IdeviceError| format!("{e:?}");
+    match words.as_slice() {
+        ["put", local, remote] => {
+            let bytes = tokio::fs::read(local).await.map_err(|e| e.to_string())?;
+            let mut file = afc_client
+                .open(*remote, AfcFopenMode::WrOnly)
+                .await
+                .map_err(err)?;
+            file.write(&bytes).await.map_err(err)?;
+            file.close().await.map_err(err)?;
+            Ok(bytes.len())
+        }
+        ["get", remote, local] => {
+            let mut file = afc_client
+                .open(*remote, AfcFopenMode::RdOnly)
+                .await
+                .map_err(err)?;
+            let bytes = file.read().await.map_err(err)?;
+            file.close().await.map_err(err)?;
+            tokio::fs::write(local, &bytes)
+                .await
+                .map_err(|e| e.to_string())?;
+            Ok(bytes.len())
+        }
+        ["rm", path] => afc_client.remove(*path).await.map(|_| 0).map_err(err),
+        ["mkdir", path] => afc_client.mk_dir(*path).await.map(|_| 0).map_err(err),
+        _ => Err("unknown operation".to_string()),
+    }
+}
+
+/// Splits on whitespace, keeping double quoted words together
+fn split_line(line: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut quoted = false;
+    let mut in_word = false;
+    for c in line.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                in_word = true;
+            }
+            c if c.is_whitespace() && !quoted => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            c => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if in_word {
+        words.push(word);
+    }
+    words
+}
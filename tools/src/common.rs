@@ -15,8 +15,7 @@ pub async fn get_provider(
     pairing_file: Option<&String>,
     label: &str,
 ) -> Result<Box<dyn IdeviceProvider>, String> {
-    let provider: Box<dyn IdeviceProvider> = if udid.is_some() {
-        let udid = udid.unwrap();
+    let provider: Box<dyn IdeviceProvider> = if let Some(udid) = udid {
         let mut usbmuxd = UsbmuxdConnection::default()
             .await
             .expect("Unable to connect to usbmxud");
@@ -27,14 +26,14 @@ pub async fn get_provider(
             }
         };
         Box::new(dev.to_provider(UsbmuxdAddr::default(), 1, label))
-    } else if host.is_some() && pairing_file.is_some() {
-        let host = match IpAddr::from_str(host.unwrap()) {
+    } else if let (Some(host), Some(pairing_file)) = (host, pairing_file) {
+        let host = match IpAddr::from_str(host) {
             Ok(h) => h,
             Err(e) => {
                 return Err(format!("Invalid host: {e:?}"));
             }
         };
-        let pairing_file = match PairingFile::read_from_file(pairing_file.unwrap()) {
+        let pairing_file = match PairingFile::read_from_file(pairing_file) {
             Ok(p) => p,
             Err(e) => {
                 return Err(format!("Unable to read pairing file: {e:?}"));
@@ -44,7 +43,9 @@ pub async fn get_provider(
         Box::new(TcpProvider {
             addr: host,
             pairing_file,
-            label: "ideviceinfo-jkcoxson".to_string(),
+            metadata: label.into(),
+            verify_udid: false,
+            pairing_file_source: None,
         })
     } else {
         let mut usbmuxd = UsbmuxdConnection::default()
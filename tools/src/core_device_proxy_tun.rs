@@ -2,10 +2,9 @@
 
 use clap::{Arg, Command};
 use idevice::{
-    core_device_proxy::{self},
+    core_device_proxy::{self, os_tun::OsTun},
     IdeviceService,
 };
-use tun_rs::AbstractDevice;
 
 mod common;
 
@@ -64,36 +63,15 @@ async fn main() {
         .expect("Unable to connect");
     let response = tun_proxy.establish_tunnel().await.unwrap();
 
-    let dev = tun_rs::create(&tun_rs::Configuration::default()).unwrap();
-    dev.add_address_v6(response.client_parameters.address.parse().unwrap(), 32)
-        .unwrap();
-    dev.set_mtu(response.client_parameters.mtu).unwrap();
-    dev.set_network_address(
-        response.client_parameters.address,
-        response.client_parameters.netmask.parse().unwrap(),
-        Some(response.server_address.parse().unwrap()),
-    )
-    .unwrap();
-
-    let async_dev = tun_rs::AsyncDevice::new(dev).unwrap();
-    async_dev.enabled(true).unwrap();
+    let tun = OsTun::create(tun_proxy).expect("Unable to create the TUN interface");
     println!("-----------------------------");
-    println!("tun device created: {:?}", async_dev.name());
+    println!("tun device created: {:?}", tun.name());
     println!("server address: {}", response.server_address);
     println!("rsd port: {}", response.server_rsd_port);
+    println!("mtu: {}", tun.parameters().mtu);
     println!("-----------------------------");
 
-    let mut buf = vec![0; 1500];
-    loop {
-        tokio::select! {
-            Ok(len) = async_dev.recv(&mut buf) => {
-                println!("tun pkt: {:?}", &buf[..len]);
-                tun_proxy.send(&buf[..len]).await.unwrap();
-            }
-            Ok(res) = tun_proxy.recv() => {
-                println!("dev pkt: {:?}", &res);
-                async_dev.send(&res).await.unwrap();
-            }
-        }
+    if let Err(e) = tun.run().await {
+        eprintln!("Tunnel stopped: {e:?}");
     }
 }
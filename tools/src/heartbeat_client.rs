@@ -2,6 +2,7 @@
 // Heartbeat client
 
 use clap::{Arg, Command};
+use futures::StreamExt;
 use idevice::{heartbeat::HeartbeatClient, IdeviceService};
 
 mod common;
@@ -55,13 +56,13 @@ async fn main() {
                 return;
             }
         };
-    let mut heartbeat_client = HeartbeatClient::connect(&*provider)
+    let heartbeat_client = HeartbeatClient::connect(&*provider)
         .await
         .expect("Unable to connect to heartbeat");
 
-    let mut interval = 15;
-    loop {
-        interval = heartbeat_client.get_marco(interval).await.unwrap();
-        heartbeat_client.send_polo().await.unwrap();
+    let (stream, _stop) = heartbeat_client.into_stream(15);
+    let mut stream = Box::pin(stream);
+    while let Some(interval) = stream.next().await {
+        println!("Next heartbeat in {}s", interval.unwrap());
     }
 }
@@ -1,8 +1,13 @@
 // Jackson Coxson
 // idevice Rust implementation of libimobiledevice's ideviceinfo
 
+use std::{collections::HashMap, time::Duration};
+
 use clap::{Arg, Command};
-use idevice::{lockdownd::LockdowndClient, pairing_file::PairingFile, IdeviceService};
+use idevice::{
+    lockdownd::LockdowndClient, notification_proxy::NotificationProxyClient,
+    pairing_file::PairingFile, provider::IdeviceProvider, IdeviceService,
+};
 
 mod common;
 
@@ -30,6 +35,33 @@ async fn main() {
                 .help("UDID of the device (overrides host/pairing file)")
                 .index(1),
         )
+        .arg(
+            Arg::new("watch")
+                .long("watch")
+                .help("Keep refreshing the values and print what changes")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("key")
+                .long("key")
+                .value_name("KEY")
+                .help("A key to watch instead of all of them, can be repeated")
+                .action(clap::ArgAction::Append),
+        )
+        .arg(
+            Arg::new("domain")
+                .long("domain")
+                .value_name("DOMAIN")
+                .help("The domain of the watched keys, such as com.apple.mobile.battery"),
+        )
+        .arg(
+            Arg::new("interval")
+                .long("interval")
+                .value_name("SECONDS")
+                .help("Seconds between refreshes in watch mode")
+                .value_parser(clap::value_parser!(u64))
+                .default_value("5"),
+        )
         .arg(
             Arg::new("about")
                 .long("about")
@@ -57,6 +89,19 @@ async fn main() {
             }
         };
 
+    if matches.get_flag("watch") {
+        let keys = matches
+            .get_many::<String>("key")
+            .map(|k| k.cloned().collect())
+            .unwrap_or_default();
+        let domain = matches.get_one::<String>("domain").cloned();
+        let interval = Duration::from_secs(*matches.get_one::<u64>("interval").unwrap());
+        if let Err(e) = watch(&*provider, keys, domain, interval).await {
+            eprintln!("Watching failed: {e:?}");
+        }
+        return;
+    }
+
     let mut lockdown_client = match LockdowndClient::connect(&*provider).await {
         Ok(l) => l,
         Err(e) => {
@@ -72,3 +117,84 @@ async fn main() {
     println!("{:?}", lockdown_client.idevice.get_type().await.unwrap());
     println!("{:#?}", lockdown_client.get_all_values().await);
 }
+
+/// Refreshes the values every interval, or right away when a notification arrives,
+/// and prints the ones that changed
+async fn watch(
+    provider: &dyn IdeviceProvider,
+    keys: Vec<String>,
+    domain: Option<String>,
+    interval: Duration,
+) -> Result<(), idevice::IdeviceError> {
+    if domain.is_some() && keys.is_empty() {
+        eprintln!("--domain needs at least one --key");
+        return Ok(());
+    }
+
+    let mut lockdown = LockdowndClient::connect(provider).await?;
+    lockdown
+        .start_session(&provider.get_pairing_file().await?)
+        .await?;
+
+    // Reading is done on its own task so a refresh can't cut a notification off halfway
+    let mut notifications = NotificationProxyClient::connect(provider).await?;
+    notifications.observe_all().await?;
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Ok(name) = notifications.next_notification().await {
+            if tx.send(name).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut notifying = true;
+    let mut previous: HashMap<String, _> = HashMap::new();
+    loop {
+        let current: HashMap<String, _> = if keys.is_empty() {
+            lockdown.get_all_values().await?.into_iter().collect()
+        } else {
+            let mut values = HashMap::new();
+            for key in &keys {
+                let value = match &domain {
+                    Some(domain) => lockdown.get_value_in_domain(key.as_str(), domain).await,
+                    None => lockdown.get_value(key.as_str()).await,
+                };
+                match value {
+                    Ok(v) => {
+                        values.insert(key.clone(), v);
+                    }
+                    Err(e) => eprintln!("Unable to get {key}: {e:?}"),
+                }
+            }
+            values
+        };
+
+        let mut changed: Vec<_> = current
+            .iter()
+            .filter(|(k, v)| previous.get(*k) != Some(*v))
+            .collect();
+        changed.sort_by(|a, b| a.0.cmp(b.0));
+        for (key, value) in changed {
+            match previous.get(key) {
+                Some(old) => println!("{key}: {old:?} -> {value:?}"),
+                None => println!("{key}: {value:?}"),
+            }
+        }
+        for key in previous.keys().filter(|k| !current.contains_key(*k)) {
+            println!("{key}: removed");
+        }
+        previous = current;
+
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => {}
+            name = rx.recv(), if notifying => match name {
+                Some(name) => println!("Notification: {name}"),
+                None => {
+                    eprintln!("Notifications stopped, only refreshing on the interval");
+                    notifying = false;
+                }
+            },
+        }
+    }
+}
@@ -2,7 +2,12 @@
 // Just lists apps for now
 
 use clap::{Arg, Command};
-use idevice::{installation_proxy::InstallationProxyClient, IdeviceService};
+use idevice::{
+    installation_proxy::{
+        ApplicationType, ClientOptions, InstallationProxyClient, ReturnAttributes,
+    },
+    IdeviceService,
+};
 
 mod common;
 
@@ -60,11 +65,15 @@ async fn main() {
     let mut instproxy_client = InstallationProxyClient::connect(&*provider)
         .await
         .expect("Unable to connect to instproxy");
-    let apps = instproxy_client
-        .get_apps(Some("User".to_string()), None)
-        .await
-        .unwrap();
-    for app in apps.keys() {
-        println!("{app}");
+    let options = ClientOptions::new()
+        .application_type(ApplicationType::User)
+        .attributes(ReturnAttributes::Standard);
+    let apps = instproxy_client.browse(options).await.unwrap();
+    for app in apps {
+        println!(
+            "{} {}",
+            app.bundle_id,
+            app.version.as_deref().unwrap_or_default()
+        );
     }
 }
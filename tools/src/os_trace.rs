@@ -0,0 +1,119 @@
+// Jackson Coxson
+// Streams the device's unified log as NDJSON
+
+use clap::{value_parser, Arg, Command};
+use futures::StreamExt;
+use idevice::{
+    os_trace_relay::{ActivityOptions, OsTraceRelayClient},
+    IdeviceService,
+};
+
+mod common;
+
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+    let matches = Command::new("os_trace")
+        .about("Stream the unified log as NDJSON")
+        .arg(
+            Arg::new("host")
+                .long("host")
+                .value_name("HOST")
+                .help("IP address of the device"),
+        )
+        .arg(
+            Arg::new("pairing_file")
+                .long("pairing-file")
+                .value_name("PATH")
+                .help("Path to the pairing file"),
+        )
+        .arg(
+            Arg::new("udid")
+                .value_name("UDID")
+                .help("UDID of the device (overrides host/pairing file)")
+                .index(1),
+        )
+        .arg(
+            Arg::new("pid")
+                .long("pid")
+                .value_name("PID")
+                .value_parser(value_parser!(u32))
+                .help("Only show messages from this process"),
+        )
+        .arg(
+            Arg::new("process")
+                .long("process")
+                .value_name("NAME")
+                .help("Only show messages from processes with this name"),
+        )
+        .arg(
+            Arg::new("match")
+                .long("match")
+                .value_name("STRING")
+                .action(clap::ArgAction::Append)
+                .help("Only show messages containing this string, can be repeated"),
+        )
+        .arg(
+            Arg::new("ignore_case")
+                .long("ignore-case")
+                .help("Match strings without regard to case")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("about")
+                .long("about")
+                .help("Show about information")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .get_matches();
+
+    if matches.get_flag("about") {
+        println!("os_trace - stream the unified log in the format of `log show --style ndjson`");
+        println!("Copyright (c) 2025 Jackson Coxson");
+        return;
+    }
+
+    let udid = matches.get_one::<String>("udid");
+    let host = matches.get_one::<String>("host");
+    let pairing_file = matches.get_one::<String>("pairing_file");
+
+    let provider = match common::get_provider(udid, host, pairing_file, "os_trace-jkcoxson").await {
+        Ok(p) => p,
+        Err(e) => {
+            eprintln!("{e}");
+            return;
+        }
+    };
+    let client = OsTraceRelayClient::connect(&*provider)
+        .await
+        .expect("Unable to connect to os_trace_relay");
+
+    let mut options = ActivityOptions::new().ignore_case(matches.get_flag("ignore_case"));
+    if let Some(pid) = matches.get_one::<u32>("pid") {
+        options = options.pid(*pid);
+    }
+    if let Some(name) = matches.get_one::<String>("process") {
+        options = options.process_name(name);
+    }
+    for s in matches.get_many::<String>("match").unwrap_or_default() {
+        options = options.matching(s);
+    }
+
+    let (stream, _stop) = client
+        .start_activity_with(options)
+        .await
+        .expect("Unable to start the log stream");
+    let mut stream = Box::pin(stream);
+    let mut stdout = std::io::stdout().lock();
+    while let Some(entry) = stream.next().await {
+        match entry {
+            Ok(entry) => entry
+                .write_ndjson(&mut stdout)
+                .expect("Unable to write to stdout"),
+            Err(e) => {
+                eprintln!("Log stream ended: {e:?}");
+                return;
+            }
+        }
+    }
+}